@@ -0,0 +1,463 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::checksum::{self, HashAlgorithm};
+use crate::writer::WriteSummary;
+
+const CHUNK_SIZE: u64 = 1024 * 1024;
+const SECTOR_SIZE: usize = 512;
+
+// A bmaptool block map: which blocks of an image hold data, with a checksum
+// for every run of them.
+pub struct Bmap {
+    pub image_size: u64,
+    pub block_size: u64,
+    pub blocks_count: u64,
+    pub ranges: Vec<BlockRange>,
+    algorithm: HashAlgorithm,
+}
+
+pub struct BlockRange {
+    pub first: u64,
+    pub last: u64,
+    checksum: Option<String>,
+}
+
+impl BlockRange {
+    fn check(&self, digest: &[u8]) -> io::Result<()> {
+        match &self.checksum {
+            Some(expected) if checksum::hex(digest) != expected.to_ascii_lowercase() => Err(invalid(&format!(
+                "Blocks {}-{} of the image do not match their checksum in the block map",
+                self.first, self.last
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Bmap {
+    pub fn mapped_size(&self) -> u64 {
+        self.ranges.iter().map(|range| self.extent(range).1).sum()
+    }
+
+    // Where a range lies in the image, in bytes. The last block may stop
+    // short at the end of the image; whatever lies past it, or past what a
+    // u64 holds, is cut off.
+    fn extent(&self, range: &BlockRange) -> (u64, u64) {
+        let offset = |block: u64| {
+            block
+                .checked_mul(self.block_size)
+                .map_or(self.image_size, |offset| offset.min(self.image_size))
+        };
+        let start = offset(range.first);
+        let end = range.last.checked_add(1).map_or(self.image_size, offset);
+        (start, end.saturating_sub(start))
+    }
+}
+
+// Version 1.x lists SHA-1 checksums; 1.4 and 2.x name the algorithm in
+// `ChecksumType`. Either way the file checksums itself with the value
+// zeroed, which catches a map that was edited or cut short.
+pub fn read_bmap(path: &Path) -> io::Result<Bmap> {
+    let bytes = fs::read(path)?;
+    parse_bmap(&String::from_utf8_lossy(&bytes))
+}
+
+fn parse_bmap(text: &str) -> io::Result<Bmap> {
+    let xml = strip_comments(text);
+
+    let version = attribute(&xml, "bmap", "version").ok_or_else(|| invalid("Not a block map"))?;
+    if !matches!(version.split('.').next(), Some("1" | "2")) {
+        return Err(invalid(&format!("Block map version {} is not supported", version)));
+    }
+
+    let algorithm = match element(&xml, "ChecksumType").as_deref() {
+        None | Some("sha1") => HashAlgorithm::Sha1,
+        Some("sha256") => HashAlgorithm::Sha256,
+        Some(other) => return Err(invalid(&format!("Unsupported block map checksum type {}", other))),
+    };
+    let number = |tag: &str| {
+        element(&xml, tag)
+            .and_then(|value| value.parse::<u64>().ok())
+            .ok_or_else(|| invalid(&format!("The block map has no valid {}", tag)))
+    };
+    let image_size = number("ImageSize")?;
+    let block_size = number("BlockSize")?;
+    let blocks_count = number("BlocksCount")?;
+    if block_size == 0 || !block_size.is_multiple_of(SECTOR_SIZE as u64) {
+        return Err(invalid(&format!("Block size {} is not a whole number of sectors", block_size)));
+    }
+    if blocks_count.checked_mul(block_size).is_none_or(|size| size < image_size) {
+        return Err(invalid(&format!(
+            "{} blocks of {} bytes do not make up a {} byte image",
+            blocks_count, block_size, image_size
+        )));
+    }
+
+    if let Some(expected) = element(&xml, "BmapFileChecksum").or_else(|| element(&xml, "BmapFileSHA1")) {
+        let zeroed = text.replacen(&expected, &"0".repeat(expected.len()), 1);
+        if checksum::hash_bytes(algorithm, zeroed.as_bytes()) != expected.to_ascii_lowercase() {
+            return Err(invalid("The block map is damaged: its own checksum does not match"));
+        }
+    }
+
+    let mut ranges = Vec::new();
+    let mut rest = xml.as_str();
+    while let Some(start) = rest.find("<Range") {
+        rest = &rest[start..];
+        let tag_end = rest.find('>').ok_or_else(|| invalid("The block map is damaged"))?;
+        let end = rest.find("</Range>").ok_or_else(|| invalid("The block map is damaged"))?;
+        let tag = &rest[..tag_end];
+        let blocks = rest[tag_end + 1..end].trim();
+        let checksum = tag_attribute(tag, "chksum").or_else(|| tag_attribute(tag, "sha1"));
+
+        let (first, last) = blocks.split_once('-').unwrap_or((blocks, blocks));
+        let (Ok(first), Ok(last)) = (first.trim().parse::<u64>(), last.trim().parse::<u64>()) else {
+            return Err(invalid(&format!("\"{}\" is not a block range", blocks)));
+        };
+        // Blocks are counted over the whole image, so the count bounds the
+        // end of a range but not its start.
+        if first > last || last >= blocks_count || first * block_size >= image_size {
+            return Err(invalid(&format!("Block range {} lies outside the image", blocks)));
+        }
+        // The image is read front to back, so the ranges must be in order.
+        if ranges.last().is_some_and(|previous: &BlockRange| previous.last >= first) {
+            return Err(invalid(&format!("Block range {} is out of order", blocks)));
+        }
+        ranges.push(BlockRange { first, last, checksum });
+        rest = &rest[end..];
+    }
+
+    Ok(Bmap {
+        image_size,
+        block_size,
+        blocks_count,
+        ranges,
+        algorithm,
+    })
+}
+
+// Looks for the block map bmaptool users keep next to the image:
+// `image.wic.bz2.bmap`, `image.wic.bmap` or `image.bmap`.
+pub fn find_bmap(image: &Path) -> Option<PathBuf> {
+    let mut name = image.file_name()?.to_str()?;
+    loop {
+        let candidate = image.with_file_name(format!("{}.bmap", name));
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        name = name.rsplit_once('.')?.0;
+    }
+}
+
+// Copies only the mapped ranges of the image to the same offsets on the
+// target, checking each against its checksum. The gaps are read past, which
+// for compressed images is the only way forward anyway, but never written.
+pub fn write_mapped<R: Read + ?Sized, W: Write + Seek + ?Sized>(
+    source: &mut R,
+    bmap: &Bmap,
+    target: &mut W,
+) -> io::Result<WriteSummary> {
+    let mut buffer = vec![0; CHUNK_SIZE as usize];
+    let mut position = 0u64;
+    let mut written = 0u64;
+    for range in &bmap.ranges {
+        let (start, length) = bmap.extent(range);
+        let skipped = io::copy(&mut source.take(start - position), &mut io::sink())?;
+        if position + skipped < start {
+            return Err(truncated());
+        }
+
+        target.seek(SeekFrom::Start(start))?;
+        let mut hasher = bmap.algorithm.hasher();
+        let mut remaining = length;
+        while remaining > 0 {
+            let filled = remaining.min(CHUNK_SIZE) as usize;
+            source.read_exact(&mut buffer[..filled]).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => truncated(),
+                _ => e,
+            })?;
+            hasher.update(&buffer[..filled]);
+
+            // Only the image's own tail can end mid-sector.
+            let padded = filled.div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+            buffer[filled..padded].fill(0);
+            target.write_all(&buffer[..padded])?;
+            remaining -= filled as u64;
+            written += padded as u64;
+        }
+
+        range.check(&hasher.finalize())?;
+        position = start + length;
+    }
+
+    target.flush()?;
+    Ok(WriteSummary {
+        written,
+        skipped: bmap.image_size.saturating_sub(written),
+    })
+}
+
+// Reads the mapped ranges back from the target and checks them against the
+// block map, which needs no second pass over the image.
+pub fn verify_mapped<T: Read + Seek + ?Sized>(
+    target: &mut T,
+    bmap: &Bmap,
+    progress: &Arc<Mutex<f32>>,
+) -> io::Result<()> {
+    let total_size = bmap.mapped_size();
+    let mut verified = 0u64;
+    let mut buffer = vec![0; CHUNK_SIZE as usize];
+    for range in &bmap.ranges {
+        let (start, length) = bmap.extent(range);
+        target.seek(SeekFrom::Start(start))?;
+        let mut hasher = bmap.algorithm.hasher();
+        let mut remaining = length;
+        while remaining > 0 {
+            let wanted = remaining.min(CHUNK_SIZE) as usize;
+            let padded = wanted.div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+            target.read_exact(&mut buffer[..padded])?;
+            hasher.update(&buffer[..wanted]);
+            remaining -= wanted as u64;
+            verified += wanted as u64;
+            if total_size > 0 {
+                *progress.lock().unwrap() = verified as f32 / total_size as f32;
+            }
+        }
+
+        range.check(&hasher.finalize()).map_err(|_| {
+            invalid(&format!(
+                "Verification failed: blocks {}-{} (from byte {}) read back differently",
+                range.first, range.last, start
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+// bmaptool explains every element in comments, some quoting tag names.
+fn strip_comments(text: &str) -> String {
+    let mut xml = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("<!--") {
+        xml.push_str(&rest[..start]);
+        rest = rest[start..].find("-->").map_or("", |end| &rest[start + end + 3..]);
+    }
+    xml.push_str(rest);
+    xml
+}
+
+fn element(xml: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find("</")? + start;
+    Some(xml[start..end].trim().to_string())
+}
+
+fn attribute(xml: &str, tag: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{} ", tag))?;
+    let end = xml[start..].find('>')? + start;
+    tag_attribute(&xml[start..end], name)
+}
+
+fn tag_attribute(tag: &str, name: &str) -> Option<String> {
+    let pattern = format!("{}=", name);
+    let start = tag.find(&format!(" {}", pattern))? + 1 + pattern.len();
+    let quote = tag[start..].chars().next().filter(|&c| c == '"' || c == '\'')?;
+    let value = &tag[start + 1..];
+    Some(value[..value.find(quote)?].trim().to_string())
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "The image ends before the blocks its block map lists")
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const BLOCK_SIZE: usize = 4096;
+    // Five blocks, the last one cut short. Blocks 0-1 and 3-4 are mapped.
+    const IMAGE_SIZE: usize = 5 * BLOCK_SIZE - 1000;
+    const MAPPED: [(u64, u64); 2] = [(0, 1), (3, 4)];
+
+    fn image() -> Vec<u8> {
+        let mut image: Vec<u8> = (0..IMAGE_SIZE).map(|i| (i % 253) as u8 + 1).collect();
+        image[2 * BLOCK_SIZE..3 * BLOCK_SIZE].fill(0);
+        image
+    }
+
+    fn range_bytes(image: &[u8], (first, last): (u64, u64)) -> &[u8] {
+        &image[first as usize * BLOCK_SIZE..((last as usize + 1) * BLOCK_SIZE).min(image.len())]
+    }
+
+    // A bmaptool 1.x map, with SHA-1 checksums in `sha1` attributes, or a
+    // 2.x map naming SHA-256 and using `chksum`. Either checksums itself.
+    fn bmap_xml(image: &[u8], version_2: bool) -> String {
+        let (algorithm, attribute) = if version_2 {
+            (HashAlgorithm::Sha256, "chksum")
+        } else {
+            (HashAlgorithm::Sha1, "sha1")
+        };
+        let ranges: String = MAPPED
+            .iter()
+            .map(|&range| {
+                let digest = checksum::hash_bytes(algorithm, range_bytes(image, range));
+                format!("        <Range {}=\"{}\"> {}-{} </Range>\n", attribute, digest, range.0, range.1)
+            })
+            .collect();
+        let zeros = "0".repeat(if version_2 { 64 } else { 40 });
+        let header = if version_2 {
+            format!(
+                "<bmap version=\"2.0\">\n    <ChecksumType> sha256 </ChecksumType>\n    <BmapFileChecksum> {} </BmapFileChecksum>\n",
+                zeros
+            )
+        } else {
+            format!("<bmap version=\"1.3\">\n    <BmapFileSHA1> {} </BmapFileSHA1>\n", zeros)
+        };
+        let text = format!(
+            "<?xml version=\"1.0\" ?>\n<!-- <Range> lines list the mapped blocks -->\n{}    \
+             <ImageSize> {} </ImageSize>\n    <BlockSize> {} </BlockSize>\n    <BlocksCount> 5 </BlocksCount>\n    \
+             <MappedBlocksCount> 4 </MappedBlocksCount>\n    <BlockMap>\n{}    </BlockMap>\n</bmap>\n",
+            header, IMAGE_SIZE, BLOCK_SIZE, ranges
+        );
+        let digest = checksum::hash_bytes(algorithm, text.as_bytes());
+        text.replacen(&zeros, &digest, 1)
+    }
+
+    fn write(image: &[u8], bmap: &Bmap) -> io::Result<(Vec<u8>, WriteSummary)> {
+        let mut target = Cursor::new(vec![0xEE; 5 * BLOCK_SIZE]);
+        let summary = write_mapped(&mut &image[..], bmap, &mut target)?;
+        Ok((target.into_inner(), summary))
+    }
+
+    #[test]
+    fn reads_version_1_maps_with_sha1_ranges() {
+        let bmap = parse_bmap(&bmap_xml(&image(), false)).unwrap();
+        assert_eq!((bmap.image_size, bmap.block_size, bmap.blocks_count), (IMAGE_SIZE as u64, 4096, 5));
+        assert!(bmap.algorithm == HashAlgorithm::Sha1);
+        let ranges: Vec<(u64, u64)> = bmap.ranges.iter().map(|range| (range.first, range.last)).collect();
+        assert_eq!(ranges, MAPPED);
+        assert!(bmap.ranges.iter().all(|range| range.checksum.as_ref().is_some_and(|hex| hex.len() == 40)));
+        assert_eq!(bmap.mapped_size(), (IMAGE_SIZE - BLOCK_SIZE) as u64);
+    }
+
+    #[test]
+    fn reads_version_2_maps_with_chksum_ranges() {
+        let bmap = parse_bmap(&bmap_xml(&image(), true)).unwrap();
+        assert!(bmap.algorithm == HashAlgorithm::Sha256);
+        assert_eq!(bmap.ranges.len(), 2);
+        assert!(bmap.ranges.iter().all(|range| range.checksum.as_ref().is_some_and(|hex| hex.len() == 64)));
+    }
+
+    #[test]
+    fn checks_the_map_against_its_own_checksum() {
+        for version_2 in [false, true] {
+            let edited = bmap_xml(&image(), version_2).replace("> 3-4 <", "> 3-3 <");
+            let error = parse_bmap(&edited).err().unwrap();
+            assert_eq!(error.to_string(), "The block map is damaged: its own checksum does not match");
+        }
+
+        let error = parse_bmap("<bmap version=\"3.0\"></bmap>").err().unwrap();
+        assert_eq!(error.to_string(), "Block map version 3.0 is not supported");
+    }
+
+    #[test]
+    fn writes_only_mapped_ranges_and_verifies_them() {
+        let image = image();
+        let bmap = parse_bmap(&bmap_xml(&image, true)).unwrap();
+        let (written, summary) = write(&image, &bmap).unwrap();
+
+        assert_eq!(&written[..2 * BLOCK_SIZE], &image[..2 * BLOCK_SIZE]);
+        // The unmapped block is never touched.
+        assert!(written[2 * BLOCK_SIZE..3 * BLOCK_SIZE].iter().all(|&byte| byte == 0xEE));
+        assert_eq!(&written[3 * BLOCK_SIZE..IMAGE_SIZE], &image[3 * BLOCK_SIZE..]);
+        // The short last block is padded to a sector, and no further.
+        let padded = IMAGE_SIZE.div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+        assert!(written[IMAGE_SIZE..padded].iter().all(|&byte| byte == 0));
+        assert!(written[padded..].iter().all(|&byte| byte == 0xEE));
+        assert_eq!(summary.written, (2 * BLOCK_SIZE + padded - 3 * BLOCK_SIZE) as u64);
+        assert_eq!(summary.skipped, IMAGE_SIZE as u64 - summary.written);
+
+        let progress = Arc::new(Mutex::new(0.0));
+        let mut target = Cursor::new(written);
+        verify_mapped(&mut target, &bmap, &progress).unwrap();
+        assert_eq!(*progress.lock().unwrap(), 1.0);
+
+        target.get_mut()[4 * BLOCK_SIZE] ^= 1;
+        let error = verify_mapped(&mut target, &bmap, &progress).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Verification failed: blocks 3-4 (from byte 12288) read back differently"
+        );
+    }
+
+    #[test]
+    fn rejects_a_range_that_does_not_match_its_checksum() {
+        let mut image = image();
+        let bmap = parse_bmap(&bmap_xml(&image, false)).unwrap();
+        image[3 * BLOCK_SIZE + 7] ^= 0xFF;
+        let error = write(&image, &bmap).err().unwrap();
+        assert_eq!(error.to_string(), "Blocks 3-4 of the image do not match their checksum in the block map");
+
+        let error = write(&image[..3 * BLOCK_SIZE + 10], &bmap).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn rejects_sizes_and_ranges_outside_the_image() {
+        let bmap = |image_size: u64, blocks_count: u64, blocks: &str| {
+            parse_bmap(&format!(
+                "<bmap version=\"2.0\"><ImageSize>{}</ImageSize><BlockSize>4096</BlockSize>\
+                 <BlocksCount>{}</BlocksCount><BlockMap><Range>{}</Range></BlockMap></bmap>",
+                image_size, blocks_count, blocks
+            ))
+            .map(|bmap| bmap.mapped_size())
+            .map_err(|e| e.to_string())
+        };
+
+        assert_eq!(bmap(IMAGE_SIZE as u64, 5, "4"), Ok((IMAGE_SIZE - 4 * BLOCK_SIZE) as u64));
+        assert_eq!(
+            bmap(IMAGE_SIZE as u64, 4, "0").err().unwrap(),
+            "4 blocks of 4096 bytes do not make up a 19480 byte image"
+        );
+        assert_eq!(
+            bmap(u64::MAX, u64::MAX / 1024, "0").err().unwrap(),
+            format!("{} blocks of 4096 bytes do not make up a {} byte image", u64::MAX / 1024, u64::MAX)
+        );
+        // Spare blocks past the image are allowed, but not mapped.
+        assert_eq!(bmap(2 * BLOCK_SIZE as u64, 3, "1-2"), Ok(BLOCK_SIZE as u64));
+        assert_eq!(bmap(2 * BLOCK_SIZE as u64, 3, "2").err().unwrap(), "Block range 2 lies outside the image");
+        assert_eq!(bmap(2 * BLOCK_SIZE as u64, 3, "1-3").err().unwrap(), "Block range 1-3 lies outside the image");
+
+        // Extents stay within the image even for ranges no map would pass.
+        let wild = Bmap {
+            image_size: IMAGE_SIZE as u64,
+            block_size: BLOCK_SIZE as u64,
+            blocks_count: 5,
+            ranges: vec![
+                BlockRange {
+                    first: 4,
+                    last: u64::MAX,
+                    checksum: None,
+                },
+                BlockRange {
+                    first: u64::MAX / 2,
+                    last: u64::MAX / 2,
+                    checksum: None,
+                },
+            ],
+            algorithm: HashAlgorithm::Sha1,
+        };
+        assert_eq!(wild.extent(&wild.ranges[0]), (4 * BLOCK_SIZE as u64, (IMAGE_SIZE - 4 * BLOCK_SIZE) as u64));
+        assert_eq!(wild.extent(&wild.ranges[1]), (IMAGE_SIZE as u64, 0));
+    }
+}
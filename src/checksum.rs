@@ -0,0 +1,393 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::digest::DynDigest;
+use sha2::Sha256;
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+// Checksum files this large are something else picked by mistake.
+const MAX_CHECKSUM_FILE_SIZE: u64 = 1024 * 1024;
+
+// Ordered from the strongest hash to the weakest.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha1,
+    Md5,
+}
+
+impl HashAlgorithm {
+    pub fn label(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "SHA-256",
+            HashAlgorithm::Sha1 => "SHA-1",
+            HashAlgorithm::Md5 => "MD5",
+        }
+    }
+
+    // Published hashes seldom name their algorithm, but each has its own
+    // length.
+    fn from_hex_length(length: usize) -> Option<Self> {
+        match length {
+            64 => Some(HashAlgorithm::Sha256),
+            40 => Some(HashAlgorithm::Sha1),
+            32 => Some(HashAlgorithm::Md5),
+            _ => None,
+        }
+    }
+
+    pub fn hasher(&self) -> Box<dyn DynDigest> {
+        match self {
+            HashAlgorithm::Sha256 => Box::new(Sha256::default()),
+            HashAlgorithm::Sha1 => Box::new(Sha1::default()),
+            HashAlgorithm::Md5 => Box::new(Md5::default()),
+        }
+    }
+}
+
+// A hash to check the image against, in lower-case hex.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    pub hex: String,
+}
+
+// Accepts a hash as pasted from a download page: any case, surrounding
+// blanks allowed, the algorithm told apart by length.
+pub fn parse_checksum(text: &str) -> Result<Checksum, String> {
+    let hex = text.trim().to_ascii_lowercase();
+    match HashAlgorithm::from_hex_length(hex.len()) {
+        Some(algorithm) if hex.chars().all(|c| c.is_ascii_hexdigit()) => Ok(Checksum { algorithm, hex }),
+        _ => Err(format!("\"{}\" is not a SHA-256, SHA-1 or MD5 hash", text.trim())),
+    }
+}
+
+// A hash listed in a checksum file, with the name of the file it belongs to
+// unless the checksum file holds nothing but the hash.
+struct ChecksumEntry {
+    file_name: Option<String>,
+    checksum: Checksum,
+}
+
+// Reads the expected hash of `image_name` from a checksum file: GNU
+// `sha256sum` output, BSD `SHA256 (name) = hash` lines, either of them
+// wrapped in a PGP signed message as Fedora ships them, or a lone hash.
+pub fn read_checksum_file(path: &Path, image_name: &str) -> Result<Checksum, String> {
+    let size = fs::metadata(path).map_err(|e| e.to_string())?.len();
+    if size > MAX_CHECKSUM_FILE_SIZE {
+        return Err(format!("{} is too large for a checksum file", path.display()));
+    }
+
+    let text = fs::read(path).map_err(|e| e.to_string())?;
+    let entries = parse_checksum_file(&String::from_utf8_lossy(&text));
+    if entries.is_empty() {
+        return Err(format!("No SHA-256, SHA-1 or MD5 hash found in {}", path.display()));
+    }
+    find_entry(&entries, image_name)
+        .cloned()
+        .ok_or_else(|| format!("{} is not listed in {}", image_name, path.display()))
+}
+
+// Looks through the image's folder for a checksum file listing it, the way
+// distributions publish them side by side. The strongest hash wins.
+pub fn find_checksum_file(image: &Path) -> Option<(PathBuf, Checksum)> {
+    let image_name = image.file_name()?.to_str()?;
+    let folder = match image.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    fs::read_dir(folder)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            let name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_lowercase());
+            ["sha256", "sha1", "md5", "checksum"].iter().any(|hint| name.contains(hint))
+        })
+        .filter_map(|path| read_checksum_file(&path, image_name).ok().map(|checksum| (path, checksum)))
+        .min_by_key(|(path, checksum)| (checksum.algorithm, path.clone()))
+}
+
+fn find_entry<'a>(entries: &'a [ChecksumEntry], image_name: &str) -> Option<&'a Checksum> {
+    // Lists made from a directory tree name files by their relative path.
+    let listed = entries.iter().find(|entry| {
+        entry
+            .file_name
+            .as_deref()
+            .is_some_and(|name| name.rsplit(['/', '\\']).next() == Some(image_name))
+    });
+    match (listed, entries) {
+        (Some(entry), _) => Some(&entry.checksum),
+        // A `.sha256` file next to the image names nothing but the hash.
+        (None, [entry]) if entry.file_name.is_none() => Some(&entry.checksum),
+        _ => None,
+    }
+}
+
+fn parse_checksum_file(text: &str) -> Vec<ChecksumEntry> {
+    // Only the signed text matters. The signature itself is not checked;
+    // that takes the distribution's public key.
+    let mut in_armor_header = false;
+    let mut in_signature = false;
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        match line.trim_end() {
+            "-----BEGIN PGP SIGNED MESSAGE-----" => in_armor_header = true,
+            "-----BEGIN PGP SIGNATURE-----" => in_signature = true,
+            "-----END PGP SIGNATURE-----" => in_signature = false,
+            // Armor headers like `Hash: SHA256` run up to the first blank line.
+            trimmed if in_armor_header => in_armor_header = !trimmed.is_empty(),
+            _ if in_signature => {}
+            _ => {
+                // Signed text escapes lines starting with a dash.
+                let line = line.strip_prefix("- ").unwrap_or(line);
+                entries.extend(parse_checksum_line(line));
+            }
+        }
+    }
+    entries
+}
+
+fn parse_checksum_line(line: &str) -> Option<ChecksumEntry> {
+    if line.trim().is_empty() || line.trim_start().starts_with('#') {
+        return None;
+    }
+
+    // BSD style: `SHA256 (name) = hash`.
+    if let Some((head, hash)) = line.rsplit_once(") = ") {
+        let (tag, name) = head.split_once(" (")?;
+        if tag.trim().is_empty() || tag.trim().contains(' ') {
+            return None;
+        }
+        return parse_checksum(hash).ok().map(|checksum| ChecksumEntry {
+            file_name: Some(name.to_string()),
+            checksum,
+        });
+    }
+
+    // GNU style: `hash  name`, or `hash *name` for binary mode. A leading
+    // backslash means backslashes and newlines in the name are escaped.
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line.trim_start()),
+    };
+    let (hash, file_name) = match line.split_once(' ') {
+        Some((hash, rest)) => {
+            let name = rest.strip_prefix([' ', '*']).unwrap_or(rest);
+            let name = if escaped { unescape_name(name) } else { name.to_string() };
+            (hash, (!name.is_empty()).then_some(name))
+        }
+        None => (line.trim_end(), None),
+    };
+    parse_checksum(hash).ok().map(|checksum| ChecksumEntry { file_name, checksum })
+}
+
+fn unescape_name(name: &str) -> String {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                unescaped.push('\\');
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+// Hashes the whole file, reporting progress as the fraction read so far.
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm, progress: &Arc<Mutex<f32>>) -> io::Result<Checksum> {
+    let mut file = File::open(path)?;
+    let total_size = file.metadata()?.len();
+    let mut hasher = algorithm.hasher();
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut read = 0u64;
+    loop {
+        let filled = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buffer[..filled]);
+        read += filled as u64;
+        if total_size > 0 {
+            *progress.lock().unwrap() = read as f32 / total_size as f32;
+        }
+    }
+
+    Ok(Checksum {
+        algorithm,
+        hex: hex(&hasher.finalize()),
+    })
+}
+
+pub fn hash_bytes(algorithm: HashAlgorithm, bytes: &[u8]) -> String {
+    let mut hasher = algorithm.hasher();
+    hasher.update(bytes);
+    hex(&hasher.finalize())
+}
+
+// Lower-case hex, the way checksum files print digests.
+pub fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    // The digests of no bytes at all.
+    const SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const SHA1: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+    const MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
+
+    fn checksum(algorithm: HashAlgorithm, hex: &str) -> Checksum {
+        Checksum {
+            algorithm,
+            hex: hex.to_string(),
+        }
+    }
+
+    fn entry(line: &str) -> (Option<String>, Checksum) {
+        let entry = parse_checksum_line(line).unwrap();
+        (entry.file_name, entry.checksum)
+    }
+
+    fn lookup(text: &str, image_name: &str) -> Option<Checksum> {
+        find_entry(&parse_checksum_file(text), image_name).cloned()
+    }
+
+    #[test]
+    fn parses_gnu_lines() {
+        let sha256 = checksum(HashAlgorithm::Sha256, SHA256);
+        assert_eq!(entry(&format!("{}  ubuntu.iso", SHA256)), (Some("ubuntu.iso".to_string()), sha256.clone()));
+        assert_eq!(entry(&format!("{} *ubuntu.iso", SHA256)), (Some("ubuntu.iso".to_string()), sha256.clone()));
+        assert_eq!(
+            entry(&format!("{}  ./images/My Image.iso", SHA256.to_uppercase())),
+            (Some("./images/My Image.iso".to_string()), sha256.clone())
+        );
+        assert_eq!(
+            entry(&format!("\\{}  odd\\nname\\\\.iso", SHA1)),
+            (Some("odd\nname\\.iso".to_string()), checksum(HashAlgorithm::Sha1, SHA1))
+        );
+        assert_eq!(entry(&format!("{}\n", MD5)), (None, checksum(HashAlgorithm::Md5, MD5)));
+
+        assert!(parse_checksum_line("# SHA256 checksums").is_none());
+        assert!(parse_checksum_line("   ").is_none());
+        assert!(parse_checksum_line("not-a-hash  ubuntu.iso").is_none());
+    }
+
+    #[test]
+    fn parses_bsd_lines() {
+        assert_eq!(
+            entry(&format!("SHA256 (Fedora-Workstation-Live-x86_64-40-1.14.iso) = {}", SHA256)),
+            (Some("Fedora-Workstation-Live-x86_64-40-1.14.iso".to_string()), checksum(HashAlgorithm::Sha256, SHA256))
+        );
+        assert_eq!(
+            entry(&format!("MD5 (name (1).iso) = {}", MD5)),
+            (Some("name (1).iso".to_string()), checksum(HashAlgorithm::Md5, MD5))
+        );
+        assert!(parse_checksum_line(&format!("SHA 256 (a.iso) = {}", SHA256)).is_none());
+    }
+
+    #[test]
+    fn reads_pgp_signed_fedora_checksum_files() {
+        let text = format!(
+            "-----BEGIN PGP SIGNED MESSAGE-----\n\
+             Hash: SHA256\n\
+             \n\
+             # Fedora-Workstation-Live-x86_64-40-1.14.iso: 2295853056 bytes\n\
+             SHA256 (Fedora-Workstation-Live-x86_64-40-1.14.iso) = {}\n\
+             - {}  -dashed.iso\n\
+             -----BEGIN PGP SIGNATURE-----\n\
+             \n\
+             iQIzBAEBCAAdFiEE{}\n\
+             -----END PGP SIGNATURE-----\n",
+            SHA256, SHA1, MD5
+        );
+
+        let entries = parse_checksum_file(&text);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            lookup(&text, "Fedora-Workstation-Live-x86_64-40-1.14.iso"),
+            Some(checksum(HashAlgorithm::Sha256, SHA256))
+        );
+        assert_eq!(lookup(&text, "-dashed.iso"), Some(checksum(HashAlgorithm::Sha1, SHA1)));
+    }
+
+    #[test]
+    fn finds_the_image_among_several() {
+        let text = format!(
+            "{sha}  debian-12.5.0-amd64-netinst.iso\n{md5}  debian-12.5.0-i386-netinst.iso\n{sha1}  isos/debian-edu.iso\n",
+            sha = SHA256,
+            md5 = MD5,
+            sha1 = SHA1
+        );
+        assert_eq!(lookup(&text, "debian-12.5.0-i386-netinst.iso"), Some(checksum(HashAlgorithm::Md5, MD5)));
+        assert_eq!(lookup(&text, "debian-edu.iso"), Some(checksum(HashAlgorithm::Sha1, SHA1)));
+        assert_eq!(lookup(&text, "debian-12.5.0-arm64-netinst.iso"), None);
+    }
+
+    #[test]
+    fn a_lone_hash_belongs_to_any_image() {
+        assert_eq!(lookup(&format!("{}\n", SHA256), "whatever.img"), Some(checksum(HashAlgorithm::Sha256, SHA256)));
+        // Once names are given, the image has to be one of them.
+        assert_eq!(lookup(&format!("{}  other.img\n", SHA256), "whatever.img"), None);
+    }
+
+    #[test]
+    fn finds_the_strongest_checksum_file_next_to_the_image() {
+        let dir = TempDir::new("checksum");
+        let image = dir.write("empty.iso", b"");
+        dir.write("empty.iso.md5", format!("{}  empty.iso\n", MD5).as_bytes());
+        dir.write("SHA256SUMS", format!("{}  empty.iso\n", SHA256).as_bytes());
+        dir.write("notes.txt", SHA1.as_bytes());
+
+        let (path, found) = find_checksum_file(&image).unwrap();
+        assert_eq!(path, dir.path().join("SHA256SUMS"));
+        assert_eq!(found, checksum(HashAlgorithm::Sha256, SHA256));
+
+        let progress = Arc::new(Mutex::new(0.0));
+        assert_eq!(hash_file(&image, HashAlgorithm::Md5, &progress).unwrap(), checksum(HashAlgorithm::Md5, MD5));
+        assert_eq!(
+            read_checksum_file(&dir.path().join("notes.txt"), "empty.iso"),
+            Ok(checksum(HashAlgorithm::Sha1, SHA1))
+        );
+    }
+
+    #[test]
+    fn hashes_files_across_chunks() {
+        let dir = TempDir::new("hash-file");
+        let fox = dir.write("fox.txt", b"The quick brown fox jumps over the lazy dog");
+        let pattern: Vec<u8> = (0..CHUNK_SIZE + CHUNK_SIZE / 2 + 7).map(|i| (i % 251) as u8).collect();
+        let large = dir.write("large.img", &pattern);
+
+        let progress = Arc::new(Mutex::new(0.0));
+        for (path, algorithm, hex) in [
+            (&fox, HashAlgorithm::Sha1, "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12"),
+            (&fox, HashAlgorithm::Md5, "9e107d9d372bb6826bd81d3542a419d6"),
+            (&large, HashAlgorithm::Sha1, "aea453141eec28f5dded8cad6847fae541be8767"),
+            (&large, HashAlgorithm::Md5, "78711584d91b4599210ca16cb466a047"),
+            (&large, HashAlgorithm::Sha256, "376d8a39e8668f7b13aec3cd0dd283c12493feb03f54067ae7b7825ba88580d1"),
+        ] {
+            *progress.lock().unwrap() = 0.0;
+            assert_eq!(hash_file(path, algorithm, &progress).unwrap(), checksum(algorithm, hex));
+            assert_eq!(*progress.lock().unwrap(), 1.0);
+            assert_eq!(hash_bytes(algorithm, &fs::read(path).unwrap()), hex);
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use xz2::read::XzDecoder;
+
+const XZ_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const XZ_FOOTER_SIZE: u64 = 12;
+const XZ_HEADER_SIZE: u64 = 12;
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const BZIP2_MAGIC: [u8; 3] = *b"BZh";
+const ZSTD_MAGIC: u32 = 0xFD2F_B528;
+// Skippable frames use any of 16 magic numbers, 0x184D2A50 to 0x184D2A5F.
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+
+// An xz index larger than this is not an image index but garbage.
+const MAX_XZ_INDEX_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+}
+
+impl Compression {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Xz => "xz",
+            Compression::Bzip2 => "bzip2",
+            Compression::Zstd => "Zstandard",
+        }
+    }
+}
+
+pub struct CompressedImage {
+    pub compression: Compression,
+    pub compressed_size: u64,
+    // The decompressed size, if the format records it. gzip only keeps it
+    // modulo 4 GiB, too little for disk images, and bzip2 not at all.
+    pub size: Option<u64>,
+}
+
+// Goes by magic number rather than extension, which downloads often lose.
+// The source is left where it was.
+pub fn detect<R: Read + Seek + ?Sized>(source: &mut R) -> io::Result<Option<Compression>> {
+    let start = source.stream_position()?;
+    let mut header = [0; 6];
+    let mut filled = 0;
+    while filled < header.len() {
+        match source.read(&mut header[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    source.seek(SeekFrom::Start(start))?;
+
+    let header = &header[..filled];
+    Ok(if header.starts_with(&XZ_MAGIC) {
+        Some(Compression::Xz)
+    } else if header.starts_with(&GZIP_MAGIC) {
+        Some(Compression::Gzip)
+    } else if header.starts_with(&BZIP2_MAGIC) {
+        Some(Compression::Bzip2)
+    } else if header.len() >= 4 && u32::from_le_bytes(header[..4].try_into().unwrap()) == ZSTD_MAGIC {
+        Some(Compression::Zstd)
+    } else {
+        None
+    })
+}
+
+// `None` for an image that is not compressed.
+pub fn probe(path: &Path) -> io::Result<Option<CompressedImage>> {
+    let mut file = File::open(path)?;
+    let Some(compression) = detect(&mut file)? else {
+        return Ok(None);
+    };
+
+    let compressed_size = file.metadata()?.len();
+    let size = match compression {
+        Compression::Xz => xz_size(&mut file, compressed_size)?,
+        Compression::Zstd => zstd_size(&mut file, compressed_size)?,
+        Compression::Gzip | Compression::Bzip2 => None,
+    };
+    Ok(Some(CompressedImage {
+        compression,
+        compressed_size,
+        size,
+    }))
+}
+
+// Wraps `source` so reads from it come out decompressed. Concatenated
+// streams, as parallel compressors write them, are read through to the end.
+pub fn decoder<'a, R: Read + 'a>(source: R, compression: Option<Compression>) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        None => Box::new(source),
+        Some(Compression::Gzip) => Box::new(MultiGzDecoder::new(source)),
+        Some(Compression::Xz) => Box::new(XzDecoder::new_multi_decoder(source)),
+        Some(Compression::Bzip2) => Box::new(MultiBzDecoder::new(source)),
+        Some(Compression::Zstd) => Box::new(zstd::stream::read::Decoder::new(source)?),
+    })
+}
+
+// Sums the uncompressed sizes in the index of every stream, walking the
+// streams back to front from their footers. Anything unexpected means the
+// size is unknown rather than an error: the decoder has the final word.
+fn xz_size<R: Read + Seek>(file: &mut R, file_size: u64) -> io::Result<Option<u64>> {
+    let mut end = file_size;
+    let mut total = 0u64;
+    while end > 0 {
+        // Stream padding: zero bytes in multiples of four.
+        if end < 4 {
+            return Ok(None);
+        }
+        let mut word = [0; 4];
+        file.seek(SeekFrom::Start(end - 4))?;
+        file.read_exact(&mut word)?;
+        if word == [0; 4] {
+            end -= 4;
+            continue;
+        }
+
+        let Some(footer_start) = end.checked_sub(XZ_FOOTER_SIZE) else {
+            return Ok(None);
+        };
+        let mut footer = [0; XZ_FOOTER_SIZE as usize];
+        file.seek(SeekFrom::Start(footer_start))?;
+        file.read_exact(&mut footer)?;
+        if &footer[10..12] != b"YZ" {
+            return Ok(None);
+        }
+
+        let index_size = (u32::from_le_bytes(footer[4..8].try_into().unwrap()) as u64 + 1) * 4;
+        let Some(index_start) = footer_start.checked_sub(index_size).filter(|_| index_size <= MAX_XZ_INDEX_SIZE) else {
+            return Ok(None);
+        };
+        let mut index = vec![0; index_size as usize];
+        file.seek(SeekFrom::Start(index_start))?;
+        file.read_exact(&mut index)?;
+
+        // The index lists every block as its unpadded and uncompressed size.
+        let Some((blocks_size, stream_total)) = parse_xz_index(&index) else {
+            return Ok(None);
+        };
+        let Some(sum) = total.checked_add(stream_total) else {
+            return Ok(None);
+        };
+        total = sum;
+        match index_start.checked_sub(blocks_size + XZ_HEADER_SIZE) {
+            Some(stream_start) => end = stream_start,
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(total))
+}
+
+fn parse_xz_index(index: &[u8]) -> Option<(u64, u64)> {
+    if index.first() != Some(&0) {
+        return None;
+    }
+
+    let mut position = 1;
+    let mut read_number = || {
+        // Little-endian base 128, at most nine bytes.
+        let mut value = 0u64;
+        for shift in 0..9 {
+            let byte = *index.get(position)?;
+            position += 1;
+            value |= ((byte & 0x7F) as u64) << (shift * 7);
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    };
+
+    let records = read_number()?;
+    let (mut blocks_size, mut total) = (0u64, 0u64);
+    for _ in 0..records {
+        let unpadded = read_number()?;
+        total = total.checked_add(read_number()?)?;
+        blocks_size = blocks_size.checked_add(unpadded.checked_next_multiple_of(4)?)?;
+    }
+    Some((blocks_size, total))
+}
+
+// Adds up the content size each frame header declares, hopping from frame
+// to frame over the block headers. A frame that leaves its size out makes
+// the total unknown.
+fn zstd_size<R: Read + Seek>(file: &mut R, file_size: u64) -> io::Result<Option<u64>> {
+    let mut read_at = |offset: u64, buffer: &mut [u8]| -> io::Result<bool> {
+        if offset + buffer.len() as u64 > file_size {
+            return Ok(false);
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buffer)?;
+        Ok(true)
+    };
+
+    let mut position = 0u64;
+    let mut total = 0u64;
+    while position < file_size {
+        let mut header = [0; 8];
+        if !read_at(position, &mut header)? {
+            return Ok(None);
+        }
+        let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+        if magic & 0xFFFF_FFF0 == ZSTD_SKIPPABLE_MAGIC {
+            position += 8 + u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+            continue;
+        }
+        if magic != ZSTD_MAGIC {
+            return Ok(None);
+        }
+
+        let descriptor = header[4];
+        let single_segment = descriptor & 0x20 != 0;
+        let has_checksum = descriptor & 0x04 != 0;
+        let window_size = if single_segment { 0 } else { 1 };
+        let dictionary_id_size = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+        let content_size_size = match descriptor >> 6 {
+            0 if single_segment => 1,
+            0 => return Ok(None),
+            1 => 2,
+            2 => 4,
+            _ => 8,
+        };
+
+        let mut content_size = [0; 8];
+        let field = position + 5 + window_size + dictionary_id_size;
+        if !read_at(field, &mut content_size[..content_size_size as usize])? {
+            return Ok(None);
+        }
+        // The two-byte form is stored minus 256.
+        let content_size = u64::from_le_bytes(content_size) + if content_size_size == 2 { 256 } else { 0 };
+        // Declared sizes are not checked until decoding, and may be absurd.
+        let Some(sum) = total.checked_add(content_size) else {
+            return Ok(None);
+        };
+        total = sum;
+
+        position = field + content_size_size;
+        loop {
+            let mut block = [0; 4];
+            if !read_at(position, &mut block[..3])? {
+                return Ok(None);
+            }
+            let block = u32::from_le_bytes(block);
+            // RLE blocks store their one byte, whatever their size.
+            let stored_size = if (block >> 1) & 0x03 == 1 { 1 } else { block as u64 >> 3 };
+            position += 3 + stored_size;
+            if block & 1 != 0 {
+                break;
+            }
+        }
+        if has_checksum {
+            position += 4;
+        }
+    }
+    Ok(Some(total))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 % 253) as u8).collect()
+    }
+
+    fn compress(compression: Compression, data: &[u8]) -> Vec<u8> {
+        match compression {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(Vec::new(), 1);
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Bzip2 => {
+                let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd => zstd::bulk::compress(data, 1).unwrap(),
+        }
+    }
+
+    fn decompress(compression: Option<Compression>, compressed: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        decoder(compressed, compression).unwrap().read_to_end(&mut output).unwrap();
+        output
+    }
+
+    // A zstd frame that declares `content_size` but holds one empty block.
+    fn zstd_frame(content_size: u64) -> Vec<u8> {
+        let mut frame = ZSTD_MAGIC.to_le_bytes().to_vec();
+        frame.push(0xE0);
+        frame.extend_from_slice(&content_size.to_le_bytes());
+        frame.extend_from_slice(&[0x01, 0x00, 0x00]);
+        frame
+    }
+
+    const ALL: [Compression; 4] = [Compression::Gzip, Compression::Xz, Compression::Bzip2, Compression::Zstd];
+
+    #[test]
+    fn detects_formats_by_magic_and_leaves_the_position() {
+        for compression in ALL {
+            let mut source = Cursor::new(compress(compression, b"image"));
+            assert_eq!(detect(&mut source).unwrap(), Some(compression));
+            assert_eq!(source.position(), 0);
+        }
+
+        let mut plain = Cursor::new(b"\0\0\0\0BZh9".to_vec());
+        plain.set_position(4);
+        assert_eq!(detect(&mut plain).unwrap(), Some(Compression::Bzip2));
+        assert_eq!(plain.position(), 4);
+        plain.set_position(0);
+        assert_eq!(detect(&mut plain).unwrap(), None);
+        assert_eq!(detect(&mut Cursor::new(vec![0x1F])).unwrap(), None);
+        assert_eq!(detect(&mut Cursor::new(Vec::new())).unwrap(), None);
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        let original = data(300_000);
+        for compression in ALL {
+            assert_eq!(decompress(Some(compression), &compress(compression, &original)), original, "{:?}", compression);
+        }
+        assert_eq!(decompress(None, &original), original);
+    }
+
+    #[test]
+    fn decodes_concatenated_streams() {
+        let (first, second) = (data(70_000), data(1000));
+        let whole = [first.as_slice(), second.as_slice()].concat();
+        for compression in ALL {
+            let mut compressed = compress(compression, &first);
+            compressed.extend_from_slice(&compress(compression, &second));
+            assert_eq!(decompress(Some(compression), &compressed), whole, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn probes_the_size_xz_and_zstd_record() {
+        let dir = TempDir::new("compression-probe");
+        let probe_bytes = |name: &str, bytes: &[u8]| {
+            let image = probe(&dir.write(name, bytes)).unwrap().unwrap();
+            assert_eq!(image.compressed_size, bytes.len() as u64);
+            (image.compression, image.size)
+        };
+
+        // Two xz streams with stream padding between and after them.
+        let mut xz = compress(Compression::Xz, &data(100_000));
+        xz.extend_from_slice(&[0; 8]);
+        xz.extend_from_slice(&compress(Compression::Xz, &data(5000)));
+        xz.extend_from_slice(&[0; 4]);
+        assert_eq!(probe_bytes("image.img.xz", &xz), (Compression::Xz, Some(105_000)));
+        assert_eq!(decompress(Some(Compression::Xz), &xz).len(), 105_000);
+
+        // Two zstd frames around a skippable one.
+        let mut zstd = compress(Compression::Zstd, &data(100_000));
+        zstd.extend_from_slice(&(ZSTD_SKIPPABLE_MAGIC + 3).to_le_bytes());
+        zstd.extend_from_slice(&5u32.to_le_bytes());
+        zstd.extend_from_slice(b"notes");
+        zstd.extend_from_slice(&compress(Compression::Zstd, &data(300)));
+        assert_eq!(probe_bytes("image.img.zst", &zstd), (Compression::Zstd, Some(100_300)));
+        assert_eq!(decompress(Some(Compression::Zstd), &zstd).len(), 100_300);
+
+        // A streamed frame leaves its size out.
+        let mut streamed = zstd::stream::write::Encoder::new(Vec::new(), 1).unwrap();
+        streamed.write_all(&data(1000)).unwrap();
+        assert_eq!(probe_bytes("streamed.zst", &streamed.finish().unwrap()), (Compression::Zstd, None));
+
+        // Declared sizes that overflow, and a truncated frame.
+        let overflowing = [zstd_frame(u64::MAX), zstd_frame(1)].concat();
+        assert_eq!(probe_bytes("overflow.zst", &overflowing), (Compression::Zstd, None));
+        assert_eq!(probe_bytes("huge.zst", &zstd_frame(u64::MAX)), (Compression::Zstd, Some(u64::MAX)));
+        assert_eq!(probe_bytes("cut.zst", &zstd_frame(7)[..10]), (Compression::Zstd, None));
+
+        assert_eq!(probe_bytes("image.img.gz", &compress(Compression::Gzip, b"image")), (Compression::Gzip, None));
+        assert_eq!(probe_bytes("image.img.bz2", &compress(Compression::Bzip2, b"image")), (Compression::Bzip2, None));
+        assert!(probe(&dir.write("image.img", &data(1000))).unwrap().is_none());
+    }
+}
@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::filesystem::{
+    verify_ntfs, ExfatVolume, FatVolume, FileSystem, FormatOptions, NtfsVolume, Timestamp, VolumeWriter,
+};
+use crate::iso::{self, Listing};
+use crate::partition::{
+    GptBuilder, MbrBuilder, PartitionScheme, PartitionTable, ALIGNMENT, MICROSOFT_BASIC_DATA, NEXT_BOOT_DEVICE,
+};
+use crate::platform::BlockTarget;
+use crate::wim::{self, SwmPart};
+
+const SECTOR_SIZE: u64 = 512;
+
+// Headroom for FAT32 metadata and cluster slack when an image file target
+// has to be sized from the ISO contents.
+const MIN_IMAGE_SIZE: u64 = 64 * 1024 * 1024;
+const SLACK_PER_ENTRY: u64 = 32 * 1024;
+
+pub fn extract_to_target(
+    source: &Path,
+    target: &mut dyn BlockTarget,
+    options: &FormatOptions,
+    progress: &Arc<Mutex<f32>>,
+) -> Result<(), String> {
+    let mut image = File::open(source).map_err(|e| e.to_string())?;
+    let descriptors = iso::read_volume_descriptors(&mut image).map_err(|e| e.to_string())?;
+    let listing = iso::list_contents(&mut image, &descriptors).map_err(|e| e.to_string())?;
+
+    // Refuse before anything on the target has been touched. Windows Setup
+    // also takes an install.wim split into `.swm` parts, which FAT32 can hold.
+    let file_system = options.file_system;
+    let mut splits = HashMap::new();
+    for entry in listing.entries.iter().filter(|e| !e.is_dir && e.size > file_system.max_file_size()) {
+        if file_system != FileSystem::Fat32 || !wim::is_wim(&entry.path) {
+            return Err(format!(
+                "{} is larger than 4 GiB, which {} cannot store; format the drive as exFAT or NTFS instead",
+                entry.path,
+                file_system.label()
+            ));
+        }
+        let parts = wim::read_wim(&mut entry.data(&mut image))
+            .and_then(|wim| wim.plan_split(wim::base_name(&entry.path), wim::DEFAULT_PART_SIZE))
+            .map_err(|e| format!("Cannot split {} for {}: {}", entry.path, file_system.label(), e))?;
+        splits.insert(entry.path.as_str(), parts);
+    }
+
+    let disk_size = match target.capacity().map_err(|e| e.to_string())? {
+        Some(capacity) => capacity,
+        None => {
+            // An image file as target: keep its size if it was made large
+            // enough beforehand, otherwise grow it to fit the files.
+            let current = target.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+            let size = current.max(image_size_for(&listing));
+            target
+                .seek(SeekFrom::Start(size - SECTOR_SIZE))
+                .and_then(|_| target.write_all(&[0; SECTOR_SIZE as usize]))
+                .map_err(|e| e.to_string())?;
+            size
+        }
+    };
+
+    // Like Rufus, name the drive after the image unless told otherwise.
+    let mut options = options.clone();
+    if options.label.trim().is_empty() {
+        options.label = descriptors.primary.volume_id.clone();
+    }
+
+    extract_image(&mut image, &listing, &splits, &options, target, disk_size, progress).map_err(|e| e.to_string())?;
+    target.sync().map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// Partitions the target with a single FAT32, exFAT or NTFS partition,
+// formats it and copies every file of the ISO onto it, files in `splits` as
+// their parts. Progress counts file bytes only, since partitioning and
+// formatting take no noticeable time.
+fn extract_image<R: Read + Seek, T: Read + Write + Seek + ?Sized>(
+    image: &mut R,
+    listing: &Listing,
+    splits: &HashMap<&str, Vec<SwmPart>>,
+    options: &FormatOptions,
+    target: &mut T,
+    disk_size: u64,
+    progress: &Arc<Mutex<f32>>,
+) -> io::Result<()> {
+    if disk_size <= ALIGNMENT {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The target is too small to partition"));
+    }
+
+    // Extracted drives boot on UEFI only: nothing on them could chain-load a
+    // BIOS boot loader, so the MBR code just hands over to the next boot
+    // device instead of hanging.
+    let table = match options.partition_scheme {
+        PartitionScheme::Mbr => PartitionTable::Mbr(
+            MbrBuilder::new(disk_size)
+                .with_boot_code(&NEXT_BOOT_DEVICE)
+                .with_disk_signature(disk_signature())
+                .with_partition(options.file_system.partition_type(), None, true)
+                .build()?,
+        ),
+        // UEFI boots from any FAT volume on a removable drive, whatever its
+        // partition type, so the volume gets the type Windows uses for data.
+        PartitionScheme::Gpt => PartitionTable::Gpt(
+            GptBuilder::new(disk_size)
+                .with_partition(MICROSOFT_BASIC_DATA, None, &options.label)
+                .build()?,
+        ),
+    };
+    let (offset, size) = table.extents()[0];
+
+    // Formatting first means bad options are rejected before the partition
+    // table is replaced. The two never overlap: the volume starts past the
+    // first MiB and, with GPT, ends before the backup table in the last
+    // sectors.
+    let mut volume: Box<dyn VolumeWriter + '_> = match options.file_system {
+        FileSystem::Fat32 => Box::new(FatVolume::format(target, offset, size, options)?),
+        FileSystem::Exfat => Box::new(ExfatVolume::format(target, offset, size, options)?),
+        FileSystem::Ntfs => Box::new(NtfsVolume::format(target, offset, size, options)?),
+    };
+    let total_size: u64 = listing
+        .entries
+        .iter()
+        .filter(|entry| !entry.is_dir && entry.symlink.is_none())
+        .map(|entry| match splits.get(entry.path.as_str()) {
+            Some(parts) => parts.iter().map(|part| part.size).sum(),
+            None => entry.size,
+        })
+        .sum();
+    let mut copied = 0u64;
+    let mut on_progress = |written| {
+        copied += written;
+        if total_size > 0 {
+            *progress.lock().unwrap() = copied as f32 / total_size as f32;
+        }
+    };
+
+    for entry in &listing.entries {
+        let modified = entry.modified.as_ref().map(|time| Timestamp {
+            year: time.year,
+            month: time.month,
+            day: time.day,
+            hour: time.hour,
+            minute: time.minute,
+            second: time.second,
+        });
+
+        // FAT has no symbolic links; the files they point to are copied anyway.
+        if entry.symlink.is_some() {
+            continue;
+        }
+
+        if entry.is_dir {
+            volume.create_dir(&entry.path, modified)?;
+            continue;
+        }
+
+        if let Some(parts) = splits.get(entry.path.as_str()) {
+            let parent = entry.path.rsplit_once('/').map_or("", |(parent, _)| parent);
+            for part in parts {
+                let path = format!("{}/{}", parent, part.name);
+                let mut data = entry.data(image);
+                volume.write_file(&path, part.size, modified, &mut part.reader(&mut data), &mut on_progress)?;
+            }
+            continue;
+        }
+
+        volume.write_file(&entry.path, entry.size, modified, &mut entry.data(image), &mut on_progress)?;
+    }
+
+    volume.finish()?;
+
+    // The MFT and its indexes are the most intricate metadata written here,
+    // so the volume is read back the way a driver would before it counts as
+    // done.
+    if options.file_system == FileSystem::Ntfs {
+        let expected: Vec<(String, Option<u64>)> = listing
+            .entries
+            .iter()
+            .filter(|entry| entry.symlink.is_none())
+            .map(|entry| (entry.path.clone(), (!entry.is_dir).then_some(entry.size)))
+            .collect();
+        verify_ntfs(target, offset, &expected)?;
+    }
+
+    match &table {
+        PartitionTable::Mbr(mbr) => mbr.write(target),
+        PartitionTable::Gpt(gpt) => gpt.write(target),
+    }
+}
+
+fn disk_signature() -> u32 {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    now.subsec_nanos() ^ now.as_secs() as u32
+}
+
+// Large enough for the files, their cluster slack and the FAT itself.
+fn image_size_for(listing: &Listing) -> u64 {
+    let needed = listing.total_file_size() + listing.entries.len() as u64 * SLACK_PER_ENTRY;
+    let size = (needed + needed / 32).max(MIN_IMAGE_SIZE) + ALIGNMENT;
+    size.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{find_volume, FileSystem};
+    use crate::iso::testiso::{directory, record, IsoBuilder, FLAG_DIRECTORY, SECTOR};
+    use crate::partition::{disk_size, read_partition_table};
+    use crate::platform::{DriveProvider, FileTarget, MockProvider};
+    use crate::testutil::TempDir;
+
+    const BOOT_FILE: &[u8] = b"MZ pretend UEFI loader";
+
+    // A plain ISO 9660 image holding just /EFI/BOOT/BOOTX64.EFI: system
+    // area, primary descriptor, terminator, three directories and the file.
+    fn boot_iso() -> Vec<u8> {
+        let (root, efi, boot, file) = (18u32, 19u32, 20u32, 21u32);
+        let mut iso = IsoBuilder::new();
+        iso.primary(16, "AYUMIOS", root, SECTOR as u32);
+        iso.terminator(17);
+        iso.put(root, &directory(root, root, &[record(b"EFI", efi, SECTOR as u32, FLAG_DIRECTORY, &[])]));
+        iso.put(efi, &directory(efi, root, &[record(b"BOOT", boot, SECTOR as u32, FLAG_DIRECTORY, &[])]));
+        let loader = record(b"BOOTX64.EFI;1", file, BOOT_FILE.len() as u32, 0, &[]);
+        iso.put(boot, &directory(boot, efi, &[loader]));
+        iso.put(file, BOOT_FILE);
+        iso.build()
+    }
+
+    fn fat32_options(partition_scheme: PartitionScheme) -> FormatOptions {
+        FormatOptions {
+            partition_scheme,
+            file_system: FileSystem::Fat32,
+            ..FormatOptions::default()
+        }
+    }
+
+    // Reads the target back the way the drive list and copy mode do: the
+    // partition table, the FAT32 volume in it and the boot loader's bytes.
+    fn check_extracted(target: &mut dyn BlockTarget) -> PartitionTable {
+        let disk_size = disk_size(target).unwrap();
+        let table = read_partition_table(target, disk_size).unwrap().unwrap();
+        let (offset, size) = table.extents()[0];
+        assert_eq!(offset, ALIGNMENT);
+        assert!(offset + size <= disk_size);
+
+        assert_eq!(find_volume(target, disk_size).unwrap(), Some(offset));
+        let mut volume = FatVolume::open(target, offset).unwrap();
+        assert_eq!(volume.read_file("/EFI/BOOT/BOOTX64.EFI").unwrap().as_deref(), Some(BOOT_FILE));
+        assert_eq!(volume.read_file("/EFI/BOOT/MISSING.EFI").unwrap(), None);
+        table
+    }
+
+    #[test]
+    fn extracts_an_iso_into_an_image_file() {
+        let dir = TempDir::new("extract-image");
+        let source = dir.write("boot.iso", &boot_iso());
+        let mut target = FileTarget::open(&dir.path().join("stick.img"), true).unwrap();
+        let progress = Arc::new(Mutex::new(0.0));
+
+        extract_to_target(&source, &mut target, &fat32_options(PartitionScheme::Mbr), &progress).unwrap();
+        assert_eq!(*progress.lock().unwrap(), 1.0);
+
+        // The empty image file grew to the smallest size extract picks.
+        assert_eq!(target.seek(SeekFrom::End(0)).unwrap(), MIN_IMAGE_SIZE + ALIGNMENT);
+        match check_extracted(&mut target) {
+            PartitionTable::Mbr(mbr) => {
+                assert_eq!(mbr.partitions.len(), 1);
+                assert!(mbr.partitions[0].active);
+                assert_eq!(mbr.partitions[0].partition_type, FileSystem::Fat32.partition_type());
+                assert!(mbr.has_boot_code());
+            }
+            PartitionTable::Gpt(_) => panic!("expected an MBR"),
+        }
+    }
+
+    #[test]
+    fn extracts_an_iso_onto_a_mock_drive_with_gpt() {
+        let dir = TempDir::new("extract-mock");
+        let source = dir.write("boot.iso", &boot_iso());
+        let provider = MockProvider::with_demo_drives();
+        let progress = Arc::new(Mutex::new(0.0));
+
+        for drive in provider.list_drives() {
+            *progress.lock().unwrap() = 0.0;
+            let mut target = provider.open_target(&drive).unwrap();
+            extract_to_target(&source, target.as_mut(), &fat32_options(PartitionScheme::Gpt), &progress).unwrap();
+            assert_eq!(*progress.lock().unwrap(), 1.0);
+
+            assert_eq!(target.capacity().unwrap(), Some(drive.size));
+            assert!(matches!(check_extracted(target.as_mut()), PartitionTable::Gpt(_)));
+        }
+    }
+}
@@ -0,0 +1,959 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::SystemTime;
+
+use super::{write_data, write_zeros, FormatOptions, Timestamp, VolumeWriter, BOOT_STUB};
+
+const SECTOR_SIZE: u64 = 512;
+const SECTOR_SHIFT: u8 = 9;
+
+// The main boot region: boot sector, eight extended boot sectors, OEM
+// parameters, a reserved sector and the checksum sector. The backup copy
+// follows right after it.
+const BOOT_REGION_SECTORS: u64 = 12;
+const CHECKSUM_SECTOR: u64 = 11;
+// Where the FAT starts, in sectors. Leaves room for both boot regions.
+const FAT_OFFSET: u32 = 128;
+
+const FIRST_CLUSTER: u32 = 2;
+const END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+const MEDIA_FIXED: u32 = 0xFFFF_FFF8;
+const MAX_CLUSTERS: u64 = 0xFFFF_FFF5;
+// The smallest volume the specification allows, in sectors: 1 MiB.
+const MIN_VOLUME_SECTORS: u64 = 2048;
+
+const MIN_CLUSTER_SIZE: u32 = 512;
+const MAX_CLUSTER_SIZE: u32 = 32 * 1024 * 1024;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_BITMAP: u8 = 0x81;
+const ENTRY_UPCASE: u8 = 0x82;
+const ENTRY_LABEL: u8 = 0x83;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xC0;
+const ENTRY_NAME: u8 = 0xC1;
+
+const ATTR_DIRECTORY: u16 = 0x10;
+const ATTR_ARCHIVE: u16 = 0x20;
+
+// Stream extension flags. Every stream here is contiguous, so the FAT is
+// left out of it and only the allocation bitmap records its clusters.
+const ALLOCATION_POSSIBLE: u8 = 0x01;
+const NO_FAT_CHAIN: u8 = 0x02;
+
+const NAME_CHARS_PER_ENTRY: usize = 15;
+const MAX_NAME_LENGTH: usize = 255;
+const MAX_LABEL_LENGTH: usize = 11;
+const MAX_DIRECTORY_SIZE: u64 = 256 * 1024 * 1024;
+
+// Marks a run of identity mappings in a compressed up-case table.
+const UPCASE_RUN: u16 = 0xFFFF;
+
+struct Geometry {
+    offset: u64,
+    volume_sectors: u64,
+    fat_length: u32,
+    heap_offset: u32,
+    cluster_count: u32,
+    cluster_shift: u8,
+}
+
+impl Geometry {
+    fn new(offset: u64, size: u64, cluster_size: u32) -> io::Result<Self> {
+        let volume_sectors = size / SECTOR_SIZE;
+        let sectors_per_cluster = cluster_size as u64 / SECTOR_SIZE;
+
+        // Size the FAT for every cluster there could be, then start the
+        // cluster heap on a cluster boundary after it.
+        let estimate = volume_sectors.saturating_sub(FAT_OFFSET as u64) / sectors_per_cluster;
+        let fat_length = ((estimate + 2) * 4).div_ceil(SECTOR_SIZE);
+        let heap_offset = (FAT_OFFSET as u64 + fat_length).div_ceil(sectors_per_cluster) * sectors_per_cluster;
+        let cluster_count = volume_sectors.saturating_sub(heap_offset) / sectors_per_cluster;
+
+        // Room for at least the bitmap, the up-case table and the root.
+        if volume_sectors < MIN_VOLUME_SECTORS || cluster_count < 16 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The volume is too small for exFAT with {} byte clusters", cluster_size),
+            ));
+        }
+        if cluster_count > MAX_CLUSTERS || heap_offset > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The volume is too large for exFAT with {} byte clusters", cluster_size),
+            ));
+        }
+
+        Ok(Self {
+            offset,
+            volume_sectors,
+            fat_length: fat_length as u32,
+            heap_offset: heap_offset as u32,
+            cluster_count: cluster_count as u32,
+            cluster_shift: sectors_per_cluster.trailing_zeros() as u8,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        SECTOR_SIZE << self.cluster_shift
+    }
+
+    fn fat_position(&self) -> u64 {
+        self.offset + FAT_OFFSET as u64 * SECTOR_SIZE
+    }
+
+    fn cluster_position(&self, cluster: u32) -> u64 {
+        self.offset + self.heap_offset as u64 * SECTOR_SIZE + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size()
+    }
+
+    fn clusters_for(&self, bytes: u64) -> u32 {
+        bytes.div_ceil(self.cluster_size()) as u32
+    }
+}
+
+// The cluster sizes Windows picks for exFAT volumes of a given size.
+fn default_cluster_size(size: u64) -> u32 {
+    const MIB: u64 = 1024 * 1024;
+    match size {
+        s if s <= 256 * MIB => 4096,
+        s if s <= 32 * 1024 * MIB => 32768,
+        _ => 128 * 1024,
+    }
+}
+
+// An exFAT volume being filled on a raw target. File data goes straight to
+// disk, each file in one contiguous run of clusters; directories, the
+// allocation bitmap and the FAT are kept in memory until `finish`.
+pub struct ExfatVolume<'a, T: ?Sized> {
+    target: &'a mut T,
+    geometry: Geometry,
+    upcase: Vec<u16>,
+    label: Vec<u16>,
+    // Timestamps for entries that come without one.
+    now: Timestamp,
+    bitmap: Vec<u8>,
+    // Only the few chains that are not contiguous need FAT entries.
+    fat: BTreeMap<u32, u32>,
+    next_free: u32,
+    bitmap_cluster: u32,
+    upcase_cluster: u32,
+    upcase_checksum: u32,
+    upcase_length: u64,
+    root_cluster: u32,
+    // The root is directories[0].
+    directories: Vec<Directory>,
+    // Directory indices keyed by up-cased path.
+    lookup: HashMap<String, usize>,
+}
+
+#[derive(Default)]
+struct Directory {
+    entries: Vec<Entry>,
+    // Up-cased names, since exFAT compares names without case.
+    names: HashSet<Vec<u16>>,
+    // Directory entries the file entry sets take up.
+    slots: u64,
+}
+
+struct Entry {
+    name: Vec<u16>,
+    attributes: u16,
+    modified: Timestamp,
+    first_cluster: u32,
+    size: u64,
+    // Index into `directories` for subdirectories.
+    directory: Option<usize>,
+}
+
+impl<'a, T: Read + Write + Seek + ?Sized> ExfatVolume<'a, T> {
+    // Writes the boot regions and up-case table of an empty volume of `size`
+    // bytes starting `offset` bytes into the target. The bitmap, the FAT and
+    // the root directory follow in `finish`.
+    pub fn format(target: &'a mut T, offset: u64, size: u64, options: &FormatOptions) -> io::Result<Self> {
+        let cluster_size = match options.cluster_size {
+            Some(cluster_size)
+                if !cluster_size.is_power_of_two()
+                    || !(MIN_CLUSTER_SIZE..=MAX_CLUSTER_SIZE).contains(&cluster_size) =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "exFAT clusters must be a power of two from 512 bytes to 32 MiB",
+                ));
+            }
+            Some(cluster_size) => cluster_size,
+            None => default_cluster_size(size),
+        };
+        let geometry = Geometry::new(offset, size, cluster_size)?;
+        let serial = options.serial.unwrap_or_else(super::random_serial);
+        let label: Vec<u16> = options.label.trim().encode_utf16().take(MAX_LABEL_LENGTH).collect();
+
+        let upcase = super::upcase_table();
+        let compressed = compress_upcase(&upcase);
+
+        let bitmap_length = (geometry.cluster_count as u64).div_ceil(8);
+        let bitmap_cluster = FIRST_CLUSTER;
+        let upcase_cluster = bitmap_cluster + geometry.clusters_for(bitmap_length);
+        let root_cluster = upcase_cluster + geometry.clusters_for(compressed.len() as u64);
+
+        // Both boot regions, the FAT and the system clusters start out empty.
+        write_zeros(target, offset, geometry.cluster_position(root_cluster + 1) - offset)?;
+
+        let boot_region = boot_region(&geometry, root_cluster, serial);
+        for copy in 0..2 {
+            target.seek(SeekFrom::Start(offset + copy * BOOT_REGION_SECTORS * SECTOR_SIZE))?;
+            target.write_all(&boot_region)?;
+        }
+
+        let mut padded = compressed.clone();
+        padded.resize(compressed.len().div_ceil(SECTOR_SIZE as usize) * SECTOR_SIZE as usize, 0);
+        target.seek(SeekFrom::Start(geometry.cluster_position(upcase_cluster)))?;
+        target.write_all(&padded)?;
+
+        let mut volume = Self {
+            target,
+            bitmap: vec![0; bitmap_length as usize],
+            fat: BTreeMap::new(),
+            next_free: FIRST_CLUSTER,
+            bitmap_cluster,
+            upcase_cluster,
+            upcase_checksum: checksum32(&compressed),
+            upcase_length: compressed.len() as u64,
+            root_cluster,
+            upcase,
+            label,
+            now: Timestamp::from_system_time(SystemTime::now()),
+            geometry,
+            directories: vec![Directory::default()],
+            lookup: HashMap::from([(String::new(), 0)]),
+        };
+
+        // Allocation starts at the first cluster, so these land where the
+        // bitmap, up-case table and root were placed above. They are the only
+        // chains recorded in the FAT.
+        for length in [upcase_cluster - bitmap_cluster, root_cluster - upcase_cluster, 1] {
+            let clusters: Vec<u32> = volume.allocate(length)?.collect();
+            volume.chain(&clusters);
+        }
+        Ok(volume)
+    }
+
+    // Resolves a directory path, creating missing directories on the way.
+    fn directory(&mut self, path: &str, modified: Option<Timestamp>) -> io::Result<usize> {
+        let mut current = 0;
+        let mut current_path = String::new();
+
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            current_path = format!("{}/{}", current_path, name);
+            let key = self.upcase_string(&current_path);
+            if let Some(&index) = self.lookup.get(&key) {
+                current = index;
+                continue;
+            }
+
+            let units = self.check_new_name(current, name)?;
+            let index = self.directories.len();
+            self.directories.push(Directory::default());
+            self.lookup.insert(key, index);
+            // Placed and sized in `finish`, once its contents are known.
+            let entry = Entry {
+                name: units,
+                attributes: ATTR_DIRECTORY,
+                modified: modified.unwrap_or(self.now),
+                first_cluster: 0,
+                size: 0,
+                directory: Some(index),
+            };
+            self.add_entry(current, entry);
+            current = index;
+        }
+        Ok(current)
+    }
+
+    fn check_new_name(&self, parent: usize, name: &str) -> io::Result<Vec<u16>> {
+        let units: Vec<u16> = name
+            .chars()
+            .map(|c| if is_name_char(c) { c } else { '_' })
+            .collect::<String>()
+            .encode_utf16()
+            .collect();
+
+        if units.is_empty() || name == "." || name == ".." {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("\"{}\" is not a valid file name", name),
+            ));
+        }
+        if units.len() > MAX_NAME_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is longer than the 255 characters exFAT allows", name),
+            ));
+        }
+
+        let parent = &self.directories[parent];
+        if parent.names.contains(&self.upcase_units(&units)) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists (exFAT names ignore letter case)", name),
+            ));
+        }
+        // Keeps a few slots spare for the root's system entries.
+        if (parent.slots + entry_count(&units) + 3) * ENTRY_SIZE as u64 > MAX_DIRECTORY_SIZE {
+            return Err(io::Error::other(format!(
+                "The directory for {} has reached the exFAT size limit",
+                name
+            )));
+        }
+        Ok(units)
+    }
+
+    fn add_entry(&mut self, parent: usize, entry: Entry) {
+        let key = self.upcase_units(&entry.name);
+        let parent = &mut self.directories[parent];
+        parent.names.insert(key);
+        parent.slots += entry_count(&entry.name);
+        parent.entries.push(entry);
+    }
+
+    // Hands out `count` consecutive clusters from the end of what has been
+    // used so far; nothing is ever freed on a volume being filled.
+    fn allocate(&mut self, count: u32) -> io::Result<std::ops::Range<u32>> {
+        let end = self.geometry.cluster_count as u64 + FIRST_CLUSTER as u64;
+        if self.next_free as u64 + count as u64 > end {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "The exFAT volume is full"));
+        }
+
+        let clusters = self.next_free..self.next_free + count;
+        for cluster in clusters.clone() {
+            let bit = (cluster - FIRST_CLUSTER) as usize;
+            self.bitmap[bit / 8] |= 1 << (bit % 8);
+        }
+        self.next_free += count;
+        Ok(clusters)
+    }
+
+    fn chain(&mut self, clusters: &[u32]) {
+        for pair in clusters.windows(2) {
+            self.fat.insert(pair[0], pair[1]);
+        }
+        if let Some(&last) = clusters.last() {
+            self.fat.insert(last, END_OF_CHAIN);
+        }
+    }
+
+    fn upcase_units(&self, units: &[u16]) -> Vec<u16> {
+        units.iter().map(|&unit| self.upcase[unit as usize]).collect()
+    }
+
+    fn upcase_string(&self, text: &str) -> String {
+        let units: Vec<u16> = text.encode_utf16().collect();
+        String::from_utf16_lossy(&self.upcase_units(&units))
+    }
+
+    // Serialises a directory into `clusters`, which must be large enough.
+    fn write_directory(&mut self, index: usize, clusters: &[u32]) -> io::Result<()> {
+        let cluster_size = self.geometry.cluster_size() as usize;
+        let mut data = vec![0u8; clusters.len() * cluster_size];
+        let mut slots = data.chunks_exact_mut(ENTRY_SIZE);
+
+        if index == 0 {
+            for entry in self.system_entries() {
+                slots.next().unwrap().copy_from_slice(&entry);
+            }
+        }
+        for entry in &self.directories[index].entries {
+            for slot in self.entry_set(entry) {
+                slots.next().unwrap().copy_from_slice(&slot);
+            }
+        }
+
+        for (cluster, chunk) in clusters.iter().zip(data.chunks(cluster_size)) {
+            self.target.seek(SeekFrom::Start(self.geometry.cluster_position(*cluster)))?;
+            self.target.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    // The label, allocation bitmap and up-case table entries that open the
+    // root directory.
+    fn system_entries(&self) -> Vec<[u8; ENTRY_SIZE]> {
+        let mut entries = Vec::new();
+
+        if !self.label.is_empty() {
+            let mut label = [0u8; ENTRY_SIZE];
+            label[0] = ENTRY_LABEL;
+            label[1] = self.label.len() as u8;
+            for (index, unit) in self.label.iter().enumerate() {
+                label[2 + index * 2..4 + index * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entries.push(label);
+        }
+
+        let mut bitmap = [0u8; ENTRY_SIZE];
+        bitmap[0] = ENTRY_BITMAP;
+        bitmap[20..24].copy_from_slice(&self.bitmap_cluster.to_le_bytes());
+        bitmap[24..32].copy_from_slice(&(self.bitmap.len() as u64).to_le_bytes());
+        entries.push(bitmap);
+
+        let mut upcase = [0u8; ENTRY_SIZE];
+        upcase[0] = ENTRY_UPCASE;
+        upcase[4..8].copy_from_slice(&self.upcase_checksum.to_le_bytes());
+        upcase[20..24].copy_from_slice(&self.upcase_cluster.to_le_bytes());
+        upcase[24..32].copy_from_slice(&self.upcase_length.to_le_bytes());
+        entries.push(upcase);
+
+        entries
+    }
+
+    // A file entry, its stream extension and the name entries, with the
+    // checksum over the whole set filled in.
+    fn entry_set(&self, entry: &Entry) -> Vec<[u8; ENTRY_SIZE]> {
+        let name_entries = entry.name.len().div_ceil(NAME_CHARS_PER_ENTRY);
+        let (timestamp, increment) = exfat_timestamp(entry.modified);
+
+        let mut file = [0u8; ENTRY_SIZE];
+        file[0] = ENTRY_FILE;
+        file[1] = 1 + name_entries as u8;
+        file[4..6].copy_from_slice(&entry.attributes.to_le_bytes());
+        // Created, modified and accessed all at once.
+        for at in [8, 12, 16] {
+            file[at..at + 4].copy_from_slice(&timestamp.to_le_bytes());
+        }
+        file[20] = increment;
+        file[21] = increment;
+
+        let mut stream = [0u8; ENTRY_SIZE];
+        stream[0] = ENTRY_STREAM;
+        stream[1] = ALLOCATION_POSSIBLE | if entry.first_cluster != 0 { NO_FAT_CHAIN } else { 0 };
+        stream[3] = entry.name.len() as u8;
+        stream[4..6].copy_from_slice(&name_hash(&self.upcase_units(&entry.name)).to_le_bytes());
+        stream[8..16].copy_from_slice(&entry.size.to_le_bytes());
+        stream[20..24].copy_from_slice(&entry.first_cluster.to_le_bytes());
+        stream[24..32].copy_from_slice(&entry.size.to_le_bytes());
+
+        let mut set = vec![file, stream];
+        for piece in entry.name.chunks(NAME_CHARS_PER_ENTRY) {
+            let mut name = [0u8; ENTRY_SIZE];
+            name[0] = ENTRY_NAME;
+            for (index, unit) in piece.iter().enumerate() {
+                name[2 + index * 2..4 + index * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            set.push(name);
+        }
+
+        let checksum = set_checksum(&set);
+        set[0][2..4].copy_from_slice(&checksum.to_le_bytes());
+        set
+    }
+}
+
+impl<T: Read + Write + Seek + ?Sized> VolumeWriter for ExfatVolume<'_, T> {
+    fn create_dir(&mut self, path: &str, modified: Option<Timestamp>) -> io::Result<()> {
+        self.directory(path, modified).map(|_| ())
+    }
+
+    fn write_file(
+        &mut self,
+        path: &str,
+        size: u64,
+        modified: Option<Timestamp>,
+        source: &mut dyn Read,
+        on_progress: &mut dyn FnMut(u64),
+    ) -> io::Result<()> {
+        let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self.directory(parent_path, None)?;
+        let units = self.check_new_name(parent, name)?;
+
+        let cluster_size = self.geometry.cluster_size();
+        let clusters = self.allocate(size.div_ceil(cluster_size) as u32)?;
+        let first_cluster = if clusters.is_empty() { 0 } else { clusters.start };
+
+        if first_cluster != 0 {
+            self.target
+                .seek(SeekFrom::Start(self.geometry.cluster_position(first_cluster)))?;
+        }
+
+        write_data(self.target, source, size, on_progress)?;
+
+        let entry = Entry {
+            name: units,
+            attributes: ATTR_ARCHIVE,
+            modified: modified.unwrap_or(self.now),
+            first_cluster,
+            size,
+            directory: None,
+        };
+        self.add_entry(parent, entry);
+        Ok(())
+    }
+
+    // Sizes and places every directory, then writes them along with the
+    // allocation bitmap and the FAT.
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let cluster_size = self.geometry.cluster_size();
+        let sizes: Vec<u32> = (0..self.directories.len())
+            .map(|index| {
+                let system = if index == 0 { self.system_entries().len() as u64 } else { 0 };
+                let slots = system + self.directories[index].slots;
+                self.geometry.clusters_for(slots * ENTRY_SIZE as u64).max(1)
+            })
+            .collect();
+
+        // The root already has its first cluster; anything more is chained
+        // on through the FAT.
+        let mut root_clusters = vec![self.root_cluster];
+        root_clusters.extend(self.allocate(sizes[0] - 1)?);
+        self.chain(&root_clusters);
+
+        let mut placements = vec![root_clusters];
+        for &size in &sizes[1..] {
+            placements.push(self.allocate(size)?.collect());
+        }
+
+        for directory in 0..self.directories.len() {
+            for entry in 0..self.directories[directory].entries.len() {
+                if let Some(child) = self.directories[directory].entries[entry].directory {
+                    let entry = &mut self.directories[directory].entries[entry];
+                    entry.first_cluster = placements[child][0];
+                    entry.size = sizes[child] as u64 * cluster_size;
+                }
+            }
+        }
+        for (index, clusters) in placements.iter().enumerate() {
+            self.write_directory(index, clusters)?;
+        }
+
+        let mut bitmap = self.bitmap.clone();
+        bitmap.resize(bitmap.len().div_ceil(SECTOR_SIZE as usize) * SECTOR_SIZE as usize, 0);
+        self.target
+            .seek(SeekFrom::Start(self.geometry.cluster_position(self.bitmap_cluster)))?;
+        self.target.write_all(&bitmap)?;
+
+        self.write_fat()?;
+        self.target.flush()
+    }
+}
+
+impl<T: Write + Seek + ?Sized> ExfatVolume<'_, T> {
+    // Writes the sectors of the FAT that hold non-zero entries; format
+    // cleared the rest.
+    fn write_fat(&mut self) -> io::Result<()> {
+        let entries_per_sector = SECTOR_SIZE as u32 / 4;
+        let mut entries = self.fat.clone();
+        entries.insert(0, MEDIA_FIXED);
+        entries.insert(1, END_OF_CHAIN);
+
+        let sectors: Vec<u32> = entries.keys().map(|cluster| cluster / entries_per_sector).collect();
+        let mut last_written = None;
+        for sector in sectors {
+            if last_written == Some(sector) {
+                continue;
+            }
+            last_written = Some(sector);
+
+            let mut bytes = [0u8; SECTOR_SIZE as usize];
+            let first = sector * entries_per_sector;
+            for (cluster, value) in entries.range(first..first + entries_per_sector) {
+                let at = ((cluster - first) * 4) as usize;
+                bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            }
+
+            let position = self.geometry.fat_position() + sector as u64 * SECTOR_SIZE;
+            self.target.seek(SeekFrom::Start(position))?;
+            self.target.write_all(&bytes)?;
+        }
+        Ok(())
+    }
+}
+
+// Slots taken by a file entry set: file, stream extension and one name
+// entry per 15 characters.
+fn entry_count(name: &[u16]) -> u64 {
+    2 + name.len().div_ceil(NAME_CHARS_PER_ENTRY) as u64
+}
+
+// The main boot region: boot sector, extended boot sectors, OEM parameters,
+// a reserved sector and the checksum of all of them.
+fn boot_region(geometry: &Geometry, root_cluster: u32, serial: u32) -> Vec<u8> {
+    let sector_size = SECTOR_SIZE as usize;
+    let mut region = vec![0u8; BOOT_REGION_SECTORS as usize * sector_size];
+
+    let boot = &mut region[..sector_size];
+    boot[0..3].copy_from_slice(&[0xEB, 0x76, 0x90]);
+    boot[3..11].copy_from_slice(b"EXFAT   ");
+    boot[64..72].copy_from_slice(&(geometry.offset / SECTOR_SIZE).to_le_bytes());
+    boot[72..80].copy_from_slice(&geometry.volume_sectors.to_le_bytes());
+    boot[80..84].copy_from_slice(&FAT_OFFSET.to_le_bytes());
+    boot[84..88].copy_from_slice(&geometry.fat_length.to_le_bytes());
+    boot[88..92].copy_from_slice(&geometry.heap_offset.to_le_bytes());
+    boot[92..96].copy_from_slice(&geometry.cluster_count.to_le_bytes());
+    boot[96..100].copy_from_slice(&root_cluster.to_le_bytes());
+    boot[100..104].copy_from_slice(&serial.to_le_bytes());
+    // Revision 1.00.
+    boot[104..106].copy_from_slice(&0x0100u16.to_le_bytes());
+    boot[108] = SECTOR_SHIFT;
+    boot[109] = geometry.cluster_shift;
+    boot[110] = 1;
+    boot[111] = 0x80;
+    // Percentage in use: not worked out.
+    boot[112] = 0xFF;
+    boot[120..120 + BOOT_STUB.len()].copy_from_slice(&BOOT_STUB);
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+
+    // Extended boot sectors carry only their signature.
+    for sector in 1..=8 {
+        region[(sector + 1) * sector_size - 2..(sector + 1) * sector_size].copy_from_slice(&[0x55, 0xAA]);
+    }
+
+    let checksum = boot_checksum(&region[..CHECKSUM_SECTOR as usize * sector_size]);
+    for slot in region[CHECKSUM_SECTOR as usize * sector_size..].chunks_exact_mut(4) {
+        slot.copy_from_slice(&checksum.to_le_bytes());
+    }
+    region
+}
+
+// Skips the volume flags and percentage in use, which may change without
+// the checksum being redone.
+fn boot_checksum(sectors: &[u8]) -> u32 {
+    sectors
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !matches!(index, 106 | 107 | 112))
+        .fold(0u32, |sum, (_, &byte)| sum.rotate_right(1).wrapping_add(byte as u32))
+}
+
+fn checksum32(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0u32, |sum, &byte| sum.rotate_right(1).wrapping_add(byte as u32))
+}
+
+// Covers the whole set except the checksum field itself.
+fn set_checksum(set: &[[u8; ENTRY_SIZE]]) -> u16 {
+    set.iter()
+        .flatten()
+        .enumerate()
+        .filter(|(index, _)| !matches!(index, 2 | 3))
+        .fold(0u16, |sum, (_, &byte)| sum.rotate_right(1).wrapping_add(byte as u16))
+}
+
+// Lets drivers skip most name comparisons during lookups.
+fn name_hash(upcased: &[u16]) -> u16 {
+    upcased
+        .iter()
+        .flat_map(|unit| unit.to_le_bytes())
+        .fold(0u16, |hash, byte| hash.rotate_right(1).wrapping_add(byte as u16))
+}
+
+// Stores long runs of identity mappings as a marker and a length, the
+// optional compression drivers all understand.
+fn compress_upcase(table: &[u16]) -> Vec<u8> {
+    let mut units = Vec::new();
+    let mut index = 0;
+    while index < table.len() {
+        let run = table[index..]
+            .iter()
+            .enumerate()
+            .take_while(|&(offset, &unit)| unit as usize == index + offset)
+            .count();
+
+        // 0xFFFF maps to itself and could not be written out plainly.
+        if run > 2 || (run > 0 && index + run == table.len()) {
+            units.extend([UPCASE_RUN, run as u16]);
+            index += run;
+        } else {
+            units.push(table[index]);
+            index += 1;
+        }
+    }
+    units.iter().flat_map(|unit| unit.to_le_bytes()).collect()
+}
+
+// exFAT timestamps pack the date and time like FAT does, with the odd
+// second in a separate 10 ms count. No UTC offset is recorded.
+fn exfat_timestamp(time: Timestamp) -> (u32, u8) {
+    if time.year < 1980 {
+        return ((1 << 21) | (1 << 16), 0);
+    }
+
+    let year = (time.year - 1980).min(127) as u32;
+    let timestamp = (year << 25)
+        | ((time.month as u32) << 21)
+        | ((time.day as u32) << 16)
+        | ((time.hour as u32) << 11)
+        | ((time.minute as u32) << 5)
+        | (time.second as u32 / 2);
+    (timestamp, (time.second % 2) * 100)
+}
+
+fn is_name_char(c: char) -> bool {
+    c >= ' ' && !"\"*/:<>?\\|".contains(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    const MIB: u64 = 1024 * 1024;
+    const OFFSET: u64 = MIB;
+    const SIZE: u64 = 64 * MIB;
+
+    // The checksums as the specification spells them out: shift right by
+    // one, carry the low bit to the top and add the next byte.
+    fn spec_checksum32(bytes: impl IntoIterator<Item = u8>) -> u32 {
+        bytes.into_iter().fold(0u32, |sum, byte| ((sum & 1) << 31 | sum >> 1).wrapping_add(byte as u32))
+    }
+
+    fn spec_checksum16(bytes: impl IntoIterator<Item = u8>) -> u16 {
+        bytes.into_iter().fold(0u16, |sum, byte| ((sum & 1) << 15 | sum >> 1).wrapping_add(byte as u16))
+    }
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    // Reads a volume back the way a driver would, checking every checksum
+    // on the way and that no cluster is used twice.
+    struct Checker<'a> {
+        image: &'a [u8],
+        heap: usize,
+        cluster_size: usize,
+        cluster_count: u32,
+        fat: Vec<u32>,
+        used: HashSet<u32>,
+        upcase: Vec<u16>,
+        // Data by path, `None` for directories.
+        files: BTreeMap<String, Option<Vec<u8>>>,
+    }
+
+    impl<'a> Checker<'a> {
+        fn new(image: &'a [u8], offset: usize) -> Self {
+            let boot = &image[offset..offset + 512];
+            assert_eq!(&boot[..11], b"\xEB\x76\x90EXFAT   ");
+            assert_eq!(u64_at(boot, 64), offset as u64 / 512);
+            assert_eq!(boot[108], SECTOR_SHIFT);
+
+            // Every word of the checksum sector repeats the checksum of the
+            // eleven sectors before it, and the backup region matches.
+            let region = &image[offset..offset + 12 * 512];
+            let checksum = spec_checksum32(
+                region[..11 * 512]
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| !matches!(index, 106 | 107 | 112))
+                    .map(|(_, &byte)| byte),
+            );
+            assert!(region[11 * 512..].chunks_exact(4).all(|word| u32_at(word, 0) == checksum));
+            for sector in 1..=8 {
+                assert_eq!(&region[sector * 512 + 510..(sector + 1) * 512], &[0x55, 0xAA]);
+            }
+            assert_eq!(&image[offset + 12 * 512..offset + 24 * 512], region);
+
+            let cluster_count = u32_at(boot, 92);
+            let fat_start = offset + u32_at(boot, 80) as usize * 512;
+            let fat = (0..cluster_count as usize + 2).map(|index| u32_at(image, fat_start + index * 4)).collect::<Vec<_>>();
+            assert_eq!(fat[..2], [MEDIA_FIXED, END_OF_CHAIN]);
+
+            Self {
+                image,
+                heap: offset + u32_at(boot, 88) as usize * 512,
+                cluster_size: 512 << boot[109],
+                cluster_count,
+                fat,
+                used: HashSet::new(),
+                upcase: Vec::new(),
+                files: BTreeMap::new(),
+            }
+        }
+
+        fn claim(&mut self, cluster: u32) {
+            assert!((FIRST_CLUSTER..self.cluster_count + FIRST_CLUSTER).contains(&cluster), "cluster {}", cluster);
+            assert!(self.used.insert(cluster), "cluster {} is used twice", cluster);
+        }
+
+        // The clusters of a stream: consecutive ones without a FAT chain,
+        // otherwise followed through the FAT.
+        fn clusters(&mut self, first: u32, length: u64, no_fat_chain: bool) -> Vec<u32> {
+            let count = length.div_ceil(self.cluster_size as u64) as usize;
+            let clusters: Vec<u32> = if no_fat_chain {
+                (first..first + count as u32).collect()
+            } else {
+                let mut clusters = vec![first];
+                while clusters.len() < count {
+                    clusters.push(self.fat[*clusters.last().unwrap() as usize]);
+                }
+                assert_eq!(self.fat[*clusters.last().unwrap() as usize], END_OF_CHAIN);
+                clusters
+            };
+            clusters.iter().for_each(|&cluster| self.claim(cluster));
+            clusters
+        }
+
+        fn read(&self, clusters: &[u32], length: u64) -> Vec<u8> {
+            let mut data: Vec<u8> = clusters
+                .iter()
+                .flat_map(|&cluster| {
+                    let start = self.heap + (cluster - FIRST_CLUSTER) as usize * self.cluster_size;
+                    self.image[start..start + self.cluster_size].iter().copied()
+                })
+                .collect();
+            data.truncate(length as usize);
+            data
+        }
+
+        fn check(mut self, root: u32) -> BTreeMap<String, Option<Vec<u8>>> {
+            let mut root_clusters = vec![root];
+            while self.fat[*root_clusters.last().unwrap() as usize] != END_OF_CHAIN {
+                root_clusters.push(self.fat[*root_clusters.last().unwrap() as usize]);
+            }
+            root_clusters.iter().for_each(|&cluster| self.claim(cluster));
+            let root_data = self.read(&root_clusters, (root_clusters.len() * self.cluster_size) as u64);
+
+            let entry = |kind: u8| root_data.chunks_exact(ENTRY_SIZE).find(|entry| entry[0] == kind).unwrap();
+            let (bitmap, upcase) = (entry(ENTRY_BITMAP), entry(ENTRY_UPCASE));
+
+            let bitmap_clusters = self.clusters(u32_at(bitmap, 20), u64_at(bitmap, 24), false);
+            let bitmap = self.read(&bitmap_clusters, u64_at(bitmap, 24));
+            assert_eq!(bitmap.len() as u64, (self.cluster_count as u64).div_ceil(8));
+
+            let upcase_clusters = self.clusters(u32_at(upcase, 20), u64_at(upcase, 24), false);
+            let compressed = self.read(&upcase_clusters, u64_at(upcase, 24));
+            assert_eq!(spec_checksum32(compressed.iter().copied()), u32_at(upcase, 4));
+            let units: Vec<u16> = compressed.chunks_exact(2).map(|unit| u16_at(unit, 0)).collect();
+            let mut index = 0;
+            while index < units.len() {
+                if units[index] == UPCASE_RUN {
+                    let start = self.upcase.len() as u16;
+                    self.upcase.extend((0..units[index + 1]).map(|offset| start + offset));
+                    index += 2;
+                } else {
+                    self.upcase.push(units[index]);
+                    index += 1;
+                }
+            }
+            assert_eq!(self.upcase.len(), 0x10000);
+            assert_eq!(self.upcase['a' as usize], 'A' as u16);
+            assert_eq!(self.upcase['ü' as usize], 'Ü' as u16);
+
+            self.walk(&root_data, "");
+
+            // Exactly the clusters in use are marked in the bitmap.
+            for cluster in FIRST_CLUSTER..self.cluster_count + FIRST_CLUSTER {
+                let bit = (cluster - FIRST_CLUSTER) as usize;
+                assert_eq!(bitmap[bit / 8] >> (bit % 8) & 1 == 1, self.used.contains(&cluster), "cluster {}", cluster);
+            }
+            self.files
+        }
+
+        fn walk(&mut self, data: &[u8], path: &str) {
+            let mut names = HashSet::new();
+            let mut at = 0;
+            while at < data.len() && data[at] != 0 {
+                if path.is_empty() && matches!(data[at], ENTRY_BITMAP | ENTRY_UPCASE | ENTRY_LABEL) {
+                    at += ENTRY_SIZE;
+                    continue;
+                }
+                assert_eq!(data[at], ENTRY_FILE);
+                let set = &data[at..at + (data[at + 1] as usize + 1) * ENTRY_SIZE];
+                at += set.len();
+
+                let checksum = spec_checksum16(
+                    set.iter().enumerate().filter(|(index, _)| !matches!(index, 2 | 3)).map(|(_, &byte)| byte),
+                );
+                assert_eq!(checksum, u16_at(set, 2));
+                let timestamp = u32_at(set, 12);
+                assert!((1..=12).contains(&(timestamp >> 21 & 15)) && timestamp >> 16 & 31 >= 1);
+
+                let stream = &set[ENTRY_SIZE..2 * ENTRY_SIZE];
+                assert_eq!(stream[0], ENTRY_STREAM);
+                let name_length = stream[3] as usize;
+                assert_eq!(set.len() / ENTRY_SIZE - 2, name_length.div_ceil(NAME_CHARS_PER_ENTRY));
+                let units: Vec<u16> = set[2 * ENTRY_SIZE..]
+                    .chunks_exact(ENTRY_SIZE)
+                    .flat_map(|entry| {
+                        assert_eq!(entry[0], ENTRY_NAME);
+                        entry[2..].chunks_exact(2).map(|unit| u16_at(unit, 0)).collect::<Vec<_>>()
+                    })
+                    .collect();
+                assert!(units[name_length..].iter().all(|&unit| unit == 0));
+                let upcased: Vec<u16> = units[..name_length].iter().map(|&unit| self.upcase[unit as usize]).collect();
+                assert_eq!(spec_checksum16(upcased.iter().flat_map(|unit| unit.to_le_bytes())), u16_at(stream, 4));
+                assert!(names.insert(upcased), "a name is used twice");
+
+                let child = format!("{}/{}", path, String::from_utf16(&units[..name_length]).unwrap());
+                let (first, length) = (u32_at(stream, 20), u64_at(stream, 24));
+                assert_eq!(u64_at(stream, 8), length);
+                let clusters = match length {
+                    0 => {
+                        assert_eq!(first, 0);
+                        Vec::new()
+                    }
+                    _ => self.clusters(first, length, stream[1] & NO_FAT_CHAIN != 0),
+                };
+                let contents = self.read(&clusters, length);
+                if u16_at(set, 4) & ATTR_DIRECTORY != 0 {
+                    self.files.insert(child.clone(), None);
+                    self.walk(&contents, &child);
+                } else {
+                    self.files.insert(child, Some(contents));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn formatted_image_reads_back_with_valid_checksums_and_bitmap() {
+        let dir = TempDir::new("exfat-image");
+        let path = dir.path().join("exfat.img");
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        file.set_len(OFFSET + SIZE).unwrap();
+
+        let options = FormatOptions {
+            label: "Ayumi".to_string(),
+            serial: Some(0x1234_ABCD),
+            ..FormatOptions::default()
+        };
+        let mut files = BTreeMap::new();
+        // 4 KiB clusters: three clusters and a bit, a name taking three name
+        // entries, one that differs from another only in case once up-cased
+        // and an empty file.
+        files.insert("/Sources/install.wim".to_string(), (0..13_000u32).map(|i| (i * 7) as u8).collect::<Vec<_>>());
+        files.insert("/Sources/A name long enough for three name entries.txt".to_string(), b"long".to_vec());
+        files.insert("/Ünïcödé.txt".to_string(), b"accents".to_vec());
+        files.insert("/empty".to_string(), Vec::new());
+        // 40 more sets of three slots overflow the root's first cluster of
+        // 128 slots, so it gets a FAT chain.
+        for number in 0..40 {
+            files.insert(format!("/file {:02}.txt", number), vec![number as u8; 100]);
+        }
+
+        let mut volume: Box<dyn VolumeWriter> = Box::new(ExfatVolume::format(&mut file, OFFSET, SIZE, &options).unwrap());
+        for (path, data) in &files {
+            volume.write_file(path, data.len() as u64, None, &mut data.as_slice(), &mut |_| {}).unwrap();
+        }
+        volume.finish().unwrap();
+        drop(file);
+
+        let image = fs::read(&path).unwrap();
+        let boot = &image[OFFSET as usize..];
+        assert_eq!(u32_at(boot, 100), 0x1234_ABCD);
+        let read_back = Checker::new(&image, OFFSET as usize).check(u32_at(boot, 96));
+
+        let mut expected: BTreeMap<String, Option<Vec<u8>>> =
+            files.into_iter().map(|(path, data)| (path, Some(data))).collect();
+        expected.insert("/Sources".to_string(), None);
+        assert!(read_back == expected);
+    }
+}
@@ -0,0 +1,1303 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use super::{invalid, write_data, write_zeros, FormatOptions, Timestamp, VolumeWriter, BOOT_STUB};
+use crate::partition;
+
+const SECTOR_SIZE: u64 = 512;
+const RESERVED_SECTORS: u32 = 32;
+const FAT_COUNT: u32 = 2;
+const FSINFO_SECTOR: u64 = 1;
+const BACKUP_BOOT_SECTOR: u64 = 6;
+const ROOT_CLUSTER: u32 = 2;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+const CLUSTER_MASK: u32 = 0x0FFF_FFFF;
+// From here up the values mark bad clusters and the ends of chains.
+const FIRST_RESERVED_CLUSTER: u32 = 0x0FFF_FFF7;
+const MEDIA_FIXED: u8 = 0xF8;
+
+// Below this many clusters drivers decide the volume is FAT16, whatever the
+// boot sector says. Above the maximum, cluster numbers run into the reserved
+// values at the top of the 28-bit range.
+const MIN_CLUSTERS: u64 = 65525;
+const MAX_CLUSTERS: u64 = 0x0FFF_FFF5;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+// Windows NT records the case of otherwise valid 8.3 names in these bits.
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+const MIN_CLUSTER_SIZE: u32 = 512;
+const MAX_CLUSTER_SIZE: u32 = 64 * 1024;
+
+// What the boot sector says when the volume has no label.
+const NO_LABEL: [u8; 11] = *b"NO NAME    ";
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+// Directories may hold at most 65536 entries.
+const MAX_DIRECTORY_SIZE: usize = 65536 * ENTRY_SIZE;
+
+// Long names are UTF-16, 13 units per entry and 255 units at most.
+const LFN_CHARS_PER_ENTRY: usize = 13;
+const LFN_MAX_LENGTH: usize = 255;
+const LFN_LAST_ENTRY: u8 = 0x40;
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+pub const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+#[derive(Clone, Copy)]
+pub struct Geometry {
+    // Where the volume starts on the target, in bytes.
+    offset: u64,
+    cluster_size: u32,
+    reserved_sectors: u32,
+    fat_count: u32,
+    total_sectors: u32,
+    fat_sectors: u32,
+    cluster_count: u32,
+    root_cluster: u32,
+    fsinfo_sector: u64,
+}
+
+impl Geometry {
+    fn new(offset: u64, size: u64, cluster_size: u32) -> io::Result<Self> {
+        let total_sectors = (size / SECTOR_SIZE).min(u32::MAX as u64);
+        let sectors_per_cluster = cluster_size as u64 / SECTOR_SIZE;
+
+        // Four bytes per cluster plus the two reserved entries. Sizing the FAT
+        // as if every sector held data overestimates it by a few sectors at
+        // most, which is simpler than solving for the exact fit.
+        let estimate = total_sectors.saturating_sub(RESERVED_SECTORS as u64) / sectors_per_cluster;
+        let fat_sectors = ((estimate + 2) * 4).div_ceil(SECTOR_SIZE);
+        let data_sectors = total_sectors
+            .saturating_sub(RESERVED_SECTORS as u64 + FAT_COUNT as u64 * fat_sectors);
+        let cluster_count = data_sectors / sectors_per_cluster;
+
+        if cluster_count < MIN_CLUSTERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The volume is too small for FAT32 with {} byte clusters", cluster_size),
+            ));
+        }
+        if cluster_count > MAX_CLUSTERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The volume is too large for FAT32 with {} byte clusters", cluster_size),
+            ));
+        }
+
+        Ok(Self {
+            offset,
+            cluster_size,
+            reserved_sectors: RESERVED_SECTORS,
+            fat_count: FAT_COUNT,
+            total_sectors: total_sectors as u32,
+            fat_sectors: fat_sectors as u32,
+            cluster_count: cluster_count as u32,
+            root_cluster: ROOT_CLUSTER,
+            fsinfo_sector: FSINFO_SECTOR,
+        })
+    }
+
+    // Reads the layout of an existing volume from its boot sector.
+    fn parse(offset: u64, sector: &[u8]) -> io::Result<Self> {
+        if !is_fat32_boot_sector(sector) {
+            return Err(invalid("Not a FAT32 volume"));
+        }
+
+        let sectors_per_cluster = sector[13] as u32;
+        let reserved_sectors = u16_at(sector, 14) as u32;
+        let fat_count = sector[16] as u32;
+        let total_sectors = u32_at(sector, 32);
+        let fat_sectors = u32_at(sector, 36);
+        if !sectors_per_cluster.is_power_of_two() || reserved_sectors == 0 || fat_count == 0 {
+            return Err(invalid("The FAT32 boot sector is corrupt"));
+        }
+
+        let data_start = reserved_sectors as u64 + fat_count as u64 * fat_sectors as u64;
+        let cluster_count = (total_sectors as u64).saturating_sub(data_start) / sectors_per_cluster as u64;
+        // The FAT has to have room for every cluster.
+        if cluster_count < MIN_CLUSTERS || (cluster_count + 2) * 4 > fat_sectors as u64 * SECTOR_SIZE {
+            return Err(invalid("The FAT32 boot sector is corrupt"));
+        }
+
+        let root_cluster = u32_at(sector, 44);
+        if root_cluster < 2 || root_cluster as u64 >= cluster_count + 2 {
+            return Err(invalid("The FAT32 root directory lies outside the volume"));
+        }
+
+        Ok(Self {
+            offset,
+            cluster_size: sectors_per_cluster * SECTOR_SIZE as u32,
+            reserved_sectors,
+            fat_count,
+            total_sectors,
+            fat_sectors,
+            cluster_count: cluster_count as u32,
+            root_cluster,
+            fsinfo_sector: u16_at(sector, 48) as u64,
+        })
+    }
+
+    fn fat_offset(&self, copy: u32) -> u64 {
+        self.offset + (self.reserved_sectors + copy * self.fat_sectors) as u64 * SECTOR_SIZE
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.fat_offset(self.fat_count) + (cluster - 2) as u64 * self.cluster_size as u64
+    }
+}
+
+// The cluster sizes Windows picks for FAT32 volumes of a given size.
+fn default_cluster_size(size: u64) -> u32 {
+    const MIB: u64 = 1024 * 1024;
+    match size {
+        s if s <= 64 * MIB => 512,
+        s if s <= 128 * MIB => 1024,
+        s if s <= 256 * MIB => 2048,
+        s if s <= 8 * 1024 * MIB => 4096,
+        s if s <= 16 * 1024 * MIB => 8192,
+        s if s <= 32 * 1024 * MIB => 16384,
+        _ => 32768,
+    }
+}
+
+// Writes an empty FAT32 filesystem of `size` bytes starting `offset` bytes
+// into the target: boot sector and its backup, FSInfo, both FATs and the
+// root directory cluster, holding just the volume label.
+pub fn format<T: Write + Seek + ?Sized>(
+    target: &mut T,
+    offset: u64,
+    size: u64,
+    options: &FormatOptions,
+) -> io::Result<Geometry> {
+    let cluster_size = match options.cluster_size {
+        Some(cluster_size)
+            if !cluster_size.is_power_of_two()
+                || !(MIN_CLUSTER_SIZE..=MAX_CLUSTER_SIZE).contains(&cluster_size) =>
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "FAT32 clusters must be a power of two from 512 bytes to 64 KiB",
+            ));
+        }
+        Some(cluster_size) => cluster_size,
+        None => default_cluster_size(size),
+    };
+    let geometry = Geometry::new(offset, size, cluster_size)?;
+    let label = volume_label(&options.label);
+    let serial = options.serial.unwrap_or_else(super::random_serial);
+
+    // The reserved area, both FATs and the root directory start out empty.
+    write_zeros(target, offset, geometry.cluster_offset(ROOT_CLUSTER + 1) - offset)?;
+
+    let boot_sector = boot_sector(&geometry, &label, serial);
+    for sector in [0, BACKUP_BOOT_SECTOR] {
+        target.seek(SeekFrom::Start(offset + sector * SECTOR_SIZE))?;
+        target.write_all(&boot_sector)?;
+    }
+
+    // Drives only take whole sectors, so even three FAT entries and a
+    // single directory entry go out as a full sector each.
+    let mut fat = [0u8; SECTOR_SIZE as usize];
+    for (index, entry) in initial_fat().iter().enumerate() {
+        fat[index * 4..index * 4 + 4].copy_from_slice(&entry.to_le_bytes());
+    }
+    for copy in 0..FAT_COUNT {
+        target.seek(SeekFrom::Start(geometry.fat_offset(copy)))?;
+        target.write_all(&fat)?;
+    }
+
+    write_fsinfo(target, &geometry, geometry.cluster_count - 1, ROOT_CLUSTER + 1)?;
+
+    if label != NO_LABEL {
+        let mut root = [0u8; SECTOR_SIZE as usize];
+        root[..ENTRY_SIZE].copy_from_slice(&directory_entry(&label, ATTR_VOLUME_ID, 0, 0, 0, None));
+        target.seek(SeekFrom::Start(geometry.cluster_offset(ROOT_CLUSTER)))?;
+        target.write_all(&root)?;
+    }
+
+    Ok(geometry)
+}
+
+// Looks for a FAT32 volume covering the whole disk ("superfloppy") or in
+// one of its partitions, and returns its offset in bytes.
+pub fn find_volume<T: Read + Seek + ?Sized>(target: &mut T, disk_size: u64) -> io::Result<Option<u64>> {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    target.seek(SeekFrom::Start(0))?;
+    target.read_exact(&mut sector)?;
+    if is_fat32_boot_sector(&sector) {
+        return Ok(Some(0));
+    }
+    let Some(table) = partition::read_partition_table(target, disk_size)? else {
+        return Ok(None);
+    };
+
+    // Partition types are not trusted; the boot sector decides.
+    for (offset, _) in table.extents() {
+        target.seek(SeekFrom::Start(offset))?;
+        target.read_exact(&mut sector)?;
+        if is_fat32_boot_sector(&sector) {
+            return Ok(Some(offset));
+        }
+    }
+    Ok(None)
+}
+
+// FAT32 has no fixed root directory and no 16-bit FAT size. Only 512 byte
+// sectors are handled, which is what USB drives use.
+fn is_fat32_boot_sector(sector: &[u8]) -> bool {
+    sector[510..512] == [0x55, 0xAA]
+        && (sector[0] == 0xEB || sector[0] == 0xE9)
+        && u16_at(sector, 11) as u64 == SECTOR_SIZE
+        && u16_at(sector, 17) == 0
+        && u16_at(sector, 22) == 0
+        && u32_at(sector, 36) != 0
+}
+
+// A FAT32 volume on a raw target, without help from the OS. Directories are
+// read when first needed and kept in memory, as is the FAT, which `finish`
+// writes back. Every read and write covers whole sectors, as raw devices
+// require.
+pub struct FatVolume<'a, T: ?Sized> {
+    target: &'a mut T,
+    geometry: Geometry,
+    fat: Vec<u32>,
+    // The range of FAT entries changed since the volume was opened.
+    dirty: Option<(usize, usize)>,
+    free_clusters: u32,
+    // Where the search for free clusters picks up.
+    next_free: u32,
+    // Keyed by the directory's first cluster.
+    directories: HashMap<u32, Directory>,
+}
+
+struct Directory {
+    clusters: Vec<u32>,
+    data: Vec<u8>,
+    // Keyed by upper-cased name, since FAT names ignore letter case.
+    entries: HashMap<String, DirEntry>,
+    short_names: HashSet<[u8; 11]>,
+    // Offset of the end-of-directory marker, where new entries go.
+    end: usize,
+}
+
+#[derive(Clone)]
+struct DirEntry {
+    // Byte offsets of the first long name slot and of the short entry.
+    first_slot: usize,
+    slot: usize,
+    attributes: u8,
+    first_cluster: u32,
+}
+
+impl DirEntry {
+    fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+}
+
+impl<'a, T: Read + Write + Seek + ?Sized> FatVolume<'a, T> {
+    pub fn format(target: &'a mut T, offset: u64, size: u64, options: &FormatOptions) -> io::Result<Self> {
+        format(target, offset, size, options)?;
+        Self::open(target, offset)
+    }
+
+    pub fn open(target: &'a mut T, offset: u64) -> io::Result<Self> {
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+        target.seek(SeekFrom::Start(offset))?;
+        target.read_exact(&mut sector)?;
+        let geometry = Geometry::parse(offset, &sector)?;
+
+        let mut bytes = vec![0; geometry.fat_sectors as usize * SECTOR_SIZE as usize];
+        target.seek(SeekFrom::Start(geometry.fat_offset(0)))?;
+        target.read_exact(&mut bytes)?;
+
+        let fat: Vec<u32> = bytes
+            .chunks_exact(4)
+            .take(geometry.cluster_count as usize + 2)
+            .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+            .collect();
+        let free_clusters = fat[2..].iter().filter(|&&entry| entry & CLUSTER_MASK == 0).count() as u32;
+
+        Ok(Self {
+            target,
+            geometry,
+            fat,
+            dirty: None,
+            free_clusters,
+            next_free: 2,
+            directories: HashMap::new(),
+        })
+    }
+
+    // Deletes the file at `path` and frees its clusters. Returns whether
+    // there was such a file.
+    pub fn remove_file(&mut self, path: &str) -> io::Result<bool> {
+        let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let Some(parent) = self.directory(parent_path, false, None)? else {
+            return Ok(false);
+        };
+
+        let directory = self.directories.get_mut(&parent).unwrap();
+        let Some(entry) = directory.entries.get(&name.to_uppercase()).cloned() else {
+            return Ok(false);
+        };
+        if entry.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is a directory", path),
+            ));
+        }
+
+        directory.entries.remove(&name.to_uppercase());
+        let short_name: [u8; 11] = directory.data[entry.slot..entry.slot + 11].try_into().unwrap();
+        directory.short_names.remove(&short_name);
+        for slot in (entry.first_slot..=entry.slot).step_by(ENTRY_SIZE) {
+            directory.data[slot] = ENTRY_DELETED;
+        }
+        self.write_directory(parent, entry.first_slot..entry.slot + ENTRY_SIZE)?;
+
+        let mut cluster = entry.first_cluster;
+        while (2..FIRST_RESERVED_CLUSTER).contains(&cluster) && (cluster as usize) < self.fat.len() {
+            let next = self.fat[cluster as usize] & CLUSTER_MASK;
+            self.set_fat(cluster, 0);
+            self.free_clusters += 1;
+            cluster = next;
+        }
+        Ok(true)
+    }
+
+    // Reads a whole file back, or `None` if there is no such file. Only the
+    // tests need this; the app never reads files off a volume.
+    #[cfg(test)]
+    pub fn read_file(&mut self, path: &str) -> io::Result<Option<Vec<u8>>> {
+        let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let Some(parent) = self.directory(parent_path, false, None)? else {
+            return Ok(None);
+        };
+        let directory = &self.directories[&parent];
+        let Some(entry) = directory.entries.get(&name.to_uppercase()).filter(|entry| !entry.is_dir()) else {
+            return Ok(None);
+        };
+        let size = u32_at(&directory.data, entry.slot + 28) as usize;
+        if size == 0 {
+            return Ok(Some(Vec::new()));
+        }
+
+        let clusters = self.chain(entry.first_cluster)?;
+        let cluster_size = self.geometry.cluster_size as usize;
+        if clusters.len() * cluster_size < size {
+            return Err(invalid("A FAT32 file is longer than its cluster chain"));
+        }
+
+        let mut data = vec![0; size];
+        for (cluster, chunk) in clusters.iter().zip(data.chunks_mut(cluster_size)) {
+            self.target.seek(SeekFrom::Start(self.geometry.cluster_offset(*cluster)))?;
+            self.target.read_exact(chunk)?;
+        }
+        Ok(Some(data))
+    }
+
+    // Writes back the FAT and FSInfo. Until this runs, new files have
+    // directory entries but their clusters are free on disk.
+    pub fn finish(self) -> io::Result<()> {
+        if let Some((first, last)) = self.dirty {
+            let entries_per_sector = SECTOR_SIZE as usize / 4;
+            let sectors = first / entries_per_sector..last / entries_per_sector + 1;
+
+            let mut bytes = Vec::with_capacity(sectors.len() * SECTOR_SIZE as usize);
+            for index in sectors.start * entries_per_sector..sectors.end * entries_per_sector {
+                // The FAT's last sector may go on past the last cluster.
+                let entry = self.fat.get(index).copied().unwrap_or(0);
+                bytes.extend_from_slice(&entry.to_le_bytes());
+            }
+
+            for copy in 0..self.geometry.fat_count {
+                let offset = self.geometry.fat_offset(copy) + sectors.start as u64 * SECTOR_SIZE;
+                self.target.seek(SeekFrom::Start(offset))?;
+                self.target.write_all(&bytes)?;
+            }
+        }
+
+        write_fsinfo(self.target, &self.geometry, self.free_clusters, self.next_free)?;
+        self.target.flush()
+    }
+
+    // Resolves a directory path to its first cluster, creating missing
+    // directories if asked to. `None` means it does not exist.
+    fn directory(&mut self, path: &str, create: bool, modified: Option<Timestamp>) -> io::Result<Option<u32>> {
+        let mut current = self.geometry.root_cluster;
+
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            self.load_directory(current)?;
+            let existing = self.directories[&current].entries.get(&name.to_uppercase()).cloned();
+
+            current = match existing {
+                Some(entry) if entry.is_dir() => entry.first_cluster,
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{} is a file, not a directory", name),
+                    ))
+                }
+                None if !create => return Ok(None),
+                None => self.add_directory(current, name, modified)?,
+            };
+        }
+
+        self.load_directory(current)?;
+        Ok(Some(current))
+    }
+
+    fn add_directory(&mut self, parent: u32, name: &str, modified: Option<Timestamp>) -> io::Result<u32> {
+        self.check_new_name(parent, name)?;
+        let cluster = self.allocate(1)?[0];
+
+        // ".." holds cluster 0 when the parent is the root.
+        let parent_cluster = if parent == self.geometry.root_cluster { 0 } else { parent };
+        let mut data = vec![0; self.geometry.cluster_size as usize];
+        data[..ENTRY_SIZE].copy_from_slice(&directory_entry(b".          ", ATTR_DIRECTORY, 0, cluster, 0, modified));
+        data[ENTRY_SIZE..2 * ENTRY_SIZE]
+            .copy_from_slice(&directory_entry(b"..         ", ATTR_DIRECTORY, 0, parent_cluster, 0, modified));
+        self.target
+            .seek(SeekFrom::Start(self.geometry.cluster_offset(cluster)))?;
+        self.target.write_all(&data)?;
+
+        self.directories.insert(
+            cluster,
+            Directory {
+                clusters: vec![cluster],
+                data,
+                entries: HashMap::new(),
+                short_names: HashSet::new(),
+                end: 2 * ENTRY_SIZE,
+            },
+        );
+
+        self.add_entry(parent, name, ATTR_DIRECTORY, cluster, 0, modified)?;
+        Ok(cluster)
+    }
+
+    fn load_directory(&mut self, first_cluster: u32) -> io::Result<()> {
+        if self.directories.contains_key(&first_cluster) {
+            return Ok(());
+        }
+
+        let clusters = self.chain(first_cluster)?;
+        let cluster_size = self.geometry.cluster_size as usize;
+        if clusters.len() * cluster_size > MAX_DIRECTORY_SIZE {
+            return Err(invalid("A FAT32 directory is larger than allowed"));
+        }
+
+        let mut data = vec![0; clusters.len() * cluster_size];
+        for (cluster, chunk) in clusters.iter().zip(data.chunks_mut(cluster_size)) {
+            self.target
+                .seek(SeekFrom::Start(self.geometry.cluster_offset(*cluster)))?;
+            self.target.read_exact(chunk)?;
+        }
+
+        let mut directory = Directory {
+            clusters,
+            data,
+            entries: HashMap::new(),
+            short_names: HashSet::new(),
+            end: 0,
+        };
+        directory.parse();
+        self.directories.insert(first_cluster, directory);
+        Ok(())
+    }
+
+    fn check_new_name(&self, parent: u32, name: &str) -> io::Result<()> {
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("\"{}\" is not a valid file name", name),
+            ));
+        }
+        if self.directories[&parent].entries.contains_key(&name.to_uppercase()) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists (FAT names ignore letter case)", name),
+            ));
+        }
+        Ok(())
+    }
+
+    fn add_entry(
+        &mut self,
+        parent: u32,
+        name: &str,
+        attributes: u8,
+        first_cluster: u32,
+        size: u32,
+        modified: Option<Timestamp>,
+    ) -> io::Result<()> {
+        let directory = &self.directories[&parent];
+        let (short_name, case_flags, needs_long_name) = short_name(name, &directory.short_names)?;
+
+        let mut slots = if needs_long_name {
+            long_name_entries(name, &short_name)?
+        } else {
+            Vec::new()
+        };
+        slots.push(directory_entry(&short_name, attributes, case_flags, first_cluster, size, modified));
+
+        // New entries go at the end, the directory growing by a cluster at a
+        // time. Gaps left by deleted entries are not reused.
+        let first_slot = directory.end;
+        let end = first_slot + slots.len() * ENTRY_SIZE;
+        if end > MAX_DIRECTORY_SIZE {
+            return Err(io::Error::other(format!(
+                "The directory for {} has reached the FAT limit of 65536 entries",
+                name
+            )));
+        }
+        while end > self.directories[&parent].data.len() {
+            self.grow_directory(parent)?;
+        }
+
+        let directory = self.directories.get_mut(&parent).unwrap();
+        for (slot, chunk) in slots.iter().zip(directory.data[first_slot..end].chunks_mut(ENTRY_SIZE)) {
+            chunk.copy_from_slice(slot);
+        }
+        directory.end = end;
+        directory.short_names.insert(short_name);
+        directory.entries.insert(
+            name.to_uppercase(),
+            DirEntry {
+                first_slot,
+                slot: end - ENTRY_SIZE,
+                attributes,
+                first_cluster,
+            },
+        );
+
+        self.write_directory(parent, first_slot..end)
+    }
+
+    fn grow_directory(&mut self, first_cluster: u32) -> io::Result<()> {
+        let cluster = self.allocate(1)?[0];
+        let last = *self.directories[&first_cluster].clusters.last().unwrap();
+        self.set_fat(last, cluster);
+
+        // Whatever was in the cluster before would read as entries.
+        let zeros = vec![0; self.geometry.cluster_size as usize];
+        self.target
+            .seek(SeekFrom::Start(self.geometry.cluster_offset(cluster)))?;
+        self.target.write_all(&zeros)?;
+
+        let directory = self.directories.get_mut(&first_cluster).unwrap();
+        directory.clusters.push(cluster);
+        directory.data.extend_from_slice(&zeros);
+        Ok(())
+    }
+
+    // Writes the sectors of a directory that hold the given bytes.
+    fn write_directory(&mut self, first_cluster: u32, bytes: Range<usize>) -> io::Result<()> {
+        let directory = &self.directories[&first_cluster];
+        let cluster_size = self.geometry.cluster_size as usize;
+        let sector_size = SECTOR_SIZE as usize;
+
+        for sector in bytes.start / sector_size..bytes.end.div_ceil(sector_size) {
+            let offset = sector * sector_size;
+            let cluster = directory.clusters[offset / cluster_size];
+            let position = self.geometry.cluster_offset(cluster) + (offset % cluster_size) as u64;
+
+            self.target.seek(SeekFrom::Start(position))?;
+            self.target
+                .write_all(&directory.data[offset..offset + sector_size])?;
+        }
+        Ok(())
+    }
+
+    // Hands out `count` free clusters chained together, searching on from
+    // where the last allocation stopped. On a fresh volume that keeps every
+    // file in one piece.
+    fn allocate(&mut self, count: u32) -> io::Result<Vec<u32>> {
+        if count > self.free_clusters {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "The FAT32 volume is full"));
+        }
+
+        let end = self.fat.len() as u32;
+        let mut clusters = Vec::with_capacity(count as usize);
+        let mut candidate = self.next_free;
+        while clusters.len() < count as usize {
+            if candidate < 2 || candidate >= end {
+                candidate = 2;
+            }
+            if self.fat[candidate as usize] & CLUSTER_MASK == 0 {
+                clusters.push(candidate);
+            }
+            candidate += 1;
+        }
+
+        for pair in clusters.windows(2) {
+            self.set_fat(pair[0], pair[1]);
+        }
+        if let Some(&last) = clusters.last() {
+            self.set_fat(last, END_OF_CHAIN);
+        }
+        self.free_clusters -= count;
+        self.next_free = candidate;
+        Ok(clusters)
+    }
+
+    fn chain(&self, first_cluster: u32) -> io::Result<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cluster = first_cluster;
+        while cluster < FIRST_RESERVED_CLUSTER {
+            // A chain longer than the FAT must loop.
+            if cluster < 2 || cluster as usize >= self.fat.len() || clusters.len() >= self.fat.len() {
+                return Err(invalid("A FAT32 cluster chain is corrupt"));
+            }
+            clusters.push(cluster);
+            cluster = self.fat[cluster as usize] & CLUSTER_MASK;
+        }
+        Ok(clusters)
+    }
+
+    // The top four bits of an entry are reserved and must be left alone.
+    fn set_fat(&mut self, cluster: u32, value: u32) {
+        let index = cluster as usize;
+        self.fat[index] = (self.fat[index] & !CLUSTER_MASK) | value;
+        self.dirty = Some(match self.dirty {
+            Some((first, last)) => (first.min(index), last.max(index)),
+            None => (index, index),
+        });
+    }
+}
+
+impl<T: Read + Write + Seek + ?Sized> VolumeWriter for FatVolume<'_, T> {
+    fn create_dir(&mut self, path: &str, modified: Option<Timestamp>) -> io::Result<()> {
+        self.directory(path, true, modified).map(|_| ())
+    }
+
+    fn write_file(
+        &mut self,
+        path: &str,
+        size: u64,
+        modified: Option<Timestamp>,
+        source: &mut dyn Read,
+        on_progress: &mut dyn FnMut(u64),
+    ) -> io::Result<()> {
+        if size > MAX_FILE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is larger than 4 GiB, which FAT32 cannot store", path),
+            ));
+        }
+
+        let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self.directory(parent_path, true, None)?.unwrap();
+        self.check_new_name(parent, name)?;
+
+        let cluster_size = self.geometry.cluster_size as u64;
+        let clusters = self.allocate(size.div_ceil(cluster_size) as u32)?;
+        let first_cluster = clusters.first().copied().unwrap_or(0);
+
+        let mut remaining = size;
+        for run in runs(&clusters) {
+            let position = self.geometry.cluster_offset(run.start);
+            self.target.seek(SeekFrom::Start(position))?;
+
+            let run_length = remaining.min(run.len() as u64 * cluster_size);
+            remaining -= run_length;
+            write_data(self.target, source, run_length, on_progress)?;
+        }
+
+        self.add_entry(parent, name, ATTR_ARCHIVE, first_cluster, size as u32, modified)
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        FatVolume::finish(*self)
+    }
+}
+
+impl Directory {
+    // Indexes the entries already on disk, joining long names to the short
+    // entries that follow them.
+    fn parse(&mut self) {
+        let mut long_name: Vec<(u8, [u16; LFN_CHARS_PER_ENTRY])> = Vec::new();
+        let mut long_name_start = 0;
+        let mut offset = 0;
+
+        while offset < self.data.len() && self.data[offset] != ENTRY_END {
+            let slot = &self.data[offset..offset + ENTRY_SIZE];
+            offset += ENTRY_SIZE;
+
+            if slot[0] == ENTRY_DELETED {
+                long_name.clear();
+                continue;
+            }
+
+            if slot[11] & 0x3F == ATTR_LONG_NAME {
+                if slot[0] & LFN_LAST_ENTRY != 0 {
+                    long_name.clear();
+                    long_name_start = offset - ENTRY_SIZE;
+                }
+                let mut units = [0u16; LFN_CHARS_PER_ENTRY];
+                for (unit, &at) in units.iter_mut().zip(LFN_CHAR_OFFSETS.iter()) {
+                    *unit = u16_at(slot, at);
+                }
+                long_name.push((slot[13], units));
+                continue;
+            }
+
+            let short_name: [u8; 11] = slot[..11].try_into().unwrap();
+            let attributes = slot[11];
+            let name = long_name_text(&long_name, &short_name);
+            let first_slot = if name.is_some() { long_name_start } else { offset - ENTRY_SIZE };
+            let name = name.unwrap_or_else(|| display_short_name(slot));
+            long_name.clear();
+
+            // The short names of the label and the dot entries are never
+            // picked for files, so they need not be recorded.
+            if attributes & ATTR_VOLUME_ID != 0 || short_name[0] == b'.' {
+                continue;
+            }
+
+            self.short_names.insert(short_name);
+            self.entries.insert(
+                name.to_uppercase(),
+                DirEntry {
+                    first_slot,
+                    slot: offset - ENTRY_SIZE,
+                    attributes,
+                    first_cluster: ((u16_at(slot, 20) as u32) << 16) | u16_at(slot, 26) as u32,
+                },
+            );
+        }
+
+        self.end = offset;
+    }
+}
+
+// Splits a list of clusters into runs of consecutive ones.
+fn runs(clusters: &[u32]) -> Vec<Range<u32>> {
+    let mut runs: Vec<Range<u32>> = Vec::new();
+    for &cluster in clusters {
+        match runs.last_mut() {
+            Some(run) if run.end == cluster => run.end += 1,
+            _ => runs.push(cluster..cluster + 1),
+        }
+    }
+    runs
+}
+
+// The media descriptor and end-of-chain marker in the two reserved entries,
+// then the root directory's single cluster.
+fn initial_fat() -> [u32; 3] {
+    [0x0FFF_FF00 | MEDIA_FIXED as u32, END_OF_CHAIN, END_OF_CHAIN]
+}
+
+// FSInfo caches the free cluster count and where to look for free clusters
+// next, so drivers need not scan the FAT on mount. Volumes made elsewhere
+// may go without one.
+fn write_fsinfo<T: Write + Seek + ?Sized>(
+    target: &mut T,
+    geometry: &Geometry,
+    free_clusters: u32,
+    next_free: u32,
+) -> io::Result<()> {
+    let reserved = geometry.reserved_sectors as u64;
+    if geometry.fsinfo_sector == 0 || geometry.fsinfo_sector >= reserved {
+        return Ok(());
+    }
+
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    sector[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    sector[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    sector[488..492].copy_from_slice(&free_clusters.to_le_bytes());
+    sector[492..496].copy_from_slice(&next_free.to_le_bytes());
+    sector[508..512].copy_from_slice(&0xAA55_0000u32.to_le_bytes());
+
+    // The backup boot sector has its own FSInfo copy right behind it.
+    for copy in [geometry.fsinfo_sector, BACKUP_BOOT_SECTOR + geometry.fsinfo_sector] {
+        if copy < reserved {
+            target.seek(SeekFrom::Start(geometry.offset + copy * SECTOR_SIZE))?;
+            target.write_all(&sector)?;
+        }
+    }
+    Ok(())
+}
+
+fn boot_sector(geometry: &Geometry, label: &[u8; 11], serial: u32) -> [u8; SECTOR_SIZE as usize] {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    sector[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    sector[3..11].copy_from_slice(b"MSWIN4.1");
+    sector[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    sector[13] = (geometry.cluster_size as u64 / SECTOR_SIZE) as u8;
+    sector[14..16].copy_from_slice(&(geometry.reserved_sectors as u16).to_le_bytes());
+    sector[16] = geometry.fat_count as u8;
+    sector[21] = MEDIA_FIXED;
+    // Geometry hints for BIOS CHS access, the usual values for LBA disks.
+    sector[24..26].copy_from_slice(&63u16.to_le_bytes());
+    sector[26..28].copy_from_slice(&255u16.to_le_bytes());
+    sector[28..32].copy_from_slice(&((geometry.offset / SECTOR_SIZE) as u32).to_le_bytes());
+    sector[32..36].copy_from_slice(&geometry.total_sectors.to_le_bytes());
+    sector[36..40].copy_from_slice(&geometry.fat_sectors.to_le_bytes());
+    sector[44..48].copy_from_slice(&geometry.root_cluster.to_le_bytes());
+    sector[48..50].copy_from_slice(&(geometry.fsinfo_sector as u16).to_le_bytes());
+    sector[50..52].copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+    sector[64] = 0x80;
+    sector[66] = 0x29;
+    sector[67..71].copy_from_slice(&serial.to_le_bytes());
+    sector[71..82].copy_from_slice(label);
+    sector[82..90].copy_from_slice(b"FAT32   ");
+    sector[90..90 + BOOT_STUB.len()].copy_from_slice(&BOOT_STUB);
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+    sector
+}
+
+fn directory_entry(
+    name: &[u8; 11],
+    attributes: u8,
+    case_flags: u8,
+    first_cluster: u32,
+    size: u32,
+    modified: Option<Timestamp>,
+) -> [u8; ENTRY_SIZE] {
+    let (date, time) = modified.map_or((0, 0), fat_date_time);
+
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[0..11].copy_from_slice(name);
+    entry[11] = attributes;
+    entry[12] = case_flags;
+    entry[14..16].copy_from_slice(&time.to_le_bytes());
+    entry[16..18].copy_from_slice(&date.to_le_bytes());
+    entry[18..20].copy_from_slice(&date.to_le_bytes());
+    entry[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    entry[22..24].copy_from_slice(&time.to_le_bytes());
+    entry[24..26].copy_from_slice(&date.to_le_bytes());
+    entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+// VFAT long names sit in front of the short entry, last piece first. Each
+// piece carries a checksum of the short name, so a long name left behind by
+// a driver that only knows 8.3 names is recognised as stale.
+fn long_name_entries(name: &str, short_name: &[u8; 11]) -> io::Result<Vec<[u8; ENTRY_SIZE]>> {
+    let mut units: Vec<u16> = name
+        .chars()
+        .map(|c| if is_long_name_char(c) { c } else { '_' })
+        .collect::<String>()
+        .encode_utf16()
+        .collect();
+    if units.len() > LFN_MAX_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is longer than the 255 characters FAT allows", name),
+        ));
+    }
+
+    // A NUL terminator if the last piece has room, then 0xFFFF padding.
+    let count = units.len().div_ceil(LFN_CHARS_PER_ENTRY);
+    if !units.len().is_multiple_of(LFN_CHARS_PER_ENTRY) {
+        units.push(0);
+    }
+    units.resize(count * LFN_CHARS_PER_ENTRY, 0xFFFF);
+
+    let checksum = short_name_checksum(short_name);
+    let mut entries = Vec::with_capacity(count);
+    for sequence in (1..=count).rev() {
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[0] = sequence as u8 | if sequence == count { LFN_LAST_ENTRY } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+
+        let piece = &units[(sequence - 1) * LFN_CHARS_PER_ENTRY..sequence * LFN_CHARS_PER_ENTRY];
+        for (unit, &at) in piece.iter().zip(LFN_CHAR_OFFSETS.iter()) {
+            entry[at..at + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+// Puts a long name read from disk back together, if it belongs to
+// `short_name`. The pieces are in disk order, last piece first.
+fn long_name_text(pieces: &[(u8, [u16; LFN_CHARS_PER_ENTRY])], short_name: &[u8; 11]) -> Option<String> {
+    let checksum = short_name_checksum(short_name);
+    if pieces.is_empty() || pieces.iter().any(|(piece_checksum, _)| *piece_checksum != checksum) {
+        return None;
+    }
+
+    let units: Vec<u16> = pieces.iter().rev().flat_map(|(_, piece)| piece.iter().copied()).collect();
+    let length = units.iter().position(|&unit| unit == 0).unwrap_or(units.len());
+    String::from_utf16(&units[..length]).ok()
+}
+
+fn display_short_name(entry: &[u8]) -> String {
+    let text = |bytes: &[u8], lowercase: bool| {
+        let text: String = bytes.iter().map(|&b| b as char).collect();
+        let text = text.trim_end();
+        if lowercase {
+            text.to_lowercase()
+        } else {
+            text.to_string()
+        }
+    };
+
+    let mut base = text(&entry[..8], entry[12] & LOWERCASE_BASE != 0);
+    let extension = text(&entry[8..11], entry[12] & LOWERCASE_EXTENSION != 0);
+    if entry[0] == 0x05 {
+        base.replace_range(..1, "\u{E5}");
+    }
+
+    if extension.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, extension)
+    }
+}
+
+// FAT dates count from 1980 and times have two second resolution.
+fn fat_date_time(time: Timestamp) -> (u16, u16) {
+    if time.year < 1980 {
+        return ((1 << 5) | 1, 0);
+    }
+
+    let year = (time.year - 1980).min(127);
+    let date = (year << 9) | ((time.month as u16) << 5) | time.day as u16;
+    let time = ((time.hour as u16) << 11) | ((time.minute as u16) << 5) | (time.second as u16 / 2);
+    (date, time)
+}
+
+fn is_short_name_char(c: u8) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+fn is_long_name_char(c: char) -> bool {
+    c >= ' ' && !"\"*/:<>?\\|".contains(c)
+}
+
+// Picks the 8.3 name for `name`, and whether it needs a long name as well.
+// Names that already fit 8.3, with one letter case per part, keep their case
+// through the NT case bits. Anything else gets a `~N` tail like Windows
+// would generate.
+fn short_name(name: &str, taken: &HashSet<[u8; 11]>) -> io::Result<([u8; 11], u8, bool)> {
+    let (base, extension) = match name.rfind('.') {
+        Some(0) | None => (name, ""),
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+    };
+
+    let fits = |part: &str, length: usize| {
+        part.len() <= length && part.bytes().all(|c| is_short_name_char(c.to_ascii_uppercase()))
+    };
+    let single_case = |part: &str| {
+        !(part.bytes().any(|c| c.is_ascii_lowercase()) && part.bytes().any(|c| c.is_ascii_uppercase()))
+    };
+
+    if !base.is_empty() && fits(base, 8) && fits(extension, 3) && single_case(base) && single_case(extension) {
+        let short = pack_short_name(&base.to_ascii_uppercase(), &extension.to_ascii_uppercase());
+        if !taken.contains(&short) {
+            let mut flags = 0;
+            if base.bytes().any(|c| c.is_ascii_lowercase()) {
+                flags |= LOWERCASE_BASE;
+            }
+            if extension.bytes().any(|c| c.is_ascii_lowercase()) {
+                flags |= LOWERCASE_EXTENSION;
+            }
+            return Ok((short, flags, false));
+        }
+    }
+
+    let clean = |part: &str| -> String {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c.is_ascii() && is_short_name_char(c as u8) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    };
+    let base = clean(base);
+    let extension: String = clean(extension).chars().take(3).collect();
+
+    for number in 1..1_000_000 {
+        let tail = format!("~{}", number);
+        let stem: String = base.chars().take(8 - tail.len()).collect();
+        let short = pack_short_name(&format!("{}{}", stem, tail), &extension);
+        if !taken.contains(&short) {
+            return Ok((short, 0, true));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("Too many files whose short names clash with {}", name),
+    ))
+}
+
+fn pack_short_name(base: &str, extension: &str) -> [u8; 11] {
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+    // 0xE5 marks deleted entries; a real leading 0xE5 is stored as 0x05.
+    if short[0] == ENTRY_DELETED {
+        short[0] = 0x05;
+    }
+    short
+}
+
+fn volume_label(label: &str) -> [u8; 11] {
+    let mut packed = [b' '; 11];
+    let cleaned: Vec<u8> = label
+        .trim()
+        .chars()
+        .map(|c| c.to_ascii_uppercase())
+        .filter(|&c| c.is_ascii() && (c == ' ' || is_short_name_char(c as u8)))
+        .map(|c| c as u8)
+        .take(11)
+        .collect();
+
+    if cleaned.is_empty() {
+        return NO_LABEL;
+    }
+    packed[..cleaned.len()].copy_from_slice(&cleaned);
+    packed
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+    const OFFSET: u64 = MIB;
+    const SIZE: u64 = 100 * MIB;
+
+    fn options(cluster_size: Option<u32>) -> FormatOptions {
+        FormatOptions {
+            cluster_size,
+            label: "Ayumi Test".to_string(),
+            serial: Some(0x1234_ABCD),
+            ..FormatOptions::default()
+        }
+    }
+
+    fn sector(disk: &[u8], index: u64) -> &[u8] {
+        let start = (OFFSET + index * SECTOR_SIZE) as usize;
+        &disk[start..start + SECTOR_SIZE as usize]
+    }
+
+    #[test]
+    fn format_writes_boot_sector_fsinfo_and_fats() {
+        let mut disk = Cursor::new(Vec::new());
+        let geometry = format(&mut disk, OFFSET, SIZE, &options(None)).unwrap();
+        let disk = disk.into_inner();
+
+        // 100 MiB gets 1 KiB clusters. With 32 reserved sectors, the FATs
+        // take (204768 / 2 + 2) * 4 bytes each, rounded up to 800 sectors,
+        // which leaves (204800 - 32 - 1600) / 2 clusters.
+        let boot = sector(&disk, 0);
+        assert_eq!(u16_at(boot, 11), 512);
+        assert_eq!(boot[13], 2);
+        assert_eq!(u16_at(boot, 14), 32);
+        assert_eq!(boot[16], 2);
+        assert_eq!(boot[21], MEDIA_FIXED);
+        assert_eq!(u32_at(boot, 28), (OFFSET / SECTOR_SIZE) as u32);
+        assert_eq!(u32_at(boot, 32), 204_800);
+        assert_eq!(u32_at(boot, 36), 800);
+        assert_eq!(u32_at(boot, 44), ROOT_CLUSTER);
+        assert_eq!(u32_at(boot, 67), 0x1234_ABCD);
+        assert_eq!(&boot[71..82], b"AYUMI TEST ");
+        assert_eq!(&boot[82..90], b"FAT32   ");
+        assert_eq!(&boot[510..], &[0x55, 0xAA]);
+        assert_eq!(sector(&disk, BACKUP_BOOT_SECTOR), boot);
+        assert_eq!(geometry.cluster_count, 101_584);
+
+        for fsinfo in [FSINFO_SECTOR, BACKUP_BOOT_SECTOR + FSINFO_SECTOR] {
+            let fsinfo = sector(&disk, fsinfo);
+            assert_eq!(u32_at(fsinfo, 0), 0x4161_5252);
+            assert_eq!(u32_at(fsinfo, 484), 0x6141_7272);
+            assert_eq!(u32_at(fsinfo, 508), 0xAA55_0000);
+            // Everything but the root directory's cluster is free.
+            assert_eq!(u32_at(fsinfo, 488), 101_583);
+            assert_eq!(u32_at(fsinfo, 492), ROOT_CLUSTER + 1);
+        }
+
+        for copy in 0..2 {
+            let fat = sector(&disk, 32 + copy * 800);
+            assert_eq!(fat[0], MEDIA_FIXED);
+            assert_eq!(u32_at(fat, 0), 0x0FFF_FFF8);
+            assert_eq!(u32_at(fat, 4), END_OF_CHAIN);
+            assert_eq!(u32_at(fat, 4 * ROOT_CLUSTER as usize), END_OF_CHAIN);
+            assert_eq!(u32_at(fat, 12), 0);
+        }
+
+        // The root directory holds just the label.
+        let root = sector(&disk, 32 + 2 * 800);
+        assert_eq!(&root[..11], b"AYUMI TEST ");
+        assert_eq!(root[11], ATTR_VOLUME_ID);
+        assert_eq!(root[ENTRY_SIZE], ENTRY_END);
+    }
+
+    #[test]
+    fn formatted_volume_opens_and_takes_files() {
+        let mut disk = Cursor::new(Vec::new());
+        let mut volume = FatVolume::format(&mut disk, OFFSET, SIZE, &options(None)).unwrap();
+        let data: Vec<u8> = (0..40_000u32).map(|i| i as u8).collect();
+        volume.write_file("/Docs/Read Me.txt", data.len() as u64, None, &mut data.as_slice(), &mut |_| {}).unwrap();
+        volume.finish().unwrap();
+
+        let mut volume = FatVolume::open(&mut disk, OFFSET).unwrap();
+        assert_eq!(volume.read_file("/DOCS/read me.TXT").unwrap(), Some(data));
+        // The root, the new directory and 40 clusters of 1 KiB for the file.
+        assert_eq!(volume.free_clusters, volume.geometry.cluster_count - 42);
+    }
+
+    #[test]
+    fn format_rejects_unusable_cluster_sizes() {
+        for cluster_size in [256, 3000, 128 * 1024] {
+            let error = format(&mut Cursor::new(Vec::new()), OFFSET, SIZE, &options(Some(cluster_size))).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert!(error.to_string().contains("power of two"), "{}", error);
+        }
+
+        // 4 KiB clusters leave 100 MiB with fewer clusters than FAT32 allows.
+        let error = format(&mut Cursor::new(Vec::new()), OFFSET, SIZE, &options(Some(4096))).err().unwrap();
+        assert_eq!(error.to_string(), "The volume is too small for FAT32 with 4096 byte clusters");
+
+        // Checked before anything is written, so nothing needs to back it.
+        let error = format(&mut Cursor::new(Vec::new()), OFFSET, 200 * 1024 * MIB, &options(Some(512))).err().unwrap();
+        assert_eq!(error.to_string(), "The volume is too large for FAT32 with 512 byte clusters");
+    }
+
+    // The short entry of `name` in the root directory and the long name
+    // slots in front of it.
+    fn root_slots(volume: &mut FatVolume<'_, Cursor<Vec<u8>>>, name: &str) -> Vec<[u8; ENTRY_SIZE]> {
+        let root = volume.geometry.root_cluster;
+        volume.load_directory(root).unwrap();
+        let directory = &volume.directories[&root];
+        let entry = &directory.entries[&name.to_uppercase()];
+        directory.data[entry.first_slot..entry.slot + ENTRY_SIZE]
+            .chunks_exact(ENTRY_SIZE)
+            .map(|slot| slot.try_into().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn long_names_span_several_slots_with_the_short_name_checksum() {
+        assert_eq!(short_name_checksum(b"README  TXT"), 0x73);
+
+        let mut disk = Cursor::new(Vec::new());
+        let mut volume = FatVolume::format(&mut disk, OFFSET, SIZE, &options(None)).unwrap();
+        // 30 UTF-16 units take three slots of 13. The short name replaces
+        // what 8.3 cannot hold with underscores.
+        let name = "Überlange Datei für FAT32.data";
+        volume.write_file(name, 3, None, &mut &b"abc"[..], &mut |_| {}).unwrap();
+        volume.finish().unwrap();
+
+        let mut volume = FatVolume::open(&mut disk, OFFSET).unwrap();
+        let slots = root_slots(&mut volume, name);
+        assert_eq!(slots.len(), 4);
+        let short_name: [u8; 11] = slots[3][..11].try_into().unwrap();
+        assert_eq!(&short_name, b"_BERLA~1DAT");
+        assert_eq!(slots[3][11], ATTR_ARCHIVE);
+
+        assert_eq!(slots.iter().map(|slot| slot[0]).collect::<Vec<_>>()[..3], [0x43, 0x02, 0x01]);
+        for slot in &slots[..3] {
+            assert_eq!(slot[11], ATTR_LONG_NAME);
+            assert_eq!(slot[13], short_name_checksum(&short_name));
+        }
+        // The last piece holds units 26 to 29, a NUL and then 0xFFFF.
+        let last: Vec<u16> = LFN_CHAR_OFFSETS.iter().map(|&at| u16_at(&slots[0], at)).collect();
+        assert_eq!(last[..4], "data".encode_utf16().collect::<Vec<_>>()[..]);
+        assert_eq!(last[4], 0);
+        assert!(last[5..].iter().all(|&unit| unit == 0xFFFF));
+        assert_eq!(volume.read_file(name).unwrap().as_deref(), Some(&b"abc"[..]));
+
+        // A long name whose checksum no longer matches is stale and ignored.
+        let pieces: Vec<_> = slots[..3]
+            .iter()
+            .map(|slot| (slot[13], LFN_CHAR_OFFSETS.map(|at| u16_at(slot, at))))
+            .collect();
+        assert_eq!(long_name_text(&pieces, &short_name).as_deref(), Some(name));
+        assert_eq!(long_name_text(&pieces, b"_BERLA~2DAT"), None);
+    }
+
+    #[test]
+    fn clashing_short_names_get_numbered_tails() {
+        let mut disk = Cursor::new(Vec::new());
+        let mut volume = FatVolume::format(&mut disk, OFFSET, SIZE, &options(None)).unwrap();
+        for number in 1..=3 {
+            let name = format!("Long File Name {}.txt", number);
+            volume.write_file(&name, 0, None, &mut io::empty(), &mut |_| {}).unwrap();
+        }
+        // Fits 8.3, but in mixed case, so it needs a long name as well.
+        volume.write_file("ReadMe.txt", 0, None, &mut io::empty(), &mut |_| {}).unwrap();
+        volume.write_file("readme.TXT", 0, None, &mut io::empty(), &mut |_| {}).err().unwrap();
+        volume.finish().unwrap();
+
+        let mut volume = FatVolume::open(&mut disk, OFFSET).unwrap();
+        for (name, short_name) in [
+            ("Long File Name 1.txt", b"LONGFI~1TXT"),
+            ("Long File Name 2.txt", b"LONGFI~2TXT"),
+            ("long file name 3.TXT", b"LONGFI~3TXT"),
+            ("ReadMe.txt", b"README~1TXT"),
+        ] {
+            assert_eq!(&root_slots(&mut volume, name).last().unwrap()[..11], short_name);
+        }
+        assert_eq!(short_name_checksum(b"LONGFI~1TXT"), 0xD4);
+
+        // Past nine the tail takes another character from the stem.
+        let taken: HashSet<[u8; 11]> = (1..=9).map(|n| pack_short_name(&format!("LONGFI~{}", n), "TXT")).collect();
+        let (short_name, _, needs_long_name) = super::short_name("Long File Name 10.txt", &taken).unwrap();
+        assert_eq!(&short_name, b"LONGF~10TXT");
+        assert!(needs_long_name);
+    }
+
+    #[test]
+    fn directories_grow_past_one_cluster() {
+        let mut disk = Cursor::new(Vec::new());
+        let mut volume = FatVolume::format(&mut disk, OFFSET, SIZE, &options(None)).unwrap();
+        // A 1 KiB cluster holds 32 slots; each file takes three.
+        let names: Vec<String> = (0..40).map(|n| format!("Installer part {:02}.cab", n)).collect();
+        for (n, name) in names.iter().enumerate() {
+            let data = vec![n as u8; 100 + n];
+            volume.write_file(&format!("/Sources/{}", name), data.len() as u64, None, &mut data.as_slice(), &mut |_| {}).unwrap();
+        }
+        volume.finish().unwrap();
+
+        let mut volume = FatVolume::open(&mut disk, OFFSET).unwrap();
+        for (n, name) in names.iter().enumerate() {
+            let data = volume.read_file(&format!("/Sources/{}", name)).unwrap().unwrap();
+            assert_eq!(data, vec![n as u8; 100 + n]);
+        }
+        let sources = volume.directory("/Sources", false, None).unwrap().unwrap();
+        // Two dot entries and 120 slots for the files.
+        assert_eq!(volume.chain(sources).unwrap().len(), 4);
+        assert_eq!(volume.directories[&sources].end, 122 * ENTRY_SIZE);
+    }
+}
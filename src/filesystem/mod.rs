@@ -0,0 +1,215 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::partition::PartitionScheme;
+
+mod exfat;
+mod fat32;
+mod ntfs;
+
+pub use exfat::ExfatVolume;
+pub use fat32::{find_volume, FatVolume, MAX_FILE_SIZE};
+pub use ntfs::{verify_ntfs, NtfsVolume};
+
+const SECTOR_SIZE: u64 = 512;
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+// `int 0x18` followed by `jmp $`: tells the BIOS to try the next boot device
+// if it ever runs a volume's boot sector.
+const BOOT_STUB: [u8; 4] = [0xCD, 0x18, 0xEB, 0xFE];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum FileSystem {
+    #[default]
+    Fat32,
+    // For images with files over 4 GiB, which FAT32 cannot hold. Not every
+    // UEFI firmware can boot from it.
+    Exfat,
+    // What Windows itself formats installer drives with. UEFI firmware
+    // rarely reads it without an extra driver.
+    Ntfs,
+}
+
+impl FileSystem {
+    pub fn label(&self) -> &'static str {
+        match self {
+            FileSystem::Fat32 => "FAT32",
+            FileSystem::Exfat => "exFAT",
+            FileSystem::Ntfs => "NTFS",
+        }
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        match self {
+            FileSystem::Fat32 => MAX_FILE_SIZE,
+            FileSystem::Exfat | FileSystem::Ntfs => u64::MAX,
+        }
+    }
+
+    // The largest cluster size each format allows.
+    pub fn max_cluster_size(&self) -> u32 {
+        match self {
+            FileSystem::Fat32 => 64 * 1024,
+            FileSystem::Exfat => 32 * 1024 * 1024,
+            FileSystem::Ntfs => 64 * 1024,
+        }
+    }
+
+    // The MBR partition type announcing this filesystem.
+    pub fn partition_type(&self) -> u8 {
+        match self {
+            FileSystem::Fat32 => 0x0C,
+            FileSystem::Exfat | FileSystem::Ntfs => 0x07,
+        }
+    }
+}
+
+// Fills a freshly formatted volume. Paths are relative to the volume root
+// with `/` separators; parent directories are created as needed.
+pub trait VolumeWriter {
+    fn create_dir(&mut self, path: &str, modified: Option<Timestamp>) -> io::Result<()>;
+
+    // Copies `size` bytes from `source` into a new file at `path`, calling
+    // `on_progress` with the byte count of every chunk written.
+    fn write_file(
+        &mut self,
+        path: &str,
+        size: u64,
+        modified: Option<Timestamp>,
+        source: &mut dyn Read,
+        on_progress: &mut dyn FnMut(u64),
+    ) -> io::Result<()>;
+
+    // Writes out whatever metadata is still held in memory.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+// A wall-clock time as stored in directory entries. Filesystems that keep no
+// time zone, like FAT, store it as-is.
+#[derive(Clone, Copy)]
+pub struct Timestamp {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl Timestamp {
+    // Converts to UTC; the OS time zone is not worth looking up for this.
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let (days, rest) = (seconds / 86400, seconds % 86400);
+
+        // Days since 1970 to a civil date, after Howard Hinnant's algorithm.
+        let z = days as i64 + 719_468;
+        let era = z / 146_097;
+        let day_of_era = z - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (rest / 3600) as u8,
+            minute: (rest / 60 % 60) as u8,
+            second: (rest % 60) as u8,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct FormatOptions {
+    pub partition_scheme: PartitionScheme,
+    pub file_system: FileSystem,
+    // `None` picks the size Windows would for a volume this large.
+    pub cluster_size: Option<u32>,
+    pub label: String,
+    // `None` derives a fresh serial number from the current time.
+    pub serial: Option<u32>,
+}
+
+// Maps every UTF-16 unit to its upper case form, where that is a single
+// unit. Surrogates and characters without one stay as they are.
+fn upcase_table() -> Vec<u16> {
+    (0..=0xFFFFu32)
+        .map(|unit| {
+            let Some(c) = char::from_u32(unit) else {
+                return unit as u16;
+            };
+            let mut upper = c.to_uppercase();
+            match (upper.next(), upper.next()) {
+                (Some(u), None) if (u as u32) <= 0xFFFF => u as u16,
+                _ => unit as u16,
+            }
+        })
+        .collect()
+}
+
+// Zeroes `length` bytes from `offset` on. Formatting clears the metadata
+// areas this way so no trace of an earlier filesystem is left to confuse
+// drivers.
+fn write_zeros<T: Write + Seek + ?Sized>(target: &mut T, offset: u64, mut length: u64) -> io::Result<()> {
+    target.seek(SeekFrom::Start(offset))?;
+    let zeros = vec![0; CHUNK_SIZE];
+    while length > 0 {
+        let chunk = length.min(CHUNK_SIZE as u64) as usize;
+        target.write_all(&zeros[..chunk])?;
+        length -= chunk as u64;
+    }
+    Ok(())
+}
+
+// Copies `length` bytes of file data from `source` to where `target` stands,
+// calling `on_progress` after every chunk. The rest of the last cluster
+// belongs to the file too, so the final write is padded out to a whole
+// sector.
+fn write_data<W: Write + ?Sized>(
+    target: &mut W,
+    source: &mut dyn Read,
+    mut length: u64,
+    on_progress: &mut dyn FnMut(u64),
+) -> io::Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    while length > 0 {
+        let chunk = length.min(CHUNK_SIZE as u64) as usize;
+        source.read_exact(&mut buffer[..chunk])?;
+
+        let padded = chunk.div_ceil(SECTOR_SIZE as usize) * SECTOR_SIZE as usize;
+        buffer[chunk..padded].fill(0);
+        target.write_all(&buffer[..padded])?;
+
+        length -= chunk as u64;
+        on_progress(chunk as u64);
+    }
+    Ok(())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Like DOS, derive the serial number from the time of formatting.
+fn random_serial() -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    (now.as_secs() as u32).wrapping_mul(0x9E37_79B9) ^ now.subsec_nanos()
+}
+
+// Accepts serial numbers the way Windows prints them, `1234-ABCD`, or as
+// plain hex. An empty string means "pick one".
+pub fn parse_serial(text: &str) -> Result<Option<u32>, String> {
+    let digits: String = text.trim().chars().filter(|&c| c != '-').collect();
+    if digits.is_empty() {
+        return Ok(None);
+    }
+
+    u32::from_str_radix(&digits, 16)
+        .map(Some)
+        .map_err(|_| format!("\"{}\" is not a volume serial number like 1234-ABCD", text.trim()))
+}
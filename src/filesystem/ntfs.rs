@@ -0,0 +1,1878 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{invalid, write_data, write_zeros, FormatOptions, Timestamp, VolumeWriter, BOOT_STUB, CHUNK_SIZE};
+
+const SECTOR_SIZE: u64 = 512;
+const RECORD_SIZE: usize = 1024;
+const INDEX_BLOCK_SIZE: usize = 4096;
+// Attributes start after the record header and its update sequence array,
+// index entries after the block header and its array.
+const RECORD_HEADER_SIZE: usize = 0x38;
+const INDEX_BLOCK_HEADER_SIZE: usize = 0x40;
+const BOOT_SIZE: u64 = 8192;
+const UPCASE_SIZE: u64 = 0x20000;
+const ATTR_DEF_SIZE: usize = 2560;
+
+const MIN_CLUSTER_SIZE: u32 = 512;
+const MAX_CLUSTER_SIZE: u32 = 64 * 1024;
+const DEFAULT_CLUSTER_SIZE: u32 = 4096;
+const MIN_VOLUME_SIZE: u64 = 8 * 1024 * 1024;
+const MIN_LOG_FILE_SIZE: u64 = 2 * 1024 * 1024;
+const MAX_LOG_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+// System files by MFT record number. Records 12 to 23 are reserved.
+const MFT: u64 = 0;
+const MFT_MIRROR: u64 = 1;
+const LOG_FILE: u64 = 2;
+const VOLUME: u64 = 3;
+const ATTR_DEF: u64 = 4;
+const ROOT: u64 = 5;
+const BITMAP: u64 = 6;
+const BOOT: u64 = 7;
+const BAD_CLUSTERS: u64 = 8;
+const SECURE: u64 = 9;
+const UPCASE: u64 = 10;
+const EXTEND: u64 = 11;
+const FIRST_UNUSED_RECORD: u64 = 16;
+const QUOTA: u64 = 24;
+const OBJECT_ID: u64 = 25;
+const REPARSE: u64 = 26;
+const FIRST_USER_RECORD: u64 = 27;
+
+const SYSTEM_FILES: [&str; 12] = [
+    "$MFT", "$MFTMirr", "$LogFile", "$Volume", "$AttrDef", ".", "$Bitmap", "$Boot", "$BadClus", "$Secure", "$UpCase",
+    "$Extend",
+];
+const EXTEND_FILES: [(u64, &str); 3] = [(QUOTA, "$Quota"), (OBJECT_ID, "$ObjId"), (REPARSE, "$Reparse")];
+
+// Attribute types.
+const STANDARD_INFORMATION: u32 = 0x10;
+const FILE_NAME: u32 = 0x30;
+const VOLUME_NAME: u32 = 0x60;
+const VOLUME_INFORMATION: u32 = 0x70;
+const DATA: u32 = 0x80;
+const INDEX_ROOT: u32 = 0x90;
+const INDEX_ALLOCATION: u32 = 0xA0;
+const BITMAP_ATTRIBUTE: u32 = 0xB0;
+const END_OF_ATTRIBUTES: u32 = 0xFFFF_FFFF;
+
+const RECORD_IN_USE: u16 = 0x01;
+const RECORD_DIRECTORY: u16 = 0x02;
+const RECORD_VIEW_INDEX: u16 = 0x08;
+
+const ATTR_HIDDEN: u32 = 0x02;
+const ATTR_SYSTEM: u32 = 0x04;
+const ATTR_ARCHIVE: u32 = 0x20;
+const ATTR_DIRECTORY_INDEX: u32 = 0x1000_0000;
+const ATTR_VIEW_INDEX: u32 = 0x2000_0000;
+
+const ENTRY_SUBNODE: u16 = 0x01;
+const ENTRY_LAST: u16 = 0x02;
+const INDEX_HAS_CHILDREN: u8 = 0x01;
+
+const COLLATION_FILE_NAME: u32 = 0x01;
+const COLLATION_ULONG: u32 = 0x10;
+const COLLATION_SID: u32 = 0x11;
+const COLLATION_SECURITY_HASH: u32 = 0x12;
+const COLLATION_ULONGS: u32 = 0x13;
+
+const NAMESPACE_WIN32: u8 = 1;
+const NAMESPACE_WIN32_AND_DOS: u8 = 3;
+
+// Every file shares the one security descriptor in $Secure. Its second
+// copy sits 256 KiB further into $SDS, as Windows keeps them.
+const SECURITY_ID: u32 = 0x100;
+const SDS_MIRROR_OFFSET: usize = 0x40000;
+const SDS_HEADER_SIZE: usize = 20;
+
+// 100 ns intervals between 1601, where FILETIME counts from, and 1970.
+const FILETIME_UNIX_EPOCH: u64 = 116_444_736_000_000_000;
+
+const MAX_NAME_LENGTH: usize = 255;
+const MAX_LABEL_LENGTH: usize = 32;
+
+// Reading back never needs more than an index or the up-case table at once.
+const MAX_METADATA_READ: u64 = 256 * 1024 * 1024;
+
+struct Geometry {
+    offset: u64,
+    // Excludes the last sector, which holds the backup boot sector.
+    total_sectors: u64,
+    cluster_size: u64,
+    cluster_count: u64,
+}
+
+impl Geometry {
+    fn new(offset: u64, size: u64, cluster_size: u32) -> io::Result<Self> {
+        let total_sectors = (size / SECTOR_SIZE).saturating_sub(1);
+        let cluster_count = total_sectors / (cluster_size as u64 / SECTOR_SIZE);
+
+        if size < MIN_VOLUME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The volume is too small for NTFS",
+            ));
+        }
+        if cluster_count > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("The volume is too large for NTFS with {} byte clusters", cluster_size),
+            ));
+        }
+
+        Ok(Self {
+            offset,
+            total_sectors,
+            cluster_size: cluster_size as u64,
+            cluster_count,
+        })
+    }
+
+    fn cluster_position(&self, lcn: u64) -> u64 {
+        self.offset + lcn * self.cluster_size
+    }
+
+    // Index blocks are numbered in clusters, or in sectors when a cluster
+    // holds more than one block.
+    fn vcns_per_index_block(&self) -> u64 {
+        if self.cluster_size <= INDEX_BLOCK_SIZE as u64 {
+            INDEX_BLOCK_SIZE as u64 / self.cluster_size
+        } else {
+            INDEX_BLOCK_SIZE as u64 / SECTOR_SIZE
+        }
+    }
+}
+
+// Clusters handed out for a non-resident stream, always in one run.
+#[derive(Clone, Copy)]
+struct Stream {
+    lcn: u64,
+    clusters: u64,
+    size: u64,
+}
+
+// Where `finish` put the system files.
+struct Layout {
+    mft: Stream,
+    mft_mirror: Stream,
+    mft_bitmap: Stream,
+    log_file: Stream,
+    attr_def: Stream,
+    bitmap: Stream,
+    upcase: Stream,
+    sds: Stream,
+}
+
+// An NTFS volume being filled on a raw target. File data goes straight to
+// disk, each file in one contiguous run of clusters; the MFT, the indexes
+// and the other system files are laid out after the data in `finish`.
+pub struct NtfsVolume<'a, T: ?Sized> {
+    target: &'a mut T,
+    geometry: Geometry,
+    upcase: Vec<u16>,
+    label: Vec<u16>,
+    serial: u64,
+    // FILETIME for entries that come without a timestamp.
+    now: u64,
+    next_free: u64,
+    boot: Stream,
+    // Files and directories by MFT record, from FIRST_USER_RECORD on.
+    nodes: Vec<Node>,
+    // Children by directory record, including the root and $Extend.
+    directories: HashMap<u64, Directory>,
+    // Directory records keyed by up-cased path.
+    lookup: HashMap<String, u64>,
+}
+
+struct Node {
+    parent: u64,
+    name: Vec<u16>,
+    modified: u64,
+    data: NodeData,
+}
+
+enum NodeData {
+    Directory,
+    // Small files live in their MFT record.
+    Resident(Vec<u8>),
+    Clusters(Stream),
+}
+
+#[derive(Default)]
+struct Directory {
+    children: Vec<u64>,
+    // Up-cased names, since Windows looks names up without case.
+    names: HashSet<Vec<u16>>,
+}
+
+impl<'a, T: Read + Write + Seek + ?Sized> NtfsVolume<'a, T> {
+    // Clears the boot area of an empty volume of `size` bytes starting
+    // `offset` bytes into the target. Everything else is written in `finish`,
+    // boot sectors last.
+    pub fn format(target: &'a mut T, offset: u64, size: u64, options: &FormatOptions) -> io::Result<Self> {
+        let cluster_size = match options.cluster_size {
+            Some(cluster_size)
+                if !cluster_size.is_power_of_two()
+                    || !(MIN_CLUSTER_SIZE..=MAX_CLUSTER_SIZE).contains(&cluster_size) =>
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "NTFS clusters must be a power of two from 512 bytes to 64 KiB",
+                ));
+            }
+            Some(cluster_size) => cluster_size,
+            None => DEFAULT_CLUSTER_SIZE,
+        };
+        let geometry = Geometry::new(offset, size, cluster_size)?;
+        // Windows shows the low half as the volume serial number.
+        let serial = (super::random_serial() as u64) << 32 | options.serial.unwrap_or_else(super::random_serial) as u64;
+        let label: Vec<u16> = options.label.trim().encode_utf16().take(MAX_LABEL_LENGTH).collect();
+
+        let boot_clusters = BOOT_SIZE.div_ceil(geometry.cluster_size);
+        write_zeros(target, offset, boot_clusters * geometry.cluster_size)?;
+
+        let mut directories = HashMap::new();
+        let upcase = super::upcase_table();
+        let upcase_name = |name: &str| -> Vec<u16> { name.encode_utf16().map(|unit| upcase[unit as usize]).collect() };
+        directories.insert(
+            ROOT,
+            Directory {
+                children: (MFT..=EXTEND).collect(),
+                names: SYSTEM_FILES.iter().map(|name| upcase_name(name)).collect(),
+            },
+        );
+        directories.insert(
+            EXTEND,
+            Directory {
+                children: EXTEND_FILES.iter().map(|&(record, _)| record).collect(),
+                names: EXTEND_FILES.iter().map(|&(_, name)| upcase_name(name)).collect(),
+            },
+        );
+
+        Ok(Self {
+            target,
+            upcase,
+            label,
+            serial,
+            now: filetime_now(),
+            next_free: boot_clusters,
+            boot: Stream {
+                lcn: 0,
+                clusters: boot_clusters,
+                size: BOOT_SIZE,
+            },
+            geometry,
+            nodes: Vec::new(),
+            directories,
+            lookup: HashMap::from([(String::new(), ROOT)]),
+        })
+    }
+
+    // Resolves a directory path, creating missing directories on the way.
+    fn directory(&mut self, path: &str, modified: Option<Timestamp>) -> io::Result<u64> {
+        let mut current = ROOT;
+        let mut current_path = String::new();
+
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            current_path = format!("{}/{}", current_path, name);
+            let key = self.upcase_string(&current_path);
+            if let Some(&record) = self.lookup.get(&key) {
+                current = record;
+                continue;
+            }
+
+            let units = self.check_new_name(current, name)?;
+            let record = self.add_node(Node {
+                parent: current,
+                name: units,
+                modified: modified.map_or(self.now, filetime),
+                data: NodeData::Directory,
+            });
+            self.directories.insert(record, Directory::default());
+            self.lookup.insert(key, record);
+            current = record;
+        }
+        Ok(current)
+    }
+
+    fn check_new_name(&self, parent: u64, name: &str) -> io::Result<Vec<u16>> {
+        let units: Vec<u16> = stored_name(name).encode_utf16().collect();
+
+        if units.is_empty() || name == "." || name == ".." {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("\"{}\" is not a valid file name", name),
+            ));
+        }
+        if units.len() > MAX_NAME_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is longer than the 255 characters NTFS allows", name),
+            ));
+        }
+        if self.directories[&parent].names.contains(&self.upcase_units(&units)) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists (Windows ignores letter case in NTFS names)", name),
+            ));
+        }
+        Ok(units)
+    }
+
+    fn add_node(&mut self, node: Node) -> u64 {
+        let record = FIRST_USER_RECORD + self.nodes.len() as u64;
+        let key = self.upcase_units(&node.name);
+        let parent = self.directories.get_mut(&node.parent).unwrap();
+        parent.names.insert(key);
+        parent.children.push(record);
+        self.nodes.push(node);
+        record
+    }
+
+    // Hands out consecutive clusters for `size` bytes from the end of what
+    // has been used so far; nothing is ever freed on a volume being filled.
+    fn allocate(&mut self, size: u64) -> io::Result<Stream> {
+        let clusters = size.div_ceil(self.geometry.cluster_size);
+        if self.next_free + clusters > self.geometry.cluster_count {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "The NTFS volume is full"));
+        }
+
+        let stream = Stream {
+            lcn: self.next_free,
+            clusters,
+            size,
+        };
+        self.next_free += clusters;
+        Ok(stream)
+    }
+
+    fn upcase_units(&self, units: &[u16]) -> Vec<u16> {
+        units.iter().map(|&unit| self.upcase[unit as usize]).collect()
+    }
+
+    fn upcase_string(&self, text: &str) -> String {
+        let units: Vec<u16> = text.encode_utf16().collect();
+        String::from_utf16_lossy(&self.upcase_units(&units))
+    }
+
+    // Writes `bytes` at the start of `stream`, padded to whole sectors.
+    fn write_stream(&mut self, stream: &Stream, bytes: &[u8]) -> io::Result<()> {
+        let mut padded = bytes.to_vec();
+        padded.resize(bytes.len().div_ceil(SECTOR_SIZE as usize) * SECTOR_SIZE as usize, 0);
+        self.target
+            .seek(SeekFrom::Start(self.geometry.cluster_position(stream.lcn)))?;
+        self.target.write_all(&padded)
+    }
+
+    // The name every file record carries and its parent indexes.
+    fn file_name(&self, record: u64, layout: &Layout) -> Option<FileName> {
+        let cluster_size = self.geometry.cluster_size;
+        let sized = |stream: &Stream| (stream.clusters * cluster_size, stream.size);
+        let (parent, name, time, flags, (allocated, size)) = match record {
+            MFT..=EXTEND => {
+                let (allocated, size) = match record {
+                    MFT => sized(&layout.mft),
+                    MFT_MIRROR => sized(&layout.mft_mirror),
+                    LOG_FILE => sized(&layout.log_file),
+                    ATTR_DEF => sized(&layout.attr_def),
+                    BITMAP => sized(&layout.bitmap),
+                    BOOT => sized(&self.boot),
+                    UPCASE => sized(&layout.upcase),
+                    _ => (0, 0),
+                };
+                let name = SYSTEM_FILES[record as usize].encode_utf16().collect();
+                (ROOT, name, self.now, system_attributes(record), (allocated, size))
+            }
+            QUOTA..=REPARSE => {
+                let name = EXTEND_FILES[(record - QUOTA) as usize].1.encode_utf16().collect();
+                (EXTEND, name, self.now, system_attributes(record), (0, 0))
+            }
+            _ => {
+                let node = self.nodes.get(record.checked_sub(FIRST_USER_RECORD)? as usize)?;
+                let (flags, sizes) = match &node.data {
+                    NodeData::Directory => (ATTR_DIRECTORY_INDEX, (0, 0)),
+                    NodeData::Resident(data) => (ATTR_ARCHIVE, (align8(data.len()) as u64, data.len() as u64)),
+                    NodeData::Clusters(stream) => (ATTR_ARCHIVE, sized(stream)),
+                };
+                (node.parent, node.name.clone(), node.modified, flags, sizes)
+            }
+        };
+        Some(FileName {
+            parent,
+            name,
+            time,
+            allocated,
+            size,
+            flags,
+        })
+    }
+
+    // Lays the directory out as a B+ tree: in the INDEX_ROOT alone while it
+    // fits there, otherwise in index blocks below it.
+    fn build_directory_index(&self, record: u64, names: &[Option<FileName>]) -> IndexTree {
+        let mut children = self.directories[&record].children.clone();
+        children.sort_by_cached_key(|&child| self.upcase_units(&names[child as usize].as_ref().unwrap().name));
+        let entries: Vec<IndexEntry> = children
+            .iter()
+            .map(|&child| IndexEntry::file(child, names[child as usize].as_ref().unwrap()))
+            .collect();
+
+        let name = names[record as usize].as_ref().unwrap();
+        let used = RECORD_HEADER_SIZE
+            + 8
+            + attribute_length(0, STANDARD_INFORMATION_SIZE)
+            + attribute_length(0, name.value().len())
+            + attribute_length(8, 0x20);
+        let root_room = RECORD_SIZE - used;
+        // A large index also needs its allocation and bitmap attributes.
+        let large_root_room = |blocks: usize| {
+            root_room.saturating_sub(INDEX_ALLOCATION_SIZE + attribute_length(8, align8(blocks.div_ceil(8))))
+        };
+        build_index(
+            entries,
+            root_room,
+            large_root_room,
+            self.geometry.vcns_per_index_block(),
+        )
+    }
+
+    fn record(
+        &self,
+        number: u64,
+        layout: &Layout,
+        names: &[Option<FileName>],
+        indexes: &HashMap<u64, (IndexTree, Option<Stream>)>,
+    ) -> Record {
+        let in_use =
+            number < FIRST_UNUSED_RECORD || (QUOTA..FIRST_USER_RECORD + self.nodes.len() as u64).contains(&number);
+        if !in_use {
+            return Record {
+                flags: 0,
+                attributes: Vec::new(),
+            };
+        }
+
+        let cluster_size = self.geometry.cluster_size;
+        let name = names[number as usize].as_ref();
+        let mut flags = RECORD_IN_USE;
+        let mut attributes = vec![standard_information(
+            name.map_or(self.now, |name| name.time),
+            name.map_or(ATTR_HIDDEN | ATTR_SYSTEM, |name| name.flags),
+        )];
+        if let Some(name) = name {
+            attributes.push(Attribute::resident(FILE_NAME, "", name.value(), true));
+        }
+
+        if let Some((tree, allocation)) = indexes.get(&number) {
+            flags |= RECORD_DIRECTORY;
+            attributes.extend(index_attributes(
+                "$I30",
+                FILE_NAME,
+                COLLATION_FILE_NAME,
+                tree,
+                allocation.as_ref(),
+                &self.geometry,
+            ));
+        }
+
+        let data = |stream: &Stream| Attribute::non_resident(DATA, "", stream, cluster_size);
+        match number {
+            MFT => {
+                attributes.push(data(&layout.mft));
+                attributes.push(Attribute::non_resident(
+                    BITMAP_ATTRIBUTE,
+                    "",
+                    &layout.mft_bitmap,
+                    cluster_size,
+                ));
+            }
+            MFT_MIRROR => attributes.push(data(&layout.mft_mirror)),
+            LOG_FILE => attributes.push(data(&layout.log_file)),
+            VOLUME => {
+                let label = self.label.iter().flat_map(|unit| unit.to_le_bytes()).collect();
+                attributes.push(Attribute::resident(VOLUME_NAME, "", label, false));
+                // NTFS 3.1, no flags set.
+                let mut information = vec![0; 12];
+                information[8] = 3;
+                information[9] = 1;
+                attributes.push(Attribute::resident(VOLUME_INFORMATION, "", information, false));
+                attributes.push(Attribute::resident(DATA, "", Vec::new(), false));
+            }
+            ATTR_DEF => attributes.push(data(&layout.attr_def)),
+            BITMAP => attributes.push(data(&layout.bitmap)),
+            BOOT => attributes.push(data(&self.boot)),
+            BAD_CLUSTERS => {
+                // $Bad lists bad clusters as the only allocated runs of a
+                // sparse stream as large as the volume.
+                let size = self.geometry.cluster_count * cluster_size;
+                attributes.push(Attribute::resident(DATA, "", Vec::new(), false));
+                attributes.push(Attribute {
+                    kind: DATA,
+                    name: "$Bad",
+                    value: Value::NonResident {
+                        runs: vec![Run {
+                            lcn: None,
+                            length: self.geometry.cluster_count,
+                        }],
+                        size,
+                        allocated: size,
+                    },
+                });
+            }
+            SECURE => {
+                flags |= RECORD_VIEW_INDEX;
+                // $SDH finds descriptors by hash and ID, $SII by ID alone.
+                let (_, header) = security_descriptor();
+                attributes.push(Attribute::non_resident(DATA, "$SDS", &layout.sds, cluster_size));
+                let hashes = IndexTree::root_only(vec![IndexEntry::view(header[0..8].to_vec(), header.to_vec())]);
+                let ids = IndexTree::root_only(vec![IndexEntry::view(header[4..8].to_vec(), header.to_vec())]);
+                attributes.extend(index_attributes(
+                    "$SDH",
+                    0,
+                    COLLATION_SECURITY_HASH,
+                    &hashes,
+                    None,
+                    &self.geometry,
+                ));
+                attributes.extend(index_attributes("$SII", 0, COLLATION_ULONG, &ids, None, &self.geometry));
+            }
+            UPCASE => attributes.push(data(&layout.upcase)),
+            QUOTA => {
+                flags |= RECORD_VIEW_INDEX;
+                let empty = IndexTree::default();
+                attributes.extend(index_attributes("$O", 0, COLLATION_SID, &empty, None, &self.geometry));
+                attributes.extend(index_attributes("$Q", 0, COLLATION_ULONG, &empty, None, &self.geometry));
+            }
+            OBJECT_ID | REPARSE => {
+                flags |= RECORD_VIEW_INDEX;
+                let name = if number == OBJECT_ID { "$O" } else { "$R" };
+                attributes.extend(index_attributes(
+                    name,
+                    0,
+                    COLLATION_ULONGS,
+                    &IndexTree::default(),
+                    None,
+                    &self.geometry,
+                ));
+            }
+            _ if number >= FIRST_USER_RECORD => match &self.nodes[(number - FIRST_USER_RECORD) as usize].data {
+                NodeData::Directory => {}
+                NodeData::Resident(bytes) => attributes.push(Attribute::resident(DATA, "", bytes.clone(), false)),
+                NodeData::Clusters(stream) => attributes.push(data(stream)),
+            },
+            _ => {}
+        }
+        Record { flags, attributes }
+    }
+}
+
+impl<T: Read + Write + Seek + ?Sized> VolumeWriter for NtfsVolume<'_, T> {
+    fn create_dir(&mut self, path: &str, modified: Option<Timestamp>) -> io::Result<()> {
+        self.directory(path, modified).map(|_| ())
+    }
+
+    fn write_file(
+        &mut self,
+        path: &str,
+        size: u64,
+        modified: Option<Timestamp>,
+        source: &mut dyn Read,
+        on_progress: &mut dyn FnMut(u64),
+    ) -> io::Result<()> {
+        let (parent_path, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let parent = self.directory(parent_path, None)?;
+        let units = self.check_new_name(parent, name)?;
+
+        // What is left of the record after the standard information, the
+        // file name and the header of a resident $DATA.
+        let name_length = attribute_length(0, FILE_NAME_HEADER_SIZE + 2 * units.len());
+        let room = RECORD_SIZE
+            - RECORD_HEADER_SIZE
+            - 8
+            - attribute_length(0, STANDARD_INFORMATION_SIZE)
+            - name_length
+            - attribute_length(0, 0);
+
+        let data = if size <= room as u64 {
+            let mut bytes = vec![0; size as usize];
+            source.read_exact(&mut bytes)?;
+            on_progress(size);
+            NodeData::Resident(bytes)
+        } else {
+            let stream = self.allocate(size)?;
+            self.target
+                .seek(SeekFrom::Start(self.geometry.cluster_position(stream.lcn)))?;
+            write_data(self.target, source, size, on_progress)?;
+            NodeData::Clusters(stream)
+        };
+
+        self.add_node(Node {
+            parent,
+            name: units,
+            modified: modified.map_or(self.now, filetime),
+            data,
+        });
+        Ok(())
+    }
+
+    // Places the system files and index blocks after the file data, then
+    // writes them, the MFT and its mirror, and finally the boot sectors.
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        let cluster_size = self.geometry.cluster_size;
+        // The mirror covers the first cluster of the MFT, at least 4 records.
+        let records_per_cluster = (cluster_size / RECORD_SIZE as u64).max(1);
+        let mirror_records = records_per_cluster.max(4);
+        let record_count = (FIRST_USER_RECORD + self.nodes.len() as u64)
+            .max(mirror_records)
+            .div_ceil(records_per_cluster)
+            * records_per_cluster;
+        let volume_size = self.geometry.cluster_count * cluster_size;
+        let log_file_size = (volume_size / 256).clamp(MIN_LOG_FILE_SIZE, MAX_LOG_FILE_SIZE);
+        let (descriptor, sds_header) = security_descriptor();
+        let sds_entry = [sds_header.as_slice(), &descriptor].concat();
+
+        let layout = Layout {
+            mft: self.allocate(record_count * RECORD_SIZE as u64)?,
+            mft_mirror: self.allocate(mirror_records * RECORD_SIZE as u64)?,
+            mft_bitmap: self.allocate(align8(record_count.div_ceil(8) as usize) as u64)?,
+            log_file: self.allocate(log_file_size.div_ceil(cluster_size) * cluster_size)?,
+            attr_def: self.allocate(ATTR_DEF_SIZE as u64)?,
+            bitmap: self.allocate(align8(self.geometry.cluster_count.div_ceil(8) as usize) as u64)?,
+            upcase: self.allocate(UPCASE_SIZE)?,
+            sds: self.allocate((SDS_MIRROR_OFFSET + sds_entry.len()) as u64)?,
+        };
+
+        let names: Vec<Option<FileName>> = (0..record_count)
+            .map(|record| self.file_name(record, &layout))
+            .collect();
+        let mut directories: Vec<u64> = self.directories.keys().copied().collect();
+        directories.sort_unstable();
+        let mut indexes = HashMap::new();
+        for record in directories {
+            let tree = self.build_directory_index(record, &names);
+            let allocation = match tree.blocks.len() {
+                0 => None,
+                blocks => Some(self.allocate((blocks * INDEX_BLOCK_SIZE) as u64)?),
+            };
+            indexes.insert(record, (tree, allocation));
+        }
+
+        // Nothing is allocated past this point, so the bitmap is final. Bits
+        // past the last cluster are set, as Windows expects.
+        let mut bitmap = vec![0u8; layout.bitmap.size as usize];
+        for cluster in (0..self.next_free).chain(self.geometry.cluster_count..bitmap.len() as u64 * 8) {
+            bitmap[(cluster / 8) as usize] |= 1 << (cluster % 8);
+        }
+        self.write_stream(&layout.bitmap, &bitmap)?;
+
+        self.target
+            .seek(SeekFrom::Start(self.geometry.cluster_position(layout.log_file.lcn)))?;
+        write_bytes(self.target, 0xFF, layout.log_file.size)?;
+
+        self.write_stream(&layout.attr_def, &attr_def())?;
+        let upcase: Vec<u8> = self.upcase.iter().flat_map(|unit| unit.to_le_bytes()).collect();
+        self.write_stream(&layout.upcase, &upcase)?;
+
+        let mut sds = vec![0; layout.sds.size as usize];
+        sds[..sds_entry.len()].copy_from_slice(&sds_entry);
+        sds[SDS_MIRROR_OFFSET..].copy_from_slice(&sds_entry);
+        self.write_stream(&layout.sds, &sds)?;
+
+        for (tree, allocation) in indexes.values() {
+            if let Some(allocation) = allocation {
+                let vcns_per_block = self.geometry.vcns_per_index_block();
+                let blocks: Vec<u8> = tree
+                    .blocks
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(index, (entries, last_child))| {
+                        index_block(index as u64 * vcns_per_block, entries, *last_child)
+                    })
+                    .collect();
+                self.write_stream(allocation, &blocks)?;
+            }
+        }
+
+        let mut mft = Vec::with_capacity(record_count as usize * RECORD_SIZE);
+        let mut mft_bitmap = vec![0u8; layout.mft_bitmap.size as usize];
+        for number in 0..record_count {
+            let record = self.record(number, &layout, &names, &indexes);
+            if record.flags & RECORD_IN_USE != 0 {
+                mft_bitmap[(number / 8) as usize] |= 1 << (number % 8);
+            }
+            mft.extend(record_bytes(number, &record)?);
+        }
+        self.write_stream(&layout.mft_bitmap, &mft_bitmap)?;
+        self.write_stream(&layout.mft, &mft)?;
+        self.write_stream(&layout.mft_mirror, &mft[..mirror_records as usize * RECORD_SIZE])?;
+
+        // The backup boot sector goes in the sector past the volume proper.
+        let boot_sector = boot_sector(&self.geometry, &layout, self.serial);
+        for sector in [0, self.geometry.total_sectors] {
+            self.target
+                .seek(SeekFrom::Start(self.geometry.offset + sector * SECTOR_SIZE))?;
+            self.target.write_all(&boot_sector)?;
+        }
+        self.target.flush()
+    }
+}
+
+const STANDARD_INFORMATION_SIZE: usize = 72;
+const FILE_NAME_HEADER_SIZE: usize = 0x42;
+// A non-resident attribute named $I30 with room for one run.
+const INDEX_ALLOCATION_SIZE: usize = 0x60;
+
+struct FileName {
+    parent: u64,
+    name: Vec<u16>,
+    time: u64,
+    allocated: u64,
+    size: u64,
+    flags: u32,
+}
+
+impl FileName {
+    fn value(&self) -> Vec<u8> {
+        let mut value = vec![0u8; FILE_NAME_HEADER_SIZE + 2 * self.name.len()];
+        value[0..8].copy_from_slice(&reference(self.parent).to_le_bytes());
+        for at in [0x08, 0x10, 0x18, 0x20] {
+            value[at..at + 8].copy_from_slice(&self.time.to_le_bytes());
+        }
+        value[0x28..0x30].copy_from_slice(&self.allocated.to_le_bytes());
+        value[0x30..0x38].copy_from_slice(&self.size.to_le_bytes());
+        value[0x38..0x3C].copy_from_slice(&self.flags.to_le_bytes());
+        value[0x40] = self.name.len() as u8;
+        // Names that are already valid short names need no separate DOS name.
+        value[0x41] = if is_short_name(&self.name) {
+            NAMESPACE_WIN32_AND_DOS
+        } else {
+            NAMESPACE_WIN32
+        };
+        for (slot, unit) in value[FILE_NAME_HEADER_SIZE..].chunks_exact_mut(2).zip(&self.name) {
+            slot.copy_from_slice(&unit.to_le_bytes());
+        }
+        value
+    }
+}
+
+struct Record {
+    flags: u16,
+    attributes: Vec<Attribute>,
+}
+
+struct Attribute {
+    kind: u32,
+    name: &'static str,
+    value: Value,
+}
+
+enum Value {
+    Resident { data: Vec<u8>, indexed: bool },
+    NonResident { runs: Vec<Run>, size: u64, allocated: u64 },
+}
+
+// A run of clusters; sparse runs have no location.
+#[derive(Clone, Copy)]
+struct Run {
+    lcn: Option<u64>,
+    length: u64,
+}
+
+impl Attribute {
+    fn resident(kind: u32, name: &'static str, data: Vec<u8>, indexed: bool) -> Self {
+        Self {
+            kind,
+            name,
+            value: Value::Resident { data, indexed },
+        }
+    }
+
+    fn non_resident(kind: u32, name: &'static str, stream: &Stream, cluster_size: u64) -> Self {
+        Self {
+            kind,
+            name,
+            value: Value::NonResident {
+                runs: vec![Run {
+                    lcn: Some(stream.lcn),
+                    length: stream.clusters,
+                }],
+                size: stream.size,
+                allocated: stream.clusters * cluster_size,
+            },
+        }
+    }
+
+    fn to_bytes(&self, id: u16) -> Vec<u8> {
+        let name: Vec<u16> = self.name.encode_utf16().collect();
+        let (name_offset, mut bytes) = match &self.value {
+            Value::Resident { data, indexed } => {
+                let value_offset = align8(0x18 + 2 * name.len());
+                let mut bytes = vec![0u8; align8(value_offset + data.len())];
+                bytes[0x10..0x14].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                bytes[0x14..0x16].copy_from_slice(&(value_offset as u16).to_le_bytes());
+                bytes[0x16] = *indexed as u8;
+                bytes[value_offset..value_offset + data.len()].copy_from_slice(data);
+                (0x18, bytes)
+            }
+            Value::NonResident { runs, size, allocated } => {
+                let runs_offset = align8(0x40 + 2 * name.len());
+                let pairs = encode_runs(runs);
+                let mut bytes = vec![0u8; align8(runs_offset + pairs.len())];
+                bytes[8] = 1;
+                let clusters: u64 = runs.iter().map(|run| run.length).sum();
+                bytes[0x18..0x20].copy_from_slice(&clusters.wrapping_sub(1).to_le_bytes());
+                bytes[0x20..0x22].copy_from_slice(&(runs_offset as u16).to_le_bytes());
+                bytes[0x28..0x30].copy_from_slice(&allocated.to_le_bytes());
+                bytes[0x30..0x38].copy_from_slice(&size.to_le_bytes());
+                bytes[0x38..0x40].copy_from_slice(&size.to_le_bytes());
+                bytes[runs_offset..runs_offset + pairs.len()].copy_from_slice(&pairs);
+                (0x40, bytes)
+            }
+        };
+        let length = bytes.len() as u32;
+        bytes[0..4].copy_from_slice(&self.kind.to_le_bytes());
+        bytes[4..8].copy_from_slice(&length.to_le_bytes());
+        bytes[9] = name.len() as u8;
+        bytes[10..12].copy_from_slice(&(name_offset as u16).to_le_bytes());
+        bytes[14..16].copy_from_slice(&id.to_le_bytes());
+        for (slot, unit) in bytes[name_offset..].chunks_exact_mut(2).zip(&name) {
+            slot.copy_from_slice(&unit.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+// The length of a resident attribute with a name of `name_length` bytes.
+fn attribute_length(name_length: usize, value_length: usize) -> usize {
+    align8(align8(0x18 + name_length) + value_length)
+}
+
+fn standard_information(time: u64, attributes: u32) -> Attribute {
+    let mut value = vec![0u8; STANDARD_INFORMATION_SIZE];
+    for at in [0x00, 0x08, 0x10, 0x18] {
+        value[at..at + 8].copy_from_slice(&time.to_le_bytes());
+    }
+    value[0x20..0x24].copy_from_slice(&attributes.to_le_bytes());
+    value[0x34..0x38].copy_from_slice(&SECURITY_ID.to_le_bytes());
+    Attribute::resident(STANDARD_INFORMATION, "", value, false)
+}
+
+fn system_attributes(record: u64) -> u32 {
+    ATTR_HIDDEN
+        | ATTR_SYSTEM
+        | match record {
+            ROOT | EXTEND => ATTR_DIRECTORY_INDEX,
+            SECURE | QUOTA | OBJECT_ID | REPARSE => ATTR_VIEW_INDEX,
+            _ => 0,
+        }
+}
+
+// Files below record 16 reuse their record number as sequence number, with
+// $MFT itself at 1.
+fn reference(record: u64) -> u64 {
+    let sequence = if record < FIRST_UNUSED_RECORD { record.max(1) } else { 1 };
+    record | sequence << 48
+}
+
+fn record_bytes(number: u64, record: &Record) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; RECORD_SIZE];
+    bytes[0..4].copy_from_slice(b"FILE");
+    bytes[4..6].copy_from_slice(&0x30u16.to_le_bytes());
+    bytes[6..8].copy_from_slice(&((RECORD_SIZE as u64 / SECTOR_SIZE) as u16 + 1).to_le_bytes());
+    bytes[0x10..0x12].copy_from_slice(&((reference(number) >> 48) as u16).to_le_bytes());
+    let links = record.attributes.iter().filter(|a| a.kind == FILE_NAME).count() as u16;
+    bytes[0x12..0x14].copy_from_slice(&links.to_le_bytes());
+    bytes[0x14..0x16].copy_from_slice(&(RECORD_HEADER_SIZE as u16).to_le_bytes());
+    bytes[0x16..0x18].copy_from_slice(&record.flags.to_le_bytes());
+    bytes[0x1C..0x20].copy_from_slice(&(RECORD_SIZE as u32).to_le_bytes());
+    bytes[0x28..0x2A].copy_from_slice(&(record.attributes.len() as u16).to_le_bytes());
+    bytes[0x2C..0x30].copy_from_slice(&(number as u32).to_le_bytes());
+
+    // Attributes are kept sorted by type, then name.
+    let mut attributes: Vec<&Attribute> = record.attributes.iter().collect();
+    attributes.sort_by_key(|a| (a.kind, a.name.encode_utf16().collect::<Vec<u16>>()));
+
+    let mut position = RECORD_HEADER_SIZE;
+    for (id, attribute) in attributes.iter().enumerate() {
+        let attribute = attribute.to_bytes(id as u16);
+        if position + attribute.len() + 8 > RECORD_SIZE {
+            return Err(io::Error::other(format!("MFT record {} overflows", number)));
+        }
+        bytes[position..position + attribute.len()].copy_from_slice(&attribute);
+        position += attribute.len();
+    }
+    bytes[position..position + 4].copy_from_slice(&END_OF_ATTRIBUTES.to_le_bytes());
+    let in_use = (position + 8) as u32;
+    bytes[0x18..0x1C].copy_from_slice(&in_use.to_le_bytes());
+
+    protect(&mut bytes, 0x30);
+    Ok(bytes)
+}
+
+// Moves the last two bytes of every sector into the update sequence array
+// and puts the sequence number in their place, so torn writes show.
+fn protect(block: &mut [u8], array_offset: usize) {
+    let sequence = 1u16.to_le_bytes();
+    block[array_offset..array_offset + 2].copy_from_slice(&sequence);
+    for sector in 0..block.len() / SECTOR_SIZE as usize {
+        let end = (sector + 1) * SECTOR_SIZE as usize - 2;
+        let slot = array_offset + 2 + 2 * sector;
+        let saved = [block[end], block[end + 1]];
+        block[slot..slot + 2].copy_from_slice(&saved);
+        block[end..end + 2].copy_from_slice(&sequence);
+    }
+}
+
+// Mapping pairs: a header byte with the sizes of the length and the LCN
+// delta that follow, both little-endian and signed.
+fn encode_runs(runs: &[Run]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut previous = 0i64;
+    for run in runs {
+        let length = run.length.to_le_bytes();
+        let length_size = signed_size(run.length as i64);
+        match run.lcn {
+            Some(lcn) => {
+                let delta = lcn as i64 - previous;
+                let delta_size = signed_size(delta);
+                bytes.push((delta_size << 4 | length_size) as u8);
+                bytes.extend(&length[..length_size]);
+                bytes.extend(&delta.to_le_bytes()[..delta_size]);
+                previous = lcn as i64;
+            }
+            None => {
+                bytes.push(length_size as u8);
+                bytes.extend(&length[..length_size]);
+            }
+        }
+    }
+    bytes.push(0);
+    bytes
+}
+
+fn signed_size(value: i64) -> usize {
+    (1..8)
+        .find(|&size| matches!(value >> (8 * size - 1), 0 | -1))
+        .unwrap_or(8)
+}
+
+struct IndexEntry {
+    // A file reference, or where a view index entry keeps its data.
+    header: [u8; 8],
+    key: Vec<u8>,
+    data: Vec<u8>,
+    child: Option<u64>,
+}
+
+impl IndexEntry {
+    fn file(record: u64, name: &FileName) -> Self {
+        Self {
+            header: reference(record).to_le_bytes(),
+            key: name.value(),
+            data: Vec::new(),
+            child: None,
+        }
+    }
+
+    fn view(key: Vec<u8>, data: Vec<u8>) -> Self {
+        let mut header = [0; 8];
+        header[0..2].copy_from_slice(&(16 + key.len() as u16).to_le_bytes());
+        header[2..4].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        Self {
+            header,
+            key,
+            data,
+            child: None,
+        }
+    }
+
+    fn length(&self) -> usize {
+        align8(16 + self.key.len() + self.data.len()) + if self.child.is_some() { 8 } else { 0 }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        write_entry(out, &self.header, &self.key, &self.data, self.child, 0);
+    }
+}
+
+fn write_entry(out: &mut Vec<u8>, header: &[u8; 8], key: &[u8], data: &[u8], child: Option<u64>, flags: u16) {
+    let length = align8(16 + key.len() + data.len()) + if child.is_some() { 8 } else { 0 };
+    let flags = flags | if child.is_some() { ENTRY_SUBNODE } else { 0 };
+    let start = out.len();
+    out.resize(start + length, 0);
+    let entry = &mut out[start..];
+    entry[0..8].copy_from_slice(header);
+    entry[8..10].copy_from_slice(&(length as u16).to_le_bytes());
+    entry[10..12].copy_from_slice(&(key.len() as u16).to_le_bytes());
+    entry[12..14].copy_from_slice(&flags.to_le_bytes());
+    entry[16..16 + key.len()].copy_from_slice(key);
+    entry[16 + key.len()..16 + key.len() + data.len()].copy_from_slice(data);
+    if let Some(vcn) = child {
+        entry[length - 8..].copy_from_slice(&vcn.to_le_bytes());
+    }
+}
+
+// A node's entries, closed by an empty last entry that points to the
+// node's rightmost child, if it has children.
+fn node_bytes(entries: &[IndexEntry], last_child: Option<u64>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for entry in entries {
+        entry.write(&mut bytes);
+    }
+    write_entry(&mut bytes, &[0; 8], &[], &[], last_child, ENTRY_LAST);
+    bytes
+}
+
+fn node_length(entries: &[IndexEntry], last_child: Option<u64>) -> usize {
+    entries.iter().map(IndexEntry::length).sum::<usize>() + if last_child.is_some() { 24 } else { 16 }
+}
+
+#[derive(Default)]
+struct IndexTree {
+    root: Vec<IndexEntry>,
+    root_child: Option<u64>,
+    // Index blocks in VCN order, each with its entries and last child.
+    blocks: Vec<(Vec<IndexEntry>, Option<u64>)>,
+}
+
+impl IndexTree {
+    fn root_only(root: Vec<IndexEntry>) -> Self {
+        Self {
+            root,
+            ..Self::default()
+        }
+    }
+}
+
+// Builds the tree bottom-up from sorted entries: each level is packed into
+// full blocks, with the entry after every block moving up a level to
+// separate it from the next, until what is left fits in the root.
+fn build_index(
+    entries: Vec<IndexEntry>,
+    root_room: usize,
+    large_root_room: impl Fn(usize) -> usize,
+    vcns_per_block: u64,
+) -> IndexTree {
+    if node_length(&entries, None) <= root_room {
+        return IndexTree::root_only(entries);
+    }
+
+    let capacity = INDEX_BLOCK_SIZE - INDEX_BLOCK_HEADER_SIZE;
+    let mut blocks = Vec::new();
+    let mut level = entries;
+    let mut level_child = None;
+    loop {
+        let mut upper = Vec::new();
+        let mut node: Vec<IndexEntry> = Vec::new();
+        let mut used = 0;
+        let mut rest = level.into_iter().peekable();
+        while let Some(entry) = rest.next() {
+            if node.is_empty() || used + entry.length() + 24 <= capacity {
+                used += entry.length();
+                node.push(entry);
+                continue;
+            }
+
+            // The last entry of a level cannot move up, since a block must
+            // follow every separator; the one before it goes instead.
+            let (mut separator, carried) = match rest.peek() {
+                Some(_) => (entry, None),
+                None => (node.pop().unwrap(), Some(entry)),
+            };
+            let vcn = blocks.len() as u64 * vcns_per_block;
+            blocks.push((node, separator.child));
+            separator.child = Some(vcn);
+            upper.push(separator);
+            node = carried.into_iter().collect();
+            used = node.iter().map(IndexEntry::length).sum();
+        }
+
+        let vcn = blocks.len() as u64 * vcns_per_block;
+        blocks.push((node, level_child));
+        level = upper;
+        level_child = Some(vcn);
+        if node_length(&level, level_child) <= large_root_room(blocks.len()) {
+            return IndexTree {
+                root: level,
+                root_child: level_child,
+                blocks,
+            };
+        }
+    }
+}
+
+// The INDEX_ROOT, and for large indexes the INDEX_ALLOCATION and BITMAP
+// attributes, of an index called `name`.
+fn index_attributes(
+    name: &'static str,
+    indexed_type: u32,
+    collation: u32,
+    tree: &IndexTree,
+    allocation: Option<&Stream>,
+    geometry: &Geometry,
+) -> Vec<Attribute> {
+    let entries = node_bytes(&tree.root, tree.root_child);
+    let mut root = vec![0u8; 0x20];
+    root[0..4].copy_from_slice(&indexed_type.to_le_bytes());
+    root[4..8].copy_from_slice(&collation.to_le_bytes());
+    root[8..12].copy_from_slice(&(INDEX_BLOCK_SIZE as u32).to_le_bytes());
+    root[12] = geometry.vcns_per_index_block() as u8;
+    root[0x10..0x14].copy_from_slice(&0x10u32.to_le_bytes());
+    let length = (0x10 + entries.len()) as u32;
+    root[0x14..0x18].copy_from_slice(&length.to_le_bytes());
+    root[0x18..0x1C].copy_from_slice(&length.to_le_bytes());
+    if tree.root_child.is_some() {
+        root[0x1C] = INDEX_HAS_CHILDREN;
+    }
+    root.extend(entries);
+
+    let mut attributes = vec![Attribute::resident(INDEX_ROOT, name, root, false)];
+    if let Some(allocation) = allocation {
+        attributes.push(Attribute::non_resident(
+            INDEX_ALLOCATION,
+            name,
+            allocation,
+            geometry.cluster_size,
+        ));
+        let mut bitmap = vec![0u8; align8(tree.blocks.len().div_ceil(8))];
+        for block in 0..tree.blocks.len() {
+            bitmap[block / 8] |= 1 << (block % 8);
+        }
+        attributes.push(Attribute::resident(BITMAP_ATTRIBUTE, name, bitmap, false));
+    }
+    attributes
+}
+
+fn index_block(vcn: u64, entries: &[IndexEntry], last_child: Option<u64>) -> Vec<u8> {
+    let mut block = vec![0u8; INDEX_BLOCK_SIZE];
+    block[0..4].copy_from_slice(b"INDX");
+    block[4..6].copy_from_slice(&0x28u16.to_le_bytes());
+    block[6..8].copy_from_slice(&((INDEX_BLOCK_SIZE as u64 / SECTOR_SIZE) as u16 + 1).to_le_bytes());
+    block[0x10..0x18].copy_from_slice(&vcn.to_le_bytes());
+
+    // Offsets in the node header count from the header itself, at 0x18.
+    let entries = node_bytes(entries, last_child);
+    let entries_offset = INDEX_BLOCK_HEADER_SIZE - 0x18;
+    block[0x18..0x1C].copy_from_slice(&(entries_offset as u32).to_le_bytes());
+    block[0x1C..0x20].copy_from_slice(&((entries_offset + entries.len()) as u32).to_le_bytes());
+    block[0x20..0x24].copy_from_slice(&((INDEX_BLOCK_SIZE - 0x18) as u32).to_le_bytes());
+    if last_child.is_some() {
+        block[0x24] = INDEX_HAS_CHILDREN;
+    }
+    block[INDEX_BLOCK_HEADER_SIZE..INDEX_BLOCK_HEADER_SIZE + entries.len()].copy_from_slice(&entries);
+
+    protect(&mut block, 0x28);
+    block
+}
+
+// A self-relative descriptor owned by Administrators that gives Everyone
+// full control, and the $SDS header that goes in front of it.
+fn security_descriptor() -> (Vec<u8>, [u8; SDS_HEADER_SIZE]) {
+    const EVERYONE: [u8; 12] = [1, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+    const ADMINISTRATORS: [u8; 16] = [1, 2, 0, 0, 0, 0, 0, 5, 32, 0, 0, 0, 0x20, 0x02, 0, 0];
+
+    let mut ace = vec![0u8, 0x03, 20, 0];
+    ace.extend(0x001F_01FFu32.to_le_bytes());
+    ace.extend(EVERYONE);
+    let mut acl = vec![2u8, 0];
+    acl.extend(((8 + ace.len()) as u16).to_le_bytes());
+    acl.extend(1u16.to_le_bytes());
+    acl.extend([0, 0]);
+    acl.extend(ace);
+
+    let dacl = 20u32;
+    let owner = dacl + acl.len() as u32;
+    let group = owner + ADMINISTRATORS.len() as u32;
+    let mut descriptor = vec![1u8, 0];
+    // Self-relative, with a DACL.
+    descriptor.extend(0x8004u16.to_le_bytes());
+    descriptor.extend(owner.to_le_bytes());
+    descriptor.extend(group.to_le_bytes());
+    descriptor.extend(0u32.to_le_bytes());
+    descriptor.extend(dacl.to_le_bytes());
+    descriptor.extend(acl);
+    descriptor.extend(ADMINISTRATORS);
+    descriptor.extend(ADMINISTRATORS);
+
+    let hash = descriptor.chunks_exact(4).fold(0u32, |hash, word| {
+        hash.rotate_left(3)
+            .wrapping_add(u32::from_le_bytes(word.try_into().unwrap()))
+    });
+    let mut header = [0u8; SDS_HEADER_SIZE];
+    header[0..4].copy_from_slice(&hash.to_le_bytes());
+    header[4..8].copy_from_slice(&SECURITY_ID.to_le_bytes());
+    header[16..20].copy_from_slice(&((SDS_HEADER_SIZE + descriptor.len()) as u32).to_le_bytes());
+    (descriptor, header)
+}
+
+// The attribute definitions of NTFS 3.1: name, type, flags and the
+// smallest and largest size allowed.
+fn attr_def() -> Vec<u8> {
+    const INDEXABLE: u32 = 0x02;
+    const RESIDENT: u32 = 0x40;
+    const LOGGED: u32 = 0x80;
+    let definitions: [(&str, u32, u32, u64, u64); 15] = [
+        ("$STANDARD_INFORMATION", 0x10, RESIDENT, 0x30, 0x48),
+        ("$ATTRIBUTE_LIST", 0x20, LOGGED, 0, u64::MAX),
+        ("$FILE_NAME", 0x30, INDEXABLE | RESIDENT, 0x44, 0x242),
+        ("$OBJECT_ID", 0x40, RESIDENT, 0, 0x100),
+        ("$SECURITY_DESCRIPTOR", 0x50, LOGGED, 0, u64::MAX),
+        ("$VOLUME_NAME", 0x60, RESIDENT, 2, 0x100),
+        ("$VOLUME_INFORMATION", 0x70, RESIDENT, 0x0C, 0x0C),
+        ("$DATA", 0x80, 0, 0, u64::MAX),
+        ("$INDEX_ROOT", 0x90, RESIDENT, 0, u64::MAX),
+        ("$INDEX_ALLOCATION", 0xA0, LOGGED, 0, u64::MAX),
+        ("$BITMAP", 0xB0, LOGGED, 0, u64::MAX),
+        ("$REPARSE_POINT", 0xC0, LOGGED, 0, 0x4000),
+        ("$EA_INFORMATION", 0xD0, RESIDENT, 8, 8),
+        ("$EA", 0xE0, 0, 0, 0x10000),
+        ("$LOGGED_UTILITY_STREAM", 0x100, LOGGED, 0, 0x10000),
+    ];
+
+    let mut table = vec![0u8; ATTR_DEF_SIZE];
+    for (definition, (name, kind, flags, min, max)) in table.chunks_exact_mut(160).zip(definitions) {
+        for (slot, unit) in definition.chunks_exact_mut(2).zip(name.encode_utf16()) {
+            slot.copy_from_slice(&unit.to_le_bytes());
+        }
+        definition[0x80..0x84].copy_from_slice(&kind.to_le_bytes());
+        if kind == FILE_NAME {
+            definition[0x88..0x8C].copy_from_slice(&COLLATION_FILE_NAME.to_le_bytes());
+        }
+        definition[0x8C..0x90].copy_from_slice(&flags.to_le_bytes());
+        definition[0x90..0x98].copy_from_slice(&min.to_le_bytes());
+        definition[0x98..0xA0].copy_from_slice(&max.to_le_bytes());
+    }
+    table
+}
+
+fn boot_sector(geometry: &Geometry, layout: &Layout, serial: u64) -> [u8; SECTOR_SIZE as usize] {
+    let mut boot = [0u8; SECTOR_SIZE as usize];
+    boot[0..3].copy_from_slice(&[0xEB, 0x52, 0x90]);
+    boot[3..11].copy_from_slice(b"NTFS    ");
+    boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    boot[13] = (geometry.cluster_size / SECTOR_SIZE) as u8;
+    boot[21] = 0xF8;
+    // Geometry for BIOSes that still ask, as for FAT.
+    boot[24..26].copy_from_slice(&63u16.to_le_bytes());
+    boot[26..28].copy_from_slice(&255u16.to_le_bytes());
+    boot[28..32].copy_from_slice(&((geometry.offset / SECTOR_SIZE) as u32).to_le_bytes());
+    boot[36..40].copy_from_slice(&0x0080_0080u32.to_le_bytes());
+    boot[40..48].copy_from_slice(&geometry.total_sectors.to_le_bytes());
+    boot[48..56].copy_from_slice(&layout.mft.lcn.to_le_bytes());
+    boot[56..64].copy_from_slice(&layout.mft_mirror.lcn.to_le_bytes());
+    boot[64] = size_in_clusters(RECORD_SIZE as u64, geometry.cluster_size);
+    boot[68] = size_in_clusters(INDEX_BLOCK_SIZE as u64, geometry.cluster_size);
+    boot[72..80].copy_from_slice(&serial.to_le_bytes());
+    boot[84..84 + BOOT_STUB.len()].copy_from_slice(&BOOT_STUB);
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    boot
+}
+
+// Sizes of a cluster or more are given in clusters, smaller ones as the
+// negated power of two.
+fn size_in_clusters(size: u64, cluster_size: u64) -> u8 {
+    if size >= cluster_size {
+        (size / cluster_size) as u8
+    } else {
+        (-(size.trailing_zeros() as i8)) as u8
+    }
+}
+
+fn filetime(time: Timestamp) -> u64 {
+    // Civil date to days since 1970, undoing Timestamp::from_system_time.
+    let year = time.year as i64 - if time.month <= 2 { 1 } else { 0 };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let shifted_month = (time.month as i64 + 9) % 12;
+    let day_of_year = (153 * shifted_month + 2) / 5 + time.day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * 86400 + time.hour as i64 * 3600 + time.minute as i64 * 60 + time.second as i64;
+    let ticks = seconds * 10_000_000 + FILETIME_UNIX_EPOCH as i64;
+    ticks.max(0) as u64
+}
+
+fn filetime_now() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    FILETIME_UNIX_EPOCH + now.as_secs() * 10_000_000 + now.subsec_nanos() as u64 / 100
+}
+
+// Upper case 8.3 names, which DOS could use as they are.
+fn is_short_name(name: &[u16]) -> bool {
+    let Ok(name) = String::from_utf16(name) else {
+        return false;
+    };
+    let (base, extension) = name.split_once('.').unwrap_or((&name, ""));
+    let valid = |part: &str| {
+        part.chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c))
+    };
+    (1..=8).contains(&base.len()) && extension.len() <= 3 && valid(base) && valid(extension)
+}
+
+fn is_name_char(c: char) -> bool {
+    c >= ' ' && !"\"*/:<>?\\|".contains(c)
+}
+
+// The name as it ends up on the volume, with what Windows cannot take
+// replaced.
+fn stored_name(name: &str) -> String {
+    name.chars().map(|c| if is_name_char(c) { c } else { '_' }).collect()
+}
+
+fn align8(length: usize) -> usize {
+    length.div_ceil(8) * 8
+}
+
+fn write_bytes<W: Write + ?Sized>(target: &mut W, byte: u8, mut length: u64) -> io::Result<()> {
+    let bytes = vec![byte; CHUNK_SIZE];
+    while length > 0 {
+        let chunk = length.min(CHUNK_SIZE as u64) as usize;
+        target.write_all(&bytes[..chunk])?;
+        length -= chunk as u64;
+    }
+    Ok(())
+}
+
+// Reads every path back from a volume through the MFT and directory
+// indexes, and checks each of `expected` is there with the right size, or
+// `None` for a directory. Index order, update sequences and the sizes
+// recorded in the indexes are checked on the way.
+pub fn verify_ntfs<T: Read + Seek + ?Sized>(
+    target: &mut T,
+    offset: u64,
+    expected: &[(String, Option<u64>)],
+) -> io::Result<()> {
+    let mut reader = NtfsReader::open(target, offset)?;
+    let mut found = HashMap::new();
+    reader.walk(ROOT, String::new(), &mut found)?;
+
+    for (path, size) in expected {
+        let path = path.split('/').map(stored_name).collect::<Vec<_>>().join("/");
+        match found.get(&path) {
+            None => return Err(invalid(&format!("{} is missing from the NTFS volume", path))),
+            Some(found) if found != size => {
+                return Err(invalid(&format!("{} has the wrong size on the NTFS volume", path)));
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+struct NtfsReader<'a, T: ?Sized> {
+    target: &'a mut T,
+    offset: u64,
+    cluster_size: u64,
+    mft: Vec<Run>,
+    upcase: Vec<u16>,
+}
+
+struct RawAttribute {
+    kind: u32,
+    name: String,
+    value: RawValue,
+}
+
+enum RawValue {
+    Resident(Vec<u8>),
+    NonResident { runs: Vec<Run>, size: u64 },
+}
+
+struct RawEntry {
+    reference: u64,
+    key: Vec<u8>,
+    child: Option<u64>,
+}
+
+impl<'a, T: Read + Seek + ?Sized> NtfsReader<'a, T> {
+    fn open(target: &'a mut T, offset: u64) -> io::Result<Self> {
+        let mut boot = [0u8; SECTOR_SIZE as usize];
+        target.seek(SeekFrom::Start(offset))?;
+        target.read_exact(&mut boot)?;
+        if &boot[3..11] != b"NTFS    " || boot[510..512] != [0x55, 0xAA] {
+            return Err(invalid("No NTFS boot sector"));
+        }
+
+        let sector_size = u16::from_le_bytes([boot[11], boot[12]]) as u64;
+        let cluster_size = sector_size * boot[13] as u64;
+        if sector_size != SECTOR_SIZE || !cluster_size.is_power_of_two() {
+            return Err(invalid("Unsupported NTFS geometry"));
+        }
+        if boot[64] != size_in_clusters(RECORD_SIZE as u64, cluster_size) {
+            return Err(invalid("Unsupported NTFS record size"));
+        }
+        let mft_lcn = u64::from_le_bytes(boot[48..56].try_into().unwrap());
+
+        // Enough of the MFT to read its own record, which maps the rest.
+        let mut reader = Self {
+            target,
+            offset,
+            cluster_size,
+            mft: vec![Run {
+                lcn: Some(mft_lcn),
+                length: (RECORD_SIZE as u64).div_ceil(cluster_size),
+            }],
+            upcase: Vec::new(),
+        };
+        let mft = reader.record(MFT)?;
+        reader.mft = match reader.stream(&mft, DATA, "")? {
+            RawValue::NonResident { runs, .. } => runs,
+            RawValue::Resident(_) => return Err(invalid("$MFT is resident")),
+        };
+
+        let upcase = reader.record(UPCASE)?;
+        let upcase = reader.stream(&upcase, DATA, "")?;
+        let upcase = reader.read_value(&upcase)?;
+        if upcase.len() != UPCASE_SIZE as usize {
+            return Err(invalid("$UpCase has the wrong size"));
+        }
+        reader.upcase = upcase
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        Ok(reader)
+    }
+
+    fn record(&mut self, number: u64) -> io::Result<Vec<RawAttribute>> {
+        let runs = self.mft.clone();
+        let mut record = self.read_runs(&runs, number * RECORD_SIZE as u64, RECORD_SIZE)?;
+        unprotect(&mut record, b"FILE")?;
+
+        let flags = u16::from_le_bytes([record[0x16], record[0x17]]);
+        let sequence = u16::from_le_bytes([record[0x10], record[0x11]]);
+        if flags & RECORD_IN_USE == 0 {
+            return Err(invalid(&format!("MFT record {} is not in use", number)));
+        }
+        if sequence as u64 != reference(number) >> 48 && number < FIRST_USER_RECORD {
+            return Err(invalid(&format!("MFT record {} has the wrong sequence number", number)));
+        }
+        parse_attributes(&record).map_err(|e| invalid(&format!("MFT record {}: {}", number, e)))
+    }
+
+    fn stream(&self, attributes: &[RawAttribute], kind: u32, name: &str) -> io::Result<RawValue> {
+        attributes
+            .iter()
+            .find(|a| a.kind == kind && a.name == name)
+            .map(|a| match &a.value {
+                RawValue::Resident(data) => RawValue::Resident(data.clone()),
+                RawValue::NonResident { runs, size } => RawValue::NonResident {
+                    runs: runs.clone(),
+                    size: *size,
+                },
+            })
+            .ok_or_else(|| invalid(&format!("Attribute {:#x} {} is missing", kind, name)))
+    }
+
+    fn read_value(&mut self, value: &RawValue) -> io::Result<Vec<u8>> {
+        match value {
+            RawValue::Resident(data) => Ok(data.clone()),
+            RawValue::NonResident { size, .. } if *size > MAX_METADATA_READ => {
+                Err(invalid("An NTFS metadata stream is implausibly large"))
+            }
+            RawValue::NonResident { runs, size } => self.read_runs(runs, 0, *size as usize),
+        }
+    }
+
+    // Reads `length` bytes from `start` on of the stream the runs map, in
+    // whole sectors.
+    fn read_runs(&mut self, runs: &[Run], start: u64, length: usize) -> io::Result<Vec<u8>> {
+        let wanted = length.div_ceil(SECTOR_SIZE as usize) * SECTOR_SIZE as usize;
+        let mut bytes = Vec::with_capacity(wanted);
+        let mut position = start;
+        let mut run_start = 0;
+        for run in runs {
+            let run_end = run_start + run.length * self.cluster_size;
+            while position < run_end && bytes.len() < wanted {
+                let chunk = (run_end - position).min((wanted - bytes.len()) as u64) as usize;
+                let filled = bytes.len();
+                bytes.resize(filled + chunk, 0);
+                if let Some(lcn) = run.lcn {
+                    let at = self.offset + lcn * self.cluster_size + (position - run_start);
+                    self.target.seek(SeekFrom::Start(at))?;
+                    self.target.read_exact(&mut bytes[filled..])?;
+                }
+                position += chunk as u64;
+            }
+            run_start = run_end;
+        }
+        if bytes.len() < length {
+            return Err(invalid("An NTFS stream ends before its data"));
+        }
+        bytes.truncate(length);
+        Ok(bytes)
+    }
+
+    // Collects the entries of a directory index in order, descending into
+    // index blocks from the root, and checks every block is reached once.
+    fn index_entries(&mut self, attributes: &[RawAttribute]) -> io::Result<Vec<RawEntry>> {
+        let RawValue::Resident(root) = self.stream(attributes, INDEX_ROOT, "$I30")? else {
+            return Err(invalid("INDEX_ROOT is not resident"));
+        };
+        let root_entries = root
+            .get(0x10..)
+            .ok_or_else(|| invalid("INDEX_ROOT is truncated"))
+            .and_then(parse_node)?;
+
+        let mut blocks = HashMap::new();
+        if attributes.iter().any(|a| a.kind == INDEX_ALLOCATION) {
+            let allocation = self.stream(attributes, INDEX_ALLOCATION, "$I30")?;
+            let allocation = self.read_value(&allocation)?;
+            let bitmap = self.stream(attributes, BITMAP_ATTRIBUTE, "$I30")?;
+            let bitmap = self.read_value(&bitmap)?;
+            let units_per_block = u32::from_le_bytes(root[8..12].try_into().unwrap()) as u64 / root[12].max(1) as u64;
+            for (index, block) in allocation.chunks_exact(INDEX_BLOCK_SIZE).enumerate() {
+                if bitmap.get(index / 8).is_none_or(|byte| byte & (1 << (index % 8)) == 0) {
+                    continue;
+                }
+                let mut block = block.to_vec();
+                unprotect(&mut block, b"INDX")?;
+                let vcn = u64::from_le_bytes(block[0x10..0x18].try_into().unwrap());
+                if vcn * units_per_block != (index * INDEX_BLOCK_SIZE) as u64 {
+                    return Err(invalid(&format!("Index block {} claims VCN {}", index, vcn)));
+                }
+                blocks.insert(vcn, parse_node(&block[0x18..])?);
+            }
+        }
+
+        let mut entries = Vec::new();
+        collect_entries(root_entries, &mut blocks, &mut entries)?;
+        if !blocks.is_empty() {
+            return Err(invalid("An index block is not reachable from the root"));
+        }
+
+        let upcased: Vec<Vec<u16>> = entries
+            .iter()
+            .map(|entry| self.upcase_units(&key_name(&entry.key)))
+            .collect();
+        if upcased.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(invalid("Directory index entries are out of order"));
+        }
+        Ok(entries)
+    }
+
+    fn upcase_units(&self, units: &[u16]) -> Vec<u16> {
+        units.iter().map(|&unit| self.upcase[unit as usize]).collect()
+    }
+
+    fn walk(&mut self, directory: u64, path: String, found: &mut HashMap<String, Option<u64>>) -> io::Result<()> {
+        let attributes = self.record(directory)?;
+        for entry in self.index_entries(&attributes)? {
+            let record = entry.reference & 0xFFFF_FFFF_FFFF;
+            if record < FIRST_USER_RECORD {
+                continue;
+            }
+            if entry.key.len() < FILE_NAME_HEADER_SIZE
+                || u64::from_le_bytes(entry.key[0..8].try_into().unwrap()) & 0xFFFF_FFFF_FFFF != directory
+            {
+                return Err(invalid(&format!("A bad index entry in {}", path)));
+            }
+
+            let name = String::from_utf16_lossy(&key_name(&entry.key));
+            let child_path = format!("{}/{}", path, name);
+            if found.contains_key(&child_path) {
+                return Err(invalid(&format!("{} is listed twice", child_path)));
+            }
+            let child = self.record(record)?;
+            let flags = u32::from_le_bytes(entry.key[0x38..0x3C].try_into().unwrap());
+            if flags & ATTR_DIRECTORY_INDEX != 0 {
+                found.insert(child_path.clone(), None);
+                self.walk(record, child_path, found)?;
+                continue;
+            }
+
+            let size = match self.stream(&child, DATA, "")? {
+                RawValue::Resident(data) => data.len() as u64,
+                RawValue::NonResident { size, .. } => size,
+            };
+            if u64::from_le_bytes(entry.key[0x30..0x38].try_into().unwrap()) != size {
+                return Err(invalid(&format!(
+                    "The index and MFT disagree on the size of {}",
+                    child_path
+                )));
+            }
+            found.insert(child_path, Some(size));
+        }
+        Ok(())
+    }
+}
+
+fn collect_entries(
+    node: Vec<RawEntry>,
+    blocks: &mut HashMap<u64, Vec<RawEntry>>,
+    entries: &mut Vec<RawEntry>,
+) -> io::Result<()> {
+    let last = node.len() - 1;
+    for (index, entry) in node.into_iter().enumerate() {
+        if let Some(vcn) = entry.child {
+            let child = blocks
+                .remove(&vcn)
+                .ok_or_else(|| invalid(&format!("Index block {} is missing or reached twice", vcn)))?;
+            collect_entries(child, blocks, entries)?;
+        }
+        if index != last {
+            entries.push(entry);
+        }
+    }
+    Ok(())
+}
+
+// The entries of an index node, starting at its node header.
+fn parse_node(node: &[u8]) -> io::Result<Vec<RawEntry>> {
+    let truncated = || invalid("An index node is truncated");
+    let header = node.get(0..16).ok_or_else(truncated)?;
+    let mut position = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+    let end = (u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize).min(node.len());
+
+    let mut entries = Vec::new();
+    loop {
+        let fixed = node
+            .get(position..position + 16)
+            .filter(|_| position + 16 <= end)
+            .ok_or_else(truncated)?;
+        let length = u16::from_le_bytes([fixed[8], fixed[9]]) as usize;
+        let key_length = u16::from_le_bytes([fixed[10], fixed[11]]) as usize;
+        let flags = u16::from_le_bytes([fixed[12], fixed[13]]);
+        let entry = node
+            .get(position..position + length)
+            .filter(|_| length >= 16 + key_length && position + length <= end)
+            .ok_or_else(truncated)?;
+
+        let child = if flags & ENTRY_SUBNODE != 0 {
+            let vcn = entry
+                .get(length.saturating_sub(8)..)
+                .filter(|_| length >= 24)
+                .ok_or_else(truncated)?;
+            Some(u64::from_le_bytes(vcn.try_into().unwrap()))
+        } else {
+            None
+        };
+        entries.push(RawEntry {
+            reference: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+            key: entry[16..16 + key_length].to_vec(),
+            child,
+        });
+        if flags & ENTRY_LAST != 0 {
+            return Ok(entries);
+        }
+        position += length;
+    }
+}
+
+fn key_name(key: &[u8]) -> Vec<u16> {
+    let length = key.get(0x40).copied().unwrap_or(0) as usize;
+    key.get(FILE_NAME_HEADER_SIZE..FILE_NAME_HEADER_SIZE + 2 * length)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect()
+}
+
+// Checks the update sequence of a record or index block and puts back the
+// bytes it replaced.
+fn unprotect(block: &mut [u8], magic: &[u8; 4]) -> io::Result<()> {
+    if &block[0..4] != magic {
+        return Err(invalid(&format!("Expected a {} block", String::from_utf8_lossy(magic))));
+    }
+    let array_offset = u16::from_le_bytes([block[4], block[5]]) as usize;
+    let count = u16::from_le_bytes([block[6], block[7]]) as usize;
+    let sectors = block.len() / SECTOR_SIZE as usize;
+    if count != sectors + 1 || array_offset + 2 * count > SECTOR_SIZE as usize - 2 {
+        return Err(invalid("Bad update sequence array"));
+    }
+
+    let sequence = [block[array_offset], block[array_offset + 1]];
+    for sector in 0..sectors {
+        let end = (sector + 1) * SECTOR_SIZE as usize - 2;
+        if block[end..end + 2] != sequence {
+            return Err(invalid("Torn NTFS record"));
+        }
+        let slot = array_offset + 2 + 2 * sector;
+        let saved = [block[slot], block[slot + 1]];
+        block[end..end + 2].copy_from_slice(&saved);
+    }
+    Ok(())
+}
+
+fn parse_attributes(record: &[u8]) -> io::Result<Vec<RawAttribute>> {
+    let truncated = || invalid("an attribute runs past the record");
+    let mut position = u16::from_le_bytes([record[0x14], record[0x15]]) as usize;
+    let used = (u32::from_le_bytes(record[0x18..0x1C].try_into().unwrap()) as usize).min(record.len());
+
+    let mut attributes = Vec::new();
+    loop {
+        let kind = record
+            .get(position..position + 4)
+            .filter(|_| position + 4 <= used)
+            .ok_or_else(truncated)?;
+        let kind = u32::from_le_bytes(kind.try_into().unwrap());
+        if kind == END_OF_ATTRIBUTES {
+            return Ok(attributes);
+        }
+        let length = record.get(position + 4..position + 8).ok_or_else(truncated)?;
+        let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+        let attribute = record
+            .get(position..position + length)
+            .filter(|_| length >= 0x18 && position + length <= used)
+            .ok_or_else(truncated)?;
+
+        let name_length = attribute[9] as usize;
+        let name_offset = u16::from_le_bytes([attribute[10], attribute[11]]) as usize;
+        let name = attribute
+            .get(name_offset..name_offset + 2 * name_length)
+            .ok_or_else(truncated)?;
+        let name: Vec<u16> = name
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+
+        let value = if attribute[8] == 0 {
+            let value_length = u32::from_le_bytes(attribute[0x10..0x14].try_into().unwrap()) as usize;
+            let value_offset = u16::from_le_bytes([attribute[0x14], attribute[0x15]]) as usize;
+            let value = attribute
+                .get(value_offset..value_offset + value_length)
+                .ok_or_else(truncated)?;
+            RawValue::Resident(value.to_vec())
+        } else {
+            let fixed = attribute.get(..0x40).ok_or_else(truncated)?;
+            let runs_offset = u16::from_le_bytes([fixed[0x20], fixed[0x21]]) as usize;
+            let runs = attribute.get(runs_offset..).ok_or_else(truncated)?;
+            RawValue::NonResident {
+                runs: decode_runs(runs)?,
+                size: u64::from_le_bytes(fixed[0x30..0x38].try_into().unwrap()),
+            }
+        };
+        attributes.push(RawAttribute {
+            kind,
+            name: String::from_utf16_lossy(&name),
+            value,
+        });
+        position += length;
+    }
+}
+
+fn decode_runs(bytes: &[u8]) -> io::Result<Vec<Run>> {
+    let truncated = || invalid("a run list is truncated");
+    let mut runs = Vec::new();
+    let mut position = 0;
+    let mut lcn = 0i64;
+    loop {
+        let header = *bytes.get(position).ok_or_else(truncated)?;
+        if header == 0 {
+            return Ok(runs);
+        }
+        let (length_size, offset_size) = ((header & 0x0F) as usize, (header >> 4) as usize);
+        if length_size > 8 || offset_size > 8 {
+            return Err(truncated());
+        }
+        let field = bytes
+            .get(position + 1..position + 1 + length_size + offset_size)
+            .ok_or_else(truncated)?;
+        let length = read_signed(&field[..length_size]);
+        if length <= 0 {
+            return Err(invalid("a run has no length"));
+        }
+
+        let run_lcn = if offset_size == 0 {
+            None
+        } else {
+            lcn += read_signed(&field[length_size..]);
+            if lcn < 0 {
+                return Err(invalid("a run starts before the volume"));
+            }
+            Some(lcn as u64)
+        };
+        runs.push(Run {
+            lcn: run_lcn,
+            length: length as u64,
+        });
+        position += 1 + length_size + offset_size;
+    }
+}
+
+fn read_signed(bytes: &[u8]) -> i64 {
+    let negative = bytes.last().is_some_and(|&byte| byte & 0x80 != 0);
+    bytes
+        .iter()
+        .rev()
+        .fold(if negative { -1 } else { 0 }, |value, &byte| value << 8 | byte as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::filesystem::FileSystem;
+
+    const OFFSET: u64 = 1024 * 1024;
+    const SIZE: u64 = 32 * 1024 * 1024;
+
+    type Expected = Vec<(String, Option<u64>)>;
+
+    // A resident file, a non-resident one two directories down, and enough
+    // names in one directory that its index spills out of the index root.
+    fn formatted_volume() -> (Cursor<Vec<u8>>, Expected) {
+        let mut files = vec![("/readme.txt".to_string(), 100), ("/Boot/EFI/bootx64.efi".to_string(), 200_000)];
+        files.extend((0..40).map(|i| (format!("/Boot/Fonts/font {:02}.pf2", i), i * 10)));
+
+        let mut disk = Cursor::new(Vec::new());
+        let options = FormatOptions {
+            file_system: FileSystem::Ntfs,
+            label: "Ayumi".to_string(),
+            ..FormatOptions::default()
+        };
+        let mut volume = Box::new(NtfsVolume::format(&mut disk, OFFSET, SIZE, &options).unwrap());
+        volume.create_dir("/Boot", None).unwrap();
+        volume.create_dir("/Boot/EFI", None).unwrap();
+        volume.create_dir("/Boot/Fonts", None).unwrap();
+        for (path, size) in &files {
+            let data = vec![0xA5; *size as usize];
+            volume.write_file(path, *size, None, &mut data.as_slice(), &mut |_| {}).unwrap();
+        }
+        volume.finish().unwrap();
+
+        let mut expected: Expected =
+            ["/Boot", "/Boot/EFI", "/Boot/Fonts"].iter().map(|path| (path.to_string(), None)).collect();
+        expected.extend(files.into_iter().map(|(path, size)| (path, Some(size))));
+        (disk, expected)
+    }
+
+    #[test]
+    fn verify_finds_every_file_and_directory() {
+        let (mut disk, expected) = formatted_volume();
+        verify_ntfs(&mut disk, OFFSET, &expected).unwrap();
+
+        // Records go out in the order things were added, after the three
+        // directories. The small file stays in its record, the large one
+        // does not.
+        let mut reader = NtfsReader::open(&mut disk, OFFSET).unwrap();
+        let readme = reader.record(FIRST_USER_RECORD + 3).unwrap();
+        assert!(matches!(reader.stream(&readme, DATA, "").unwrap(), RawValue::Resident(data) if data.len() == 100));
+        let loader = reader.record(FIRST_USER_RECORD + 4).unwrap();
+        assert!(matches!(reader.stream(&loader, DATA, "").unwrap(), RawValue::NonResident { size: 200_000, .. }));
+        let fonts = reader.record(FIRST_USER_RECORD + 2).unwrap();
+        assert!(matches!(reader.stream(&fonts, INDEX_ALLOCATION, "$I30").unwrap(), RawValue::NonResident { .. }));
+    }
+
+    #[test]
+    fn verify_reports_missing_paths_and_wrong_sizes() {
+        let (mut disk, mut expected) = formatted_volume();
+
+        expected.push(("/Boot/EFI/grubx64.efi".to_string(), Some(1)));
+        let error = verify_ntfs(&mut disk, OFFSET, &expected).err().unwrap();
+        assert_eq!(error.to_string(), "/Boot/EFI/grubx64.efi is missing from the NTFS volume");
+
+        expected.pop();
+        expected[0].1 = Some(0);
+        let error = verify_ntfs(&mut disk, OFFSET, &expected).err().unwrap();
+        assert_eq!(error.to_string(), "/Boot has the wrong size on the NTFS volume");
+
+        let error = verify_ntfs(&mut disk, 0, &expected).err().unwrap();
+        assert_eq!(error.to_string(), "No NTFS boot sector");
+    }
+}
@@ -0,0 +1,348 @@
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::SECTOR_SIZE;
+
+const BOOT_SYSTEM_ID: &[u8] = b"EL TORITO SPECIFICATION";
+
+const ENTRY_SIZE: usize = 32;
+// One catalog sector holds 64 entries. Images with more boot entries than a
+// few sectors' worth do not exist; anything larger is a corrupt catalog.
+const MAX_ENTRIES: usize = 256;
+
+const HEADER_VALIDATION: u8 = 0x01;
+const HEADER_MORE_SECTIONS: u8 = 0x90;
+const HEADER_FINAL_SECTION: u8 = 0x91;
+const EXTENSION_ENTRY: u8 = 0x44;
+const BOOTABLE: u8 = 0x88;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    X86,
+    PowerPc,
+    Mac,
+    Efi,
+    Other(u8),
+}
+
+impl From<u8> for Platform {
+    fn from(id: u8) -> Self {
+        match id {
+            0x00 => Platform::X86,
+            0x01 => Platform::PowerPc,
+            0x02 => Platform::Mac,
+            0xEF => Platform::Efi,
+            other => Platform::Other(other),
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Platform::X86 => write!(f, "x86 BIOS"),
+            Platform::PowerPc => write!(f, "PowerPC"),
+            Platform::Mac => write!(f, "Mac"),
+            Platform::Efi => write!(f, "EFI"),
+            Platform::Other(id) => write!(f, "unknown (0x{:02X})", id),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Emulation {
+    None,
+    Floppy12,
+    Floppy144,
+    Floppy288,
+    HardDisk,
+    Other(u8),
+}
+
+impl From<u8> for Emulation {
+    fn from(media: u8) -> Self {
+        match media & 0x0F {
+            0 => Emulation::None,
+            1 => Emulation::Floppy12,
+            2 => Emulation::Floppy144,
+            3 => Emulation::Floppy288,
+            4 => Emulation::HardDisk,
+            other => Emulation::Other(other),
+        }
+    }
+}
+
+impl fmt::Display for Emulation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Emulation::None => write!(f, "no emulation"),
+            Emulation::Floppy12 => write!(f, "1.2 MB floppy"),
+            Emulation::Floppy144 => write!(f, "1.44 MB floppy"),
+            Emulation::Floppy288 => write!(f, "2.88 MB floppy"),
+            Emulation::HardDisk => write!(f, "hard disk"),
+            Emulation::Other(id) => write!(f, "unknown (0x{:X})", id),
+        }
+    }
+}
+
+pub struct ValidationEntry {
+    pub platform: Platform,
+    pub id: String,
+}
+
+// The initial/default entry and every section entry share one layout. The
+// default entry takes its platform from the validation entry, section
+// entries from their section header.
+pub struct BootEntry {
+    pub platform: Platform,
+    pub is_default: bool,
+    pub bootable: bool,
+    pub emulation: Emulation,
+    pub load_segment: u16,
+    pub sector_count: u16,
+    pub load_rba: u32,
+}
+
+pub struct BootCatalog {
+    pub validation: ValidationEntry,
+    pub entries: Vec<BootEntry>,
+}
+
+impl BootCatalog {
+    fn boots_on(&self, platform: Platform) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| entry.bootable && entry.platform == platform)
+    }
+
+    pub fn boots_on_uefi(&self) -> bool {
+        self.boots_on(Platform::Efi)
+    }
+
+    pub fn firmware_label(&self) -> &'static str {
+        match (self.boots_on(Platform::X86), self.boots_on(Platform::Efi)) {
+            (true, true) => "BIOS+UEFI",
+            (true, false) => "BIOS",
+            (false, true) => "UEFI",
+            (false, false) => "Not bootable",
+        }
+    }
+}
+
+// The boot record volume descriptor only names the system and, for El
+// Torito, points at the sector holding the boot catalog.
+pub fn parse_boot_record(sector: &[u8]) -> Option<u32> {
+    let system_id = &sector[7..39];
+    if !system_id.starts_with(BOOT_SYSTEM_ID) || system_id[BOOT_SYSTEM_ID.len()..].iter().any(|&b| b != 0) {
+        return None;
+    }
+
+    Some(u32::from_le_bytes([sector[71], sector[72], sector[73], sector[74]]))
+}
+
+pub fn read_boot_catalog<R: Read + Seek>(reader: &mut R, catalog_sector: u32) -> io::Result<BootCatalog> {
+    reader.seek(SeekFrom::Start(catalog_sector as u64 * SECTOR_SIZE))?;
+
+    let mut entry = [0u8; ENTRY_SIZE];
+    reader.read_exact(&mut entry)?;
+    let validation = parse_validation(&entry)?;
+
+    reader.read_exact(&mut entry)?;
+    let mut entries = vec![parse_entry(&entry, validation.platform, true)];
+
+    // Section headers follow the default entry until one is marked final.
+    let mut more_sections = true;
+    while more_sections && entries.len() < MAX_ENTRIES {
+        if reader.read_exact(&mut entry).is_err() {
+            break;
+        }
+
+        match entry[0] {
+            HEADER_MORE_SECTIONS | HEADER_FINAL_SECTION => {
+                more_sections = entry[0] == HEADER_MORE_SECTIONS;
+                let platform = Platform::from(entry[1]);
+                let count = u16::from_le_bytes([entry[2], entry[3]]) as usize;
+
+                let mut read = 0;
+                while read < count && entries.len() < MAX_ENTRIES {
+                    reader.read_exact(&mut entry)?;
+                    if entry[0] == EXTENSION_ENTRY {
+                        continue;
+                    }
+                    entries.push(parse_entry(&entry, platform, false));
+                    read += 1;
+                }
+            }
+            _ => break,
+        }
+    }
+
+    Ok(BootCatalog { validation, entries })
+}
+
+fn parse_validation(entry: &[u8; ENTRY_SIZE]) -> io::Result<ValidationEntry> {
+    if entry[0] != HEADER_VALIDATION || entry[30] != 0x55 || entry[31] != 0xAA {
+        return Err(invalid("The boot catalog has no validation entry"));
+    }
+
+    // All 16-bit words of the validation entry must sum to zero.
+    let sum = entry
+        .chunks_exact(2)
+        .fold(0u16, |acc, word| acc.wrapping_add(u16::from_le_bytes([word[0], word[1]])));
+    if sum != 0 {
+        return Err(invalid("The boot catalog validation entry has a bad checksum"));
+    }
+
+    Ok(ValidationEntry {
+        platform: Platform::from(entry[1]),
+        id: String::from_utf8_lossy(&entry[4..28])
+            .trim_end_matches([' ', '\0'])
+            .to_string(),
+    })
+}
+
+fn parse_entry(entry: &[u8; ENTRY_SIZE], platform: Platform, is_default: bool) -> BootEntry {
+    // A load segment of zero means the traditional 0x7C0.
+    let load_segment = match u16::from_le_bytes([entry[2], entry[3]]) {
+        0 => 0x7C0,
+        segment => segment,
+    };
+
+    BootEntry {
+        platform,
+        is_default,
+        bootable: entry[0] == BOOTABLE,
+        emulation: Emulation::from(entry[1]),
+        load_segment,
+        sector_count: u16::from_le_bytes([entry[6], entry[7]]),
+        load_rba: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::iso::read_volume_descriptors;
+    use crate::iso::testiso::IsoBuilder;
+
+    const CATALOG: u32 = 20;
+
+    // The validation entry, whose words sum to zero, and the default entry.
+    fn catalog(platform: u8, default_bootable: bool) -> Vec<u8> {
+        let mut catalog = vec![0; 64];
+        catalog[0] = HEADER_VALIDATION;
+        catalog[1] = platform;
+        catalog[4..10].copy_from_slice(b"AYUMI ");
+        catalog[30] = 0x55;
+        catalog[31] = 0xAA;
+        let sum = catalog[..32]
+            .chunks_exact(2)
+            .fold(0u16, |acc, word| acc.wrapping_add(u16::from_le_bytes([word[0], word[1]])));
+        catalog[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+        catalog[32..64].copy_from_slice(&entry(default_bootable, 0, 4, 30));
+        catalog
+    }
+
+    fn entry(bootable: bool, media: u8, sector_count: u16, load_rba: u32) -> [u8; ENTRY_SIZE] {
+        let mut entry = [0; ENTRY_SIZE];
+        entry[0] = if bootable { BOOTABLE } else { 0 };
+        entry[1] = media;
+        entry[6..8].copy_from_slice(&sector_count.to_le_bytes());
+        entry[8..12].copy_from_slice(&load_rba.to_le_bytes());
+        entry
+    }
+
+    fn section(catalog: &mut Vec<u8>, last: bool, platform: u8, entries: &[[u8; ENTRY_SIZE]]) {
+        let mut header = [0; ENTRY_SIZE];
+        header[0] = if last { HEADER_FINAL_SECTION } else { HEADER_MORE_SECTIONS };
+        header[1] = platform;
+        let count = entries.iter().filter(|entry| entry[0] != EXTENSION_ENTRY).count() as u16;
+        header[2..4].copy_from_slice(&count.to_le_bytes());
+        catalog.extend_from_slice(&header);
+        entries.iter().for_each(|entry| catalog.extend_from_slice(entry));
+    }
+
+    // A descriptor set whose boot record points at the catalog.
+    fn read(catalog: &[u8]) -> io::Result<BootCatalog> {
+        let mut iso = IsoBuilder::new();
+        iso.primary(16, "BOOT", 19, 2048);
+        iso.boot_record(17, CATALOG);
+        iso.terminator(18);
+        iso.sector_mut(CATALOG)[..catalog.len()].copy_from_slice(catalog);
+        let mut image = Cursor::new(iso.build());
+
+        let sector = read_volume_descriptors(&mut image)?.boot_catalog_sector;
+        assert_eq!(sector, Some(CATALOG));
+        read_boot_catalog(&mut image, CATALOG)
+    }
+
+    #[test]
+    fn reads_a_bios_only_catalog() {
+        let catalog = read(&catalog(0x00, true)).unwrap();
+        assert!(catalog.validation.platform == Platform::X86);
+        assert_eq!(catalog.validation.id, "AYUMI");
+        assert_eq!(catalog.entries.len(), 1);
+
+        let entry = &catalog.entries[0];
+        assert!(entry.platform == Platform::X86 && entry.is_default && entry.bootable);
+        assert!(entry.emulation == Emulation::None);
+        assert_eq!((entry.load_segment, entry.sector_count, entry.load_rba), (0x7C0, 4, 30));
+        assert_eq!(catalog.firmware_label(), "BIOS");
+    }
+
+    #[test]
+    fn reads_a_uefi_only_catalog() {
+        let uefi = read(&catalog(0xEF, true)).unwrap();
+        assert!(uefi.entries[0].platform == Platform::Efi);
+        assert_eq!(uefi.firmware_label(), "UEFI");
+
+        let unbootable = read(&catalog(0x00, false)).unwrap();
+        assert_eq!(unbootable.firmware_label(), "Not bootable");
+    }
+
+    #[test]
+    fn reads_a_bios_and_uefi_catalog_with_sections() {
+        let mut data = catalog(0x00, true);
+        section(&mut data, false, 0x02, &[entry(false, 0x02, 1, 40)]);
+        let mut extension = [0; ENTRY_SIZE];
+        extension[0] = EXTENSION_ENTRY;
+        section(&mut data, true, 0xEF, &[extension, entry(true, 0x00, 2880, 50)]);
+
+        let catalog = read(&data).unwrap();
+        assert_eq!(catalog.entries.len(), 3);
+        let mac = &catalog.entries[1];
+        assert!(mac.platform == Platform::Mac && !mac.is_default && !mac.bootable);
+        assert!(mac.emulation == Emulation::Floppy144);
+        let efi = &catalog.entries[2];
+        assert!(efi.platform == Platform::Efi && !efi.is_default && efi.bootable);
+        assert_eq!((efi.sector_count, efi.load_rba), (2880, 50));
+        assert_eq!(catalog.firmware_label(), "BIOS+UEFI");
+    }
+
+    #[test]
+    fn rejects_a_damaged_validation_entry() {
+        let mut data = catalog(0x00, true);
+        data[5] ^= 1;
+        let error = read(&data).err().unwrap();
+        assert_eq!(error.to_string(), "The boot catalog validation entry has a bad checksum");
+
+        let error = read(&[0; 64]).err().unwrap();
+        assert_eq!(error.to_string(), "The boot catalog has no validation entry");
+    }
+
+    #[test]
+    fn ignores_boot_records_of_other_systems() {
+        let mut record = [0; SECTOR_SIZE as usize];
+        record[7..30].copy_from_slice(BOOT_SYSTEM_ID);
+        record[71] = 20;
+        assert_eq!(parse_boot_record(&record), Some(20));
+        record[30] = b'X';
+        assert_eq!(parse_boot_record(&record), None);
+    }
+}
@@ -0,0 +1,185 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+const MBR_SIZE: usize = 512;
+const PARTITION_TABLE: usize = 446;
+// The boot code area of an MBR ends where the disk signature begins.
+const BOOT_CODE_END: usize = 440;
+const GPT_PROTECTIVE: u8 = 0xEE;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum HybridMarker {
+    // isohybrid from Syslinux, identified by the isohdpfx boot code.
+    Syslinux,
+    // grub-mkrescue/xorriso images carrying GRUB's boot_hybrid.img.
+    Grub,
+}
+
+// What lives in the first sectors of an image, in front of the ISO 9660
+// system area. Hybrid images put a partition table there so the same file
+// boots from optical media and, when written raw, from a USB stick.
+pub struct HybridInfo {
+    pub mbr: bool,
+    pub gpt: bool,
+    pub marker: Option<HybridMarker>,
+}
+
+impl HybridInfo {
+    pub fn is_hybrid(&self) -> bool {
+        self.mbr || self.gpt
+    }
+
+    pub fn description(&self) -> String {
+        let table = match (self.mbr, self.gpt) {
+            (_, true) => "protective MBR + GPT",
+            (true, false) => "MBR partition table",
+            (false, false) => return "Plain ISO 9660 (no partition table)".to_string(),
+        };
+
+        match self.marker {
+            Some(HybridMarker::Syslinux) => format!("Syslinux isohybrid ({})", table),
+            Some(HybridMarker::Grub) => format!("GRUB hybrid ({})", table),
+            None => format!("Hybrid ({})", table),
+        }
+    }
+}
+
+pub fn detect_hybrid<R: Read + Seek>(reader: &mut R) -> io::Result<HybridInfo> {
+    // MBR in LBA 0 and, if present, the GPT header in LBA 1.
+    let mut head = [0u8; MBR_SIZE * 2];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut head)?;
+
+    let (mbr, gpt_sector) = head.split_at(MBR_SIZE);
+    let entries: Vec<&[u8]> = mbr[PARTITION_TABLE..PARTITION_TABLE + 64].chunks(16).collect();
+
+    let signed = mbr[510] == 0x55 && mbr[511] == 0xAA;
+    // Every status byte must be 0x00 or 0x80; anything else means the
+    // signature is a coincidence in boot code or data, not a real table.
+    let sane = entries.iter().all(|entry| entry[0] == 0x00 || entry[0] == 0x80);
+    let used: Vec<&&[u8]> = entries
+        .iter()
+        .filter(|entry| entry[4] != 0 && u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]) != 0)
+        .collect();
+    let mbr_valid = signed && sane && !used.is_empty();
+
+    let protective = used.iter().any(|entry| entry[4] == GPT_PROTECTIVE);
+    let gpt = mbr_valid && protective && &gpt_sector[..8] == b"EFI PART";
+
+    Ok(HybridInfo {
+        mbr: mbr_valid,
+        gpt,
+        marker: if mbr_valid { find_marker(&mbr[..BOOT_CODE_END]) } else { None },
+    })
+}
+
+// Both boot loaders embed their own error strings in the boot code, which
+// makes them easy to recognise.
+fn find_marker(boot_code: &[u8]) -> Option<HybridMarker> {
+    let contains = |needle: &[u8]| boot_code.windows(needle.len()).any(|window| window == needle);
+
+    if contains(b"isolinux") {
+        Some(HybridMarker::Syslinux)
+    } else if contains(b"GRUB") {
+        Some(HybridMarker::Grub)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::iso::read_image_info;
+    use crate::iso::testiso::IsoBuilder;
+    use crate::testutil::TempDir;
+    use crate::writer::WriteMode;
+
+    // An ISO whose first 1024 bytes are `head`: the MBR and, for GPT, the
+    // header in LBA 1.
+    fn iso_with_head(head: &[u8]) -> Vec<u8> {
+        let mut iso = IsoBuilder::new();
+        iso.sector_mut(0)[..head.len()].copy_from_slice(head);
+        iso.primary(16, "HYBRID", 18, 2048);
+        iso.terminator(17);
+        iso.sector_mut(18);
+        iso.build()
+    }
+
+    fn mbr(boot_code: &[u8], partition_type: u8) -> Vec<u8> {
+        let mut head = vec![0; 1024];
+        head[..boot_code.len()].copy_from_slice(boot_code);
+        let entry = &mut head[PARTITION_TABLE..PARTITION_TABLE + 16];
+        entry[0] = 0x80;
+        entry[4] = partition_type;
+        entry[12..16].copy_from_slice(&100u32.to_le_bytes());
+        head[510] = 0x55;
+        head[511] = 0xAA;
+        head
+    }
+
+    fn gpt(boot_code: &[u8]) -> Vec<u8> {
+        let mut head = mbr(boot_code, GPT_PROTECTIVE);
+        head[512..520].copy_from_slice(b"EFI PART");
+        head
+    }
+
+    fn detect(image: &[u8]) -> HybridInfo {
+        detect_hybrid(&mut Cursor::new(image)).unwrap()
+    }
+
+    #[test]
+    fn recognises_a_syslinux_isohybrid_mbr() {
+        let info = detect(&iso_with_head(&mbr(b"\xFA\x31\xC0isolinux.bin missing or corrupt.", 0x17)));
+        assert!(info.mbr && !info.gpt && info.is_hybrid());
+        assert!(info.marker == Some(HybridMarker::Syslinux));
+        assert_eq!(info.description(), "Syslinux isohybrid (MBR partition table)");
+    }
+
+    #[test]
+    fn recognises_a_grub_hybrid_gpt() {
+        let mut head = gpt(b"\xEB\x63GRUB Geom Hard Disk Read Error");
+        let info = detect(&iso_with_head(&head));
+        assert!(info.mbr && info.gpt);
+        assert!(info.marker == Some(HybridMarker::Grub));
+        assert_eq!(info.description(), "GRUB hybrid (protective MBR + GPT)");
+
+        // Without the GPT header the protective entry is just an MBR entry.
+        head[512..520].fill(0);
+        let info = detect(&iso_with_head(&head));
+        assert!(info.mbr && !info.gpt);
+    }
+
+    #[test]
+    fn ignores_signatures_that_are_no_partition_table() {
+        let info = detect(&iso_with_head(&[]));
+        assert!(!info.is_hybrid() && info.marker.is_none());
+        assert_eq!(info.description(), "Plain ISO 9660 (no partition table)");
+
+        // The boot signature alone, with a status byte no table has.
+        let mut head = mbr(b"isolinux", 0x17);
+        head[PARTITION_TABLE] = 0x12;
+        assert!(!detect(&iso_with_head(&head)).is_hybrid());
+
+        // Signed, but with no partition in use.
+        let mut head = mbr(b"isolinux", 0x17);
+        head[PARTITION_TABLE + 4] = 0;
+        assert!(!detect(&iso_with_head(&head)).is_hybrid());
+    }
+
+    #[test]
+    fn hybrid_images_default_to_raw_writes() {
+        let dir = TempDir::new("hybrid");
+        let cases = [
+            (mbr(b"isolinux", 0x17), WriteMode::RawImage),
+            (gpt(b"GRUB"), WriteMode::RawImage),
+            (Vec::new(), WriteMode::Extract),
+        ];
+        for (head, mode) in cases {
+            let path = dir.write("image.iso", &iso_with_head(&head));
+            let info = read_image_info(&path).unwrap();
+            assert!(WriteMode::recommended_for(&info) == mode);
+        }
+    }
+}
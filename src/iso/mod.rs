@@ -0,0 +1,104 @@
+use std::fs::File;
+use std::io::{self, Read, Seek};
+use std::path::Path;
+
+mod eltorito;
+mod hybrid;
+#[cfg(test)]
+pub mod testiso;
+mod tree;
+mod udf;
+mod volume;
+
+pub use eltorito::{read_boot_catalog, BootCatalog};
+pub use hybrid::{detect_hybrid, HybridInfo};
+pub use tree::{entries, IsoEntry, NameScheme};
+pub use udf::UdfVolume;
+pub use volume::{read_volume_descriptors, PrimaryVolumeDescriptor, VolumeDescriptors};
+
+pub const SECTOR_SIZE: u64 = 2048;
+
+// Everything the GUI shows about a picked image before it is written.
+pub struct ImageInfo {
+    pub primary: PrimaryVolumeDescriptor,
+    // `None` when the image has no El Torito boot record at all.
+    pub boot_catalog: Option<Result<BootCatalog, String>>,
+    pub hybrid: HybridInfo,
+    pub udf: Option<UdfVolume>,
+    pub contents: Result<Listing, String>,
+}
+
+pub struct Listing {
+    pub scheme: NameScheme,
+    pub entries: Vec<IsoEntry>,
+}
+
+impl Listing {
+    pub fn total_file_size(&self) -> u64 {
+        self.entries.iter().filter(|entry| !entry.is_dir).map(|entry| entry.size).sum()
+    }
+}
+
+impl ImageInfo {
+    pub fn firmware_label(&self) -> &'static str {
+        match &self.boot_catalog {
+            Some(Ok(catalog)) => catalog.firmware_label(),
+            Some(Err(_)) => "Broken boot catalog",
+            None => "Not bootable",
+        }
+    }
+}
+
+pub fn read_image_info(path: &Path) -> Result<ImageInfo, String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let descriptors = read_volume_descriptors(&mut file).map_err(|e| e.to_string())?;
+
+    let boot_catalog = descriptors
+        .boot_catalog_sector
+        .map(|sector| read_boot_catalog(&mut file, sector).map_err(|e| e.to_string()));
+
+    let hybrid = detect_hybrid(&mut file).map_err(|e| e.to_string())?;
+    // A damaged UDF volume is reported as the listing error, just as
+    // `list_contents` fails on it when the image is extracted.
+    let (udf, contents) = match UdfVolume::open(&mut file) {
+        Ok(udf) => {
+            let contents = list_volume(&mut file, &descriptors, udf.as_ref()).map_err(|e| e.to_string());
+            (udf, contents)
+        }
+        Err(e) => (None, Err(e.to_string())),
+    };
+
+    Ok(ImageInfo {
+        primary: descriptors.primary,
+        boot_catalog,
+        hybrid,
+        udf,
+        contents,
+    })
+}
+
+// UDF wins when present: Windows install images are UDF-primary and their
+// ISO 9660 tree is only a stub telling old systems to use UDF.
+pub fn list_contents<R: Read + Seek>(reader: &mut R, descriptors: &VolumeDescriptors) -> io::Result<Listing> {
+    let udf = UdfVolume::open(reader)?;
+    list_volume(reader, descriptors, udf.as_ref())
+}
+
+fn list_volume<R: Read + Seek>(
+    reader: &mut R,
+    descriptors: &VolumeDescriptors,
+    udf: Option<&UdfVolume>,
+) -> io::Result<Listing> {
+    if let Some(volume) = udf {
+        return Ok(Listing {
+            scheme: NameScheme::Udf,
+            entries: volume.entries(reader)?,
+        });
+    }
+
+    let mut walker = entries(reader, descriptors)?;
+    let scheme = walker.scheme();
+    let entries = walker.by_ref().collect::<io::Result<Vec<_>>>()?;
+
+    Ok(Listing { scheme, entries })
+}
@@ -0,0 +1,127 @@
+use super::SECTOR_SIZE;
+
+pub const SECTOR: usize = SECTOR_SIZE as usize;
+
+// Numeric fields of descriptors and directory records are stored
+// little-endian and then big-endian.
+pub fn both_endian_u32(value: u32) -> [u8; 8] {
+    let mut field = [0; 8];
+    field[..4].copy_from_slice(&value.to_le_bytes());
+    field[4..].copy_from_slice(&value.to_be_bytes());
+    field
+}
+
+pub const FLAG_DIRECTORY: u8 = 0x02;
+
+// A directory record with its system use area, padded to an even length.
+pub fn record(identifier: &[u8], sector: u32, size: u32, flags: u8, system_use: &[u8]) -> Vec<u8> {
+    let mut record = vec![0; 33 + identifier.len() + (1 - identifier.len() % 2)];
+    record.extend_from_slice(system_use);
+    record.resize(record.len().next_multiple_of(2), 0);
+    record[0] = record.len() as u8;
+    record[2..10].copy_from_slice(&both_endian_u32(sector));
+    record[10..18].copy_from_slice(&both_endian_u32(size));
+    record[25] = flags;
+    record[32] = identifier.len() as u8;
+    record[33..33 + identifier.len()].copy_from_slice(identifier);
+    record
+}
+
+// The records of a one-sector directory: "." and ".." and then the
+// children.
+pub fn directory(own: u32, parent: u32, children: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = record(&[0], own, SECTOR as u32, FLAG_DIRECTORY, &[]);
+    data.extend(record(&[1], parent, SECTOR as u32, FLAG_DIRECTORY, &[]));
+    children.iter().for_each(|child| data.extend(child));
+    data
+}
+
+// Puts small ISO 9660 images together sector by sector. The image starts
+// with the empty system area and grows to hold whatever is put in it.
+pub struct IsoBuilder {
+    image: Vec<u8>,
+    primary: Option<u32>,
+}
+
+impl IsoBuilder {
+    pub fn new() -> Self {
+        Self {
+            image: vec![0; 16 * SECTOR],
+            primary: None,
+        }
+    }
+
+    pub fn sector_mut(&mut self, sector: u32) -> &mut [u8] {
+        let start = sector as usize * SECTOR;
+        if self.image.len() < start + SECTOR {
+            self.image.resize(start + SECTOR, 0);
+        }
+        &mut self.image[start..start + SECTOR]
+    }
+
+    pub fn descriptor(&mut self, sector: u32, kind: u8) -> &mut [u8] {
+        let descriptor = self.sector_mut(sector);
+        descriptor[0] = kind;
+        descriptor[1..6].copy_from_slice(b"CD001");
+        descriptor[6] = 1;
+        descriptor
+    }
+
+    // A primary descriptor with 2048-byte blocks whose root directory takes
+    // `root_size` bytes from `root`. The volume size is filled in by
+    // `build`.
+    pub fn primary(&mut self, sector: u32, volume_id: &str, root: u32, root_size: u32) -> &mut [u8] {
+        self.primary = Some(sector);
+        let descriptor = self.descriptor(sector, 1);
+        descriptor[8..72].fill(b' ');
+        descriptor[40..40 + volume_id.len()].copy_from_slice(volume_id.as_bytes());
+        descriptor[128..130].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+        descriptor[130..132].copy_from_slice(&(SECTOR as u16).to_be_bytes());
+        descriptor[156] = 34;
+        descriptor[158..166].copy_from_slice(&both_endian_u32(root));
+        descriptor[166..174].copy_from_slice(&both_endian_u32(root_size));
+        descriptor[181] = FLAG_DIRECTORY;
+        descriptor[188] = 1;
+        descriptor
+    }
+
+    // An El Torito boot record pointing at the boot catalog.
+    pub fn boot_record(&mut self, sector: u32, catalog: u32) {
+        let descriptor = self.descriptor(sector, 0);
+        descriptor[7..30].copy_from_slice(b"EL TORITO SPECIFICATION");
+        descriptor[71..75].copy_from_slice(&catalog.to_le_bytes());
+    }
+
+    // A Joliet supplementary descriptor with its own root directory.
+    pub fn joliet(&mut self, sector: u32, root: u32, root_size: u32) {
+        let descriptor = self.descriptor(sector, 2);
+        descriptor[88..91].copy_from_slice(b"%/E");
+        descriptor[156] = 34;
+        descriptor[158..166].copy_from_slice(&both_endian_u32(root));
+        descriptor[166..174].copy_from_slice(&both_endian_u32(root_size));
+        descriptor[181] = FLAG_DIRECTORY;
+    }
+
+    // Writes `data` from the start of `sector` on, over as many sectors as
+    // it takes.
+    pub fn put(&mut self, sector: u32, data: &[u8]) {
+        let start = sector as usize * SECTOR;
+        let end = (start + data.len()).next_multiple_of(SECTOR);
+        if self.image.len() < end {
+            self.image.resize(end, 0);
+        }
+        self.image[start..start + data.len()].copy_from_slice(data);
+    }
+
+    pub fn terminator(&mut self, sector: u32) {
+        self.descriptor(sector, 255);
+    }
+
+    pub fn build(mut self) -> Vec<u8> {
+        let sectors = (self.image.len() / SECTOR) as u32;
+        if let Some(primary) = self.primary {
+            self.sector_mut(primary)[80..88].copy_from_slice(&both_endian_u32(sectors));
+        }
+        self.image
+    }
+}
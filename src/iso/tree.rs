@@ -0,0 +1,726 @@
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::volume::{both_endian_u32, parse_dec_datetime, parse_record_datetime, IsoDateTime, RootDirectory, VolumeDescriptors};
+use super::SECTOR_SIZE;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+// Set on every record of a multi-extent file except the last one.
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+// Continuation areas can chain; a loop in them must not hang the reader.
+const MAX_CONTINUATIONS: usize = 16;
+// Deeper than any real image, even with Rock Ridge relocation undone.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum NameScheme {
+    RockRidge,
+    Joliet,
+    Iso9660,
+    Udf,
+}
+
+impl NameScheme {
+    pub fn label(&self) -> &'static str {
+        match self {
+            NameScheme::RockRidge => "Rock Ridge",
+            NameScheme::Joliet => "Joliet",
+            NameScheme::Iso9660 => "ISO 9660",
+            NameScheme::Udf => "UDF",
+        }
+    }
+}
+
+// A run of bytes inside the image that holds (part of) a file's data. UDF
+// extents that were allocated but never recorded have no offset and read
+// as zeros.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Extent {
+    pub offset: Option<u64>,
+    pub length: u64,
+}
+
+pub struct IsoEntry {
+    // Absolute path inside the image, e.g. `/EFI/BOOT/BOOTX64.EFI`.
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    // Files over 4 GiB are split over several directory records, each with
+    // its own extent. They are listed here in file order.
+    pub extents: Vec<Extent>,
+    pub modified: Option<IsoDateTime>,
+    // POSIX mode bits from a Rock Ridge PX entry.
+    pub mode: Option<u32>,
+    // Target of a Rock Ridge symbolic link.
+    pub symlink: Option<String>,
+}
+
+impl IsoEntry {
+    // `ls -l` style rendering of the Rock Ridge mode bits.
+    pub fn permissions(&self) -> Option<String> {
+        let mode = self.mode?;
+        let kind = match mode & 0o170000 {
+            0o040000 => 'd',
+            0o120000 => 'l',
+            _ => '-',
+        };
+
+        let mut text = String::from(kind);
+        for shift in [6, 3, 0] {
+            let bits = (mode >> shift) & 0o7;
+            text.push(if bits & 4 != 0 { 'r' } else { '-' });
+            text.push(if bits & 2 != 0 { 'w' } else { '-' });
+            text.push(if bits & 1 != 0 { 'x' } else { '-' });
+        }
+        Some(text)
+    }
+
+    // Streams the file's data, hopping from extent to extent.
+    pub fn data<'a, R: Read + Seek>(&'a self, reader: &'a mut R) -> EntryData<'a, R> {
+        EntryData {
+            reader,
+            extents: &self.extents,
+            position: 0,
+        }
+    }
+}
+
+pub struct EntryData<'a, R> {
+    reader: &'a mut R,
+    extents: &'a [Extent],
+    // Offset within the file. The reader is moved to match on every read,
+    // so it may be shared with other readers in between.
+    position: u64,
+}
+
+impl<R> EntryData<'_, R> {
+    fn size(&self) -> u64 {
+        self.extents.iter().map(|extent| extent.length).sum()
+    }
+}
+
+impl<R: Read + Seek> Read for EntryData<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut start = 0;
+        for extent in self.extents {
+            if self.position >= start + extent.length {
+                start += extent.length;
+                continue;
+            }
+
+            let skip = self.position - start;
+            let wanted = (extent.length - skip).min(buf.len() as u64) as usize;
+            let Some(offset) = extent.offset else {
+                buf[..wanted].fill(0);
+                self.position += wanted as u64;
+                return Ok(wanted);
+            };
+            self.reader.seek(SeekFrom::Start(offset + skip))?;
+            let read = self.reader.read(&mut buf[..wanted])?;
+            if read == 0 && wanted > 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The image ends inside a file"));
+            }
+            self.position += read as u64;
+            return Ok(read);
+        }
+        Ok(0)
+    }
+}
+
+impl<R> Seek for EntryData<'_, R> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let target = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.size().checked_add_signed(delta),
+        };
+        self.position = target.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Cannot seek before the start of a file")
+        })?;
+        Ok(self.position)
+    }
+}
+
+// Picks the richest naming the image offers: Rock Ridge keeps POSIX names,
+// modes and symlinks, Joliet at least keeps long mixed-case names.
+fn choose_scheme<R: Read + Seek>(reader: &mut R, descriptors: &VolumeDescriptors) -> io::Result<(NameScheme, RootDirectory, usize)> {
+    let root = descriptors.primary.root_directory;
+    if let Some(skip) = detect_rock_ridge(reader, root)? {
+        return Ok((NameScheme::RockRidge, root, skip));
+    }
+
+    match descriptors.joliet_root {
+        Some(joliet) => Ok((NameScheme::Joliet, joliet, 0)),
+        None => Ok((NameScheme::Iso9660, root, 0)),
+    }
+}
+
+pub fn entries<'a, R: Read + Seek>(reader: &'a mut R, descriptors: &VolumeDescriptors) -> io::Result<Entries<'a, R>> {
+    let (scheme, root, skip) = choose_scheme(reader, descriptors)?;
+
+    let mut visited = HashSet::new();
+    visited.insert(root.sector);
+    let image_size = reader.seek(SeekFrom::End(0))?;
+
+    Ok(Entries {
+        reader,
+        scheme,
+        skip,
+        image_size,
+        stack: vec![PendingDirectory {
+            path: String::new(),
+            sector: root.sector,
+            size: root.size,
+            depth: 0,
+        }],
+        pending: VecDeque::new(),
+        visited,
+    })
+}
+
+struct PendingDirectory {
+    path: String,
+    sector: u32,
+    size: u32,
+    depth: usize,
+}
+
+// Walks the tree lazily, one directory at a time. A directory is always
+// yielded before anything inside it.
+pub struct Entries<'a, R> {
+    reader: &'a mut R,
+    scheme: NameScheme,
+    skip: usize,
+    // Sizes in records are checked against it before anything is read, so
+    // a damaged record cannot ask for gigabytes.
+    image_size: u64,
+    stack: Vec<PendingDirectory>,
+    pending: VecDeque<(IsoEntry, usize)>,
+    visited: HashSet<u32>,
+}
+
+impl<R: Read + Seek> Entries<'_, R> {
+    pub fn scheme(&self) -> NameScheme {
+        self.scheme
+    }
+
+    fn read_directory(&mut self, directory: &PendingDirectory) -> io::Result<Vec<IsoEntry>> {
+        self.reader.seek(SeekFrom::Start(directory.sector as u64 * SECTOR_SIZE))?;
+
+        // Directories reached through a Rock Ridge CL link come without a
+        // size; their own "." record, which always comes first, has it.
+        let mut size = directory.size as usize;
+        if size == 0 {
+            let mut record = [0u8; 34];
+            self.reader.read_exact(&mut record)?;
+            size = both_endian_u32(&record[10..18]) as usize;
+            self.reader.seek(SeekFrom::Start(directory.sector as u64 * SECTOR_SIZE))?;
+        }
+
+        if directory.sector as u64 * SECTOR_SIZE + size as u64 > self.image_size {
+            return Err(invalid("A directory extends past the end of the image"));
+        }
+        let mut data = vec![0; size];
+        self.reader.read_exact(&mut data)?;
+
+        let mut entries: Vec<IsoEntry> = Vec::new();
+        // Name of the previous record when it announced further extents.
+        let mut continued: Option<String> = None;
+        let mut position = 0;
+
+        while position < data.len() {
+            let length = data[position] as usize;
+            if length == 0 {
+                // Records never straddle sectors; the rest of this one is padding.
+                position = (position / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+                continue;
+            }
+            if length < 34 || position + length > data.len() {
+                return Err(invalid("Corrupt directory record"));
+            }
+
+            let record = &data[position..position + length];
+            position += length;
+
+            let identifier_length = record[32] as usize;
+            if 33 + identifier_length > record.len() {
+                return Err(invalid("Corrupt directory record"));
+            }
+            let identifier = &record[33..33 + identifier_length];
+            // "." and ".." are stored as the single bytes 0 and 1.
+            if identifier == [0] || identifier == [1] {
+                continue;
+            }
+
+            // The system use area follows the identifier, padded to an even offset.
+            let system_use_start = 33 + identifier_length + (1 - identifier_length % 2);
+            let system_use = record.get(system_use_start + self.skip..).unwrap_or(&[]);
+            let rock_ridge = if self.scheme == NameScheme::RockRidge {
+                self.read_rock_ridge(system_use)?
+            } else {
+                RockRidge::default()
+            };
+
+            if rock_ridge.relocated {
+                // The real home of this directory is a CL entry elsewhere.
+                continue;
+            }
+
+            let flags = record[25];
+            let extended_attributes = record[1] as u64;
+            let mut extent_sector = both_endian_u32(&record[2..10]);
+            let mut data_length = both_endian_u32(&record[10..18]);
+            let mut is_dir = flags & FLAG_DIRECTORY != 0;
+            if let Some(child) = rock_ridge.child_link {
+                extent_sector = child;
+                data_length = 0;
+                is_dir = true;
+            }
+
+            let name = match (&rock_ridge.name, self.scheme) {
+                (Some(name), _) => name.clone(),
+                (None, NameScheme::Joliet) => ucs2_name(identifier),
+                (None, _) => iso9660_name(identifier),
+            };
+
+            // Mastering tools park deep directories in this one and link them
+            // back with CL; once relocation is undone it is an empty husk.
+            if self.scheme == NameScheme::RockRidge
+                && directory.depth == 0
+                && is_dir
+                && (name == "rr_moved" || name == ".rr_moved")
+            {
+                continue;
+            }
+
+            let extent = Extent {
+                offset: Some((extent_sector as u64 + extended_attributes) * SECTOR_SIZE),
+                length: data_length as u64,
+            };
+
+            if continued.as_deref() == Some(name.as_str()) {
+                let previous = entries.last_mut().unwrap();
+                previous.extents.push(extent);
+                previous.size += extent.length;
+            } else {
+                let mut extents = Vec::new();
+                if is_dir || extent.length > 0 {
+                    extents.push(extent);
+                }
+                let size = if is_dir { data_length as u64 } else { extent.length };
+
+                entries.push(IsoEntry {
+                    path: format!("{}/{}", directory.path, name),
+                    is_dir,
+                    size,
+                    extents,
+                    modified: rock_ridge.modified.or_else(|| parse_record_datetime(&record[18..25])),
+                    mode: rock_ridge.mode,
+                    symlink: rock_ridge.symlink,
+                });
+            }
+
+            continued = if flags & FLAG_MULTI_EXTENT != 0 && !is_dir {
+                Some(name)
+            } else {
+                None
+            };
+        }
+
+        Ok(entries)
+    }
+
+    // Collects the Rock Ridge entries of one record, following CE
+    // continuation areas as needed.
+    fn read_rock_ridge(&mut self, system_use: &[u8]) -> io::Result<RockRidge> {
+        let mut result = RockRidge::default();
+        let mut name = Vec::new();
+        let mut symlink = String::new();
+        let mut symlink_continues = false;
+        let mut area = system_use.to_vec();
+
+        for _ in 0..MAX_CONTINUATIONS {
+            let mut continuation = None;
+            let mut position = 0;
+
+            while position + 4 <= area.len() {
+                let signature = &area[position..position + 2];
+                let length = area[position + 2] as usize;
+                if length < 4 || position + length > area.len() {
+                    break;
+                }
+                let entry = &area[position..position + length];
+                position += length;
+
+                match signature {
+                    // Flags 2 and 4 stand for "." and "..", never real names.
+                    b"NM" if length >= 5 && entry[4] & 0x06 == 0 => {
+                        name.extend_from_slice(&entry[5..]);
+                    }
+                    b"PX" if length >= 12 => {
+                        result.mode = Some(both_endian_u32(&entry[4..12]));
+                    }
+                    b"SL" if length >= 5 => {
+                        append_symlink_components(&mut symlink, &mut symlink_continues, &entry[5..]);
+                        result.symlink = Some(symlink.clone());
+                    }
+                    b"TF" if length >= 5 => result.modified = parse_tf_modified(entry),
+                    b"CL" if length >= 12 => result.child_link = Some(both_endian_u32(&entry[4..12])),
+                    b"RE" => result.relocated = true,
+                    b"CE" if length >= 28 => {
+                        continuation = Some((
+                            both_endian_u32(&entry[4..12]),
+                            both_endian_u32(&entry[12..20]),
+                            both_endian_u32(&entry[20..28]),
+                        ));
+                    }
+                    b"ST" => break,
+                    _ => {}
+                }
+            }
+
+            match continuation {
+                // A continuation area lies within one logical block.
+                Some((_, offset, length)) if offset as u64 + length as u64 > SECTOR_SIZE => {
+                    return Err(invalid("Corrupt Rock Ridge continuation area"));
+                }
+                Some((sector, offset, length)) => {
+                    if sector as u64 * SECTOR_SIZE + SECTOR_SIZE > self.image_size {
+                        return Err(invalid("Corrupt Rock Ridge continuation area"));
+                    }
+                    area = vec![0; length as usize];
+                    self.reader
+                        .seek(SeekFrom::Start(sector as u64 * SECTOR_SIZE + offset as u64))?;
+                    self.reader.read_exact(&mut area)?;
+                }
+                None => break,
+            }
+        }
+
+        if !name.is_empty() {
+            result.name = Some(String::from_utf8_lossy(&name).to_string());
+        }
+        Ok(result)
+    }
+}
+
+impl<R: Read + Seek> Iterator for Entries<'_, R> {
+    type Item = io::Result<IsoEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((entry, depth)) = self.pending.pop_front() {
+                if let (true, Some(offset)) = (entry.is_dir && depth < MAX_DEPTH, entry.extents.first().and_then(|extent| extent.offset)) {
+                    let sector = (offset / SECTOR_SIZE) as u32;
+                    // Guard against directories that (through corruption or
+                    // CL links) point back at one of their ancestors.
+                    if self.visited.insert(sector) {
+                        self.stack.push(PendingDirectory {
+                            path: entry.path.clone(),
+                            sector,
+                            size: entry.size as u32,
+                            depth: depth + 1,
+                        });
+                    }
+                }
+                return Some(Ok(entry));
+            }
+
+            let directory = self.stack.pop()?;
+            match self.read_directory(&directory) {
+                Ok(entries) => self
+                    .pending
+                    .extend(entries.into_iter().map(|entry| (entry, directory.depth))),
+                Err(e) => {
+                    self.stack.clear();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct RockRidge {
+    name: Option<String>,
+    mode: Option<u32>,
+    symlink: Option<String>,
+    modified: Option<IsoDateTime>,
+    child_link: Option<u32>,
+    relocated: bool,
+}
+
+// Rock Ridge is announced by a SUSP "SP" entry at the start of the system use
+// area of the root's "." record. Its last byte is how many bytes every other
+// system use area starts with before the SUSP entries.
+fn detect_rock_ridge<R: Read + Seek>(reader: &mut R, root: RootDirectory) -> io::Result<Option<usize>> {
+    let mut sector = vec![0; SECTOR_SIZE as usize];
+    reader.seek(SeekFrom::Start(root.sector as u64 * SECTOR_SIZE))?;
+    reader.read_exact(&mut sector)?;
+
+    let length = sector[0] as usize;
+    let identifier_length = sector[32] as usize;
+    if length < 34 || identifier_length != 1 || sector[33] != 0 {
+        return Ok(None);
+    }
+
+    let system_use = &sector[34..length];
+    if system_use.len() >= 7 && &system_use[..2] == b"SP" && system_use[4] == 0xBE && system_use[5] == 0xEF {
+        Ok(Some(system_use[6] as usize))
+    } else {
+        Ok(None)
+    }
+}
+
+// SL components each carry flags: 1 = the name continues in the next
+// component, 2 = ".", 4 = "..", 8 = the root directory.
+fn append_symlink_components(target: &mut String, continues: &mut bool, mut components: &[u8]) {
+    while components.len() >= 2 {
+        let flags = components[0];
+        let length = components[1] as usize;
+        let content = components.get(2..2 + length).unwrap_or(&[]);
+        components = components.get(2 + length..).unwrap_or(&[]);
+
+        if !*continues && !target.is_empty() && !target.ends_with('/') {
+            target.push('/');
+        }
+
+        if flags & 0x08 != 0 {
+            target.clear();
+            target.push('/');
+        } else if flags & 0x02 != 0 {
+            target.push('.');
+        } else if flags & 0x04 != 0 {
+            target.push_str("..");
+        } else {
+            target.push_str(&String::from_utf8_lossy(content));
+        }
+
+        *continues = flags & 0x01 != 0;
+    }
+}
+
+// TF lists the timestamps named by its flag bits in a fixed order: creation,
+// modification, access, attributes, ... Bit 7 selects the 17-byte format.
+fn parse_tf_modified(entry: &[u8]) -> Option<IsoDateTime> {
+    let flags = entry[4];
+    let width = if flags & 0x80 != 0 { 17 } else { 7 };
+    if flags & 0x02 == 0 {
+        return None;
+    }
+
+    let index = (flags & 0x01) as usize;
+    let start = 5 + index * width;
+    let field = entry.get(start..start + width)?;
+    if width == 17 {
+        parse_dec_datetime(field)
+    } else {
+        parse_record_datetime(field)
+    }
+}
+
+// Joliet identifiers are big-endian UCS-2.
+fn ucs2_name(identifier: &[u8]) -> String {
+    let units: Vec<u16> = identifier
+        .chunks_exact(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+        .collect();
+    strip_version(&String::from_utf16_lossy(&units))
+}
+
+fn iso9660_name(identifier: &[u8]) -> String {
+    strip_version(&String::from_utf8_lossy(identifier))
+}
+
+// "README.TXT;1" becomes "README.TXT"; "NOEXT.;1" becomes "NOEXT".
+fn strip_version(name: &str) -> String {
+    let name = match name.rfind(';') {
+        Some(index) => &name[..index],
+        None => name,
+    };
+    name.strip_suffix('.').unwrap_or(name).to_string()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::iso::read_volume_descriptors;
+    use crate::iso::testiso::{both_endian_u32, directory, record, IsoBuilder, FLAG_DIRECTORY, SECTOR};
+
+    fn susp(signature: &[u8; 2], body: &[u8]) -> Vec<u8> {
+        [&signature[..], &[4 + body.len() as u8, 1], body].concat()
+    }
+
+    fn nm(flags: u8, name: &str) -> Vec<u8> {
+        susp(b"NM", &[&[flags], name.as_bytes()].concat())
+    }
+
+    fn px(mode: u32) -> Vec<u8> {
+        susp(b"PX", &[both_endian_u32(mode), both_endian_u32(1), [0; 8], [0; 8]].concat())
+    }
+
+    fn ce(sector: u32, offset: u32, length: u32) -> Vec<u8> {
+        susp(b"CE", &[both_endian_u32(sector), both_endian_u32(offset), both_endian_u32(length)].concat())
+    }
+
+    fn list(image: Vec<u8>) -> io::Result<(NameScheme, Vec<IsoEntry>, Cursor<Vec<u8>>)> {
+        let mut image = Cursor::new(image);
+        let descriptors = read_volume_descriptors(&mut image)?;
+        let mut walker = entries(&mut image, &descriptors)?;
+        let scheme = walker.scheme();
+        let listed = walker.by_ref().collect::<io::Result<Vec<_>>>()?;
+        Ok((scheme, listed, image))
+    }
+
+    fn read_all(entry: &IsoEntry, image: &mut Cursor<Vec<u8>>) -> Vec<u8> {
+        let mut data = Vec::new();
+        entry.data(image).read_to_end(&mut data).unwrap();
+        data
+    }
+
+    // The root's "." record announces Rock Ridge with an SP entry.
+    fn rock_ridge_root(own: u32, children: &[Vec<u8>]) -> Vec<u8> {
+        let sp = susp(b"SP", &[0xBE, 0xEF, 0]);
+        let mut data = record(&[0], own, SECTOR as u32, FLAG_DIRECTORY, &sp);
+        data.extend(record(&[1], own, SECTOR as u32, FLAG_DIRECTORY, &[]));
+        children.iter().for_each(|child| data.extend(child));
+        data
+    }
+
+    // Root at 20 and /docs at 21, a continuation area in 22 and file data
+    // from 30 on. `oversized` makes the continuation area run past its
+    // block.
+    fn rock_ridge_iso(oversized: bool) -> Vec<u8> {
+        let mut iso = IsoBuilder::new();
+        iso.primary(16, "ROCKRIDGE", 20, SECTOR as u32);
+        iso.terminator(17);
+
+        // /usr/sha + re is one component split over two.
+        let components: &[u8] = &[0x08, 0, 0, 3, b'u', b's', b'r', 1, 3, b's', b'h', b'a', 0, 2, b'r', b'e', 0x04, 0];
+        let symlink = [nm(0, "latest"), px(0o120777), susp(b"SL", &[&[0], components].concat())].concat();
+        let rest = nm(0, "for one record.md");
+        let continuation_length = if oversized { SECTOR as u32 } else { rest.len() as u32 };
+        let long_name = [nm(1, "A name too long "), ce(22, 100, continuation_length)].concat();
+        let big = [nm(0, "big.bin"), px(0o100644)].concat();
+        iso.put(
+            20,
+            &rock_ridge_root(
+                20,
+                &[
+                    record(b"README.TXT;1", 30, 11, 0, &[nm(0, "Read Me.txt"), px(0o100644)].concat()),
+                    record(b"LINK.;1", 0, 0, 0, &symlink),
+                    record(b"LONGNAME.TXT;1", 31, 5, 0, &long_name),
+                    record(b"BIG.BIN;1", 32, SECTOR as u32, FLAG_MULTI_EXTENT, &big),
+                    record(b"BIG.BIN;1", 34, 100, 0, &big),
+                    record(b"DOCS", 21, SECTOR as u32, FLAG_DIRECTORY, &[nm(0, "docs"), px(0o040755)].concat()),
+                ],
+            ),
+        );
+        iso.put(21, &directory(21, 20, &[record(b"NOTES.;1", 35, 3, 0, &nm(0, "notes"))]));
+        iso.put(22, &[vec![0; 100], rest].concat());
+
+        iso.put(30, b"hello world");
+        iso.put(31, b"12345");
+        iso.put(32, &[0xA5; SECTOR]);
+        iso.put(34, &[0x5A; 100]);
+        iso.put(35, b"abc");
+        iso.build()
+    }
+
+    #[test]
+    fn reads_rock_ridge_names_modes_symlinks_and_continuations() {
+        let (scheme, listed, mut image) = list(rock_ridge_iso(false)).unwrap();
+        assert!(scheme == NameScheme::RockRidge);
+        let paths: Vec<&str> = listed.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            ["/Read Me.txt", "/latest", "/A name too long for one record.md", "/big.bin", "/docs", "/docs/notes"]
+        );
+
+        assert_eq!(listed[0].permissions().as_deref(), Some("-rw-r--r--"));
+        assert_eq!(read_all(&listed[0], &mut image), b"hello world");
+        assert_eq!(listed[1].symlink.as_deref(), Some("/usr/share/.."));
+        assert_eq!(listed[1].permissions().as_deref(), Some("lrwxrwxrwx"));
+        assert_eq!(read_all(&listed[2], &mut image), b"12345");
+        assert!(listed[4].is_dir);
+        assert_eq!(listed[4].permissions().as_deref(), Some("drwxr-xr-x"));
+        assert_eq!(read_all(&listed[5], &mut image), b"abc");
+    }
+
+    #[test]
+    fn joins_the_extents_of_a_multi_extent_file() {
+        let (_, listed, mut image) = list(rock_ridge_iso(false)).unwrap();
+        let big = &listed[3];
+        assert_eq!(big.size, SECTOR as u64 + 100);
+        assert_eq!(
+            big.extents,
+            [
+                Extent {
+                    offset: Some(32 * SECTOR as u64),
+                    length: SECTOR as u64,
+                },
+                Extent {
+                    offset: Some(34 * SECTOR as u64),
+                    length: 100,
+                },
+            ]
+        );
+        let data = read_all(big, &mut image);
+        assert_eq!(data, [vec![0xA5; SECTOR], vec![0x5A; 100]].concat());
+
+        // Reads can start anywhere, including across the gap.
+        let mut reader = big.data(&mut image);
+        reader.seek(SeekFrom::End(-102)).unwrap();
+        let mut tail = [0; 4];
+        reader.read_exact(&mut tail).unwrap();
+        assert_eq!(tail, [0xA5, 0xA5, 0x5A, 0x5A]);
+    }
+
+    #[test]
+    fn reads_joliet_names_when_there_is_no_rock_ridge() {
+        let mut iso = IsoBuilder::new();
+        iso.primary(16, "JOLIET", 20, SECTOR as u32);
+        iso.joliet(17, 21, SECTOR as u32);
+        iso.terminator(18);
+        iso.put(20, &directory(20, 20, &[record(b"READ_ME.TXT;1", 30, 3, 0, &[])]));
+        let name: Vec<u8> = "Read me – naïve.txt;1".encode_utf16().flat_map(u16::to_be_bytes).collect();
+        iso.put(21, &directory(21, 21, &[record(&name, 30, 3, 0, &[])]));
+        iso.put(30, b"abc");
+        let image = iso.build();
+
+        let (scheme, listed, _) = list(image.clone()).unwrap();
+        assert!(scheme == NameScheme::Joliet);
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].path, "/Read me – naïve.txt");
+        assert_eq!(listed[0].size, 3);
+
+        // The plain ISO 9660 tree only has the short name.
+        let mut image = image;
+        image[17 * SECTOR] = 3;
+        let (scheme, listed, _) = list(image).unwrap();
+        assert!(scheme == NameScheme::Iso9660);
+        assert_eq!(listed[0].path, "/READ_ME.TXT");
+    }
+
+    #[test]
+    fn rejects_sizes_past_the_end_of_the_image() {
+        // A continuation area longer than the block it lies in.
+        let error = list(rock_ridge_iso(true)).err().unwrap();
+        assert_eq!(error.to_string(), "Corrupt Rock Ridge continuation area");
+
+        // A directory claiming nearly 4 GiB.
+        let mut iso = IsoBuilder::new();
+        iso.primary(16, "HUGE", 20, SECTOR as u32);
+        iso.terminator(17);
+        iso.put(20, &directory(20, 20, &[record(b"DOCS", 21, 0xFFFF_F800, FLAG_DIRECTORY, &[])]));
+        let error = list(iso.build()).err().unwrap();
+        assert_eq!(error.to_string(), "A directory extends past the end of the image");
+    }
+}
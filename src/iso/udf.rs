@@ -0,0 +1,919 @@
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::tree::{Extent, IsoEntry};
+use super::volume::IsoDateTime;
+use super::SECTOR_SIZE;
+
+// UDF on optical media, and so in every ISO file, uses 2048-byte blocks.
+const BLOCK_SIZE: u64 = SECTOR_SIZE;
+// Where the Anchor Volume Descriptor Pointer lives on every UDF volume.
+const ANCHOR_SECTOR: u64 = 256;
+// Volume descriptor sequences are short; a longer one is corrupt or looping.
+const MAX_DESCRIPTORS: usize = 256;
+const MAX_DEPTH: usize = 64;
+const MAX_AD_CONTINUATIONS: usize = 1024;
+
+const TAG_ANCHOR: u16 = 2;
+const TAG_POINTER: u16 = 3;
+const TAG_PARTITION: u16 = 5;
+const TAG_LOGICAL_VOLUME: u16 = 6;
+const TAG_TERMINATING: u16 = 8;
+const TAG_FILE_SET: u16 = 256;
+const TAG_FILE_IDENTIFIER: u16 = 257;
+const TAG_ALLOCATION_EXTENT: u16 = 258;
+const TAG_FILE_ENTRY: u16 = 261;
+const TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+const FILE_TYPE_DIRECTORY: u8 = 4;
+const FILE_TYPE_SYMLINK: u8 = 12;
+
+const FID_DIRECTORY: u8 = 0x02;
+const FID_DELETED: u8 = 0x04;
+const FID_PARENT: u8 = 0x08;
+
+// Top two bits of an allocation descriptor's length field.
+const EXTENT_RECORDED: u32 = 0;
+const EXTENT_NEXT_DESCRIPTORS: u32 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct LbAddr {
+    block: u32,
+    partition: u16,
+}
+
+enum Partition {
+    // Blocks map straight onto sectors starting at `start`. Sparable
+    // partitions are read the same way: an image file has no defects to
+    // remap.
+    Physical { start: u64 },
+    // UDF 2.50+ keeps file entries and directories in a metadata file that
+    // lives inside a physical partition; its blocks are blocks of that file.
+    Metadata { extents: Vec<Extent> },
+}
+
+pub struct UdfVolume {
+    pub volume_id: String,
+    // Taken from the domain identifier, e.g. 0x0250 for UDF 2.50.
+    pub revision: u16,
+    partitions: Vec<Partition>,
+    root: LbAddr,
+}
+
+impl UdfVolume {
+    pub fn revision_label(&self) -> String {
+        format!("{:x}.{:02x}", self.revision >> 8, self.revision & 0xFF)
+    }
+
+    // Returns `None` when the image simply has no UDF file system.
+    pub fn open<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut anchor = vec![0; BLOCK_SIZE as usize];
+        reader.seek(SeekFrom::Start(ANCHOR_SECTOR * BLOCK_SIZE))?;
+        if reader.read_exact(&mut anchor).is_err() || tag_id(&anchor) != Some(TAG_ANCHOR) {
+            return Ok(None);
+        }
+
+        // Main volume descriptor sequence extent: length, then location.
+        let mut sequence_length = u32_at(&anchor, 16) as u64;
+        let mut sequence_sector = u32_at(&anchor, 20) as u64;
+
+        let mut physical = Vec::new();
+        let mut logical_volume = None;
+        let mut read = 0;
+        while sequence_length >= BLOCK_SIZE && read < MAX_DESCRIPTORS {
+            let mut block = vec![0; BLOCK_SIZE as usize];
+            reader.seek(SeekFrom::Start(sequence_sector * BLOCK_SIZE))?;
+            reader.read_exact(&mut block)?;
+            read += 1;
+            sequence_sector += 1;
+            sequence_length -= BLOCK_SIZE;
+
+            match tag_id(&block) {
+                // Partition number and starting sector.
+                Some(TAG_PARTITION) => physical.push((u16_at(&block, 22), u32_at(&block, 188) as u64)),
+                Some(TAG_LOGICAL_VOLUME) => logical_volume = Some(block),
+                Some(TAG_POINTER) => {
+                    sequence_length = u32_at(&block, 20) as u64;
+                    sequence_sector = u32_at(&block, 24) as u64;
+                }
+                Some(TAG_TERMINATING) | None => break,
+                Some(_) => {}
+            }
+        }
+
+        let lvd = logical_volume.ok_or_else(|| invalid("The UDF volume has no logical volume descriptor"))?;
+        if u32_at(&lvd, 212) as u64 != BLOCK_SIZE {
+            return Err(invalid("Unsupported UDF logical block size"));
+        }
+
+        let map_table_length = u32_at(&lvd, 264) as usize;
+        let map_count = u32_at(&lvd, 268) as usize;
+        let maps = lvd
+            .get(440..440 + map_table_length)
+            .ok_or_else(|| invalid("Corrupt UDF partition map table"))?;
+
+        let mut volume = UdfVolume {
+            volume_id: dstring(&lvd[84..212]),
+            revision: u16_at(&lvd, 216 + 24),
+            partitions: Vec::new(),
+            root: LbAddr { block: 0, partition: 0 },
+        };
+
+        let find_physical = |number: u16| {
+            physical
+                .iter()
+                .find(|(partition, _)| *partition == number)
+                .map(|(_, start)| *start)
+                .ok_or_else(|| invalid("A UDF partition map names a missing partition"))
+        };
+
+        let mut position = 0;
+        let mut metadata_maps = Vec::new();
+        for _ in 0..map_count {
+            let map = maps.get(position..).filter(|map| map.len() >= 2).ok_or_else(|| invalid("Corrupt UDF partition map"))?;
+            let length = map[1] as usize;
+            if length < 6 || length > map.len() {
+                return Err(invalid("Corrupt UDF partition map"));
+            }
+
+            match map[0] {
+                1 => volume.partitions.push(Partition::Physical {
+                    start: find_physical(u16_at(map, 4))?,
+                }),
+                2 if length >= 64 => {
+                    let identifier = &map[5..28];
+                    if identifier.starts_with(b"*UDF Metadata Partition") {
+                        let start = find_physical(u16_at(map, 38))?;
+                        metadata_maps.push((volume.partitions.len(), start, u32_at(map, 40), u32_at(map, 44)));
+                        volume.partitions.push(Partition::Metadata { extents: Vec::new() });
+                    } else if identifier.starts_with(b"*UDF Sparable Partition") {
+                        volume.partitions.push(Partition::Physical {
+                            start: find_physical(u16_at(map, 38))?,
+                        });
+                    } else {
+                        return Err(invalid("Unsupported UDF partition type (virtual partitions are not supported)"));
+                    }
+                }
+                _ => return Err(invalid("Unsupported UDF partition map")),
+            }
+            position += length;
+        }
+
+        // The metadata file's allocation descriptors are relative to the
+        // physical partition it lives in. Fall back to the mirror copy if
+        // the main one is unreadable.
+        for (index, start, main, mirror) in metadata_maps {
+            let extents = volume
+                .read_metadata_file(reader, start, main)
+                .or_else(|_| volume.read_metadata_file(reader, start, mirror))?;
+            volume.partitions[index] = Partition::Metadata { extents };
+        }
+
+        // The logical volume contents use field holds a long_ad pointing at
+        // the file set descriptor, which in turn holds the root ICB.
+        let file_set = volume.read_block(reader, long_ad_location(&lvd[248..264]))?;
+        if tag_id(&file_set) != Some(TAG_FILE_SET) {
+            return Err(invalid("The UDF volume has no file set descriptor"));
+        }
+        volume.root = long_ad_location(&file_set[400..416]);
+
+        Ok(Some(volume))
+    }
+
+    pub fn entries<R: Read + Seek>(&self, reader: &mut R) -> io::Result<Vec<IsoEntry>> {
+        let root = self.read_file_entry(reader, self.root)?;
+        if root.file_type != FILE_TYPE_DIRECTORY {
+            return Err(invalid("The UDF root is not a directory"));
+        }
+
+        let mut entries = Vec::new();
+        let mut visited = HashSet::new();
+        visited.insert(self.root);
+        let mut stack = vec![(String::new(), root, 0)];
+
+        while let Some((path, directory, depth)) = stack.pop() {
+            let data = self.read_data(reader, &directory)?;
+            let mut position = 0;
+
+            while position + 38 <= data.len() {
+                let fid = &data[position..];
+                if tag_id(fid) != Some(TAG_FILE_IDENTIFIER) {
+                    return Err(invalid("Corrupt UDF file identifier descriptor"));
+                }
+
+                let characteristics = fid[18];
+                let name_length = fid[19] as usize;
+                let icb = long_ad_location(&fid[20..36]);
+                let implementation_use = u16_at(fid, 36) as usize;
+                let total = (38 + implementation_use + name_length + 3) & !3;
+                if position + total > data.len() {
+                    return Err(invalid("Corrupt UDF file identifier descriptor"));
+                }
+                position += total;
+
+                if characteristics & (FID_DELETED | FID_PARENT) != 0 {
+                    continue;
+                }
+
+                let name_start = 38 + implementation_use;
+                let name = osta_string(&fid[name_start..name_start + name_length]);
+                let entry = self.read_file_entry(reader, icb)?;
+                let is_dir = characteristics & FID_DIRECTORY != 0 || entry.file_type == FILE_TYPE_DIRECTORY;
+
+                let symlink = if entry.file_type == FILE_TYPE_SYMLINK {
+                    Some(symlink_target(&self.read_data(reader, &entry)?))
+                } else {
+                    None
+                };
+
+                let entry_path = format!("{}/{}", path, name);
+                entries.push(IsoEntry {
+                    path: entry_path.clone(),
+                    is_dir,
+                    size: entry.size,
+                    extents: self.resolve_extents(&entry)?,
+                    modified: entry.modified.clone(),
+                    mode: Some(entry.mode),
+                    symlink,
+                });
+
+                if is_dir && depth < MAX_DEPTH && visited.insert(icb) {
+                    stack.push((entry_path, entry, depth + 1));
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn read_metadata_file<R: Read + Seek>(&self, reader: &mut R, start: u64, block: u32) -> io::Result<Vec<Extent>> {
+        let mut data = vec![0; BLOCK_SIZE as usize];
+        reader.seek(SeekFrom::Start((start + block as u64) * BLOCK_SIZE))?;
+        reader.read_exact(&mut data)?;
+
+        let entry = parse_file_entry(&data, (start + block as u64) * BLOCK_SIZE)?;
+        let mut extents = Vec::new();
+        for descriptor in self.allocation_descriptors(reader, &entry)? {
+            match descriptor {
+                Allocation::Recorded { location, length } => extents.push(Extent {
+                    offset: Some((start + location.block as u64) * BLOCK_SIZE),
+                    length,
+                }),
+                Allocation::Embedded(extent) => extents.push(extent),
+                Allocation::Unrecorded { .. } => {}
+            }
+        }
+        Ok(extents)
+    }
+
+    fn sector_of(&self, location: LbAddr) -> io::Result<u64> {
+        match self.partitions.get(location.partition as usize) {
+            Some(Partition::Physical { start }) => Ok(start + location.block as u64),
+            Some(Partition::Metadata { extents }) => {
+                let mut offset = location.block as u64 * BLOCK_SIZE;
+                for extent in extents {
+                    if offset < extent.length {
+                        return extent
+                            .offset
+                            .map(|start| (start + offset) / BLOCK_SIZE)
+                            .ok_or_else(|| invalid("A UDF block lies outside the metadata file"));
+                    }
+                    offset -= extent.length;
+                }
+                Err(invalid("A UDF block lies outside the metadata file"))
+            }
+            None => Err(invalid("A UDF address names a missing partition")),
+        }
+    }
+
+    fn read_block<R: Read + Seek>(&self, reader: &mut R, location: LbAddr) -> io::Result<Vec<u8>> {
+        let mut block = vec![0; BLOCK_SIZE as usize];
+        reader.seek(SeekFrom::Start(self.sector_of(location)? * BLOCK_SIZE))?;
+        reader.read_exact(&mut block)?;
+        Ok(block)
+    }
+
+    fn read_file_entry<R: Read + Seek>(&self, reader: &mut R, location: LbAddr) -> io::Result<FileEntry> {
+        let block = self.read_block(reader, location)?;
+        let mut entry = parse_file_entry(&block, self.sector_of(location)? * BLOCK_SIZE)?;
+        entry.partition = location.partition;
+        entry.allocations = self.allocation_descriptors(reader, &entry)?;
+        Ok(entry)
+    }
+
+    // Walks the allocation descriptors of a file entry, following type 3
+    // descriptors into allocation extent descriptors as needed.
+    fn allocation_descriptors<R: Read + Seek>(&self, reader: &mut R, entry: &FileEntry) -> io::Result<Vec<Allocation>> {
+        if entry.ad_type == 3 {
+            return Ok(vec![Allocation::Embedded(Extent {
+                offset: Some(entry.ad_offset),
+                length: entry.size,
+            })]);
+        }
+
+        let mut allocations = Vec::new();
+        let mut area = entry.ad_area.clone();
+        for _ in 0..MAX_AD_CONTINUATIONS {
+            let mut next = None;
+            let step = match entry.ad_type {
+                0 => 8,
+                1 => 16,
+                2 => 20,
+                _ => return Err(invalid("Unknown UDF allocation descriptor type")),
+            };
+
+            for descriptor in area.chunks_exact(step) {
+                let raw_length = u32_at(descriptor, 0);
+                let length = (raw_length & 0x3FFF_FFFF) as u64;
+                if length == 0 {
+                    break;
+                }
+
+                let location = match entry.ad_type {
+                    0 => LbAddr {
+                        block: u32_at(descriptor, 4),
+                        partition: entry.partition,
+                    },
+                    1 => long_ad_location(descriptor),
+                    _ => LbAddr {
+                        block: u32_at(descriptor, 12),
+                        partition: u16_at(descriptor, 16),
+                    },
+                };
+
+                match raw_length >> 30 {
+                    EXTENT_RECORDED => allocations.push(Allocation::Recorded { location, length }),
+                    EXTENT_NEXT_DESCRIPTORS => {
+                        next = Some(location);
+                        break;
+                    }
+                    _ => allocations.push(Allocation::Unrecorded { length }),
+                }
+            }
+
+            match next {
+                Some(location) => {
+                    let block = self.read_block(reader, location)?;
+                    if tag_id(&block) != Some(TAG_ALLOCATION_EXTENT) {
+                        return Err(invalid("Corrupt UDF allocation extent descriptor"));
+                    }
+                    let length = (u32_at(&block, 20) as usize).min(block.len() - 24);
+                    area = block[24..24 + length].to_vec();
+                }
+                None => return Ok(allocations),
+            }
+        }
+
+        Err(invalid("Too many UDF allocation extents"))
+    }
+
+    // Converts allocations into byte ranges of the image, trimmed to the
+    // information length. Preallocated space past the end is dropped.
+    fn resolve_extents(&self, entry: &FileEntry) -> io::Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        let mut remaining = entry.size;
+
+        for allocation in &entry.allocations {
+            if remaining == 0 {
+                break;
+            }
+
+            match allocation {
+                Allocation::Recorded { location, length } => {
+                    // An extent of a metadata partition may be scattered
+                    // over several runs of the image.
+                    let length = (*length).min(remaining);
+                    let mut mapped = 0;
+                    while mapped < length {
+                        let block = LbAddr {
+                            block: location.block + (mapped / BLOCK_SIZE) as u32,
+                            partition: location.partition,
+                        };
+                        let offset = self.sector_of(block)? * BLOCK_SIZE;
+                        let run = self.contiguous_run(block, length - mapped);
+
+                        match extents.last_mut() {
+                            Some(Extent {
+                                offset: Some(last),
+                                length: last_length,
+                            }) if *last + *last_length == offset => {
+                                *last_length += run;
+                            }
+                            _ => extents.push(Extent {
+                                offset: Some(offset),
+                                length: run,
+                            }),
+                        }
+                        mapped += run;
+                    }
+                    remaining -= length;
+                }
+                Allocation::Embedded(extent) => {
+                    extents.push(Extent {
+                        offset: extent.offset,
+                        length: extent.length.min(remaining),
+                    });
+                    remaining -= extent.length.min(remaining);
+                }
+                // Allocated but never written, so it reads as zeros.
+                Allocation::Unrecorded { length } => {
+                    extents.push(Extent {
+                        offset: None,
+                        length: (*length).min(remaining),
+                    });
+                    remaining -= (*length).min(remaining);
+                }
+            }
+        }
+
+        Ok(extents)
+    }
+
+    // How many bytes starting at `location` are contiguous in the image.
+    fn contiguous_run(&self, location: LbAddr, wanted: u64) -> u64 {
+        match self.partitions.get(location.partition as usize) {
+            Some(Partition::Metadata { extents }) => {
+                let mut offset = location.block as u64 * BLOCK_SIZE;
+                for extent in extents {
+                    if offset < extent.length {
+                        return (extent.length - offset).min(wanted);
+                    }
+                    offset -= extent.length;
+                }
+                wanted.min(BLOCK_SIZE)
+            }
+            _ => wanted,
+        }
+    }
+
+    // Reads a directory or symlink whole. Its size comes from the file
+    // entry, so it is held against the image before anything is allocated.
+    fn read_data<R: Read + Seek>(&self, reader: &mut R, entry: &FileEntry) -> io::Result<Vec<u8>> {
+        let image_size = reader.seek(SeekFrom::End(0))?;
+        let mut data = Vec::new();
+        for extent in self.resolve_extents(entry)? {
+            let start = data.len();
+            if start as u64 + extent.length > image_size
+                || extent.offset.is_some_and(|offset| offset + extent.length > image_size)
+            {
+                return Err(invalid("A UDF directory extends past the end of the image"));
+            }
+            data.resize(start + extent.length as usize, 0);
+            if let Some(offset) = extent.offset {
+                reader.seek(SeekFrom::Start(offset))?;
+                reader.read_exact(&mut data[start..])?;
+            }
+        }
+        Ok(data)
+    }
+}
+
+enum Allocation {
+    Recorded { location: LbAddr, length: u64 },
+    Unrecorded { length: u64 },
+    // File data stored inside the file entry itself.
+    Embedded(Extent),
+}
+
+struct FileEntry {
+    file_type: u8,
+    mode: u32,
+    size: u64,
+    modified: Option<IsoDateTime>,
+    ad_type: u8,
+    ad_area: Vec<u8>,
+    // Absolute image offset of the allocation descriptor area.
+    ad_offset: u64,
+    partition: u16,
+    allocations: Vec<Allocation>,
+}
+
+// File entries and extended file entries differ only in where their fields
+// sit; extended ones add a creation time and stream directory.
+fn parse_file_entry(block: &[u8], block_offset: u64) -> io::Result<FileEntry> {
+    let (modified_at, lengths_at) = match tag_id(block) {
+        Some(TAG_FILE_ENTRY) => (84, 168),
+        Some(TAG_EXTENDED_FILE_ENTRY) => (92, 208),
+        _ => return Err(invalid("Corrupt UDF file entry")),
+    };
+
+    let extended_attributes = u32_at(block, lengths_at) as usize;
+    let ad_length = u32_at(block, lengths_at + 4) as usize;
+    let ad_start = lengths_at + 8 + extended_attributes;
+    let ad_area = block
+        .get(ad_start..ad_start + ad_length)
+        .ok_or_else(|| invalid("Corrupt UDF file entry"))?;
+
+    let file_type = block[27];
+    Ok(FileEntry {
+        file_type,
+        mode: posix_mode(file_type, u32_at(block, 44)),
+        size: u64_at(block, 56),
+        modified: udf_timestamp(&block[modified_at..modified_at + 12]),
+        ad_type: (u16_at(block, 34) & 0x07) as u8,
+        ad_area: ad_area.to_vec(),
+        ad_offset: block_offset + ad_start as u64,
+        partition: 0,
+        allocations: Vec::new(),
+    })
+}
+
+// UDF permissions use five bits per class (execute, write, read, change
+// attributes, delete) with "other" in the lowest bits.
+fn posix_mode(file_type: u8, permissions: u32) -> u32 {
+    let kind = match file_type {
+        FILE_TYPE_DIRECTORY => 0o040000,
+        FILE_TYPE_SYMLINK => 0o120000,
+        _ => 0o100000,
+    };
+
+    // Execute, write and read line up with POSIX x, w and r.
+    let class = |shift: u32| (permissions >> shift) & 0o7;
+    kind | class(10) << 6 | class(5) << 3 | class(0)
+}
+
+// Symlink targets are a list of path components: 1 and 2 mean the root, 3
+// "..", 4 "." and 5 a named component.
+fn symlink_target(data: &[u8]) -> String {
+    let mut parts: Vec<String> = Vec::new();
+    let mut absolute = false;
+    let mut position = 0;
+
+    while position + 4 <= data.len() {
+        let kind = data[position];
+        let length = data[position + 1] as usize;
+        let identifier = data.get(position + 4..position + 4 + length).unwrap_or(&[]);
+        position += 4 + length;
+
+        match kind {
+            1 | 2 => {
+                absolute = true;
+                parts.clear();
+            }
+            3 => parts.push("..".to_string()),
+            4 => parts.push(".".to_string()),
+            5 => parts.push(osta_string(identifier)),
+            _ => {}
+        }
+    }
+
+    let joined = parts.join("/");
+    if absolute {
+        format!("/{}", joined)
+    } else {
+        joined
+    }
+}
+
+fn udf_timestamp(field: &[u8]) -> Option<IsoDateTime> {
+    let type_and_zone = u16_at(field, 0);
+    let year = u16_at(field, 2);
+    if year == 0 {
+        return None;
+    }
+
+    // A signed 12-bit offset in minutes; -2047 means "not specified".
+    let mut minutes = (type_and_zone & 0x0FFF) as i16;
+    if minutes & 0x0800 != 0 {
+        minutes -= 0x1000;
+    }
+    if minutes == -2047 {
+        minutes = 0;
+    }
+
+    Some(IsoDateTime {
+        year,
+        month: field[4],
+        day: field[5],
+        hour: field[6],
+        minute: field[7],
+        second: field[8],
+        gmt_offset: (minutes / 15) as i8,
+    })
+}
+
+// OSTA compressed unicode: the first byte says whether the rest is 8-bit
+// or big-endian 16-bit characters (254 and 255 are the UDF 2.50 variants).
+fn osta_string(bytes: &[u8]) -> String {
+    match bytes.split_first() {
+        Some((8 | 254, rest)) => rest.iter().map(|&b| b as char).collect(),
+        Some((16 | 255, rest)) => {
+            let units: Vec<u16> = rest
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::new(),
+    }
+}
+
+// A fixed-size field whose last byte is the used length of the OSTA string.
+fn dstring(field: &[u8]) -> String {
+    let used = (*field.last().unwrap_or(&0) as usize).min(field.len() - 1);
+    osta_string(&field[..used])
+}
+
+// Descriptor tags carry an id and a checksum over their other 15 bytes.
+fn tag_id(block: &[u8]) -> Option<u16> {
+    if block.len() < 16 {
+        return None;
+    }
+
+    let checksum = block[..16]
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| *index != 4)
+        .fold(0u8, |acc, (_, &b)| acc.wrapping_add(b));
+    if checksum != block[4] {
+        return None;
+    }
+
+    Some(u16_at(block, 0))
+}
+
+fn long_ad_location(descriptor: &[u8]) -> LbAddr {
+    LbAddr {
+        block: u32_at(descriptor, 4),
+        partition: u16_at(descriptor, 8),
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::iso::testiso::{directory, IsoBuilder};
+    use crate::iso::{list_contents, read_volume_descriptors, NameScheme};
+
+    const BS: usize = BLOCK_SIZE as usize;
+    const PARTITION_START: u32 = 300;
+    const HELLO: &[u8] = b"Hello, UDF!\n";
+
+    fn put16(data: &mut [u8], at: usize, value: u16) {
+        data[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn put32(data: &mut [u8], at: usize, value: u32) {
+        data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn pattern(length: usize, seed: u8) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8 ^ seed).collect()
+    }
+
+    // Fills in a descriptor tag. Its checksum only covers the tag itself.
+    fn tag(descriptor: &mut [u8], id: u16) {
+        put16(descriptor, 0, id);
+        put16(descriptor, 2, 3);
+        descriptor[4] = 0;
+        descriptor[4] = descriptor[..16].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    }
+
+    fn block(id: u16) -> Vec<u8> {
+        let mut block = vec![0; BS];
+        tag(&mut block, id);
+        block
+    }
+
+    fn long_ad(length: u32, block: u32, partition: u16) -> Vec<u8> {
+        let mut ad = vec![0; 16];
+        put32(&mut ad, 0, length);
+        put32(&mut ad, 4, block);
+        put16(&mut ad, 8, partition);
+        ad
+    }
+
+    fn osta(name: &str) -> Vec<u8> {
+        [&[8], name.as_bytes()].concat()
+    }
+
+    fn fid(name: Option<&str>, icb: u32, partition: u16, is_dir: bool) -> Vec<u8> {
+        let name = name.map(osta).unwrap_or_default();
+        let mut fid = vec![0; (38 + name.len() + 3) & !3];
+        put16(&mut fid, 16, 1);
+        fid[18] = if name.is_empty() { FID_PARENT | FID_DIRECTORY } else if is_dir { FID_DIRECTORY } else { 0 };
+        fid[19] = name.len() as u8;
+        fid[20..36].copy_from_slice(&long_ad(BS as u32, icb, partition));
+        fid[38..38 + name.len()].copy_from_slice(&name);
+        tag(&mut fid, TAG_FILE_IDENTIFIER);
+        fid
+    }
+
+    // Modified 2024-05-06 07:08:09, an hour east of GMT.
+    fn file_entry(extended: bool, file_type: u8, size: u64, ad_type: u16, ads: &[u8]) -> Vec<u8> {
+        let (id, modified_at, lengths_at) = if extended {
+            (TAG_EXTENDED_FILE_ENTRY, 92, 208)
+        } else {
+            (TAG_FILE_ENTRY, 84, 168)
+        };
+        let mut entry = vec![0; BS];
+        put16(&mut entry, 20, 4);
+        entry[27] = file_type;
+        put16(&mut entry, 34, ad_type);
+        put32(&mut entry, 44, 0x14A5);
+        entry[56..64].copy_from_slice(&size.to_le_bytes());
+        entry[modified_at..modified_at + 12].copy_from_slice(&[0x3C, 0x10, 0xE8, 0x07, 5, 6, 7, 8, 9, 0, 0, 0]);
+        put32(&mut entry, lengths_at + 4, ads.len() as u32);
+        entry[lengths_at + 8..lengths_at + 8 + ads.len()].copy_from_slice(ads);
+        tag(&mut entry, id);
+        entry
+    }
+
+    // A UDF volume behind a stub ISO 9660 tree, with one partition from
+    // sector 300 on. With `metadata`, file entries are extended ones and
+    // live in a metadata partition whose file is split in two extents, and
+    // allocation descriptors are long ones; otherwise they are short.
+    //
+    // The tree: /sources/install.wim, whose descriptors go on in an
+    // allocation extent descriptor, /hello.txt embedded in its entry,
+    // /sparse.bin with an unrecorded extent in the middle and /link, a
+    // symlink to /sources/install.wim.
+    fn udf_image(metadata: bool) -> Vec<u8> {
+        let mut iso = IsoBuilder::new();
+        iso.primary(16, "STUB", 40, BS as u32);
+        iso.terminator(17);
+        for (sector, id) in [(18, b"BEA01"), (19, b"NSR03"), (20, b"TEA01")] {
+            iso.sector_mut(sector)[1..6].copy_from_slice(id);
+        }
+        iso.put(40, &directory(40, 40, &[]));
+
+        let mut partition = block(TAG_PARTITION);
+        put32(&mut partition, 188, PARTITION_START);
+        put32(&mut partition, 192, 250);
+        iso.put(32, &partition);
+
+        let files = if metadata { 1 } else { 0 };
+        let mut maps = vec![1, 6, 1, 0, 0, 0];
+        if metadata {
+            let mut map = vec![0; 64];
+            map[0] = 2;
+            map[1] = 64;
+            map[5..28].copy_from_slice(b"*UDF Metadata Partition");
+            put32(&mut map, 40, 10);
+            put32(&mut map, 44, 10);
+            maps.extend(map);
+        }
+        let mut volume = vec![0; BS];
+        volume[84..91].copy_from_slice(&[8, b'U', b'D', b'F', b'V', b'O', b'L']);
+        volume[211] = 7;
+        put32(&mut volume, 212, BS as u32);
+        volume[217..236].copy_from_slice(b"*OSTA UDF Compliant");
+        put16(&mut volume, 240, 0x0250);
+        volume[248..264].copy_from_slice(&long_ad(BS as u32, 0, files));
+        put32(&mut volume, 264, maps.len() as u32);
+        put32(&mut volume, 268, 1 + files as u32);
+        volume[440..440 + maps.len()].copy_from_slice(&maps);
+        tag(&mut volume, TAG_LOGICAL_VOLUME);
+        iso.put(33, &volume);
+        iso.put(34, &block(TAG_TERMINATING));
+
+        let mut anchor = block(TAG_ANCHOR);
+        put32(&mut anchor, 16, 16 * BS as u32);
+        put32(&mut anchor, 20, 32);
+        iso.put(256, &anchor);
+
+        // Blocks of the partition files live in, mapped through the
+        // metadata file when there is one.
+        let physical = |block: u32| match (metadata, block) {
+            (false, _) => block,
+            (true, 0..16) => 20 + block,
+            (true, _) => 100 + block - 16,
+        };
+        let ad = |length: u32, block: u32, partition: u16| match metadata {
+            true => long_ad(length, block, partition),
+            false => long_ad(length, block, partition)[..8].to_vec(),
+        };
+        let ad_type = if metadata { 1 } else { 0 };
+        let mut put_file = |block: u32, data: &[u8]| iso.put(PARTITION_START + physical(block), data);
+
+        let mut file_set = block(TAG_FILE_SET);
+        file_set[400..416].copy_from_slice(&long_ad(BS as u32, 1, files));
+        put_file(0, &file_set);
+
+        let root: Vec<u8> = [
+            fid(None, 1, files, true),
+            fid(Some("sources"), 17, files, true),
+            fid(Some("hello.txt"), 5, files, false),
+            fid(Some("sparse.bin"), 6, files, false),
+            fid(Some("link"), 7, files, false),
+        ]
+        .concat();
+        put_file(1, &file_entry(metadata, FILE_TYPE_DIRECTORY, root.len() as u64, ad_type, &ad(root.len() as u32, 2, files)));
+        put_file(2, &root);
+
+        let sources = [fid(None, 1, files, true), fid(Some("install.wim"), 8, files, false)].concat();
+        let sources_ads = ad(sources.len() as u32, 4, files);
+        put_file(17, &file_entry(metadata, FILE_TYPE_DIRECTORY, sources.len() as u64, ad_type, &sources_ads));
+        put_file(4, &sources);
+
+        put_file(5, &file_entry(metadata, 5, HELLO.len() as u64, 3, HELLO));
+
+        let sparse = [ad(BS as u32, 150, 0), ad(1 << 30 | BS as u32, 0, 0), ad(100, 152, 0)].concat();
+        put_file(6, &file_entry(metadata, 5, 2 * BS as u64 + 100, ad_type, &sparse));
+
+        let target = [&[2, 0, 0, 0, 5, 8, 0, 0], &osta("sources")[..], &[5, 12, 0, 0], &osta("install.wim")].concat();
+        put_file(7, &file_entry(metadata, FILE_TYPE_SYMLINK, target.len() as u64, 3, &target));
+
+        let wim = [ad(BS as u32, 170, 0), ad(3 << 30 | BS as u32, 9, files)].concat();
+        put_file(8, &file_entry(metadata, 5, BS as u64 + 100, ad_type, &wim));
+        let mut extent = block(TAG_ALLOCATION_EXTENT);
+        let rest = ad(100, 171, 0);
+        put32(&mut extent, 20, rest.len() as u32);
+        extent[24..24 + rest.len()].copy_from_slice(&rest);
+        put_file(9, &extent);
+
+        if metadata {
+            let extents = [long_ad(16 * BS as u32, 20, 0)[..8].to_vec(), long_ad(16 * BS as u32, 100, 0)[..8].to_vec()];
+            iso.put(PARTITION_START + 10, &file_entry(true, 250, 32 * BS as u64, 0, &extents.concat()));
+        }
+        iso.put(PARTITION_START + 150, &pattern(BS, 0x11));
+        iso.put(PARTITION_START + 152, &pattern(100, 0x22));
+        iso.put(PARTITION_START + 170, &pattern(BS + 100, 0x33));
+        iso.sector_mut(599);
+        iso.build()
+    }
+
+    fn read_all(entry: &IsoEntry, image: &mut Cursor<Vec<u8>>) -> Vec<u8> {
+        let mut data = Vec::new();
+        entry.data(image).read_to_end(&mut data).unwrap();
+        data
+    }
+
+    fn check_volume(metadata: bool) {
+        let mut image = Cursor::new(udf_image(metadata));
+        let volume = UdfVolume::open(&mut image).unwrap().unwrap();
+        assert_eq!(volume.volume_id, "UDFVOL");
+        assert_eq!(volume.revision_label(), "2.50");
+
+        // UDF wins over the stub ISO 9660 tree.
+        let descriptors = read_volume_descriptors(&mut image).unwrap();
+        let listing = list_contents(&mut image, &descriptors).unwrap();
+        assert!(listing.scheme == NameScheme::Udf);
+        let entries = listing.entries;
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["/sources", "/hello.txt", "/sparse.bin", "/link", "/sources/install.wim"]);
+
+        assert!(entries[0].is_dir);
+        assert_eq!(entries[0].permissions().as_deref(), Some("dr-xr-xr-x"));
+        assert_eq!(entries[1].modified.as_ref().unwrap().to_string(), "2024-05-06 07:08:09 UTC+01:00");
+        assert_eq!(read_all(&entries[1], &mut image), HELLO);
+
+        let sparse = read_all(&entries[2], &mut image);
+        assert_eq!(sparse, [pattern(BS, 0x11), vec![0; BS], pattern(100, 0x22)].concat());
+        assert_eq!(entries[2].extents[1].offset, None);
+
+        assert_eq!(entries[3].symlink.as_deref(), Some("/sources/install.wim"));
+        assert_eq!(entries[4].size, BS as u64 + 100);
+        assert_eq!(read_all(&entries[4], &mut image), pattern(BS + 100, 0x33));
+    }
+
+    #[test]
+    fn reads_a_udf_volume_with_short_allocation_descriptors() {
+        check_volume(false);
+    }
+
+    #[test]
+    fn reads_a_metadata_partition_with_extended_file_entries() {
+        check_volume(true);
+    }
+
+    #[test]
+    fn bounds_directory_reads_by_the_image() {
+        // The root's file entry sits in partition block 1.
+        let root = (PARTITION_START as usize + 1) * BS;
+
+        // An information length no directory has is cut to its extents.
+        let mut image = udf_image(false);
+        image[root + 56..root + 64].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut image = Cursor::new(image);
+        let volume = UdfVolume::open(&mut image).unwrap().unwrap();
+        assert_eq!(volume.entries(&mut image).unwrap().len(), 5);
+
+        // An extent of nearly 1 GiB is refused instead of allocated.
+        let mut image = udf_image(false);
+        image[root + 56..root + 64].copy_from_slice(&u64::MAX.to_le_bytes());
+        image[root + 176..root + 180].copy_from_slice(&0x3FFF_F800u32.to_le_bytes());
+        let mut image = Cursor::new(image);
+        let volume = UdfVolume::open(&mut image).unwrap().unwrap();
+        let error = volume.entries(&mut image).err().unwrap();
+        assert_eq!(error.to_string(), "A UDF directory extends past the end of the image");
+    }
+}
@@ -0,0 +1,316 @@
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::eltorito;
+use super::SECTOR_SIZE;
+
+// The first 16 sectors are the system area; descriptors start after it.
+const FIRST_DESCRIPTOR_SECTOR: u64 = 16;
+// A real descriptor set is a handful of sectors long. Stop looking well
+// before reading through a whole file that merely looks like an ISO.
+const MAX_DESCRIPTORS: u64 = 64;
+
+const TYPE_BOOT_RECORD: u8 = 0;
+const TYPE_PRIMARY: u8 = 1;
+const TYPE_SUPPLEMENTARY: u8 = 2;
+const TYPE_TERMINATOR: u8 = 255;
+
+// Escape sequences announcing UCS-2 level 1, 2 and 3 in a Joliet SVD.
+const JOLIET_ESCAPES: [&[u8]; 3] = [b"%/@", b"%/C", b"%/E"];
+
+pub struct VolumeDescriptors {
+    pub primary: PrimaryVolumeDescriptor,
+    // Set when an El Torito boot record points at a boot catalog.
+    pub boot_catalog_sector: Option<u32>,
+    // Root of the parallel UCS-2 directory tree, if the image has one.
+    pub joliet_root: Option<RootDirectory>,
+}
+
+#[derive(Clone, Copy)]
+pub struct RootDirectory {
+    pub sector: u32,
+    pub size: u32,
+}
+
+pub struct PrimaryVolumeDescriptor {
+    pub system_id: String,
+    pub volume_id: String,
+    pub volume_space_size: u32,
+    pub logical_block_size: u16,
+    pub publisher_id: String,
+    pub data_preparer_id: String,
+    pub application_id: String,
+    pub creation_date: Option<IsoDateTime>,
+    pub modification_date: Option<IsoDateTime>,
+    pub root_directory: RootDirectory,
+}
+
+impl PrimaryVolumeDescriptor {
+    pub fn volume_size(&self) -> u64 {
+        self.volume_space_size as u64 * self.logical_block_size as u64
+    }
+}
+
+// The 17-byte "dec-datetime" format used for volume timestamps.
+#[derive(Clone)]
+pub struct IsoDateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    // Offset from GMT in 15 minute intervals, -48 (west) to +52 (east).
+    pub gmt_offset: i8,
+}
+
+impl fmt::Display for IsoDateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let offset = self.gmt_offset as i32 * 15;
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC{}{:02}:{:02}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            if offset < 0 { '-' } else { '+' },
+            offset.abs() / 60,
+            offset.abs() % 60
+        )
+    }
+}
+
+pub fn read_volume_descriptors<R: Read + Seek>(reader: &mut R) -> io::Result<VolumeDescriptors> {
+    let mut primary = None;
+    let mut boot_catalog_sector = None;
+    let mut joliet_root = None;
+    let mut sector = vec![0; SECTOR_SIZE as usize];
+
+    for index in FIRST_DESCRIPTOR_SECTOR..FIRST_DESCRIPTOR_SECTOR + MAX_DESCRIPTORS {
+        reader.seek(SeekFrom::Start(index * SECTOR_SIZE))?;
+        reader.read_exact(&mut sector).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                invalid("The file is too small to be an ISO 9660 image")
+            } else {
+                e
+            }
+        })?;
+
+        if &sector[1..6] != b"CD001" {
+            return Err(invalid("Not an ISO 9660 image (no CD001 volume descriptor)"));
+        }
+
+        match sector[0] {
+            TYPE_BOOT_RECORD if boot_catalog_sector.is_none() => {
+                boot_catalog_sector = eltorito::parse_boot_record(&sector);
+            }
+            TYPE_PRIMARY if primary.is_none() => primary = Some(parse_primary(&sector)?),
+            TYPE_SUPPLEMENTARY if joliet_root.is_none() => {
+                let escapes = &sector[88..120];
+                if JOLIET_ESCAPES.iter().any(|escape| escapes.starts_with(escape)) {
+                    joliet_root = Some(parse_root_record(&sector));
+                }
+            }
+            TYPE_TERMINATOR => {
+                let primary = primary.ok_or_else(|| invalid("The image has no primary volume descriptor"))?;
+                return Ok(VolumeDescriptors {
+                    primary,
+                    boot_catalog_sector,
+                    joliet_root,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Err(invalid("The volume descriptor set has no terminator"))
+}
+
+fn parse_primary(sector: &[u8]) -> io::Result<PrimaryVolumeDescriptor> {
+    if sector[6] != 1 {
+        return Err(invalid("Unsupported primary volume descriptor version"));
+    }
+
+    let logical_block_size = both_endian_u16(&sector[128..132]);
+    if logical_block_size == 0 || !logical_block_size.is_power_of_two() {
+        return Err(invalid("The primary volume descriptor has an invalid block size"));
+    }
+
+    Ok(PrimaryVolumeDescriptor {
+        system_id: text(&sector[8..40]),
+        volume_id: text(&sector[40..72]),
+        volume_space_size: both_endian_u32(&sector[80..88]),
+        logical_block_size,
+        publisher_id: text(&sector[318..446]),
+        data_preparer_id: text(&sector[446..574]),
+        application_id: text(&sector[574..702]),
+        creation_date: parse_dec_datetime(&sector[813..830]),
+        modification_date: parse_dec_datetime(&sector[830..847]),
+        root_directory: parse_root_record(sector),
+    })
+}
+
+// Primary and supplementary descriptors both embed the 34-byte directory
+// record of their root directory at offset 156.
+fn parse_root_record(sector: &[u8]) -> RootDirectory {
+    RootDirectory {
+        sector: both_endian_u32(&sector[158..166]),
+        size: both_endian_u32(&sector[166..174]),
+    }
+}
+
+// Timestamps are ASCII digits "YYYYMMDDHHMMSShh" plus a signed offset byte.
+// All zeros (or all '0' digits) means the date is not specified.
+pub fn parse_dec_datetime(field: &[u8]) -> Option<IsoDateTime> {
+    let digits = &field[..16];
+    if !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    let number = |range: std::ops::Range<usize>| {
+        digits[range]
+            .iter()
+            .fold(0u16, |acc, d| acc * 10 + (d - b'0') as u16)
+    };
+
+    let year = number(0..4);
+    if year == 0 {
+        return None;
+    }
+
+    Some(IsoDateTime {
+        year,
+        month: number(4..6) as u8,
+        day: number(6..8) as u8,
+        hour: number(8..10) as u8,
+        minute: number(10..12) as u8,
+        second: number(12..14) as u8,
+        gmt_offset: field[16] as i8,
+    })
+}
+
+// Directory records use a compact 7-byte form: years since 1900, month, day,
+// hour, minute, second and the same GMT offset byte.
+pub fn parse_record_datetime(field: &[u8]) -> Option<IsoDateTime> {
+    if field[..6].iter().all(|&b| b == 0) {
+        return None;
+    }
+
+    Some(IsoDateTime {
+        year: 1900 + field[0] as u16,
+        month: field[1],
+        day: field[2],
+        hour: field[3],
+        minute: field[4],
+        second: field[5],
+        gmt_offset: field[6] as i8,
+    })
+}
+
+// Numeric fields are stored twice, little-endian first and then big-endian.
+// Some mastering tools get the big-endian half wrong, so only the
+// little-endian half is trusted.
+fn both_endian_u16(field: &[u8]) -> u16 {
+    u16::from_le_bytes([field[0], field[1]])
+}
+
+pub fn both_endian_u32(field: &[u8]) -> u32 {
+    u32::from_le_bytes([field[0], field[1], field[2], field[3]])
+}
+
+// Identifier fields are padded with spaces; some tools pad with NULs.
+fn text(field: &[u8]) -> String {
+    String::from_utf8_lossy(field)
+        .trim_end_matches([' ', '\0'])
+        .to_string()
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::iso::testiso::IsoBuilder;
+
+    fn read(iso: IsoBuilder) -> io::Result<VolumeDescriptors> {
+        read_volume_descriptors(&mut Cursor::new(iso.build()))
+    }
+
+    #[test]
+    fn parses_the_primary_descriptor() {
+        let mut iso = IsoBuilder::new();
+        let primary = iso.primary(16, "UBUNTU 24_04 AMD64", 20, 4096);
+        primary[8..13].copy_from_slice(b"LINUX");
+        // Only the little-endian half of a number counts; this big-endian
+        // half is wrong, as some mastering tools write it.
+        primary[84..88].copy_from_slice(&7u32.to_be_bytes());
+        primary[318..446].fill(b' ');
+        primary[318..325].copy_from_slice(b"ACME CO");
+        primary[446..454].copy_from_slice(b"PREPARER");
+        primary[574..585].copy_from_slice(b"XORRISO 1.5");
+        primary[813..829].copy_from_slice(b"2024042311300500");
+        primary[829] = (-20i8) as u8;
+        primary[830..846].copy_from_slice(b"0000000000000000");
+        iso.terminator(17);
+        iso.sector_mut(21);
+
+        let primary = read(iso).unwrap().primary;
+        assert_eq!(primary.system_id, "LINUX");
+        assert_eq!(primary.volume_id, "UBUNTU 24_04 AMD64");
+        assert_eq!(primary.publisher_id, "ACME CO");
+        assert_eq!(primary.data_preparer_id, "PREPARER");
+        assert_eq!(primary.application_id, "XORRISO 1.5");
+        assert_eq!(primary.volume_space_size, 22);
+        assert_eq!(primary.logical_block_size, 2048);
+        assert_eq!(primary.volume_size(), 22 * 2048);
+        assert_eq!(primary.root_directory.sector, 20);
+        assert_eq!(primary.root_directory.size, 4096);
+        let created = primary.creation_date.unwrap();
+        assert_eq!(created.to_string(), "2024-04-23 11:30:05 UTC-05:00");
+        assert!(primary.modification_date.is_none());
+    }
+
+    #[test]
+    fn reads_dates_with_their_offset_from_gmt() {
+        let mut field = *b"19991231235959000";
+        field[16] = 52;
+        assert_eq!(parse_dec_datetime(&field).unwrap().to_string(), "1999-12-31 23:59:59 UTC+13:00");
+        assert!(parse_dec_datetime(&[0; 17]).is_none());
+        assert!(parse_dec_datetime(b"2024-04-23 11:30\0").is_none());
+    }
+
+    #[test]
+    fn rejects_what_is_no_iso_9660_image() {
+        let error = |iso: IsoBuilder| read(iso).err().unwrap().to_string();
+
+        assert_eq!(error(IsoBuilder::new()), "The file is too small to be an ISO 9660 image");
+
+        let mut iso = IsoBuilder::new();
+        iso.sector_mut(16)[1..6].copy_from_slice(b"CD002");
+        assert_eq!(error(iso), "Not an ISO 9660 image (no CD001 volume descriptor)");
+
+        let mut iso = IsoBuilder::new();
+        iso.terminator(16);
+        assert_eq!(error(iso), "The image has no primary volume descriptor");
+
+        let mut iso = IsoBuilder::new();
+        iso.primary(16, "BROKEN", 18, 2048)[128..130].copy_from_slice(&3000u16.to_le_bytes());
+        iso.terminator(17);
+        assert_eq!(error(iso), "The primary volume descriptor has an invalid block size");
+
+        // Descriptors that go on and on without a terminator.
+        let mut iso = IsoBuilder::new();
+        iso.primary(16, "ENDLESS", 18, 2048);
+        for sector in 17..17 + MAX_DESCRIPTORS as u32 {
+            iso.descriptor(sector, 3);
+        }
+        assert_eq!(error(iso), "The volume descriptor set has no terminator");
+    }
+}
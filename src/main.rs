@@ -1,12 +1,43 @@
 use std::fs::File;
 use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use eframe::egui;
 use rfd::FileDialog;
-use winapi::um::fileapi::{GetLogicalDrives, GetDriveTypeA};
-use std::ffi::CStr;
+
+mod bmap;
+mod checksum;
+mod compression;
+mod extract;
+mod filesystem;
+mod iso;
+mod partition;
+mod platform;
+mod sparse;
+#[cfg(test)]
+mod testutil;
+mod vdisk;
+mod wim;
+mod writer;
+mod zip;
+
+use checksum::{Checksum, HashAlgorithm};
+use filesystem::{FatVolume, FileSystem, FormatOptions, Timestamp, VolumeWriter};
+use partition::{PartitionScheme, PartitionTable};
+use platform::{DriveInfo, DriveProvider};
+use writer::{WriteMode, WritePhase};
 
 fn main() -> Result<(), eframe::Error> {
+    // `split-wim` runs on its own, without opening a window.
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("split-wim") {
+        if let Err(e) = split_wim_command(&args[2..]) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([700.0, 600.0]),
@@ -20,70 +51,860 @@ fn main() -> Result<(), eframe::Error> {
     )
 }
 
+// split-wim <file.wim> [output directory] [part size in MiB]
+fn split_wim_command(args: &[String]) -> Result<(), String> {
+    let usage = "Usage: ayumi_usb split-wim <file.wim> [output directory] [part size in MiB]";
+    let source = Path::new(args.first().ok_or(usage)?);
+    let destination = match args.get(1) {
+        Some(destination) => Path::new(destination),
+        None => source.parent().unwrap_or(Path::new(".")),
+    };
+    let part_size = match args.get(2) {
+        Some(size) => size.parse::<u64>().map_err(|_| usage.to_string())? * 1024 * 1024,
+        None => wim::DEFAULT_PART_SIZE,
+    };
+    if part_size == 0 || part_size > FileSystem::Fat32.max_file_size() {
+        return Err("The part size must be between 1 and 4095 MiB".to_string());
+    }
+
+    let mut file = File::open(source).map_err(|e| format!("Cannot open {}: {}", source.display(), e))?;
+    let total_size = file.metadata().map_err(|e| e.to_string())?.len();
+    let wim = wim::read_wim(&mut file).map_err(|e| format!("{}: {}", source.display(), e))?;
+    for image in &wim.images {
+        println!("Image {}: {}", image.index, image.name);
+    }
+
+    let mut copied = 0u64;
+    let mut shown = 0;
+    let parts = wim::split_file(source, destination, part_size, &mut |written| {
+        copied += written;
+        let percent = (copied * 100 / total_size.max(1)).min(100);
+        if percent != shown {
+            shown = percent;
+            eprint!("\r{}%", percent);
+        }
+    })
+    .map_err(|e| format!("Cannot split {}: {}", source.display(), e))?;
+    eprintln!();
+
+    for part in parts {
+        println!("{}", part.display());
+    }
+    Ok(())
+}
+
+// The hash last computed and the image it was computed for.
+type ComputedHash = (String, Result<Checksum, String>);
+
 struct AyumiApp {
+    provider: Arc<dyn DriveProvider>,
     iso_path: String,
-    usb_drives: Vec<String>,
-    selected_drive: Option<String>,
+    // The path the image details below were read from. Typing in the path
+    // field only reloads them once editing ends, with Enter or by leaving
+    // the field, so no half-typed path gets probed.
+    loaded_path: String,
+    image_info: Option<Result<iso::ImageInfo, String>>,
+    // Set instead of `image_info` for compressed disk images.
+    compressed_image: Option<compression::CompressedImage>,
+    // And for Android sparse images.
+    sparse_image: Option<sparse::SparseHeader>,
+    // And for virtual machine disks.
+    virtual_disk: Option<Result<vdisk::VirtualDisk, String>>,
+    // Likewise for ZIP archives: the disk images inside and the one to write.
+    zip_entries: Option<Result<Vec<zip::ZipEntry>, String>>,
+    zip_entry: Option<String>,
+    usb_drives: Vec<DriveInfo>,
+    selected_drive: Option<DriveInfo>,
+    partition_table: Option<Result<Option<PartitionTable>, String>>,
+    custom_target: String,
+    write_mode: WriteMode,
+    format_options: FormatOptions,
+    serial_text: String,
+    verify_after_write: bool,
+    skip_zero_blocks: bool,
+    bmap: Option<(PathBuf, Result<Arc<bmap::Bmap>, String>)>,
+    checksum_text: String,
+    // A checksum file found next to the image that lists it.
+    nearby_checksum: Option<(PathBuf, Checksum)>,
+    hash_progress: Arc<Mutex<f32>>,
+    is_hashing: Arc<Mutex<bool>>,
+    computed_hash: Arc<Mutex<Option<ComputedHash>>>,
     burning_progress: Arc<Mutex<f32>>,
+    burn_phase: Arc<Mutex<WritePhase>>,
     is_burning: Arc<Mutex<bool>>,
     burn_error: Arc<Mutex<Option<String>>>,
+    // How much a finished raw write wrote and skipped.
+    burn_summary: Arc<Mutex<Option<String>>>,
 }
 
 impl Default for AyumiApp {
     fn default() -> Self {
+        let provider = platform::default_provider();
+
         Self {
+            usb_drives: provider.list_drives(),
+            provider,
             iso_path: String::new(),
-            usb_drives: Self::get_usb_drives(),
+            loaded_path: String::new(),
+            image_info: None,
+            compressed_image: None,
+            sparse_image: None,
+            virtual_disk: None,
+            zip_entries: None,
+            zip_entry: None,
             selected_drive: None,
+            partition_table: None,
+            custom_target: String::new(),
+            write_mode: WriteMode::Extract,
+            format_options: FormatOptions::default(),
+            serial_text: String::new(),
+            verify_after_write: true,
+            skip_zero_blocks: false,
+            bmap: None,
+            checksum_text: String::new(),
+            nearby_checksum: None,
+            hash_progress: Arc::new(Mutex::new(0.0)),
+            is_hashing: Arc::new(Mutex::new(false)),
+            computed_hash: Arc::new(Mutex::new(None)),
             burning_progress: Arc::new(Mutex::new(0.0)),
+            burn_phase: Arc::new(Mutex::new(WritePhase::Writing)),
             is_burning: Arc::new(Mutex::new(false)),
             burn_error: Arc::new(Mutex::new(None)),
+            burn_summary: Arc::new(Mutex::new(None)),
         }
     }
 }
 
 impl AyumiApp {
-    fn get_usb_drives() -> Vec<String> {
-        let mut drives = Vec::new();
-        let drive_bits = unsafe { GetLogicalDrives() };
+    fn load_image_info(&mut self) {
+        let path = Path::new(&self.iso_path);
+        let is_zip = File::open(path).and_then(|mut file| zip::is_zip(&mut file)).unwrap_or(false);
+        self.zip_entries = is_zip.then(|| {
+            File::open(path)
+                .and_then(|mut file| zip::read_entries(&mut file))
+                .map(|entries| entries.into_iter().filter(zip::ZipEntry::is_disk_image).collect::<Vec<_>>())
+                .map_err(|e| e.to_string())
+        });
+        self.zip_entry = match &self.zip_entries {
+            Some(Ok(entries)) => entries.first().map(|entry| entry.name.clone()),
+            _ => None,
+        };
+        if is_zip {
+            self.compressed_image = None;
+            self.sparse_image = None;
+            self.virtual_disk = None;
+            self.image_info = None;
+            self.write_mode = WriteMode::RawImage;
+            return;
+        }
+
+        // Virtual disks are read through their own tables, and written raw
+        // as the disk they hold.
+        self.virtual_disk = vdisk::probe(Path::new(&self.iso_path)).map_err(|e| e.to_string()).transpose();
+        if self.virtual_disk.is_some() {
+            self.compressed_image = None;
+            self.sparse_image = None;
+            self.image_info = None;
+            self.write_mode = WriteMode::RawImage;
+            return;
+        }
+
+        // Compressed images are disk images, never ISOs: raw mode is the
+        // only way to write them.
+        self.compressed_image = compression::probe(Path::new(&self.iso_path)).ok().flatten();
+        self.sparse_image = None;
+        if self.compressed_image.is_some() {
+            self.image_info = None;
+            self.write_mode = WriteMode::RawImage;
+            return;
+        }
+
+        // Likewise Android sparse images, which only make sense expanded.
+        self.sparse_image = sparse::probe(Path::new(&self.iso_path)).ok().flatten();
+        if self.sparse_image.is_some() {
+            self.image_info = None;
+            self.write_mode = WriteMode::RawImage;
+            return;
+        }
+
+        self.image_info = if self.iso_path.is_empty() {
+            None
+        } else {
+            Some(iso::read_image_info(Path::new(&self.iso_path)))
+        };
+
+        // Default to what suits the image; the user can still override it.
+        if let Some(Ok(info)) = &self.image_info {
+            self.write_mode = WriteMode::recommended_for(info);
+            self.format_options.label = info.primary.volume_id.clone();
+            // FAT32 boots on more firmware, but only exFAT holds files over 4 GiB.
+            // A large install.wim does not count: it gets split for FAT32.
+            let needs_exfat = info.contents.as_ref().is_ok_and(|listing| {
+                listing.entries.iter().any(|entry| {
+                    !entry.is_dir && entry.size > FileSystem::Fat32.max_file_size() && !wim::is_wim(&entry.path)
+                })
+            });
+            self.format_options.file_system = if needs_exfat { FileSystem::Exfat } else { FileSystem::Fat32 };
+        }
+    }
+
+    fn recommended_mode(&self) -> Option<WriteMode> {
+        match &self.image_info {
+            Some(Ok(info)) => Some(WriteMode::recommended_for(info)),
+            _ if self.compressed_image.is_some()
+                || self.sparse_image.is_some()
+                || self.virtual_disk.is_some()
+                || self.zip_entries.is_some() =>
+            {
+                Some(WriteMode::RawImage)
+            }
+            _ => None,
+        }
+    }
+
+    fn show_zip_entries(&mut self, ui: &mut egui::Ui) {
+        let entries = match &self.zip_entries {
+            Some(Ok(entries)) if entries.is_empty() => {
+                ui.colored_label(
+                    egui::Color32::LIGHT_RED,
+                    "The archive holds no disk image (.iso, .img, .simg, .raw or .bin).",
+                );
+                return;
+            }
+            Some(Ok(entries)) => entries,
+            Some(Err(e)) => {
+                ui.colored_label(egui::Color32::LIGHT_RED, format!("Cannot read archive: {}", e));
+                return;
+            }
+            None => return,
+        };
+
+        egui::Grid::new("zip_entry").num_columns(2).show(ui, |ui| {
+            ui.label("Image in archive:");
+            egui::ComboBox::from_id_salt("zip_entry")
+                .selected_text(self.zip_entry.clone().unwrap_or_default())
+                .show_ui(ui, |ui| {
+                    for entry in entries {
+                        ui.selectable_value(&mut self.zip_entry, Some(entry.name.clone()), &entry.name);
+                    }
+                });
+            ui.end_row();
+
+            if let Some(entry) = entries.iter().find(|entry| Some(&entry.name) == self.zip_entry.as_ref()) {
+                ui.label("Image size:");
+                ui.label(platform::format_size(entry.size));
+                ui.end_row();
+
+                ui.label("Stored as:");
+                ui.label(format!("{}, {}", entry.method_label(), platform::format_size(entry.compressed_size)));
+                ui.end_row();
+            }
+        });
+    }
+
+    fn show_compressed_image(ui: &mut egui::Ui, image: &compression::CompressedImage) {
+        egui::Grid::new("compressed_image").num_columns(2).show(ui, |ui| {
+            ui.label("Compression:");
+            ui.label(image.compression.label());
+            ui.end_row();
+
+            ui.label("Compressed size:");
+            ui.label(platform::format_size(image.compressed_size));
+            ui.end_row();
+
+            ui.label("Image size:");
+            ui.label(image.size.map_or_else(
+                || format!("not recorded by {}", image.compression.label()),
+                platform::format_size,
+            ));
+            ui.end_row();
+        });
+    }
+
+    fn show_sparse_image(ui: &mut egui::Ui, header: &sparse::SparseHeader) {
+        egui::Grid::new("sparse_image").num_columns(2).show(ui, |ui| {
+            ui.label("Format:");
+            ui.label("Android sparse image");
+            ui.end_row();
+
+            ui.label("Blocks:");
+            ui.label(format!(
+                "{} of {}, in {} chunks",
+                header.total_blocks,
+                platform::format_size(header.block_size as u64),
+                header.total_chunks
+            ));
+            ui.end_row();
+
+            ui.label("Image size:");
+            ui.label(platform::format_size(header.image_size()));
+            ui.end_row();
+        });
+        ui.label("Blocks the image leaves out are not written, as with fastboot.");
+    }
+
+    fn show_virtual_disk(ui: &mut egui::Ui, disk: &Result<vdisk::VirtualDisk, String>) {
+        let disk = match disk {
+            Ok(disk) => disk,
+            Err(e) => {
+                ui.colored_label(egui::Color32::LIGHT_RED, format!("Cannot read virtual disk: {}", e));
+                return;
+            }
+        };
+        egui::Grid::new("virtual_disk").num_columns(2).show(ui, |ui| {
+            ui.label("Format:");
+            ui.label(disk.format.label());
+            ui.end_row();
+
+            ui.label("Disk size:");
+            ui.label(platform::format_size(disk.size));
+            ui.end_row();
+
+            ui.label("File size:");
+            ui.label(platform::format_size(disk.file_size));
+            ui.end_row();
+        });
+    }
+
+    fn show_image_details(ui: &mut egui::Ui, info: &iso::ImageInfo) {
+        let pvd = &info.primary;
+        let or_dash = |value: &str| {
+            if value.is_empty() {
+                "—".to_string()
+            } else {
+                value.to_string()
+            }
+        };
+
+        egui::Grid::new("image_details").num_columns(2).show(ui, |ui| {
+            ui.label("Volume ID:");
+            ui.label(or_dash(&pvd.volume_id));
+            ui.end_row();
+
+            ui.label("System ID:");
+            ui.label(or_dash(&pvd.system_id));
+            ui.end_row();
+
+            ui.label("Publisher:");
+            ui.label(or_dash(&pvd.publisher_id));
+            ui.end_row();
+
+            ui.label("Data preparer:");
+            ui.label(or_dash(&pvd.data_preparer_id));
+            ui.end_row();
+
+            ui.label("Application:");
+            ui.label(or_dash(&pvd.application_id));
+            ui.end_row();
+
+            ui.label("Created:");
+            ui.label(pvd.creation_date.as_ref().map_or("—".to_string(), |d| d.to_string()));
+            ui.end_row();
+
+            ui.label("Modified:");
+            ui.label(pvd.modification_date.as_ref().map_or("—".to_string(), |d| d.to_string()));
+            ui.end_row();
+
+            ui.label("Volume size:");
+            ui.label(format!(
+                "{} ({} blocks of {} bytes)",
+                platform::format_size(pvd.volume_size()),
+                pvd.volume_space_size,
+                pvd.logical_block_size
+            ));
+            ui.end_row();
+
+            ui.label("Boots on:");
+            ui.label(info.firmware_label());
+            ui.end_row();
+
+            ui.label("Layout:");
+            ui.label(info.hybrid.description());
+            ui.end_row();
+
+            if let Some(udf) = &info.udf {
+                ui.label("UDF volume:");
+                ui.label(format!("{} (UDF {})", or_dash(&udf.volume_id), udf.revision_label()));
+                ui.end_row();
+            }
+        });
+
+        match &info.boot_catalog {
+            Some(Ok(catalog)) => Self::show_boot_catalog(ui, catalog),
+            Some(Err(e)) => {
+                ui.colored_label(egui::Color32::LIGHT_RED, format!("Cannot read boot catalog: {}", e));
+            }
+            None => {}
+        }
+
+        match &info.contents {
+            Ok(listing) => Self::show_contents(ui, listing),
+            Err(e) => {
+                ui.colored_label(egui::Color32::LIGHT_RED, format!("Cannot list files: {}", e));
+            }
+        }
+    }
+
+    fn show_contents(ui: &mut egui::Ui, listing: &iso::Listing) {
+        let title = format!(
+            "Contents: {} entries, {} ({} names)",
+            listing.entries.len(),
+            platform::format_size(listing.total_file_size()),
+            listing.scheme.label()
+        );
+
+        egui::CollapsingHeader::new(title).id_salt("image_contents").show(ui, |ui| {
+            let row_height = ui.text_style_height(&egui::TextStyle::Body);
+            egui::ScrollArea::vertical()
+                .max_height(200.0)
+                .show_rows(ui, row_height, listing.entries.len(), |ui, rows| {
+                    egui::Grid::new("contents_grid").num_columns(5).striped(true).show(ui, |ui| {
+                        for entry in &listing.entries[rows] {
+                            match &entry.symlink {
+                                Some(target) => ui.label(format!("{} → {}", entry.path, target)),
+                                None => ui.label(&entry.path),
+                            };
+                            ui.label(if entry.is_dir {
+                                "<dir>".to_string()
+                            } else {
+                                platform::format_size(entry.size)
+                            });
+                            ui.label(entry.modified.as_ref().map_or(String::new(), |d| d.to_string()));
+                            ui.monospace(entry.permissions().unwrap_or_default());
+                            ui.label(match (entry.extents.iter().find_map(|extent| extent.offset), entry.extents.len()) {
+                                (None, _) => String::new(),
+                                (Some(offset), 1) => format!("LBA {}", offset / iso::SECTOR_SIZE),
+                                (Some(offset), count) => {
+                                    format!("LBA {} (+{} extents)", offset / iso::SECTOR_SIZE, count - 1)
+                                }
+                            });
+                            ui.end_row();
+                        }
+                    });
+                });
+        });
+    }
+
+    fn show_boot_catalog(ui: &mut egui::Ui, catalog: &iso::BootCatalog) {
+        egui::CollapsingHeader::new("El Torito boot catalog").show(ui, |ui| {
+            ui.label(format!(
+                "Validation entry: platform {}, ID \"{}\"",
+                catalog.validation.platform, catalog.validation.id
+            ));
+
+            egui::Grid::new("boot_entries").num_columns(6).striped(true).show(ui, |ui| {
+                for header in ["Entry", "Platform", "Bootable", "Emulation", "Load segment", "Image"] {
+                    ui.strong(header);
+                }
+                ui.end_row();
+
+                for (index, entry) in catalog.entries.iter().enumerate() {
+                    ui.label(if entry.is_default {
+                        "default".to_string()
+                    } else {
+                        format!("section {}", index)
+                    });
+                    ui.label(entry.platform.to_string());
+                    ui.label(if entry.bootable { "yes" } else { "no" });
+                    ui.label(entry.emulation.to_string());
+                    ui.label(format!("0x{:04X}", entry.load_segment));
+                    ui.label(format!("{} sectors at LBA {}", entry.sector_count, entry.load_rba));
+                    ui.end_row();
+                }
+            });
+        });
+    }
+
+    fn read_partition_table(&mut self) {
+        self.partition_table = self.selected_drive.as_ref().map(|drive| {
+            let mut target = self
+                .provider
+                .open_target(drive)
+                .map_err(|e| format!("Cannot open {}: {}", drive.device, e))?;
+            partition::disk_size(target.as_mut())
+                .and_then(|disk_size| partition::read_partition_table(target.as_mut(), disk_size))
+                .map_err(|e| e.to_string())
+        });
+    }
+
+    fn show_partition_table(ui: &mut egui::Ui, table: &PartitionTable) {
+        let rows: Vec<[String; 4]> = match table {
+            PartitionTable::Mbr(mbr) => {
+                ui.label(format!(
+                    "MBR, disk signature {:08X}, {}",
+                    mbr.disk_signature,
+                    if mbr.has_boot_code() { "with boot code" } else { "no boot code" }
+                ));
+                mbr.partitions
+                    .iter()
+                    .map(|partition| {
+                        [
+                            format!(
+                                "0x{:02X} {}",
+                                partition.partition_type,
+                                partition::partition_type_name(partition.partition_type)
+                            ),
+                            if partition.active { "active".to_string() } else { String::new() },
+                            format!("LBA {}", partition.start_lba),
+                            platform::format_size(partition.size()),
+                        ]
+                    })
+                    .collect()
+            }
+            PartitionTable::Gpt(gpt) => {
+                ui.label(format!("GPT, disk GUID {}", gpt.disk_guid));
+                if gpt.from_backup {
+                    ui.colored_label(egui::Color32::YELLOW, "The primary table is damaged; showing the backup.");
+                }
+                gpt.partitions
+                    .iter()
+                    .map(|partition| {
+                        [
+                            partition::type_name(&partition.type_guid).to_string(),
+                            partition.name.clone(),
+                            format!("LBA {}", partition.first_lba),
+                            platform::format_size(partition.size()),
+                        ]
+                    })
+                    .collect()
+            }
+        };
+        if rows.is_empty() {
+            ui.label("No partitions.");
+            return;
+        }
+
+        egui::Grid::new("partition_table").num_columns(5).striped(true).show(ui, |ui| {
+            for header in ["#", "Type", "Details", "Start", "Size"] {
+                ui.strong(header);
+            }
+            ui.end_row();
+
+            for (index, row) in rows.iter().enumerate() {
+                ui.label((index + 1).to_string());
+                for cell in row {
+                    ui.label(cell);
+                }
+                ui.end_row();
+            }
+        });
+    }
+
+    fn show_format_options(&mut self, ui: &mut egui::Ui) {
+        let cluster_label = |size: Option<u32>| match size {
+            None => "Default".to_string(),
+            Some(size) if size < 1024 => format!("{} bytes", size),
+            Some(size) if size < 1024 * 1024 => format!("{} KiB", size / 1024),
+            Some(size) => format!("{} MiB", size / (1024 * 1024)),
+        };
+
+        egui::CollapsingHeader::new("Format options").id_salt("format_options").show(ui, |ui| {
+            egui::Grid::new("format_options_grid").num_columns(2).show(ui, |ui| {
+                ui.label("Partition scheme:");
+                ui.horizontal(|ui| {
+                    for scheme in [PartitionScheme::Mbr, PartitionScheme::Gpt] {
+                        ui.radio_value(&mut self.format_options.partition_scheme, scheme, scheme.label());
+                    }
+                });
+                ui.end_row();
+
+                ui.label("File system:");
+                egui::ComboBox::from_id_salt("file_system")
+                    .selected_text(self.format_options.file_system.label())
+                    .show_ui(ui, |ui| {
+                        for file_system in [FileSystem::Fat32, FileSystem::Exfat, FileSystem::Ntfs] {
+                            ui.selectable_value(&mut self.format_options.file_system, file_system, file_system.label());
+                        }
+                    });
+                ui.end_row();
+
+                ui.label("Volume label:");
+                ui.text_edit_singleline(&mut self.format_options.label);
+                ui.end_row();
+
+                ui.label("Cluster size:");
+                egui::ComboBox::from_id_salt("cluster_size")
+                    .selected_text(cluster_label(self.format_options.cluster_size))
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.format_options.cluster_size, None, cluster_label(None));
+                        let max_cluster_size = self.format_options.file_system.max_cluster_size();
+                        for size in (9..=25).map(|shift| 1 << shift).take_while(|&size| size <= max_cluster_size) {
+                            let size = Some(size);
+                            ui.selectable_value(&mut self.format_options.cluster_size, size, cluster_label(size));
+                        }
+                    });
+                ui.end_row();
+
+                ui.label("Serial number:");
+                ui.add(egui::TextEdit::singleline(&mut self.serial_text).hint_text("random"));
+                ui.end_row();
+            });
+        });
+    }
+
+    fn load_bmap(&mut self, path: PathBuf) {
+        let result = bmap::read_bmap(&path).map(Arc::new).map_err(|e| e.to_string());
+        self.bmap = Some((path, result));
+    }
+
+    fn show_bmap(&mut self, ui: &mut egui::Ui) {
+        let mut pick = false;
+        let mut clear = false;
+        ui.horizontal(|ui| {
+            ui.label("Block map:");
+            match &self.bmap {
+                Some((path, Ok(bmap))) => {
+                    ui.label(format!(
+                        "{}, {} of {} blocks hold data ({})",
+                        path.file_name().unwrap_or_default().to_string_lossy(),
+                        bmap.ranges.iter().map(|range| range.last - range.first + 1).sum::<u64>(),
+                        bmap.blocks_count,
+                        platform::format_size(bmap.mapped_size())
+                    ));
+                }
+                Some((path, Err(e))) => {
+                    ui.colored_label(
+                        egui::Color32::LIGHT_RED,
+                        format!("{}: {}", path.file_name().unwrap_or_default().to_string_lossy(), e),
+                    );
+                }
+                None => {
+                    ui.label("none, the whole image is written");
+                }
+            }
+            pick = ui.button("📄 Pick").clicked();
+            clear = self.bmap.is_some() && ui.button("✖").clicked();
+        });
+
+        if pick {
+            if let Some(path) = FileDialog::new().add_filter("Block Maps", &["bmap"]).pick_file() {
+                self.load_bmap(path);
+            }
+        }
+        if clear {
+            self.bmap = None;
+        }
+    }
+
+    fn show_checksum(&mut self, ui: &mut egui::Ui) {
+        let is_hashing = *self.is_hashing.lock().unwrap();
+        let mut use_checksum_file = None;
+        ui.horizontal(|ui| {
+            ui.label("Expected hash:");
+            ui.add(
+                egui::TextEdit::singleline(&mut self.checksum_text)
+                    .hint_text("SHA-256, SHA-1 or MD5")
+                    .desired_width(360.0),
+            );
+
+            if ui.button("📄 Checksum file").clicked() {
+                if let Some(path) = FileDialog::new()
+                    .add_filter("Checksum Files", &["sha256", "sha1", "md5", "txt"])
+                    .add_filter("All Files", &["*"])
+                    .pick_file()
+                {
+                    use_checksum_file = Some(path);
+                }
+            }
 
-        for i in 0..26 {
-            if drive_bits & (1 << i) != 0 {
-                let drive_letter = format!("{}:\\", (b'A' + i as u8) as char);
-                let drive_type = unsafe { GetDriveTypeA(drive_letter.as_ptr() as *const i8) };
+            if ui.add_enabled(!is_hashing, egui::Button::new("🔐 Verify")).clicked() {
+                if let Err(e) = self.start_hashing() {
+                    rfd::MessageDialog::new()
+                        .set_title("Error")
+                        .set_description(&e)
+                        .show();
+                }
+            }
+        });
 
-                if drive_type == 2 {
-                    drives.push(drive_letter);
+        if let Some((path, expected)) = &self.nearby_checksum {
+            if self.checksum_text.trim() != expected.hex {
+                ui.horizontal(|ui| {
+                    let name = path.file_name().unwrap_or_default().to_string_lossy();
+                    ui.label(format!("{} next to the image lists its {} hash.", name, expected.algorithm.label()));
+                    if ui.add_enabled(!is_hashing, egui::Button::new("Use it")).clicked() {
+                        use_checksum_file = Some(path.clone());
+                    }
+                });
+            }
+        }
+
+        // Picking a checksum file verifies the image against it right away.
+        if let Some(path) = use_checksum_file {
+            let image_name = Path::new(&self.iso_path).file_name().unwrap_or_default().to_string_lossy();
+            let result = checksum::read_checksum_file(&path, &image_name).and_then(|expected| {
+                self.checksum_text = expected.hex;
+                if is_hashing {
+                    Ok(())
+                } else {
+                    self.start_hashing()
                 }
+            });
+            if let Err(e) = result {
+                rfd::MessageDialog::new()
+                    .set_title("Error")
+                    .set_description(&e)
+                    .show();
             }
         }
 
-        drives
+        if *self.is_hashing.lock().unwrap() {
+            let progress = *self.hash_progress.lock().unwrap();
+            ui.add(egui::ProgressBar::new(progress).show_percentage());
+        }
+
+        let computed_hash = self.computed_hash.lock().unwrap();
+        match computed_hash.as_ref().filter(|(path, _)| *path == self.iso_path) {
+            Some((_, Ok(actual))) => {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{}:", actual.algorithm.label()));
+                    ui.monospace(&actual.hex);
+                });
+                if self.checksum_text.trim().is_empty() {
+                    return;
+                }
+                match checksum::parse_checksum(&self.checksum_text) {
+                    Ok(expected) if expected.algorithm != actual.algorithm => {
+                        ui.colored_label(
+                            egui::Color32::YELLOW,
+                            format!("Verify again to compare the {} hash.", expected.algorithm.label()),
+                        );
+                    }
+                    Ok(expected) if expected.hex == actual.hex => {
+                        ui.colored_label(egui::Color32::GREEN, "✔ The image matches the expected hash.");
+                    }
+                    Ok(_) => {
+                        ui.colored_label(
+                            egui::Color32::LIGHT_RED,
+                            "✖ The image does not match the expected hash; it is damaged or not the one published.",
+                        );
+                    }
+                    Err(e) => {
+                        ui.colored_label(egui::Color32::LIGHT_RED, e);
+                    }
+                }
+            }
+            Some((_, Err(e))) => {
+                ui.colored_label(egui::Color32::LIGHT_RED, format!("Cannot hash image: {}", e));
+            }
+            None => {}
+        }
+    }
+
+    // Hashes the image with the algorithm of the expected hash, or SHA-256
+    // when there is none to compare against yet.
+    fn start_hashing(&self) -> Result<(), String> {
+        if self.iso_path.is_empty() {
+            return Err("Please select an ISO file.".to_string());
+        }
+        let algorithm = if self.checksum_text.trim().is_empty() {
+            HashAlgorithm::Sha256
+        } else {
+            checksum::parse_checksum(&self.checksum_text)?.algorithm
+        };
+
+        let iso_path = self.iso_path.clone();
+        let progress = Arc::clone(&self.hash_progress);
+        let is_hashing = Arc::clone(&self.is_hashing);
+        let computed_hash = Arc::clone(&self.computed_hash);
+        *is_hashing.lock().unwrap() = true;
+        *progress.lock().unwrap() = 0.0;
+        *computed_hash.lock().unwrap() = None;
+
+        std::thread::spawn(move || {
+            let result = checksum::hash_file(Path::new(&iso_path), algorithm, &progress).map_err(|e| e.to_string());
+            *computed_hash.lock().unwrap() = Some((iso_path, result));
+            *is_hashing.lock().unwrap() = false;
+        });
+
+        Ok(())
     }
 
     fn copy_iso(&self) -> Result<(), String> {
         if self.iso_path.is_empty() {
             return Err("Please select an ISO file.".to_string());
         }
+        if self.compressed_image.is_some() && self.write_mode != WriteMode::RawImage {
+            return Err("Compressed images can only be written in raw mode.".to_string());
+        }
+        if self.sparse_image.is_some() && self.write_mode != WriteMode::RawImage {
+            return Err("Android sparse images can only be written in raw mode.".to_string());
+        }
+        match &self.virtual_disk {
+            Some(Err(e)) => return Err(format!("Cannot read the virtual disk: {}", e)),
+            Some(Ok(_)) if self.write_mode != WriteMode::RawImage => {
+                return Err("Virtual disks can only be written in raw mode.".to_string());
+            }
+            _ => {}
+        }
+        if self.zip_entries.is_some() {
+            // Copying would put the archive itself on the volume, not the
+            // image picked inside it.
+            if self.write_mode != WriteMode::RawImage {
+                return Err("Images inside a ZIP archive can only be written in raw mode.".to_string());
+            }
+            if self.zip_entry.is_none() {
+                return Err("Please pick an image inside the archive.".to_string());
+            }
+        }
+        match self.computed_hash.lock().unwrap().as_ref() {
+            Some((path, Ok(actual))) if *path == self.iso_path => check_checksum(&self.checksum_text, Some(actual))?,
+            _ => check_checksum(&self.checksum_text, None)?,
+        }
     
         if let Some(drive) = &self.selected_drive {
-            let drive_path = drive.split(' ').next().unwrap_or("");
-            let source = std::path::Path::new(&self.iso_path);
-            let destination = std::path::Path::new(drive_path).join(source.file_name().unwrap());
-    
-            // Clone iso_path and selected_drive for use in the thread
+            // Clone what the thread needs
             let iso_path = self.iso_path.clone();
-            let selected_drive = self.selected_drive.clone();
+            let drive = drive.clone();
+            let write_mode = self.write_mode;
+            let bmap = match &self.bmap {
+                Some((_, Ok(bmap))) => Some(Arc::clone(bmap)),
+                Some((_, Err(e))) if write_mode == WriteMode::RawImage => {
+                    return Err(format!("Cannot use the block map: {}", e));
+                }
+                _ => None,
+            };
+            let raw_options = writer::RawOptions {
+                zip_entry: self.zip_entry.clone(),
+                bmap,
+                verify: self.verify_after_write,
+                skip_zeros: self.skip_zero_blocks,
+            };
+            let format_options = FormatOptions {
+                serial: filesystem::parse_serial(&self.serial_text)?,
+                ..self.format_options.clone()
+            };
+            let provider = Arc::clone(&self.provider);
     
             let progress = Arc::clone(&self.burning_progress);
+            let phase = Arc::clone(&self.burn_phase);
             let is_burning = Arc::clone(&self.is_burning);
             let burn_error = Arc::clone(&self.burn_error);
+            let burn_summary = Arc::clone(&self.burn_summary);
     
             std::thread::spawn(move || {
                 *is_burning.lock().unwrap() = true;
                 *burn_error.lock().unwrap() = None;
+                *burn_summary.lock().unwrap() = None;
+                *phase.lock().unwrap() = WritePhase::Writing;
+                *progress.lock().unwrap() = 0.0;
     
-                if let Err(e) = copy_with_progress(&std::path::Path::new(&iso_path), &destination, &progress) {
+                let source = Path::new(&iso_path);
+                let result = match write_mode {
+                    WriteMode::CopyFile => copy_to_volume(source, &drive, provider.as_ref(), &progress),
+                    WriteMode::Extract => provider
+                        .open_target(&drive)
+                        .map_err(|e| format!("Cannot open {}: {}", drive.device, e))
+                        .and_then(|mut target| extract::extract_to_target(source, target.as_mut(), &format_options, &progress)),
+                    WriteMode::RawImage => provider
+                        .open_target(&drive)
+                        .map_err(|e| format!("Cannot open {}: {}", drive.device, e))
+                        .and_then(|mut target| {
+                            writer::write_image_to_target(source, target.as_mut(), &raw_options, &phase, &progress)
+                        })
+                        .map(|summary| *burn_summary.lock().unwrap() = Some(summary.describe())),
+                };
+                if let Err(e) = result {
                     *burn_error.lock().unwrap() = Some(e);
                 }
     
@@ -97,35 +918,97 @@ impl AyumiApp {
     }
 }
 
-fn copy_with_progress(
-    source: &std::path::Path,
-    destination: &std::path::Path,
+// Writing waits until an entered hash has been checked and matches, so a
+// damaged download never reaches the drive. `computed` is the hash taken of
+// the selected image, if any.
+fn check_checksum(expected_text: &str, computed: Option<&Checksum>) -> Result<(), String> {
+    if expected_text.trim().is_empty() {
+        return Ok(());
+    }
+    let expected = checksum::parse_checksum(expected_text)?;
+    match computed {
+        Some(actual) if actual.algorithm == expected.algorithm => {
+            if actual.hex == expected.hex {
+                Ok(())
+            } else {
+                Err("The ISO does not match the expected hash. Download it again.".to_string())
+            }
+        }
+        _ => Err("Please verify the ISO against the expected hash first.".to_string()),
+    }
+}
+
+// Drops the ISO as a plain file into the root of the drive's filesystem,
+// replacing an earlier copy. A FAT32 volume is written directly, so it does
+// not need to be mounted; exFAT, NTFS and anything else are written through
+// the filesystem the system mounted.
+fn copy_to_volume(
+    source: &Path,
+    drive: &DriveInfo,
+    provider: &dyn DriveProvider,
     progress: &Arc<Mutex<f32>>,
 ) -> Result<(), String> {
     let mut src_file = File::open(source).map_err(|e| e.to_string())?;
-    let mut dest_file = File::create(destination).map_err(|e| e.to_string())?;
+    let metadata = src_file.metadata().map_err(|e| e.to_string())?;
+    let total_size = metadata.len();
+    let modified = metadata.modified().ok().map(Timestamp::from_system_time);
+    let name = source.file_name().unwrap().to_string_lossy();
+
+    let mut target = provider
+        .open_target(drive)
+        .map_err(|e| format!("Cannot open {}: {}", drive.device, e))?;
+    let offset = partition::disk_size(target.as_mut())
+        .and_then(|disk_size| filesystem::find_volume(target.as_mut(), disk_size))
+        .map_err(|e| e.to_string())?;
+    let Some(offset) = offset else {
+        // Let go of the device first; on Windows it holds the volumes locked.
+        drop(target);
+        return copy_to_mount_point(&mut src_file, total_size, &name, drive, progress);
+    };
 
-    let total_size = src_file.metadata().map_err(|e| e.to_string())?.len();
-    let mut buffer = vec![0; 8192];
-    let mut copied = 0;
+    let mut volume = FatVolume::open(target.as_mut(), offset).map_err(|e| e.to_string())?;
+    volume.remove_file(&name).map_err(|e| e.to_string())?;
 
+    let mut copied = 0u64;
+    volume
+        .write_file(&name, total_size, modified, &mut src_file, &mut |written| {
+            copied += written;
+            if total_size > 0 {
+                *progress.lock().unwrap() = copied as f32 / total_size as f32;
+            }
+        })
+        .map_err(|e| e.to_string())?;
+    volume.finish().map_err(|e| e.to_string())?;
+
+    target.sync().map_err(|e| e.to_string())
+}
+
+fn copy_to_mount_point(
+    src_file: &mut File,
+    total_size: u64,
+    name: &str,
+    drive: &DriveInfo,
+    progress: &Arc<Mutex<f32>>,
+) -> Result<(), String> {
+    let mount_point = drive.mount_point.as_ref().ok_or_else(|| {
+        format!("{} holds no FAT32 volume and is not mounted; use ISO mode to format it", drive.device)
+    })?;
+    let mut dest_file = File::create(Path::new(mount_point).join(name)).map_err(|e| e.to_string())?;
+
+    let mut buffer = vec![0; 1024 * 1024];
+    let mut copied = 0u64;
     loop {
-        let bytes_read = src_file.read(&mut buffer).map_err(|e| e.to_string())?;
-        if bytes_read == 0 {
+        let read = src_file.read(&mut buffer).map_err(|e| e.to_string())?;
+        if read == 0 {
             break;
         }
-
-        dest_file
-            .write_all(&buffer[..bytes_read])
-            .map_err(|e| e.to_string())?;
-        copied += bytes_read as u64;
-
-        // Update progress
-        let mut progress_lock = progress.lock().unwrap();
-        *progress_lock = copied as f32 / total_size as f32;
+        dest_file.write_all(&buffer[..read]).map_err(|e| e.to_string())?;
+        copied += read as u64;
+        if total_size > 0 {
+            *progress.lock().unwrap() = copied as f32 / total_size as f32;
+        }
     }
-
-    Ok(())
+    dest_file.sync_all().map_err(|e| e.to_string())
 }
 
 impl eframe::App for AyumiApp {
@@ -134,39 +1017,210 @@ impl eframe::App for AyumiApp {
             ui.heading("🌸 AyumiISO for Windows 🌸");
 
             // ISO Selection
+            let mut iso_changed = false;
             ui.horizontal(|ui| {
                 ui.label("ISO File:");
-                ui.text_edit_singleline(&mut self.iso_path);
+                // A single-line field also loses focus on Enter.
+                iso_changed |=
+                    ui.text_edit_singleline(&mut self.iso_path).lost_focus() && self.iso_path != self.loaded_path;
 
                 if ui.button("Browse").clicked() {
                     if let Some(path) = FileDialog::new()
-                        .add_filter("ISO Files", &["iso"])
+                        .add_filter("Disk Images", &["iso", "img", "simg", "vhd", "vhdx", "qcow2", "vmdk", "xz", "gz", "bz2", "zst", "zip"])
                         .pick_file()
                     {
                         self.iso_path = path.display().to_string();
+                        iso_changed = true;
                     }
                 }
+
+                if let Some(Ok(info)) = &self.image_info {
+                    ui.strong(info.firmware_label());
+                }
             });
 
+            if iso_changed {
+                self.loaded_path = self.iso_path.clone();
+                self.load_image_info();
+                self.nearby_checksum = checksum::find_checksum_file(Path::new(&self.iso_path));
+                self.bmap = None;
+                if let Some(path) = bmap::find_bmap(Path::new(&self.iso_path)) {
+                    self.load_bmap(path);
+                }
+            }
+
+            // Image Details
+            if let Some(image) = &self.compressed_image {
+                ui.separator();
+                ui.heading("Image details");
+                Self::show_compressed_image(ui, image);
+            }
+
+            if let Some(disk) = &self.virtual_disk {
+                ui.separator();
+                ui.heading("Image details");
+                Self::show_virtual_disk(ui, disk);
+            }
+
+            if let Some(header) = &self.sparse_image {
+                ui.separator();
+                ui.heading("Image details");
+                Self::show_sparse_image(ui, header);
+            }
+
+            if self.zip_entries.is_some() {
+                ui.separator();
+                ui.heading("Image details");
+                self.show_zip_entries(ui);
+            }
+
+            if let Some(info) = &self.image_info {
+                ui.separator();
+                ui.heading("Image details");
+
+                match info {
+                    Ok(info) => Self::show_image_details(ui, info),
+                    Err(e) => {
+                        ui.colored_label(egui::Color32::LIGHT_RED, format!("Cannot read image: {}", e));
+                    }
+                }
+            }
+
+            // Checksum
+            if !self.iso_path.is_empty() {
+                ui.separator();
+                ui.heading("Checksum");
+                self.show_checksum(ui);
+            }
+
             // USB Drive Detection
             ui.separator();
             ui.heading("Available USB Drives:");
 
             if ui.button("🔄 Refresh USB Drives").clicked() {
-                self.usb_drives = Self::get_usb_drives();
+                self.usb_drives = self.provider.list_drives();
             }
 
             for drive in &self.usb_drives {
                 let is_selected = self.selected_drive.as_ref() == Some(drive);
-                let response = ui.add(egui::SelectableLabel::new(is_selected, drive));
+                let response = ui.add(egui::SelectableLabel::new(is_selected, drive.display_name()));
 
-                if response.clicked() {
+                if response.clicked() && !is_selected {
                     self.selected_drive = Some(drive.clone());
+                    self.partition_table = None;
                 }
             }
 
+            ui.horizontal(|ui| {
+                ui.label("Device or image file:");
+                ui.text_edit_singleline(&mut self.custom_target);
+
+                if ui.button("Use").clicked() && !self.custom_target.is_empty() {
+                    self.selected_drive = Some(DriveInfo::from_path(&self.custom_target));
+                    self.partition_table = None;
+                }
+
+                if ui.button("New image file").clicked() {
+                    if let Some(path) = FileDialog::new().add_filter("Disk Images", &["img"]).save_file() {
+                        self.custom_target = path.display().to_string();
+                        self.selected_drive = Some(DriveInfo::new_image(&self.custom_target));
+                        self.partition_table = None;
+                    }
+                }
+            });
+
             if let Some(drive) = &self.selected_drive {
-                ui.label(format!("Selected Drive: {}", drive));
+                let mut read_table = false;
+                ui.horizontal(|ui| {
+                    ui.label(format!("Selected Drive: {}", drive.display_name()));
+                    read_table = ui.button("🔍 Partition table").clicked();
+                });
+                if read_table {
+                    self.read_partition_table();
+                }
+            }
+
+            match &self.partition_table {
+                Some(Ok(Some(table))) => Self::show_partition_table(ui, table),
+                Some(Ok(None)) => {
+                    ui.label("The drive has no partition table.");
+                }
+                Some(Err(e)) => {
+                    ui.colored_label(egui::Color32::LIGHT_RED, format!("Cannot read partition table: {}", e));
+                }
+                None => {}
+            }
+
+            // Write Mode
+            ui.separator();
+            let recommended = self.recommended_mode();
+            ui.label("Write mode:");
+            for mode in [WriteMode::Extract, WriteMode::CopyFile, WriteMode::RawImage] {
+                let label = if recommended == Some(mode) {
+                    format!("{} (recommended)", mode.label())
+                } else {
+                    mode.label().to_string()
+                };
+                ui.radio_value(&mut self.write_mode, mode, label);
+            }
+
+            if let Some(recommended) = recommended.filter(|mode| *mode != self.write_mode) {
+                ui.colored_label(
+                    egui::Color32::YELLOW,
+                    format!(
+                        "This image probably won't boot this way; \"{}\" is recommended.",
+                        recommended.label()
+                    ),
+                );
+            }
+
+            if self.write_mode == WriteMode::Extract {
+                self.show_format_options(ui);
+            }
+
+            if self.write_mode == WriteMode::RawImage {
+                self.show_bmap(ui);
+                ui.checkbox(&mut self.verify_after_write, "Verify after writing by reading the drive back");
+                // A block map already says which blocks to leave alone.
+                if self.bmap.is_none() {
+                    ui.checkbox(&mut self.skip_zero_blocks, "Skip all-zero blocks instead of writing them");
+                    if self.skip_zero_blocks {
+                        ui.colored_label(
+                            egui::Color32::YELLOW,
+                            "Skipped blocks keep whatever the drive held before. Only use this on a wiped drive, or verification will fail on the old data.",
+                        );
+                    }
+                }
+            }
+
+            match self.write_mode {
+                WriteMode::RawImage => {
+                    ui.colored_label(
+                        egui::Color32::LIGHT_RED,
+                        "Raw mode overwrites the whole target, including its partition table.",
+                    );
+                }
+                WriteMode::Extract => {
+                    ui.colored_label(
+                        egui::Color32::LIGHT_RED,
+                        "ISO mode repartitions and formats the target; everything on it is erased.",
+                    );
+                    ui.colored_label(
+                        egui::Color32::YELLOW,
+                        "The drive will only boot on UEFI. For BIOS or CSM boot, write the image in DD mode.",
+                    );
+                    let catalog = match &self.image_info {
+                        Some(Ok(info)) => info.boot_catalog.as_ref().and_then(|catalog| catalog.as_ref().ok()),
+                        _ => None,
+                    };
+                    if catalog.is_some_and(|catalog| !catalog.boots_on_uefi()) {
+                        ui.colored_label(
+                            egui::Color32::LIGHT_RED,
+                            "This image has no UEFI boot entry, so a drive written in ISO mode will not boot.",
+                        );
+                    }
+                }
+                WriteMode::CopyFile => {}
             }
 
             // Copy ISO
@@ -191,7 +1245,8 @@ impl eframe::App for AyumiApp {
             let is_burning = *self.is_burning.lock().unwrap();
             if is_burning {
                 let progress = *self.burning_progress.lock().unwrap();
-                ui.add(egui::ProgressBar::new(progress).show_percentage());
+                let phase = *self.burn_phase.lock().unwrap();
+                ui.add(egui::ProgressBar::new(progress).text(format!("{} {:.0}%", phase.label(), progress * 100.0)));
             }
 
             // Display Errors
@@ -202,10 +1257,132 @@ impl eframe::App for AyumiApp {
                     .show();
             }
 
+            if let Some(summary) = self.burn_summary.lock().unwrap().take() {
+                rfd::MessageDialog::new()
+                    .set_title("Finished")
+                    .set_description(&summary)
+                    .show();
+            }
+
             // Request a repaint to update the UI
-            if is_burning {
+            if is_burning || *self.is_hashing.lock().unwrap() {
                 ctx.request_repaint();
             }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use platform::MockProvider;
+    use testutil::TempDir;
+
+    #[test]
+    fn copy_mode_writes_fat32_directly_and_other_volumes_through_their_mount_point() {
+        let dir = TempDir::new("copy-mode");
+        let source = dir.write("ayumi.iso", b"pretend image");
+        let provider = MockProvider::with_demo_drives();
+        let progress = Arc::new(Mutex::new(0.0));
+        let mut drive = provider.list_drives().remove(0);
+
+        // A blank drive that is not mounted has nowhere to take the file.
+        let error = copy_to_volume(&source, &drive, &provider, &progress).err().unwrap();
+        assert_eq!(error, "mock0 holds no FAT32 volume and is not mounted; use ISO mode to format it");
+
+        let mount_point = dir.path().join("mnt");
+        fs::create_dir(&mount_point).unwrap();
+        drive.mount_point = Some(mount_point.display().to_string());
+        copy_to_volume(&source, &drive, &provider, &progress).unwrap();
+        assert_eq!(fs::read(mount_point.join("ayumi.iso")).unwrap(), b"pretend image");
+        assert_eq!(*progress.lock().unwrap(), 1.0);
+
+        // FAT32 is written directly, mounted or not.
+        fs::remove_file(mount_point.join("ayumi.iso")).unwrap();
+        let mut target = provider.open_target(&drive).unwrap();
+        FatVolume::format(target.as_mut(), 0, drive.size, &FormatOptions::default()).unwrap().finish().unwrap();
+        drop(target);
+        copy_to_volume(&source, &drive, &provider, &progress).unwrap();
+        assert!(!mount_point.join("ayumi.iso").exists());
+
+        let mut target = provider.open_target(&drive).unwrap();
+        let mut volume = FatVolume::open(target.as_mut(), 0).unwrap();
+        assert_eq!(volume.read_file("ayumi.iso").unwrap().as_deref(), Some(&b"pretend image"[..]));
+    }
+
+    #[test]
+    fn writes_only_once_an_entered_hash_matches() {
+        let sha1 = |hex: &str| Checksum {
+            algorithm: HashAlgorithm::Sha1,
+            hex: hex.to_string(),
+        };
+        let actual = sha1("2fd4e1c67a2d28fced849ee1bb76e7391b93eb12");
+
+        assert_eq!(check_checksum("  ", None), Ok(()));
+        assert_eq!(check_checksum(" 2FD4E1C67A2D28FCED849EE1BB76E7391B93EB12\n", Some(&actual)), Ok(()));
+        assert_eq!(
+            check_checksum("da39a3ee5e6b4b0d3255bfef95601890afd80709", Some(&actual)).err().unwrap(),
+            "The ISO does not match the expected hash. Download it again."
+        );
+        // A hash of another kind, or none at all, settles nothing.
+        for computed in [None, Some(&actual)] {
+            assert_eq!(
+                check_checksum("9e107d9d372bb6826bd81d3542a419d6", computed).err().unwrap(),
+                "Please verify the ISO against the expected hash first."
+            );
+        }
+        assert!(check_checksum("not a hash", Some(&actual)).is_err());
+    }
+
+    #[test]
+    fn a_mismatching_hash_refuses_to_write() {
+        let dir = TempDir::new("checksum-refusal");
+        let image = dir.write("ayumi.img", &[0x5A; 4096]);
+        let provider = Arc::new(MockProvider::with_demo_drives());
+        let drive = provider.list_drives().remove(0);
+        let app = AyumiApp {
+            provider: provider.clone(),
+            iso_path: image.display().to_string(),
+            selected_drive: Some(drive.clone()),
+            write_mode: WriteMode::RawImage,
+            checksum_text: "d41d8cd98f00b204e9800998ecf8427e".to_string(),
+            ..AyumiApp::default()
+        };
+        let computed = Checksum {
+            algorithm: HashAlgorithm::Md5,
+            hex: checksum::hash_bytes(HashAlgorithm::Md5, &[0x5A; 4096]),
+        };
+        *app.computed_hash.lock().unwrap() = Some((app.iso_path.clone(), Ok(computed)));
+
+        assert_eq!(app.copy_iso().err().unwrap(), "The ISO does not match the expected hash. Download it again.");
+        assert!(!*app.is_burning.lock().unwrap());
+        let mut written = vec![0; 4096];
+        provider.open_target(&drive).unwrap().read_exact(&mut written).unwrap();
+        assert!(written.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn images_in_zip_archives_are_only_written_raw() {
+        let dir = TempDir::new("zip-modes");
+        let archive = dir.write("firmware.zip", b"PK\x05\x06");
+        let provider = Arc::new(MockProvider::with_demo_drives());
+        let drive = provider.list_drives().remove(0);
+        let mut app = AyumiApp {
+            provider: provider.clone(),
+            iso_path: archive.display().to_string(),
+            selected_drive: Some(drive.clone()),
+            zip_entries: Some(Ok(Vec::new())),
+            ..AyumiApp::default()
+        };
+
+        for mode in [WriteMode::Extract, WriteMode::CopyFile] {
+            app.write_mode = mode;
+            assert_eq!(app.copy_iso().err().unwrap(), "Images inside a ZIP archive can only be written in raw mode.");
+        }
+        app.write_mode = WriteMode::RawImage;
+        assert_eq!(app.copy_iso().err().unwrap(), "Please pick an image inside the archive.");
+        assert!(!*app.is_burning.lock().unwrap());
+    }
+}
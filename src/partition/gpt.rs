@@ -0,0 +1,519 @@
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use super::mbr::{Mbr, MbrPartition, BOOT_CODE_SIZE};
+use super::{ALIGNMENT, NEXT_BOOT_DEVICE, SECTOR_SIZE};
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const REVISION: u32 = 0x0001_0000;
+const HEADER_SIZE: usize = 92;
+const ENTRY_SIZE: usize = 128;
+const ENTRY_COUNT: usize = 128;
+// 128 entries of 128 bytes: the minimum array the spec allows, 32 sectors.
+const ENTRY_ARRAY_SECTORS: u64 = (ENTRY_SIZE * ENTRY_COUNT) as u64 / SECTOR_SIZE;
+const NAME_UNITS: usize = 36;
+
+// The only partition a GPT disk shows legacy tools, covering the whole disk.
+pub const PROTECTIVE_TYPE: u8 = 0xEE;
+
+// Real tables have at most a few hundred entries; anything past this is
+// damage, not data.
+const MAX_ENTRY_ARRAY: usize = 1024 * 1024;
+
+// GUIDs are stored with their first three fields little-endian.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Guid([u8; 16]);
+
+impl Guid {
+    const fn new(data1: u32, data2: u16, data3: u16, data4: [u8; 8]) -> Self {
+        let a = data1.to_le_bytes();
+        let b = data2.to_le_bytes();
+        let c = data3.to_le_bytes();
+        Self([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], data4[0], data4[1], data4[2], data4[3], data4[4],
+            data4[5], data4[6], data4[7],
+        ])
+    }
+
+    // A version 4 (random) GUID.
+    pub fn random() -> io::Result<Self> {
+        let mut bytes = [0u8; 16];
+        getrandom::getrandom(&mut bytes).map_err(io::Error::other)?;
+        bytes[7] = bytes[7] & 0x0F | 0x40;
+        bytes[8] = bytes[8] & 0x3F | 0x80;
+        Ok(Self(bytes))
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9],
+            b[10],
+            b[11],
+            b[12],
+            b[13],
+            b[14],
+            b[15]
+        )
+    }
+}
+
+pub const EFI_SYSTEM: Guid = Guid::new(0xC12A7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+pub const MICROSOFT_BASIC_DATA: Guid =
+    Guid::new(0xEBD0A0A2, 0xB9E5, 0x4433, [0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+pub const LINUX_FILESYSTEM: Guid =
+    Guid::new(0x0FC63DAF, 0x8483, 0x4772, [0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]);
+const MICROSOFT_RESERVED: Guid =
+    Guid::new(0xE3C9E316, 0x0B5C, 0x4DB8, [0x81, 0x7D, 0xF9, 0x2D, 0xF0, 0x02, 0x15, 0xAE]);
+const WINDOWS_RECOVERY: Guid =
+    Guid::new(0xDE94BBA4, 0x06D1, 0x4D40, [0xA1, 0x6A, 0xBF, 0xD5, 0x01, 0x79, 0xD6, 0xAC]);
+const BIOS_BOOT: Guid = Guid::new(0x21686148, 0x6449, 0x6E6F, [0x74, 0x4E, 0x65, 0x65, 0x64, 0x45, 0x46, 0x49]);
+const LINUX_SWAP: Guid = Guid::new(0x0657FD6D, 0xA4AB, 0x43C4, [0x84, 0xE5, 0x09, 0x33, 0xC8, 0x4B, 0x4F, 0x4F]);
+const APPLE_HFS: Guid = Guid::new(0x48465300, 0x0000, 0x11AA, [0xAA, 0x11, 0x00, 0x30, 0x65, 0x43, 0xEC, 0xAC]);
+
+pub fn type_name(type_guid: &Guid) -> &'static str {
+    match *type_guid {
+        EFI_SYSTEM => "EFI system",
+        MICROSOFT_BASIC_DATA => "Microsoft basic data",
+        MICROSOFT_RESERVED => "Microsoft reserved",
+        WINDOWS_RECOVERY => "Windows recovery",
+        LINUX_FILESYSTEM => "Linux filesystem",
+        LINUX_SWAP => "Linux swap",
+        BIOS_BOOT => "BIOS boot",
+        APPLE_HFS => "Apple HFS+",
+        _ => "Unknown",
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GptPartition {
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    // Inclusive, like everything in GPT.
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl GptPartition {
+    pub fn offset(&self) -> u64 {
+        self.first_lba * SECTOR_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        (self.last_lba + 1 - self.first_lba) * SECTOR_SIZE
+    }
+
+    fn parse(entry: &[u8]) -> Option<Self> {
+        let type_guid = Guid(entry[0..16].try_into().unwrap());
+        if type_guid.is_nil() {
+            return None;
+        }
+
+        let units: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        Some(Self {
+            type_guid,
+            unique_guid: Guid(entry[16..32].try_into().unwrap()),
+            first_lba: u64_at(entry, 32),
+            last_lba: u64_at(entry, 40),
+            attributes: u64_at(entry, 48),
+            name: String::from_utf16_lossy(&units),
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[0..16].copy_from_slice(&self.type_guid.0);
+        entry[16..32].copy_from_slice(&self.unique_guid.0);
+        entry[32..40].copy_from_slice(&self.first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&self.last_lba.to_le_bytes());
+        entry[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+        // Names are cut at a character boundary rather than mid surrogate pair.
+        let mut units = Vec::new();
+        for c in self.name.chars() {
+            let mut buffer = [0u16; 2];
+            let encoded = c.encode_utf16(&mut buffer);
+            if units.len() + encoded.len() > NAME_UNITS {
+                break;
+            }
+            units.extend_from_slice(encoded);
+        }
+        for (index, unit) in units.iter().enumerate() {
+            entry[56 + index * 2..58 + index * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        entry
+    }
+}
+
+// A GUID partition table. It lives twice on disk: a primary copy after the
+// protective MBR and a backup in the last sectors, each a header plus an
+// array of partition entries, both guarded by CRC32s.
+#[derive(Clone, Debug)]
+pub struct Gpt {
+    pub disk_guid: Guid,
+    pub disk_sectors: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub partitions: Vec<GptPartition>,
+    // Set when the primary copy was damaged and the backup was read instead.
+    pub from_backup: bool,
+}
+
+impl Gpt {
+    // Writes the protective MBR and both copies of the table, and clears the
+    // rest of the gap before the first partition.
+    pub fn write<T: Write + Seek + ?Sized>(&self, target: &mut T) -> io::Result<()> {
+        let last_lba = self.disk_sectors - 1;
+        let backup_entries_lba = last_lba - ENTRY_ARRAY_SECTORS;
+
+        let mut entries = vec![0u8; ENTRY_SIZE * ENTRY_COUNT];
+        for (index, partition) in self.partitions.iter().take(ENTRY_COUNT).enumerate() {
+            entries[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE].copy_from_slice(&partition.to_bytes());
+        }
+        let entries_crc = crc32fast::hash(&entries);
+
+        let protective = Mbr {
+            boot_code: {
+                let mut boot_code = NEXT_BOOT_DEVICE.to_vec();
+                boot_code.resize(BOOT_CODE_SIZE, 0);
+                boot_code
+            },
+            disk_signature: 0,
+            partitions: vec![MbrPartition {
+                active: false,
+                partition_type: PROTECTIVE_TYPE,
+                start_lba: 1,
+                sector_count: last_lba.min(u32::MAX as u64) as u32,
+            }],
+        };
+
+        let gap_end = self.partitions.iter().map(GptPartition::offset).min().unwrap_or(ALIGNMENT);
+        let entries_end = (2 + ENTRY_ARRAY_SECTORS) * SECTOR_SIZE;
+        let mut gap = vec![0; gap_end.clamp(entries_end, ALIGNMENT) as usize];
+        gap[..512].copy_from_slice(&protective.to_bytes());
+        gap[512..512 + HEADER_SIZE].copy_from_slice(&self.header(1, last_lba, 2, entries_crc));
+        gap[1024..entries_end as usize].copy_from_slice(&entries);
+        target.seek(SeekFrom::Start(0))?;
+        target.write_all(&gap)?;
+
+        // The backup puts its entries before its header, at the very end.
+        let mut backup = entries;
+        backup.resize(backup.len() + SECTOR_SIZE as usize, 0);
+        let header_start = backup.len() - SECTOR_SIZE as usize;
+        backup[header_start..header_start + HEADER_SIZE]
+            .copy_from_slice(&self.header(last_lba, 1, backup_entries_lba, entries_crc));
+        target.seek(SeekFrom::Start(backup_entries_lba * SECTOR_SIZE))?;
+        target.write_all(&backup)
+    }
+
+    fn header(&self, my_lba: u64, alternate_lba: u64, entries_lba: u64, entries_crc: u32) -> [u8; HEADER_SIZE] {
+        let mut header = [0u8; HEADER_SIZE];
+        header[0..8].copy_from_slice(SIGNATURE);
+        header[8..12].copy_from_slice(&REVISION.to_le_bytes());
+        header[12..16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&my_lba.to_le_bytes());
+        header[32..40].copy_from_slice(&alternate_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&self.first_usable_lba.to_le_bytes());
+        header[48..56].copy_from_slice(&self.last_usable_lba.to_le_bytes());
+        header[56..72].copy_from_slice(&self.disk_guid.0);
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(ENTRY_COUNT as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        // The header CRC is taken with its own field still zero.
+        let crc = crc32fast::hash(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+}
+
+// Reads the primary table and falls back to the backup when the primary is
+// damaged. `None` when neither copy is intact.
+pub fn read_gpt<T: Read + Seek + ?Sized>(target: &mut T, disk_sectors: u64) -> io::Result<Option<Gpt>> {
+    if let Some(gpt) = read_copy(target, 1, disk_sectors)? {
+        return Ok(Some(gpt));
+    }
+    if disk_sectors < 2 {
+        return Ok(None);
+    }
+    Ok(read_copy(target, disk_sectors - 1, disk_sectors)?.map(|gpt| Gpt { from_backup: true, ..gpt }))
+}
+
+fn read_copy<T: Read + Seek + ?Sized>(target: &mut T, lba: u64, disk_sectors: u64) -> io::Result<Option<Gpt>> {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    target.seek(SeekFrom::Start(lba * SECTOR_SIZE))?;
+    target.read_exact(&mut sector)?;
+
+    let header_size = u32_at(&sector, 12) as usize;
+    if &sector[0..8] != SIGNATURE || !(HEADER_SIZE..=SECTOR_SIZE as usize).contains(&header_size) {
+        return Ok(None);
+    }
+    let mut header = sector[..header_size].to_vec();
+    let crc = u32_at(&header, 16);
+    header[16..20].fill(0);
+    if crc32fast::hash(&header) != crc || u64_at(&header, 24) != lba {
+        return Ok(None);
+    }
+
+    let entry_size = u32_at(&header, 84) as usize;
+    let array_size = u32_at(&header, 80) as usize * entry_size;
+    if entry_size < ENTRY_SIZE || array_size > MAX_ENTRY_ARRAY {
+        return Ok(None);
+    }
+    // A header that passes its CRC can still point its entries off the disk.
+    let entries_lba = u64_at(&header, 72);
+    let array_sectors = (array_size as u64).div_ceil(SECTOR_SIZE);
+    if entries_lba.checked_add(array_sectors).is_none_or(|end| end > disk_sectors) {
+        return Ok(None);
+    }
+    let mut entries = vec![0u8; array_size];
+    target.seek(SeekFrom::Start(entries_lba * SECTOR_SIZE))?;
+    target.read_exact(&mut entries)?;
+    if crc32fast::hash(&entries) != u32_at(&header, 88) {
+        return Ok(None);
+    }
+
+    Ok(Some(Gpt {
+        disk_guid: Guid(header[56..72].try_into().unwrap()),
+        disk_sectors,
+        first_usable_lba: u64_at(&header, 40),
+        last_usable_lba: u64_at(&header, 48),
+        partitions: entries.chunks_exact(entry_size).filter_map(GptPartition::parse).collect(),
+        from_backup: false,
+    }))
+}
+
+struct PlannedPartition {
+    type_guid: Guid,
+    // `None` takes the rest of the disk.
+    size: Option<u64>,
+    name: String,
+}
+
+// Lays out partitions one after another, each starting on a 1 MiB boundary,
+// with fresh GUIDs for the disk and every partition.
+pub struct GptBuilder {
+    disk_size: u64,
+    partitions: Vec<PlannedPartition>,
+}
+
+impl GptBuilder {
+    pub fn new(disk_size: u64) -> Self {
+        Self {
+            disk_size,
+            partitions: Vec::new(),
+        }
+    }
+
+    pub fn with_partition(mut self, type_guid: Guid, size: Option<u64>, name: &str) -> Self {
+        self.partitions.push(PlannedPartition {
+            type_guid,
+            size,
+            name: name.to_string(),
+        });
+        self
+    }
+
+    pub fn build(self) -> io::Result<Gpt> {
+        if self.partitions.len() > ENTRY_COUNT {
+            return Err(invalid(format!("A GPT holds at most {} partitions", ENTRY_COUNT)));
+        }
+
+        let disk_sectors = self.disk_size / SECTOR_SIZE;
+        let first_usable_lba = 2 + ENTRY_ARRAY_SECTORS;
+        let Some(last_usable_lba) = disk_sectors.checked_sub(2 + ENTRY_ARRAY_SECTORS) else {
+            return Err(invalid("The disk is too small for a GPT".to_string()));
+        };
+
+        let alignment = ALIGNMENT / SECTOR_SIZE;
+        let mut next = alignment;
+        let mut partitions = Vec::new();
+        for (index, planned) in self.partitions.into_iter().enumerate() {
+            let available = (last_usable_lba + 1).saturating_sub(next);
+            let sectors = match planned.size {
+                None => available,
+                Some(size) => size / SECTOR_SIZE,
+            };
+            if sectors == 0 || sectors > available {
+                return Err(invalid(format!("Partition {} does not fit on the disk", index + 1)));
+            }
+
+            partitions.push(GptPartition {
+                type_guid: planned.type_guid,
+                unique_guid: Guid::random()?,
+                first_lba: next,
+                last_lba: next + sectors - 1,
+                attributes: 0,
+                name: planned.name,
+            });
+            next = (next + sectors).div_ceil(alignment) * alignment;
+        }
+
+        Ok(Gpt {
+            disk_guid: Guid::random()?,
+            disk_sectors,
+            first_usable_lba,
+            last_usable_lba,
+            partitions,
+            from_backup: false,
+        })
+    }
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const DISK_SIZE: u64 = 8 * 1024 * 1024;
+    const DISK_SECTORS: u64 = DISK_SIZE / SECTOR_SIZE;
+
+    fn written_disk() -> (Gpt, Vec<u8>) {
+        let gpt = GptBuilder::new(DISK_SIZE)
+            .with_partition(EFI_SYSTEM, Some(2 * 1024 * 1024), "EFI system partition")
+            .with_partition(MICROSOFT_BASIC_DATA, None, "Data")
+            .build()
+            .unwrap();
+        let mut disk = Cursor::new(vec![0xA5; DISK_SIZE as usize]);
+        gpt.write(&mut disk).unwrap();
+        (gpt, disk.into_inner())
+    }
+
+    fn header_at(disk: &[u8], lba: u64) -> &[u8] {
+        let start = (lba * SECTOR_SIZE) as usize;
+        &disk[start..start + HEADER_SIZE]
+    }
+
+    fn reseal(disk: &mut [u8], lba: u64) {
+        let start = (lba * SECTOR_SIZE) as usize;
+        disk[start + 16..start + 20].fill(0);
+        let crc = crc32fast::hash(&disk[start..start + HEADER_SIZE]);
+        disk[start + 16..start + 20].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn read(disk: &[u8]) -> Option<Gpt> {
+        read_gpt(&mut Cursor::new(disk), DISK_SECTORS).unwrap()
+    }
+
+    fn layout(gpt: &Gpt) -> Vec<(Guid, Guid, u64, u64, String)> {
+        gpt.partitions
+            .iter()
+            .map(|p| (p.type_guid, p.unique_guid, p.first_lba, p.last_lba, p.name.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn headers_and_entry_arrays_carry_their_crcs() {
+        let (_, disk) = written_disk();
+        for lba in [1, DISK_SECTORS - 1] {
+            let mut header = header_at(&disk, lba).to_vec();
+            let crc = u32_at(&header, 16);
+            header[16..20].fill(0);
+            assert_eq!(crc32fast::hash(&header), crc);
+
+            let entries_start = (u64_at(&header, 72) * SECTOR_SIZE) as usize;
+            let entries = &disk[entries_start..entries_start + ENTRY_SIZE * ENTRY_COUNT];
+            assert_eq!(crc32fast::hash(entries), u32_at(&header, 88));
+        }
+    }
+
+    #[test]
+    fn backup_sits_at_the_last_lba_with_its_lbas_swapped() {
+        let (gpt, disk) = written_disk();
+        let primary = header_at(&disk, 1);
+        let backup = header_at(&disk, DISK_SECTORS - 1);
+        assert_eq!(&backup[0..8], SIGNATURE);
+        assert_eq!((u64_at(primary, 24), u64_at(primary, 32)), (1, DISK_SECTORS - 1));
+        assert_eq!((u64_at(backup, 24), u64_at(backup, 32)), (DISK_SECTORS - 1, 1));
+        assert_eq!(u64_at(primary, 72), 2);
+        assert_eq!(u64_at(backup, 72), DISK_SECTORS - 1 - ENTRY_ARRAY_SECTORS);
+        // Everything else is shared between the copies.
+        assert_eq!(&primary[40..72], &backup[40..72]);
+        assert_eq!(&primary[80..92], &backup[80..92]);
+
+        let read = read(&disk).unwrap();
+        assert!(!read.from_backup);
+        assert_eq!(read.disk_guid, gpt.disk_guid);
+        assert_eq!((read.first_usable_lba, read.last_usable_lba), (34, DISK_SECTORS - 34));
+        assert_eq!(layout(&read), layout(&gpt));
+        assert_eq!(read.partitions[0].first_lba, 2048);
+        assert_eq!(read.partitions[1].last_lba, read.last_usable_lba);
+    }
+
+    #[test]
+    fn falls_back_to_the_backup_when_the_primary_is_damaged() {
+        let (gpt, disk) = written_disk();
+
+        let mut bad_header = disk.clone();
+        bad_header[512 + 40] ^= 1;
+        let mut bad_entries = disk.clone();
+        bad_entries[1024 + 60] ^= 1;
+        // A resealed header whose entries lie past the end of the disk.
+        let mut entries_off_disk = disk.clone();
+        entries_off_disk[512 + 72..512 + 80].copy_from_slice(&(DISK_SECTORS - 1).to_le_bytes());
+        reseal(&mut entries_off_disk, 1);
+
+        for damaged in [bad_header, bad_entries, entries_off_disk] {
+            let read = read(&damaged).unwrap();
+            assert!(read.from_backup);
+            assert_eq!(layout(&read), layout(&gpt));
+        }
+
+        let mut both = disk;
+        both[512] = 0;
+        both[((DISK_SECTORS - 1) * SECTOR_SIZE) as usize] = 0;
+        assert!(read(&both).is_none());
+    }
+
+    #[test]
+    fn names_are_cut_before_a_split_surrogate_pair() {
+        let entry = |name: &str| {
+            let partition = GptPartition {
+                type_guid: LINUX_FILESYSTEM,
+                unique_guid: Guid::default(),
+                first_lba: 2048,
+                last_lba: 4095,
+                attributes: 0,
+                name: name.to_string(),
+            };
+            GptPartition::parse(&partition.to_bytes()).unwrap().name
+        };
+
+        let fits = format!("{}\u{1F600}", "a".repeat(NAME_UNITS - 2));
+        assert_eq!(entry(&fits), fits);
+        // One unit short for the pair: it is dropped whole, not halved.
+        let long = format!("{}\u{1F600}", "a".repeat(NAME_UNITS - 1));
+        assert_eq!(entry(&long), "a".repeat(NAME_UNITS - 1));
+        assert_eq!(entry(&"ü".repeat(40)), "ü".repeat(NAME_UNITS));
+    }
+}
@@ -0,0 +1,367 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+use super::gpt::PROTECTIVE_TYPE;
+use super::{ALIGNMENT, SECTOR_SIZE};
+
+pub const BOOT_CODE_SIZE: usize = 440;
+const ENTRIES_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const MAX_PARTITIONS: usize = 4;
+
+const STATUS_ACTIVE: u8 = 0x80;
+
+// CHS fields maxed out: partitions are only reachable through LBA, which
+// every BIOS since the late nineties prefers anyway.
+const CHS_UNUSED: [u8; 3] = [0xFE, 0xFF, 0xFF];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MbrPartition {
+    pub active: bool,
+    pub partition_type: u8,
+    pub start_lba: u32,
+    pub sector_count: u32,
+}
+
+impl MbrPartition {
+    pub fn offset(&self) -> u64 {
+        self.start_lba as u64 * SECTOR_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        self.sector_count as u64 * SECTOR_SIZE
+    }
+
+    fn parse(entry: &[u8]) -> Option<Self> {
+        let partition_type = entry[4];
+        let sector_count = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+        if partition_type == 0 || sector_count == 0 {
+            return None;
+        }
+
+        Some(Self {
+            active: entry[0] & STATUS_ACTIVE != 0,
+            partition_type,
+            start_lba: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+            sector_count,
+        })
+    }
+
+    fn to_bytes(&self) -> [u8; ENTRY_SIZE] {
+        let mut entry = [0u8; ENTRY_SIZE];
+        entry[0] = if self.active { STATUS_ACTIVE } else { 0 };
+        entry[1..4].copy_from_slice(&CHS_UNUSED);
+        entry[4] = self.partition_type;
+        entry[5..8].copy_from_slice(&CHS_UNUSED);
+        entry[8..12].copy_from_slice(&self.start_lba.to_le_bytes());
+        entry[12..16].copy_from_slice(&self.sector_count.to_le_bytes());
+        entry
+    }
+}
+
+// A classic master boot record: boot code, disk signature and up to four
+// primary partitions.
+#[derive(Clone, Debug)]
+pub struct Mbr {
+    // Always BOOT_CODE_SIZE bytes.
+    pub boot_code: Vec<u8>,
+    pub disk_signature: u32,
+    pub partitions: Vec<MbrPartition>,
+}
+
+impl Mbr {
+    // `None` unless the sector ends in the 0x55AA signature and every status
+    // byte is 0x00 or 0x80. A volume boot record may pass both, so callers
+    // that care about superfloppies have to rule those out first.
+    pub fn parse(sector: &[u8]) -> Option<Self> {
+        if sector.len() < SECTOR_SIZE as usize || sector[510..512] != [0x55, 0xAA] {
+            return None;
+        }
+        let entries = &sector[ENTRIES_OFFSET..510];
+        if entries.chunks(ENTRY_SIZE).any(|entry| entry[0] & !STATUS_ACTIVE != 0) {
+            return None;
+        }
+
+        Some(Self {
+            boot_code: sector[..BOOT_CODE_SIZE].to_vec(),
+            disk_signature: u32::from_le_bytes(sector[440..444].try_into().unwrap()),
+            partitions: sector[ENTRIES_OFFSET..510]
+                .chunks(ENTRY_SIZE)
+                .filter_map(MbrPartition::parse)
+                .collect(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; SECTOR_SIZE as usize] {
+        let mut sector = [0u8; SECTOR_SIZE as usize];
+        sector[..BOOT_CODE_SIZE].copy_from_slice(&self.boot_code);
+        sector[440..444].copy_from_slice(&self.disk_signature.to_le_bytes());
+        for (index, partition) in self.partitions.iter().take(MAX_PARTITIONS).enumerate() {
+            let start = ENTRIES_OFFSET + index * ENTRY_SIZE;
+            sector[start..start + ENTRY_SIZE].copy_from_slice(&partition.to_bytes());
+        }
+        sector[510] = 0x55;
+        sector[511] = 0xAA;
+        sector
+    }
+
+    // The stand-in MBR of a GPT disk.
+    pub fn is_protective(&self) -> bool {
+        self.partitions.iter().any(|partition| partition.partition_type == PROTECTIVE_TYPE)
+    }
+
+    pub fn has_boot_code(&self) -> bool {
+        self.boot_code.iter().any(|&byte| byte != 0)
+    }
+
+    // Writes the MBR and clears the rest of the gap before the first
+    // partition, so leftovers such as an old GPT header do not linger.
+    pub fn write<T: Write + Seek + ?Sized>(&self, target: &mut T) -> io::Result<()> {
+        let gap_end = self.partitions.iter().map(MbrPartition::offset).min().unwrap_or(ALIGNMENT);
+        let mut gap = vec![0; gap_end.clamp(SECTOR_SIZE, ALIGNMENT) as usize];
+        gap[..SECTOR_SIZE as usize].copy_from_slice(&self.to_bytes());
+
+        target.seek(SeekFrom::Start(0))?;
+        target.write_all(&gap)
+    }
+}
+
+// The boot sector of a superfloppy, a drive formatted without a partition
+// table. Its jump instruction and BIOS parameter block, or for exFAT its
+// OEM name, tell it apart from an MBR with the same 0x55AA signature.
+pub fn is_volume_boot_record(sector: &[u8]) -> bool {
+    let jump = (sector[0] == 0xEB && sector[2] == 0x90) || sector[0] == 0xE9;
+    let bytes_per_sector = u16::from_le_bytes([sector[11], sector[12]]);
+    let parameter_block = matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096) && sector[13].is_power_of_two();
+    jump && (parameter_block || &sector[3..11] == b"EXFAT   ")
+}
+
+struct PlannedPartition {
+    partition_type: u8,
+    // `None` takes the rest of the disk.
+    size: Option<u64>,
+    active: bool,
+}
+
+// Lays out primary partitions one after another, each starting on a 1 MiB
+// boundary.
+pub struct MbrBuilder {
+    disk_size: u64,
+    boot_code: Vec<u8>,
+    disk_signature: u32,
+    partitions: Vec<PlannedPartition>,
+}
+
+impl MbrBuilder {
+    pub fn new(disk_size: u64) -> Self {
+        Self {
+            disk_size,
+            boot_code: Vec::new(),
+            disk_signature: 0,
+            partitions: Vec::new(),
+        }
+    }
+
+    pub fn with_boot_code(mut self, boot_code: &[u8]) -> Self {
+        self.boot_code = boot_code.to_vec();
+        self
+    }
+
+    pub fn with_disk_signature(mut self, disk_signature: u32) -> Self {
+        self.disk_signature = disk_signature;
+        self
+    }
+
+    pub fn with_partition(mut self, partition_type: u8, size: Option<u64>, active: bool) -> Self {
+        self.partitions.push(PlannedPartition {
+            partition_type,
+            size,
+            active,
+        });
+        self
+    }
+
+    pub fn build(self) -> io::Result<Mbr> {
+        if self.boot_code.len() > BOOT_CODE_SIZE {
+            return Err(invalid(format!("Boot code is limited to {} bytes", BOOT_CODE_SIZE)));
+        }
+        if self.partitions.len() > MAX_PARTITIONS {
+            return Err(invalid(format!("An MBR holds at most {} primary partitions", MAX_PARTITIONS)));
+        }
+        if self.partitions.iter().filter(|partition| partition.active).count() > 1 {
+            return Err(invalid("Only one partition can be active".to_string()));
+        }
+
+        let disk_sectors = self.disk_size / SECTOR_SIZE;
+        let alignment = ALIGNMENT / SECTOR_SIZE;
+        let mut next = alignment;
+        let mut partitions = Vec::new();
+
+        for (index, planned) in self.partitions.iter().enumerate() {
+            let available = disk_sectors.saturating_sub(next);
+            let sectors = match planned.size {
+                // Disks past 2 TiB only get the part an MBR can describe.
+                None => available.min((u32::MAX as u64).saturating_sub(next)),
+                Some(size) => size / SECTOR_SIZE,
+            };
+            if sectors == 0 || sectors > available {
+                return Err(invalid(format!("Partition {} does not fit on the disk", index + 1)));
+            }
+            if next + sectors > u32::MAX as u64 {
+                return Err(invalid(format!(
+                    "Partition {} ends past 2 TiB, which an MBR cannot address",
+                    index + 1
+                )));
+            }
+
+            partitions.push(MbrPartition {
+                active: planned.active,
+                partition_type: planned.partition_type,
+                start_lba: next as u32,
+                sector_count: sectors as u32,
+            });
+            next = (next + sectors).div_ceil(alignment) * alignment;
+        }
+
+        let mut boot_code = self.boot_code;
+        boot_code.resize(BOOT_CODE_SIZE, 0);
+        Ok(Mbr {
+            boot_code,
+            disk_signature: self.disk_signature,
+            partitions,
+        })
+    }
+}
+
+// Names for the partition types a USB drive is likely to carry.
+pub fn partition_type_name(partition_type: u8) -> &'static str {
+    match partition_type {
+        0x01 => "FAT12",
+        0x04 | 0x06 | 0x0E => "FAT16",
+        0x05 | 0x0F => "Extended",
+        0x07 => "NTFS/exFAT",
+        0x0B | 0x0C => "FAT32",
+        0x17 => "Hidden NTFS",
+        0x1B | 0x1C => "Hidden FAT32",
+        0x27 => "Windows recovery",
+        0x82 => "Linux swap",
+        0x83 => "Linux",
+        0x8E => "Linux LVM",
+        0xA5 => "FreeBSD",
+        0xA8 | 0xAF => "macOS",
+        PROTECTIVE_TYPE => "GPT protective",
+        0xEF => "EFI system",
+        0xFD => "Linux RAID",
+        _ => "Unknown",
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::partition::{read_partition_table, PartitionTable};
+
+    const MIB: u64 = 1024 * 1024;
+    const DISK_SIZE: u64 = 64 * MIB;
+
+    fn read_mbr(disk: &[u8]) -> Option<Mbr> {
+        match read_partition_table(&mut Cursor::new(disk), disk.len() as u64).unwrap()? {
+            PartitionTable::Mbr(mbr) => Some(mbr),
+            PartitionTable::Gpt(_) => panic!("expected an MBR"),
+        }
+    }
+
+    #[test]
+    fn lays_out_partitions_on_mebibyte_boundaries() {
+        let mbr = MbrBuilder::new(DISK_SIZE)
+            .with_partition(0x0C, Some(10 * MIB + 512), false)
+            .with_partition(0x83, Some(5 * MIB), false)
+            .with_partition(0x07, None, true)
+            .build()
+            .unwrap();
+
+        let layout: Vec<(u32, u32, bool)> =
+            mbr.partitions.iter().map(|p| (p.start_lba, p.sector_count, p.active)).collect();
+        // The odd sector pushes the second partition to the next MiB; the
+        // last one takes the rest of the disk.
+        assert_eq!(layout, [(2048, 20481, false), (24576, 10240, false), (34816, 96256, true)]);
+        assert!(mbr.partitions.iter().all(|p| p.offset().is_multiple_of(ALIGNMENT)));
+        assert_eq!(mbr.partitions[2].offset() + mbr.partitions[2].size(), DISK_SIZE);
+    }
+
+    #[test]
+    fn refuses_what_an_mbr_cannot_hold() {
+        let five = (0..5).fold(MbrBuilder::new(DISK_SIZE), |builder, _| builder.with_partition(0x0C, Some(MIB), false));
+        assert_eq!(five.build().err().unwrap().to_string(), "An MBR holds at most 4 primary partitions");
+
+        let two_active = MbrBuilder::new(DISK_SIZE).with_partition(0x0C, Some(MIB), true).with_partition(0x0C, None, true);
+        assert_eq!(two_active.build().err().unwrap().to_string(), "Only one partition can be active");
+
+        let long_code = MbrBuilder::new(DISK_SIZE).with_boot_code(&[0x90; BOOT_CODE_SIZE + 1]);
+        assert_eq!(long_code.build().err().unwrap().to_string(), "Boot code is limited to 440 bytes");
+
+        let too_large = MbrBuilder::new(DISK_SIZE).with_partition(0x0C, Some(DISK_SIZE), false);
+        assert_eq!(too_large.build().err().unwrap().to_string(), "Partition 1 does not fit on the disk");
+    }
+
+    #[test]
+    fn writes_and_reads_back_boot_code_signature_and_partitions() {
+        let mbr = MbrBuilder::new(DISK_SIZE)
+            .with_boot_code(&[0xCD, 0x18])
+            .with_disk_signature(0xDEAD_BEEF)
+            .with_partition(0xEF, Some(8 * MIB), false)
+            .with_partition(0x0C, None, true)
+            .build()
+            .unwrap();
+
+        // Whatever was in the gap before the first partition is cleared.
+        let mut disk = Cursor::new(vec![0xA5; DISK_SIZE as usize]);
+        mbr.write(&mut disk).unwrap();
+        let disk = disk.into_inner();
+        assert!(disk[512..ALIGNMENT as usize].iter().all(|&byte| byte == 0));
+        assert_eq!(disk[ALIGNMENT as usize], 0xA5);
+        assert_eq!(&disk[440..444], &0xDEAD_BEEFu32.to_le_bytes());
+        assert_eq!(&disk[446..462], &[0x00, 0xFE, 0xFF, 0xFF, 0xEF, 0xFE, 0xFF, 0xFF, 0, 8, 0, 0, 0, 0x40, 0, 0]);
+        assert_eq!(disk[462], STATUS_ACTIVE);
+
+        let read = read_mbr(&disk).unwrap();
+        assert_eq!(read.boot_code, mbr.boot_code);
+        assert_eq!(&read.boot_code[..2], &[0xCD, 0x18]);
+        assert!(read.has_boot_code() && !read.is_protective());
+        assert_eq!(read.disk_signature, 0xDEAD_BEEF);
+        assert_eq!(read.partitions, mbr.partitions);
+    }
+
+    #[test]
+    fn tells_volume_boot_records_and_garbage_from_an_mbr() {
+        let mbr = MbrBuilder::new(DISK_SIZE).with_partition(0x0C, None, true).build().unwrap();
+        let sector = mbr.to_bytes();
+
+        // A FAT32 superfloppy: its boot code runs into the partition table
+        // area, where it can look like an entry.
+        let mut fat32 = sector;
+        fat32[..13].copy_from_slice(b"\xEB\x58\x90MSWIN4.1\x00\x02");
+        fat32[13] = 8;
+        assert!(read_mbr(&fat32).is_none());
+
+        // exFAT leaves the BIOS parameter block empty.
+        let mut exfat = sector;
+        exfat[..11].copy_from_slice(b"\xEB\x76\x90EXFAT   ");
+        assert!(read_mbr(&exfat).is_none());
+
+        let mut bad_status = sector;
+        bad_status[446 + 16] = 0x12;
+        assert!(read_mbr(&bad_status).is_none());
+        assert!(Mbr::parse(&bad_status).is_none());
+
+        // GRUB's MBR starts with a jump too, but has no parameter block.
+        let mut grub = sector;
+        grub[..3].copy_from_slice(&[0xEB, 0x63, 0x90]);
+        assert_eq!(read_mbr(&grub).unwrap().partitions, mbr.partitions);
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::platform::BlockTarget;
+
+mod gpt;
+mod mbr;
+
+pub use gpt::{type_name, Gpt, GptBuilder, MICROSOFT_BASIC_DATA};
+pub use mbr::{partition_type_name, Mbr, MbrBuilder};
+
+pub const SECTOR_SIZE: u64 = 512;
+
+// Partitions start on 1 MiB boundaries, which keeps them aligned to the
+// erase blocks of flash media and is what current partitioning tools do too.
+pub const ALIGNMENT: u64 = 1024 * 1024;
+
+// `int 0x18`: hand over to the next boot device if the BIOS runs the MBR.
+pub const NEXT_BOOT_DEVICE: [u8; 2] = [0xCD, 0x18];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PartitionScheme {
+    #[default]
+    Mbr,
+    // For UEFI-only machines and drives over 2 TiB. Older BIOSes cannot boot
+    // from it.
+    Gpt,
+}
+
+impl PartitionScheme {
+    pub fn label(&self) -> &'static str {
+        match self {
+            PartitionScheme::Mbr => "MBR",
+            PartitionScheme::Gpt => "GPT",
+        }
+    }
+}
+
+pub enum PartitionTable {
+    Mbr(Mbr),
+    Gpt(Gpt),
+}
+
+impl PartitionTable {
+    // Offset and size of every partition, in bytes.
+    pub fn extents(&self) -> Vec<(u64, u64)> {
+        match self {
+            PartitionTable::Mbr(mbr) => mbr.partitions.iter().map(|p| (p.offset(), p.size())).collect(),
+            PartitionTable::Gpt(gpt) => gpt.partitions.iter().map(|p| (p.offset(), p.size())).collect(),
+        }
+    }
+}
+
+// Reads the partition table at the start of a disk, if it has one. A
+// protective MBR means the real table is a GPT. A superfloppy has none.
+pub fn read_partition_table<T: Read + Seek + ?Sized>(
+    target: &mut T,
+    disk_size: u64,
+) -> io::Result<Option<PartitionTable>> {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    target.seek(SeekFrom::Start(0))?;
+    target.read_exact(&mut sector)?;
+    if mbr::is_volume_boot_record(&sector) {
+        return Ok(None);
+    }
+    let Some(mbr) = Mbr::parse(&sector) else {
+        return Ok(None);
+    };
+
+    if mbr.is_protective() {
+        if let Some(gpt) = gpt::read_gpt(target, disk_size / SECTOR_SIZE)? {
+            return Ok(Some(PartitionTable::Gpt(gpt)));
+        }
+    }
+    Ok(Some(PartitionTable::Mbr(mbr)))
+}
+
+// Devices know their size; image files are simply measured.
+pub fn disk_size(target: &mut dyn BlockTarget) -> io::Result<u64> {
+    match target.capacity()? {
+        Some(capacity) => Ok(capacity),
+        None => target.seek(SeekFrom::End(0)),
+    }
+}
@@ -0,0 +1,153 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::BlockTarget;
+
+// A device node or image file opened through the regular file API. This is
+// all Linux needs for `/dev/sdX`, and what every platform uses for targets
+// that are plain image files. Only image files the user asked for are
+// created; a mistyped device path fails instead of leaving a stray file.
+pub struct FileTarget {
+    file: File,
+    is_device: bool,
+}
+
+impl FileTarget {
+    pub fn open(path: &Path, create: bool) -> io::Result<Self> {
+        // Never truncate: for block devices it is meaningless and for image
+        // files it would throw away whatever lies past the end of the image.
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(create).truncate(false);
+        exclusive_if_device(&mut options, path);
+        let file = options.open(path).map_err(|e| match e.raw_os_error() {
+            #[cfg(target_os = "linux")]
+            Some(libc::EBUSY) => io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                "the drive is in use, most likely because it is mounted; unmount it first",
+            ),
+            _ => e,
+        })?;
+        let is_device = !file.metadata()?.is_file();
+
+        Ok(Self { file, is_device })
+    }
+}
+
+// Opening a block device with O_EXCL (and without O_CREAT) makes Linux
+// refuse with EBUSY while the disk or any of its partitions is mounted, or
+// held by another exclusive opener such as a second write.
+#[cfg(target_os = "linux")]
+fn exclusive_if_device(options: &mut OpenOptions, path: &Path) {
+    use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+
+    if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_block_device()) {
+        options.create(false).custom_flags(libc::O_EXCL);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exclusive_if_device(_options: &mut OpenOptions, _path: &Path) {}
+
+impl BlockTarget for FileTarget {
+    fn capacity(&mut self) -> io::Result<Option<u64>> {
+        if !self.is_device {
+            return Ok(None);
+        }
+
+        // Block devices report a zero length in their metadata; seeking to
+        // the end is the portable way to learn their size.
+        let position = self.file.stream_position()?;
+        let size = self.file.seek(SeekFrom::End(0))?;
+        self.file.seek(SeekFrom::Start(position))?;
+        Ok(Some(size))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()
+    }
+
+    // Image files are what the page cache holds; only devices have a medium
+    // that can disagree with it.
+    #[cfg(target_os = "linux")]
+    fn drop_cache(&mut self) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        if !self.is_device {
+            return Ok(());
+        }
+        match unsafe { libc::posix_fadvise(self.file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) } {
+            0 => Ok(()),
+            error => Err(io::Error::from_raw_os_error(error)),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn drop_cache(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for FileTarget {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for FileTarget {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for FileTarget {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+// Fallback for platforms without a drive enumerator: nothing is listed, but
+// targets typed in by hand can still be written.
+#[cfg(not(any(target_os = "linux", windows)))]
+pub struct FileProvider;
+
+#[cfg(not(any(target_os = "linux", windows)))]
+impl super::DriveProvider for FileProvider {
+    fn list_drives(&self) -> Vec<super::DriveInfo> {
+        Vec::new()
+    }
+
+    fn open_target(&self, drive: &super::DriveInfo) -> io::Result<Box<dyn BlockTarget>> {
+        Ok(Box::new(FileTarget::open(Path::new(&drive.device), drive.new_image)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn creates_only_image_files_that_were_asked_for() {
+        let dir = TempDir::new("file-target");
+        let path = dir.path().join("new.img");
+
+        let error = FileTarget::open(&path, false).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(!path.exists());
+
+        let mut target = FileTarget::open(&path, true).unwrap();
+        target.write_all(b"image").unwrap();
+        assert_eq!(target.capacity().unwrap(), None);
+        drop(target);
+
+        // Reopening keeps what is already there.
+        FileTarget::open(&path, false).unwrap().write_all(b"I").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"Image");
+    }
+}
@@ -0,0 +1,288 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use super::{BlockTarget, DriveInfo, DriveProvider, FileTarget, Transport};
+
+// Both roots are configurable so a fake sysfs tree and mount table can stand
+// in for the real ones.
+pub struct SysfsProvider {
+    sysfs_root: PathBuf,
+    mounts_file: PathBuf,
+}
+
+impl Default for SysfsProvider {
+    fn default() -> Self {
+        Self::with_roots("/sys", "/proc/mounts")
+    }
+}
+
+impl SysfsProvider {
+    pub fn with_roots(sysfs_root: impl Into<PathBuf>, mounts_file: impl Into<PathBuf>) -> Self {
+        Self {
+            sysfs_root: sysfs_root.into(),
+            mounts_file: mounts_file.into(),
+        }
+    }
+}
+
+impl DriveProvider for SysfsProvider {
+    // Walks `<sysfs_root>/block`, which only lists whole disks; partitions
+    // live in subdirectories of their parent disk.
+    fn list_drives(&self) -> Vec<DriveInfo> {
+        let entries = match fs::read_dir(self.sysfs_root.join("block")) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        let mounts = fs::read_to_string(&self.mounts_file).unwrap_or_default();
+
+        let mut drives = Vec::new();
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(drive) = read_disk(&entry.path(), &name, &mounts) {
+                drives.push(drive);
+            }
+        }
+
+        drives.sort_by(|a, b| a.device.cmp(&b.device));
+        drives
+    }
+
+    fn open_target(&self, drive: &DriveInfo) -> io::Result<Box<dyn BlockTarget>> {
+        Ok(Box::new(FileTarget::open(Path::new(&drive.device), drive.new_image)?))
+    }
+}
+
+fn read_disk(disk_dir: &Path, name: &str, mounts: &str) -> Option<DriveInfo> {
+    // Optical drives and the eMMC boot/RPMB areas are never write targets.
+    if name.starts_with("sr") || name.contains("boot") || name.contains("rpmb") {
+        return None;
+    }
+
+    // Virtual devices (loop, zram, dm-*) have no backing `device` link.
+    if !disk_dir.join("device").exists() {
+        return None;
+    }
+
+    // The size attribute is always in 512-byte units, whatever the logical
+    // block size of the disk. Card readers without a card report zero.
+    let size = read_attr(&disk_dir.join("size"))?.parse::<u64>().ok()? * 512;
+    if size == 0 {
+        return None;
+    }
+
+    let removable = read_attr(&disk_dir.join("removable")).as_deref() == Some("1");
+    let transport = detect_transport(disk_dir, name);
+    if transport == Transport::Other && !removable {
+        return None;
+    }
+
+    Some(DriveInfo {
+        device: format!("/dev/{}", name),
+        mount_point: find_mount_point(mounts, name),
+        vendor: read_attr(&disk_dir.join("device/vendor")).unwrap_or_default(),
+        model: read_attr(&disk_dir.join("device/model")).unwrap_or_default(),
+        size,
+        transport,
+        removable,
+        new_image: false,
+    })
+}
+
+fn detect_transport(disk_dir: &Path, name: &str) -> Transport {
+    // `/sys/block/sdb` links into the device hierarchy, e.g.
+    // `devices/pci0000:00/.../usb2/2-1/2-1:1.0/host6/.../block/sdb`, so the
+    // bus a disk hangs off can be read from the resolved path.
+    let resolved = fs::canonicalize(disk_dir).unwrap_or_else(|_| disk_dir.to_path_buf());
+    let on_usb = resolved.components().any(|component| match component {
+        Component::Normal(part) => {
+            let part = part.to_string_lossy();
+            part.strip_prefix("usb")
+                .is_some_and(|bus| !bus.is_empty() && bus.chars().all(|c| c.is_ascii_digit()))
+        }
+        _ => false,
+    });
+
+    if on_usb {
+        Transport::Usb
+    } else if name.starts_with("mmcblk") {
+        // Soldered eMMC also shows up as mmcblk, but reports type "MMC".
+        match read_attr(&disk_dir.join("device/type")).as_deref() {
+            Some("SD") => Transport::Sd,
+            _ => Transport::Other,
+        }
+    } else {
+        Transport::Other
+    }
+}
+
+fn find_mount_point(mounts: &str, disk: &str) -> Option<String> {
+    let prefix = format!("/dev/{}", disk);
+
+    mounts.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let device = fields.next()?;
+        let mount_point = fields.next()?;
+
+        // Match the disk itself and its partitions: sdb1, mmcblk0p1. Disks
+        // whose name ends in a digit separate the partition number with a
+        // `p`, so mmcblk1 does not take in mmcblk10.
+        let suffix = device.strip_prefix(&prefix)?;
+        let number = if disk.ends_with(|c: char| c.is_ascii_digit()) {
+            suffix.strip_prefix('p')
+        } else {
+            Some(suffix)
+        };
+        let is_partition = number.is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()));
+        (suffix.is_empty() || is_partition).then(|| unescape_mount_path(mount_point))
+    })
+}
+
+// /proc/mounts escapes space, tab, newline and backslash as octal sequences.
+fn unescape_mount_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let octal = bytes.get(i + 1..i + 4).filter(|digits| digits.iter().all(|b| (b'0'..=b'7').contains(b)));
+        if let (b'\\', Some(digits)) = (bytes[i], octal) {
+            let value = digits.iter().fold(0u32, |acc, d| acc * 8 + u32::from(d - b'0'));
+            out.push(value as u8);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn read_attr(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok().map(|value| value.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    // Lays out a disk the way sysfs does: the real directory sits under the
+    // bus it hangs off, `block/<name>` links to it and `device` holds the
+    // identification attributes.
+    fn add_disk(root: &Path, bus_path: &str, name: &str, sectors: u64, removable: bool) -> PathBuf {
+        let disk_dir = root.join("devices").join(bus_path).join("block").join(name);
+        fs::create_dir_all(disk_dir.join("device")).unwrap();
+        fs::write(disk_dir.join("size"), format!("{}\n", sectors)).unwrap();
+        fs::write(disk_dir.join("removable"), if removable { "1\n" } else { "0\n" }).unwrap();
+        symlink(&disk_dir, root.join("block").join(name)).unwrap();
+        disk_dir
+    }
+
+    fn set_attr(disk_dir: &Path, name: &str, value: &str) {
+        fs::write(disk_dir.join(name), format!("{}\n", value)).unwrap();
+    }
+
+    #[test]
+    fn lists_usb_and_sd_disks_from_a_fake_sysfs() {
+        let dir = TempDir::new("sysfs");
+        let root = dir.path().join("sys");
+        fs::create_dir_all(root.join("block")).unwrap();
+
+        let usb = "pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0";
+        let stick = add_disk(&root, usb, "sdb", 4096, false);
+        set_attr(&stick, "device/vendor", "SanDisk ");
+        set_attr(&stick, "device/model", "Cruzer Blade    ");
+        // Partitions sit inside their disk and are never listed themselves.
+        fs::create_dir_all(stick.join("sdb1")).unwrap();
+        set_attr(&stick, "sdb1/size", "2048");
+
+        let card = add_disk(&root, "platform/fe320000.mmc/mmc_host/mmc1/mmc1:aaaa", "mmcblk0", 2048, false);
+        set_attr(&card, "device/type", "SD");
+
+        let emmc = add_disk(&root, "platform/fe330000.mmc/mmc_host/mmc0/mmc0:0001", "mmcblk1", 8192, false);
+        set_attr(&emmc, "device/type", "MMC");
+        add_disk(&root, "platform/fe330000.mmc/mmc_host/mmc0/mmc0:0001", "mmcblk1boot0", 64, false);
+
+        add_disk(&root, "pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0", "sda", 1 << 20, false);
+        let firewire = add_disk(&root, "pci0000:00/0000:00:1c.0/fw0/fw0.0", "sdd", 1024, true);
+        set_attr(&firewire, "device/vendor", "Acme");
+        // A card reader with no card in it.
+        add_disk(&root, "pci0000:00/0000:00:14.0/usb1/1-3/1-3:1.0/host7/target7:0:0/7:0:0:0", "sdc", 0, true);
+        add_disk(&root, "pci0000:00/0000:00:1f.2/ata2/host1/target1:0:0/1:0:0:0", "sr0", 2048, true);
+
+        // Virtual devices have no `device` link.
+        let loop_dir = root.join("devices/virtual/block/loop0");
+        fs::create_dir_all(&loop_dir).unwrap();
+        set_attr(&loop_dir, "size", "1024");
+        symlink(&loop_dir, root.join("block/loop0")).unwrap();
+
+        let mounts = dir.write(
+            "mounts",
+            b"/dev/sda2 / ext4 rw 0 0\n/dev/sdb1 /media/user/My\\040Stick vfat rw 0 0\n",
+        );
+
+        let drives = SysfsProvider::with_roots(&root, &mounts).list_drives();
+        assert_eq!(
+            drives,
+            vec![
+                DriveInfo {
+                    device: "/dev/mmcblk0".to_string(),
+                    mount_point: None,
+                    vendor: String::new(),
+                    model: String::new(),
+                    size: 2048 * 512,
+                    transport: Transport::Sd,
+                    removable: false,
+                    new_image: false,
+                },
+                DriveInfo {
+                    device: "/dev/sdb".to_string(),
+                    mount_point: Some("/media/user/My Stick".to_string()),
+                    vendor: "SanDisk".to_string(),
+                    model: "Cruzer Blade".to_string(),
+                    size: 4096 * 512,
+                    transport: Transport::Usb,
+                    removable: false,
+                    new_image: false,
+                },
+                DriveInfo {
+                    device: "/dev/sdd".to_string(),
+                    mount_point: None,
+                    vendor: "Acme".to_string(),
+                    model: String::new(),
+                    size: 1024 * 512,
+                    transport: Transport::Other,
+                    removable: true,
+                    new_image: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_mounts_of_partitions_but_not_of_similarly_named_disks() {
+        let mounts = "/dev/mmcblk10p1 /media/other vfat rw 0 0\n\
+                      /dev/nvme0n10 /srv ext4 rw 0 0\n\
+                      /dev/sdb12 /media/stick vfat rw 0 0\n\
+                      /dev/mmcblk1p2 /media/card exfat rw 0 0\n\
+                      /dev/nvme0n1 /data ext4 rw 0 0\n";
+        assert_eq!(find_mount_point(mounts, "mmcblk1").as_deref(), Some("/media/card"));
+        assert_eq!(find_mount_point(mounts, "nvme0n1").as_deref(), Some("/data"));
+        assert_eq!(find_mount_point(mounts, "sdb").as_deref(), Some("/media/stick"));
+        assert_eq!(find_mount_point(mounts, "sd"), None);
+        assert_eq!(find_mount_point(mounts, "mmcblk10").as_deref(), Some("/media/other"));
+        assert_eq!(find_mount_point("/dev/mmcblk1p /x vfat rw 0 0\n", "mmcblk1"), None);
+        assert_eq!(find_mount_point("/dev/sdb1p2 /x vfat rw 0 0\n", "sdb"), None);
+    }
+
+    #[test]
+    fn lists_nothing_without_a_block_directory() {
+        let dir = TempDir::new("sysfs-empty");
+        let provider = SysfsProvider::with_roots(dir.path(), dir.path().join("mounts"));
+        assert!(provider.list_drives().is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+use super::{BlockTarget, DriveInfo, DriveProvider, Transport};
+
+// Drives that only exist in memory. Each disk is a fixed-size buffer that
+// stays alive in the provider, so whatever was written to it can be opened
+// and read back later, just like a real stick.
+#[derive(Default)]
+pub struct MockProvider {
+    drives: Vec<DriveInfo>,
+    disks: HashMap<String, Arc<Mutex<Vec<u8>>>>,
+}
+
+impl MockProvider {
+    pub fn with_demo_drives() -> Self {
+        let mut provider = Self::default();
+        provider.add_drive("mock0", "Ayumi", "Virtual Stick", 64 * 1024 * 1024, Transport::Usb);
+        // Both are large enough for FAT32 with the default cluster size once
+        // the partition starts 1 MiB in.
+        provider.add_drive("mock1", "Ayumi", "Virtual Card", 48 * 1024 * 1024, Transport::Sd);
+        provider
+    }
+
+    pub fn add_drive(&mut self, device: &str, vendor: &str, model: &str, size: u64, transport: Transport) {
+        self.drives.push(DriveInfo {
+            device: device.to_string(),
+            mount_point: None,
+            vendor: vendor.to_string(),
+            model: model.to_string(),
+            size,
+            transport,
+            removable: true,
+            new_image: false,
+        });
+        self.disks
+            .insert(device.to_string(), Arc::new(Mutex::new(vec![0; size as usize])));
+    }
+}
+
+impl DriveProvider for MockProvider {
+    fn list_drives(&self) -> Vec<DriveInfo> {
+        self.drives.clone()
+    }
+
+    fn open_target(&self, drive: &DriveInfo) -> io::Result<Box<dyn BlockTarget>> {
+        let disk = self.disks.get(&drive.device).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("No mock drive named {}", drive.device))
+        })?;
+
+        Ok(Box::new(MockTarget {
+            disk: Arc::clone(disk),
+            position: 0,
+        }))
+    }
+}
+
+struct MockTarget {
+    disk: Arc<Mutex<Vec<u8>>>,
+    position: u64,
+}
+
+impl BlockTarget for MockTarget {
+    fn capacity(&mut self) -> io::Result<Option<u64>> {
+        Ok(Some(self.disk.lock().unwrap().len() as u64))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn drop_cache(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for MockTarget {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let disk = self.disk.lock().unwrap();
+        let start = (self.position as usize).min(disk.len());
+        let count = buf.len().min(disk.len() - start);
+
+        buf[..count].copy_from_slice(&disk[start..start + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for MockTarget {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut disk = self.disk.lock().unwrap();
+        let start = self.position as usize;
+
+        // Like a real device, the disk cannot grow past its capacity.
+        if start + buf.len() > disk.len() {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "No space left on mock drive"));
+        }
+
+        disk[start..start + buf.len()].copy_from_slice(buf);
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MockTarget {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.disk.lock().unwrap().len() as i64;
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+
+        if position < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before start of mock drive"));
+        }
+
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{FatVolume, FormatOptions};
+    use crate::partition::ALIGNMENT;
+
+    fn format_fat32(provider: &MockProvider, drive: &DriveInfo) -> io::Result<()> {
+        let mut target = provider.open_target(drive)?;
+        let size = target.capacity()?.unwrap() - ALIGNMENT;
+        FatVolume::format(target.as_mut(), ALIGNMENT, size, &FormatOptions::default())?.finish()
+    }
+
+    #[test]
+    fn demo_drives_take_fat32_with_default_options() {
+        let provider = MockProvider::with_demo_drives();
+        let drives = provider.list_drives();
+        assert_eq!(drives.len(), 2);
+        for drive in &drives {
+            format_fat32(&provider, drive).unwrap();
+        }
+    }
+
+    #[test]
+    fn drive_too_small_for_fat32_is_refused() {
+        let mut provider = MockProvider::default();
+        provider.add_drive("tiny", "Ayumi", "Tiny Card", 32 * 1024 * 1024, Transport::Sd);
+        let error = format_fat32(&provider, &provider.list_drives()[0]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("too small for FAT32"), "{}", error);
+    }
+
+    #[test]
+    fn writes_past_the_end_fail() {
+        let mut provider = MockProvider::default();
+        provider.add_drive("mock", "Ayumi", "Stick", 1024, Transport::Usb);
+        let mut target = provider.open_target(&provider.list_drives()[0]).unwrap();
+
+        target.seek(SeekFrom::Start(1000)).unwrap();
+        let error = target.write_all(&[1; 100]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::WriteZero);
+
+        // Another handle sees what the first one wrote.
+        target.seek(SeekFrom::Start(0)).unwrap();
+        target.write_all(b"boot").unwrap();
+        let mut again = provider.open_target(&provider.list_drives()[0]).unwrap();
+        let mut head = [0; 4];
+        again.read_exact(&mut head).unwrap();
+        assert_eq!(&head, b"boot");
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::{self, Read, Seek, Write};
+use std::sync::Arc;
+
+mod file;
+#[cfg(target_os = "linux")]
+mod linux;
+mod mock;
+#[cfg(windows)]
+mod windows;
+
+pub use file::FileTarget;
+pub use mock::MockProvider;
+
+// Discovers candidate target drives and opens them for raw I/O. The GUI and
+// the write engine only talk to this trait, so they never see an OS API.
+pub trait DriveProvider: Send + Sync {
+    fn list_drives(&self) -> Vec<DriveInfo>;
+    fn open_target(&self, drive: &DriveInfo) -> io::Result<Box<dyn BlockTarget>>;
+}
+
+// A whole disk, or something pretending to be one, opened for writing from
+// offset 0.
+pub trait BlockTarget: Read + Write + Seek + Send {
+    // The number of addressable bytes, or `None` when the target simply grows
+    // as it is written, like a regular image file.
+    fn capacity(&mut self) -> io::Result<Option<u64>>;
+
+    // Pushes every buffered write down to the medium.
+    fn sync(&mut self) -> io::Result<()>;
+
+    // Forgets whatever the OS cached of the target, so the next reads come
+    // from the medium itself. Only meaningful after `sync`.
+    fn drop_cache(&mut self) -> io::Result<()>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Transport {
+    Usb,
+    Sd,
+    Other,
+}
+
+impl Transport {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Transport::Usb => "USB",
+            Transport::Sd => "SD",
+            Transport::Other => "other",
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct DriveInfo {
+    // What raw writes open: `/dev/sdb` on Linux, `E:\` on Windows, or any
+    // path the user typed in, such as an image file.
+    pub device: String,
+    // Where the drive's filesystem is mounted, if anywhere. Copy mode writes
+    // there when the drive holds no FAT32 volume; every other write goes
+    // through the device.
+    pub mount_point: Option<String>,
+    pub vendor: String,
+    pub model: String,
+    pub size: u64,
+    pub transport: Transport,
+    pub removable: bool,
+    // Set when the user asked for a new image file at `device`; only then
+    // is a missing path created rather than reported.
+    pub new_image: bool,
+}
+
+impl DriveInfo {
+    pub fn from_path(path: &str) -> Self {
+        Self {
+            device: path.to_string(),
+            mount_point: None,
+            vendor: String::new(),
+            model: String::new(),
+            size: 0,
+            transport: Transport::Other,
+            removable: false,
+            new_image: false,
+        }
+    }
+
+    pub fn new_image(path: &str) -> Self {
+        Self {
+            new_image: true,
+            ..Self::from_path(path)
+        }
+    }
+
+    pub fn display_name(&self) -> String {
+        let mut details = Vec::new();
+
+        let name = format!("{} {}", self.vendor, self.model).trim().to_string();
+        if !name.is_empty() {
+            details.push(name);
+        }
+        if self.size > 0 {
+            details.push(format_size(self.size));
+        }
+        if self.transport != Transport::Other {
+            details.push(self.transport.label().to_string());
+        } else if self.removable {
+            details.push("removable".to_string());
+        }
+        if let Some(mount_point) = self.mount_point.as_ref().filter(|m| **m != self.device) {
+            details.push(format!("mounted at {}", mount_point));
+        }
+
+        if details.is_empty() {
+            self.device.clone()
+        } else {
+            format!("{} ({})", self.device, details.join(", "))
+        }
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// Picks the provider for the running OS. Setting `AYUMI_MOCK_DRIVES` swaps in
+// in-memory drives so the GUI can be exercised without real hardware.
+pub fn default_provider() -> Arc<dyn DriveProvider> {
+    if std::env::var_os("AYUMI_MOCK_DRIVES").is_some() {
+        return Arc::new(MockProvider::with_demo_drives());
+    }
+    native_provider()
+}
+
+#[cfg(target_os = "linux")]
+fn native_provider() -> Arc<dyn DriveProvider> {
+    Arc::new(linux::SysfsProvider::default())
+}
+
+#[cfg(windows)]
+fn native_provider() -> Arc<dyn DriveProvider> {
+    Arc::new(windows::WindowsProvider)
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn native_provider() -> Arc<dyn DriveProvider> {
+    Arc::new(file::FileProvider)
+}
@@ -0,0 +1,200 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+use std::ptr;
+
+use winapi::shared::minwindef::{DWORD, LPVOID};
+use winapi::um::fileapi::{FindFirstVolumeW, FindNextVolumeW, FindVolumeClose, GetDriveTypeA, GetLogicalDrives};
+use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use winapi::um::ioapiset::DeviceIoControl;
+use winapi::um::winioctl::{
+    DISK_EXTENT, FSCTL_DISMOUNT_VOLUME, FSCTL_LOCK_VOLUME, GET_LENGTH_INFORMATION, IOCTL_DISK_GET_LENGTH_INFO,
+    IOCTL_STORAGE_GET_DEVICE_NUMBER, IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS, STORAGE_DEVICE_NUMBER,
+};
+
+use super::{BlockTarget, DriveInfo, DriveProvider, FileTarget, Transport};
+
+const DRIVE_REMOVABLE: u32 = 2;
+
+pub struct WindowsProvider;
+
+impl DriveProvider for WindowsProvider {
+    fn list_drives(&self) -> Vec<DriveInfo> {
+        let mut drives = Vec::new();
+        let drive_bits = unsafe { GetLogicalDrives() };
+
+        for i in 0..26 {
+            if drive_bits & (1 << i) != 0 {
+                let drive_letter = format!("{}:\\", (b'A' + i as u8) as char);
+                let root = CString::new(drive_letter.clone()).unwrap();
+                let drive_type = unsafe { GetDriveTypeA(root.as_ptr()) };
+
+                if drive_type == DRIVE_REMOVABLE {
+                    drives.push(DriveInfo {
+                        device: drive_letter.clone(),
+                        mount_point: Some(drive_letter),
+                        vendor: String::new(),
+                        model: String::new(),
+                        size: 0,
+                        transport: Transport::Usb,
+                        removable: true,
+                        new_image: false,
+                    });
+                }
+            }
+        }
+
+        drives
+    }
+
+    // A drive letter only names one volume. Writing from offset 0 of the disk
+    // needs the physical drive behind it, and Windows refuses writes into
+    // sectors of a mounted volume unless that volume is locked and dismounted.
+    // That goes for every volume on the disk, not just the one with the
+    // letter.
+    fn open_target(&self, drive: &DriveInfo) -> io::Result<Box<dyn BlockTarget>> {
+        let letter = match drive.device.as_bytes() {
+            [letter, b':', ..] if letter.is_ascii_alphabetic() => *letter as char,
+            _ => return Ok(Box::new(FileTarget::open(Path::new(&drive.device), drive.new_image)?)),
+        };
+
+        let volume = open_device(&format!("\\\\.\\{}:", letter))?;
+        let mut number: STORAGE_DEVICE_NUMBER = unsafe { mem::zeroed() };
+        ioctl(&volume, IOCTL_STORAGE_GET_DEVICE_NUMBER, &mut number)?;
+        drop(volume);
+
+        let mut volumes = Vec::new();
+        for path in volumes_on_disk(number.DeviceNumber)? {
+            let volume = open_device(&path)?;
+            ioctl(&volume, FSCTL_LOCK_VOLUME, &mut ())?;
+            ioctl(&volume, FSCTL_DISMOUNT_VOLUME, &mut ())?;
+            volumes.push(volume);
+        }
+
+        let disk = open_device(&format!("\\\\.\\PhysicalDrive{}", number.DeviceNumber))?;
+        Ok(Box::new(PhysicalDrive {
+            disk,
+            _volumes: volumes,
+        }))
+    }
+}
+
+struct PhysicalDrive {
+    disk: File,
+    // Keeping the volume handles open keeps the volumes locked.
+    _volumes: Vec<File>,
+}
+
+impl BlockTarget for PhysicalDrive {
+    fn capacity(&mut self) -> io::Result<Option<u64>> {
+        let mut info: GET_LENGTH_INFORMATION = unsafe { mem::zeroed() };
+        ioctl(&self.disk, IOCTL_DISK_GET_LENGTH_INFO, &mut info)?;
+        Ok(Some(unsafe { *info.Length.QuadPart() } as u64))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.disk.sync_all()
+    }
+
+    // Raw disk handles bypass the cache manager; reads already hit the disk.
+    fn drop_cache(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for PhysicalDrive {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.disk.read(buf)
+    }
+}
+
+impl Write for PhysicalDrive {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.disk.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.flush()
+    }
+}
+
+impl Seek for PhysicalDrive {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.disk.seek(pos)
+    }
+}
+
+// Room for volumes that span several disks; VOLUME_DISK_EXTENTS declares
+// a single extent.
+#[repr(C)]
+struct DiskExtents {
+    count: DWORD,
+    extents: [DISK_EXTENT; 16],
+}
+
+// Every volume with an extent on the disk, as a path to open it by.
+// Volumes that cannot be queried, such as empty card readers and optical
+// drives, are not on a disk.
+fn volumes_on_disk(disk_number: DWORD) -> io::Result<Vec<String>> {
+    let mut name = [0u16; 261];
+    let search = unsafe { FindFirstVolumeW(name.as_mut_ptr(), name.len() as DWORD) };
+    if search == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut paths = Vec::new();
+    loop {
+        let length = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        // Volume names end in a backslash, which would open the root
+        // directory instead of the volume.
+        let path = String::from_utf16_lossy(&name[..length]).trim_end_matches('\\').to_string();
+        let mut extents: DiskExtents = unsafe { mem::zeroed() };
+        let queried = open_device(&path)
+            .and_then(|volume| ioctl(&volume, IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS, &mut extents))
+            .is_ok();
+        let count = (extents.count as usize).min(extents.extents.len());
+        if queried && extents.extents[..count].iter().any(|extent| extent.DiskNumber == disk_number) {
+            paths.push(path);
+        }
+
+        if unsafe { FindNextVolumeW(search, name.as_mut_ptr(), name.len() as DWORD) } == 0 {
+            break;
+        }
+    }
+    unsafe { FindVolumeClose(search) };
+    Ok(paths)
+}
+
+fn open_device(path: &str) -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).open(path)
+}
+
+fn ioctl<T>(device: &File, code: DWORD, output: &mut T) -> io::Result<()> {
+    let mut returned: DWORD = 0;
+    let buffer = if mem::size_of::<T>() == 0 {
+        ptr::null_mut()
+    } else {
+        output as *mut T as LPVOID
+    };
+    let ok = unsafe {
+        DeviceIoControl(
+            device.as_raw_handle() as _,
+            code,
+            ptr::null_mut(),
+            0,
+            buffer,
+            mem::size_of::<T>() as DWORD,
+            &mut returned,
+            ptr::null_mut(),
+        )
+    };
+
+    if ok == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,441 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::writer::WriteSummary;
+
+const SPARSE_MAGIC: u32 = 0xED26_FF3A;
+const MAJOR_VERSION: u16 = 1;
+const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_RAW: u16 = 0xCAC1;
+const CHUNK_FILL: u16 = 0xCAC2;
+const CHUNK_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_CRC32: u16 = 0xCAC4;
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+const SECTOR_SIZE: u64 = 512;
+
+// The file header of an Android sparse image, as fastboot and img2simg
+// write them.
+pub struct SparseHeader {
+    pub block_size: u32,
+    pub total_blocks: u32,
+    pub total_chunks: u32,
+    file_header_size: u16,
+    chunk_header_size: u16,
+}
+
+impl SparseHeader {
+    // The size of the image once every chunk is expanded.
+    pub fn image_size(&self) -> u64 {
+        self.block_size as u64 * self.total_blocks as u64
+    }
+}
+
+// A piece of the expanded image: bytes to put at an offset, or blocks the
+// image does not care about.
+enum Extent<'a> {
+    Data(&'a [u8]),
+    DontCare(u64),
+}
+
+// `None` for an image that is not sparse.
+pub fn probe(path: &Path) -> io::Result<Option<SparseHeader>> {
+    Ok(detect(Box::new(File::open(path)?))?.0)
+}
+
+// Looks for the sparse magic at the start of the image, which may come out
+// of a decompressor and so cannot be seeked back. A sparse image is handed
+// back just past its header, anything else whole.
+pub fn detect<'a>(mut image: Box<dyn Read + 'a>) -> io::Result<(Option<SparseHeader>, Box<dyn Read + 'a>)> {
+    let mut head = Vec::with_capacity(FILE_HEADER_SIZE);
+    image.by_ref().take(FILE_HEADER_SIZE as u64).read_to_end(&mut head)?;
+    if head.len() < FILE_HEADER_SIZE || u32_at(&head, 0) != SPARSE_MAGIC {
+        return Ok((None, Box::new(io::Cursor::new(head).chain(image))));
+    }
+
+    if u16_at(&head, 4) != MAJOR_VERSION {
+        return Err(invalid(&format!("Android sparse format version {} is not supported", u16_at(&head, 4))));
+    }
+    let header = SparseHeader {
+        block_size: u32_at(&head, 12),
+        total_blocks: u32_at(&head, 16),
+        total_chunks: u32_at(&head, 20),
+        file_header_size: u16_at(&head, 8),
+        chunk_header_size: u16_at(&head, 10),
+    };
+    if (header.file_header_size as usize) < FILE_HEADER_SIZE || (header.chunk_header_size as usize) < CHUNK_HEADER_SIZE {
+        return Err(invalid("The sparse image header is damaged"));
+    }
+    // Blocks land on the drive where they belong, so they must be whole
+    // sectors.
+    if header.block_size == 0 || !(header.block_size as u64).is_multiple_of(SECTOR_SIZE) {
+        return Err(invalid(&format!("Sparse block size {} is not a whole number of sectors", header.block_size)));
+    }
+
+    // Later versions may grow the header; the extra fields are skipped.
+    skip(&mut image, header.file_header_size as u64 - FILE_HEADER_SIZE as u64)?;
+    Ok((Some(header), image))
+}
+
+// Expands the chunks onto the target. Blocks the image does not care about
+// are seeked past and keep whatever the drive held, as fastboot leaves them.
+pub fn write_sparse<R: Read + ?Sized, W: Write + Seek + ?Sized>(
+    source: &mut R,
+    header: &SparseHeader,
+    target: &mut W,
+    skip_zeros: bool,
+) -> io::Result<WriteSummary> {
+    let mut summary = WriteSummary::default();
+    expand(source, header, |offset, extent| {
+        match extent {
+            Extent::Data(data) if skip_zeros && data.iter().all(|&byte| byte == 0) => {
+                summary.skipped += data.len() as u64;
+            }
+            Extent::Data(data) => {
+                target.seek(SeekFrom::Start(offset))?;
+                target.write_all(data)?;
+                summary.written += data.len() as u64;
+            }
+            Extent::DontCare(length) => summary.skipped += length,
+        }
+        Ok(())
+    })?;
+    target.flush()?;
+    Ok(summary)
+}
+
+// Reads back every block the image sets and compares it; the blocks it does
+// not care about are not compared.
+pub fn verify_sparse<R: Read + ?Sized, T: Read + Seek + ?Sized>(
+    source: &mut R,
+    header: &SparseHeader,
+    target: &mut T,
+) -> io::Result<()> {
+    let mut actual = vec![0; CHUNK_SIZE];
+    expand(source, header, |offset, extent| {
+        let Extent::Data(expected) = extent else {
+            return Ok(());
+        };
+        let actual = &mut actual[..expected.len()];
+        target.seek(SeekFrom::Start(offset))?;
+        target.read_exact(actual).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid(&format!(
+                "Verification failed: the drive ends before byte {}, within the image",
+                offset + expected.len() as u64
+            )),
+            _ => e,
+        })?;
+        match expected.iter().zip(actual.iter()).position(|(expected, actual)| expected != actual) {
+            Some(index) => Err(invalid(&format!(
+                "Verification failed: the drive returned different data at byte {} (0x{:X})",
+                offset + index as u64,
+                offset + index as u64
+            ))),
+            None => Ok(()),
+        }
+    })
+}
+
+// Walks the chunks front to back, handing each piece of the expanded image
+// to `each` in at most 1 MiB at a time. CRC32 chunks hold the checksum of
+// everything expanded so far, blocks the image does not care about counting
+// as zeros.
+fn expand<R: Read + ?Sized>(
+    source: &mut R,
+    header: &SparseHeader,
+    mut each: impl FnMut(u64, Extent) -> io::Result<()>,
+) -> io::Result<()> {
+    let block_size = header.block_size as u64;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut crc = crc32fast::Hasher::new();
+    let mut offset = 0u64;
+
+    for index in 0..header.total_chunks {
+        let mut chunk = [0; CHUNK_HEADER_SIZE];
+        read_exact(source, &mut chunk)?;
+        skip(source, header.chunk_header_size as u64 - CHUNK_HEADER_SIZE as u64)?;
+        let kind = u16_at(&chunk, 0);
+        let length = u32_at(&chunk, 4) as u64 * block_size;
+        let data_size = (u32_at(&chunk, 8) as u64)
+            .checked_sub(header.chunk_header_size as u64)
+            .ok_or_else(|| invalid(&format!("Chunk {} of the sparse image is damaged", index)))?;
+        if offset + length > header.image_size() {
+            return Err(invalid(&format!("Chunk {} of the sparse image runs past its end", index)));
+        }
+        let expected_size = match kind {
+            CHUNK_RAW => length,
+            CHUNK_FILL | CHUNK_CRC32 => 4,
+            CHUNK_DONT_CARE => 0,
+            // Reported as unknown below.
+            _ => data_size,
+        };
+        if data_size != expected_size {
+            return Err(invalid(&format!("Chunk {} of the sparse image has the wrong size", index)));
+        }
+
+        match kind {
+            CHUNK_RAW => {
+                let mut remaining = length;
+                while remaining > 0 {
+                    let piece = &mut buffer[..remaining.min(CHUNK_SIZE as u64) as usize];
+                    read_exact(source, piece)?;
+                    crc.update(piece);
+                    each(offset + length - remaining, Extent::Data(piece))?;
+                    remaining -= piece.len() as u64;
+                }
+            }
+            CHUNK_FILL => {
+                let mut pattern = [0; 4];
+                read_exact(source, &mut pattern)?;
+                for word in buffer.chunks_exact_mut(4) {
+                    word.copy_from_slice(&pattern);
+                }
+                let mut remaining = length;
+                while remaining > 0 {
+                    let piece = &buffer[..remaining.min(CHUNK_SIZE as u64) as usize];
+                    crc.update(piece);
+                    each(offset + length - remaining, Extent::Data(piece))?;
+                    remaining -= piece.len() as u64;
+                }
+            }
+            CHUNK_DONT_CARE => {
+                buffer.fill(0);
+                let mut remaining = length;
+                while remaining > 0 {
+                    let piece = remaining.min(CHUNK_SIZE as u64);
+                    crc.update(&buffer[..piece as usize]);
+                    remaining -= piece;
+                }
+                each(offset, Extent::DontCare(length))?;
+            }
+            CHUNK_CRC32 => {
+                let mut expected = [0; 4];
+                read_exact(source, &mut expected)?;
+                if crc.clone().finalize() != u32::from_le_bytes(expected) {
+                    return Err(invalid(&format!(
+                        "The sparse image is damaged: the CRC-32 in chunk {} does not match",
+                        index
+                    )));
+                }
+            }
+            kind => return Err(invalid(&format!("Chunk {} of the sparse image has unknown type 0x{:04X}", index, kind))),
+        }
+        offset += length;
+    }
+
+    if offset != header.image_size() {
+        return Err(invalid(&format!(
+            "The sparse image's chunks cover {} of its {} blocks",
+            offset / block_size,
+            header.total_blocks
+        )));
+    }
+    Ok(())
+}
+
+fn read_exact<R: Read + ?Sized>(source: &mut R, buffer: &mut [u8]) -> io::Result<()> {
+    source.read_exact(buffer).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("The sparse image ends before its last chunk"),
+        _ => e,
+    })
+}
+
+fn skip<R: Read + ?Sized>(source: &mut R, length: u64) -> io::Result<()> {
+    if io::copy(&mut source.take(length), &mut io::sink())? < length {
+        return Err(invalid("The sparse image ends before its last chunk"));
+    }
+    Ok(())
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const BLOCK_SIZE: usize = 1024;
+
+    // Builds a sparse image the way img2simg lays one out, optionally with
+    // headers grown by a later format version.
+    struct Simg {
+        extra_header: usize,
+        blocks: u32,
+        chunk_count: u32,
+        chunks: Vec<u8>,
+        crc: crc32fast::Hasher,
+    }
+
+    impl Simg {
+        fn new(extra_header: usize) -> Self {
+            Self {
+                extra_header,
+                blocks: 0,
+                chunk_count: 0,
+                chunks: Vec::new(),
+                crc: crc32fast::Hasher::new(),
+            }
+        }
+
+        fn chunk(mut self, kind: u16, blocks: u32, payload: &[u8]) -> Self {
+            let header_size = CHUNK_HEADER_SIZE + self.extra_header;
+            self.chunks.extend_from_slice(&kind.to_le_bytes());
+            self.chunks.extend_from_slice(&[0; 2]);
+            self.chunks.extend_from_slice(&blocks.to_le_bytes());
+            self.chunks.extend_from_slice(&((header_size + payload.len()) as u32).to_le_bytes());
+            self.chunks.extend(vec![0; self.extra_header]);
+            self.chunks.extend_from_slice(payload);
+            self.blocks += blocks;
+            self.chunk_count += 1;
+            self
+        }
+
+        fn raw(mut self, data: &[u8]) -> Self {
+            self.crc.update(data);
+            self.chunk(CHUNK_RAW, (data.len() / BLOCK_SIZE) as u32, data)
+        }
+
+        fn fill(mut self, pattern: [u8; 4], blocks: u32) -> Self {
+            self.crc.update(&pattern.repeat(blocks as usize * BLOCK_SIZE / 4));
+            self.chunk(CHUNK_FILL, blocks, &pattern)
+        }
+
+        fn dont_care(mut self, blocks: u32) -> Self {
+            self.crc.update(&vec![0; blocks as usize * BLOCK_SIZE]);
+            self.chunk(CHUNK_DONT_CARE, blocks, &[])
+        }
+
+        fn crc(self, flip: u32) -> Self {
+            let crc = self.crc.clone().finalize() ^ flip;
+            self.chunk(CHUNK_CRC32, 0, &crc.to_le_bytes())
+        }
+
+        fn build(self) -> Vec<u8> {
+            let mut image = Vec::new();
+            image.extend_from_slice(&SPARSE_MAGIC.to_le_bytes());
+            image.extend_from_slice(&MAJOR_VERSION.to_le_bytes());
+            image.extend_from_slice(&0u16.to_le_bytes());
+            image.extend_from_slice(&((FILE_HEADER_SIZE + self.extra_header) as u16).to_le_bytes());
+            image.extend_from_slice(&((CHUNK_HEADER_SIZE + self.extra_header) as u16).to_le_bytes());
+            image.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+            image.extend_from_slice(&self.blocks.to_le_bytes());
+            image.extend_from_slice(&self.chunk_count.to_le_bytes());
+            image.extend_from_slice(&0u32.to_le_bytes());
+            image.extend(vec![0; self.extra_header]);
+            image.extend(self.chunks);
+            image
+        }
+    }
+
+    fn pattern(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8 + 1).collect()
+    }
+
+    // Two raw blocks, three filled, two not cared about, a zero-filled one
+    // and a last raw block, checked halfway and at the end.
+    fn sample(extra_header: usize) -> (Vec<u8>, Vec<u8>) {
+        let first = pattern(2 * BLOCK_SIZE);
+        let last = pattern(BLOCK_SIZE + 7)[7..].to_vec();
+        let image = Simg::new(extra_header)
+            .raw(&first)
+            .fill([0xDE, 0xAD, 0xBE, 0xEF], 3)
+            .crc(0)
+            .dont_care(2)
+            .fill([0; 4], 1)
+            .raw(&last)
+            .crc(0)
+            .build();
+
+        let mut expanded = first;
+        expanded.extend([0xDE, 0xAD, 0xBE, 0xEF].repeat(3 * BLOCK_SIZE / 4));
+        expanded.extend(vec![0xEE; 2 * BLOCK_SIZE]);
+        expanded.extend(vec![0; BLOCK_SIZE]);
+        expanded.extend(last);
+        (image, expanded)
+    }
+
+    fn open(image: Vec<u8>) -> (SparseHeader, Box<dyn Read>) {
+        let (header, rest) = detect(Box::new(Cursor::new(image))).unwrap();
+        (header.unwrap(), rest)
+    }
+
+    fn write(image: Vec<u8>, skip_zeros: bool) -> io::Result<(Vec<u8>, WriteSummary)> {
+        let (header, mut rest) = open(image);
+        let mut target = Cursor::new(vec![0xEE; header.image_size() as usize]);
+        let summary = write_sparse(&mut rest, &header, &mut target, skip_zeros)?;
+        Ok((target.into_inner(), summary))
+    }
+
+    #[test]
+    fn expands_every_chunk_type() {
+        for extra_header in [0, 4] {
+            let (image, expanded) = sample(extra_header);
+            let (header, _) = open(image.clone());
+            assert_eq!((header.block_size, header.total_blocks, header.total_chunks), (1024, 9, 7));
+            assert_eq!(header.image_size(), 9 * BLOCK_SIZE as u64);
+
+            // The blocks not cared about keep what the drive held.
+            let (written, summary) = write(image.clone(), false).unwrap();
+            assert_eq!(written, expanded);
+            assert_eq!((summary.written, summary.skipped), (7 * 1024, 2 * 1024));
+
+            let (header, mut rest) = open(image.clone());
+            verify_sparse(&mut rest, &header, &mut Cursor::new(written)).unwrap();
+
+            let (_, summary) = write(image, true).unwrap();
+            assert_eq!((summary.written, summary.skipped), (6 * 1024, 3 * 1024));
+        }
+    }
+
+    #[test]
+    fn passes_other_images_through_untouched() {
+        let image = pattern(100);
+        let (header, mut rest) = detect(Box::new(Cursor::new(image.clone()))).unwrap();
+        assert!(header.is_none());
+        let mut read = Vec::new();
+        rest.read_to_end(&mut read).unwrap();
+        assert_eq!(read, image);
+    }
+
+    #[test]
+    fn rejects_a_crc_mismatch() {
+        let image = Simg::new(0).raw(&pattern(BLOCK_SIZE)).dont_care(1).crc(1).build();
+        let error = write(image, false).err().unwrap();
+        assert_eq!(error.to_string(), "The sparse image is damaged: the CRC-32 in chunk 2 does not match");
+    }
+
+    #[test]
+    fn rejects_a_truncated_image() {
+        let (image, _) = sample(0);
+        for length in [image.len() - 1, image.len() - 16, FILE_HEADER_SIZE + 5] {
+            let error = write(image[..length].to_vec(), false).err().unwrap();
+            assert_eq!(error.to_string(), "The sparse image ends before its last chunk");
+        }
+    }
+
+    #[test]
+    fn rejects_verification_against_a_different_drive() {
+        let (image, mut expanded) = sample(0);
+        expanded[3 * BLOCK_SIZE + 1] = 0;
+        let (header, mut rest) = open(image);
+        let error = verify_sparse(&mut rest, &header, &mut Cursor::new(expanded)).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "Verification failed: the drive returned different data at byte 3073 (0xC01)"
+        );
+    }
+}
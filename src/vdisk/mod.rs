@@ -0,0 +1,487 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use flate2::read::DeflateDecoder;
+
+mod qcow2;
+mod vhd;
+mod vhdx;
+mod vmdk;
+
+// VMDKs split into extent files start with their text descriptor instead.
+const VMDK_DESCRIPTOR: &[u8] = b"# Disk DescriptorFile";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    FixedVhd,
+    DynamicVhd,
+    Vhdx,
+    Qcow2,
+    SparseVmdk,
+}
+
+impl Format {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Format::FixedVhd => "VHD, fixed size",
+            Format::DynamicVhd => "VHD, dynamically expanding",
+            Format::Vhdx => "VHDX",
+            Format::Qcow2 => "qcow2",
+            Format::SparseVmdk => "VMDK, monolithic sparse",
+        }
+    }
+}
+
+pub struct VirtualDisk {
+    pub format: Format,
+    // The size of the disk the guest sees, which is what gets written.
+    pub size: u64,
+    pub file_size: u64,
+}
+
+// Where one block of the disk is kept in the file.
+enum Location {
+    Stored(u64),
+    // Never written, so it reads as zeros.
+    Zero,
+    // A deflate-compressed cluster: where it starts and at most how long
+    // it is.
+    Deflated { offset: u64, length: u64 },
+}
+
+// How a format lays out the disk in its file. Every block is the same size
+// and is found through `locate`, which may read and cache the format's
+// tables.
+trait Layout {
+    fn block_size(&self) -> u64;
+    fn locate(&mut self, file: &mut File, block: u64) -> io::Result<Location>;
+}
+
+// Reads a virtual disk front to back as the flat image it stands for, so
+// the raw write loop takes it like any other image.
+pub struct DiskReader {
+    pub disk: VirtualDisk,
+    file: File,
+    layout: Box<dyn Layout>,
+    position: u64,
+    block: Option<(u64, Location)>,
+    // The current block decompressed, for compressed clusters.
+    inflated: Vec<u8>,
+}
+
+// `None` for a file that is no virtual disk.
+pub fn probe(path: &Path) -> io::Result<Option<VirtualDisk>> {
+    Ok(open(File::open(path)?)?.map(|reader| reader.disk))
+}
+
+// Goes by the signature each format puts at the start of the file, or for
+// VHD, the footer at its end.
+pub fn open(mut file: File) -> io::Result<Option<DiskReader>> {
+    let file_size = file.metadata()?.len();
+    let mut head = Vec::new();
+    file.by_ref().take(VMDK_DESCRIPTOR.len() as u64).read_to_end(&mut head)?;
+
+    let (format, size, layout) = if head.starts_with(vhdx::SIGNATURE) {
+        vhdx::open(&mut file)?
+    } else if head.starts_with(qcow2::MAGIC) {
+        qcow2::open(&mut file)?
+    } else if head.starts_with(vmdk::MAGIC) {
+        vmdk::open(&mut file)?
+    } else if head.starts_with(VMDK_DESCRIPTOR) {
+        return Err(unsupported(
+            "This VMDK only describes the disk; VMDKs whose data lies in separate extent files are not supported",
+        ));
+    } else if let Some(footer) = vhd::find_footer(&mut file, file_size)? {
+        vhd::open(&mut file, &footer, file_size)?
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(DiskReader {
+        disk: VirtualDisk {
+            format,
+            size,
+            file_size,
+        },
+        file,
+        layout,
+        position: 0,
+        block: None,
+        inflated: Vec::new(),
+    }))
+}
+
+impl Read for DiskReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.disk.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block_size = self.layout.block_size();
+        let index = self.position / block_size;
+        let within = self.position % block_size;
+        let count = (buf.len() as u64).min(block_size - within).min(self.disk.size - self.position) as usize;
+        let buf = &mut buf[..count];
+
+        if self.block.as_ref().is_none_or(|(cached, _)| *cached != index) {
+            let location = self.layout.locate(&mut self.file, index)?;
+            if let Location::Deflated { offset, length } = location {
+                self.inflated.resize(block_size as usize, 0);
+                self.file.seek(SeekFrom::Start(offset))?;
+                DeflateDecoder::new(self.file.by_ref().take(length))
+                    .read_exact(&mut self.inflated)
+                    .map_err(|_| invalid(&format!("Compressed cluster {} of the disk is damaged", index)))?;
+            }
+            self.block = Some((index, location));
+        }
+
+        match self.block.as_ref().map(|(_, location)| location) {
+            Some(Location::Stored(offset)) => {
+                self.file.seek(SeekFrom::Start(offset + within))?;
+                self.file.read_exact(buf).map_err(|e| match e.kind() {
+                    io::ErrorKind::UnexpectedEof => invalid("The disk file ends before the data its tables point to"),
+                    _ => e,
+                })?;
+            }
+            Some(Location::Deflated { .. }) => buf.copy_from_slice(&self.inflated[within as usize..within as usize + count]),
+            Some(Location::Zero) | None => buf.fill(0),
+        }
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+// Reads `length` bytes at `offset`, for the formats' headers and tables.
+// Sizes come from the file itself, so they are checked against it before
+// anything is allocated.
+fn read_at(file: &mut File, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+    let file_size = file.metadata()?.len();
+    if offset.checked_add(length as u64).is_none_or(|end| end > file_size) {
+        return Err(invalid("The disk file is cut short"));
+    }
+    let mut data = vec![0; length];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => invalid("The disk file is cut short"),
+        _ => e,
+    })?;
+    Ok(data)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn unsupported(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+
+    use super::*;
+    use crate::testutil::TempDir;
+
+    const KIB: usize = 1024;
+    const MIB: usize = 1024 * KIB;
+
+    // Some data, then a run of zeros, then more data ending mid-block for
+    // the formats whose disks need not be whole blocks.
+    fn disk(size: usize, zeros: std::ops::Range<usize>) -> Vec<u8> {
+        let mut disk: Vec<u8> = (0..size).map(|i| (i % 251) as u8 + 1).collect();
+        disk[zeros].fill(0);
+        disk
+    }
+
+    fn put(file: &mut Vec<u8>, offset: usize, data: &[u8]) {
+        if file.len() < offset + data.len() {
+            file.resize(offset + data.len(), 0);
+        }
+        file[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    fn put_be32(file: &mut Vec<u8>, offset: usize, value: u32) {
+        put(file, offset, &value.to_be_bytes());
+    }
+
+    fn put_be64(file: &mut Vec<u8>, offset: usize, value: u64) {
+        put(file, offset, &value.to_be_bytes());
+    }
+
+    fn put_le32(file: &mut Vec<u8>, offset: usize, value: u32) {
+        put(file, offset, &value.to_le_bytes());
+    }
+
+    fn put_le64(file: &mut Vec<u8>, offset: usize, value: u64) {
+        put(file, offset, &value.to_le_bytes());
+    }
+
+    fn vhd_checksum(data: &mut [u8], field: usize) {
+        data[field..field + 4].fill(0);
+        let sum = data.iter().fold(0u32, |sum, &byte| sum.wrapping_add(byte as u32));
+        data[field..field + 4].copy_from_slice(&(!sum).to_be_bytes());
+    }
+
+    fn vhd_footer(size: u64, disk_type: u32, data_offset: u64) -> Vec<u8> {
+        let mut footer = vec![0; 512];
+        put(&mut footer, 0, b"conectix");
+        put_be32(&mut footer, 8, 2);
+        put_be32(&mut footer, 12, 0x0001_0000);
+        put_be64(&mut footer, 16, data_offset);
+        put_be64(&mut footer, 40, size);
+        put_be64(&mut footer, 48, size);
+        put_be32(&mut footer, 60, disk_type);
+        vhd_checksum(&mut footer, 64);
+        footer
+    }
+
+    fn fixed_vhd(disk: &[u8]) -> Vec<u8> {
+        [disk, &vhd_footer(disk.len() as u64, 2, u64::MAX)].concat()
+    }
+
+    // A footer copy, the dynamic header, the block table, then each block
+    // that holds data behind its sector bitmap.
+    fn dynamic_vhd(disk: &[u8], block_size: usize) -> Vec<u8> {
+        let blocks = disk.len().div_ceil(block_size);
+        let footer = vhd_footer(disk.len() as u64, 3, 512);
+        let mut file = footer.clone();
+
+        let mut header = vec![0; 1024];
+        put(&mut header, 0, b"cxsparse");
+        put_be64(&mut header, 8, u64::MAX);
+        put_be64(&mut header, 16, 1536);
+        put_be32(&mut header, 24, 0x0001_0000);
+        put_be32(&mut header, 28, blocks as u32);
+        put_be32(&mut header, 32, block_size as u32);
+        vhd_checksum(&mut header, 36);
+        put(&mut file, 512, &header);
+
+        let mut position = (1536 + blocks * 4).next_multiple_of(512);
+        for (index, block) in disk.chunks(block_size).enumerate() {
+            let entry = if block.iter().all(|&byte| byte == 0) {
+                u32::MAX
+            } else {
+                put(&mut file, position, &[0xFF; 512]);
+                put(&mut file, position + 512, block);
+                file.resize(position + 512 + block_size, 0);
+                let sector = position / 512;
+                position += 512 + block_size;
+                sector as u32
+            };
+            put_be32(&mut file, 1536 + index * 4, entry);
+        }
+        [file, footer].concat()
+    }
+
+    fn vhdx_guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> Vec<u8> {
+        [&a.to_le_bytes()[..], &b.to_le_bytes(), &c.to_le_bytes(), &d].concat()
+    }
+
+    // Headers and region tables at their fixed offsets, metadata at 1 MiB,
+    // the block table at 2 MiB and 1 MiB blocks from 3 MiB on. Zero blocks
+    // take each of the states that read as zeros.
+    fn vhdx(disk: &[u8]) -> Vec<u8> {
+        let mut file = vec![0; 3 * MIB];
+        put(&mut file, 0, b"vhdxfile");
+        for (offset, sequence) in [(64 * KIB, 1), (128 * KIB, 2)] {
+            let mut header = vec![0; 4096];
+            put(&mut header, 0, b"head");
+            put_le64(&mut header, 8, sequence);
+            put(&mut header, 66, &1u16.to_le_bytes());
+            let crc = vhdx::crc32c(&header);
+            put_le32(&mut header, 4, crc);
+            put(&mut file, offset, &header);
+        }
+
+        let mut regions = vec![0; 64 * KIB];
+        put(&mut regions, 0, b"regi");
+        put_le32(&mut regions, 8, 2);
+        let bat = vhdx_guid(0x2DC2_7766, 0xF623, 0x4200, [0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08]);
+        let metadata = vhdx_guid(0x8B7C_A206, 0x4790, 0x4B9A, [0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E]);
+        for (entry, (id, offset)) in [(bat, 2 * MIB), (metadata, MIB)].into_iter().enumerate() {
+            put(&mut regions, 16 + entry * 32, &id);
+            put_le64(&mut regions, 32 + entry * 32, offset as u64);
+            put_le32(&mut regions, 40 + entry * 32, MIB as u32);
+            put_le32(&mut regions, 44 + entry * 32, 1);
+        }
+        let crc = vhdx::crc32c(&regions);
+        put_le32(&mut regions, 4, crc);
+        put(&mut file, 192 * KIB, &regions);
+        put(&mut file, 256 * KIB, &regions);
+
+        put(&mut file, MIB, b"metadata");
+        put(&mut file, MIB + 10, &4u16.to_le_bytes());
+        let items = [
+            (vhdx_guid(0xCAA1_6737, 0xFA36, 0x4D43, [0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B]), MIB as u64),
+            (vhdx_guid(0x2FA5_4224, 0xCD1B, 0x4876, [0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8]), disk.len() as u64),
+            (vhdx_guid(0x8141_BF1D, 0xA96F, 0x4709, [0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F]), 512),
+            (vhdx_guid(0xCDA3_48C7, 0x445D, 0x4471, [0x9C, 0xC9, 0xE9, 0x88, 0x52, 0x51, 0xC5, 0x56]), 4096),
+        ];
+        for (index, (id, value)) in items.iter().enumerate() {
+            let entry = MIB + 32 + index * 32;
+            let value_offset = 64 * KIB + index * 8;
+            put(&mut file, entry, id);
+            put_le32(&mut file, entry + 16, value_offset as u32);
+            put_le32(&mut file, entry + 20, 8);
+            put_le32(&mut file, entry + 24, 4);
+            put_le64(&mut file, MIB + value_offset, *value);
+        }
+
+        for (index, block) in disk.chunks(MIB).enumerate() {
+            let entry = if block.iter().all(|&byte| byte == 0) {
+                [0, 2, 3][index % 3]
+            } else {
+                let offset = file.len();
+                put(&mut file, offset, block);
+                file.resize(offset + MIB, 0);
+                offset as u64 | 6
+            };
+            put_le64(&mut file, 2 * MIB + index * 8, entry);
+        }
+        file
+    }
+
+    // 4 KiB clusters: the header, the L1 table, one L2 table, then the
+    // clusters. Zero clusters are left out or marked as zeros; with
+    // `compress`, every other data cluster is deflated and packed tightly.
+    fn qcow2(disk: &[u8], compress: bool) -> Vec<u8> {
+        const CLUSTER: usize = 4096;
+        let mut file = vec![0; 3 * CLUSTER];
+        put(&mut file, 0, b"QFI\xFB");
+        put_be32(&mut file, 4, 3);
+        put_be32(&mut file, 20, 12);
+        put_be64(&mut file, 24, disk.len() as u64);
+        put_be32(&mut file, 36, 1);
+        put_be64(&mut file, 40, CLUSTER as u64);
+        put_be32(&mut file, 96, 4);
+        put_be32(&mut file, 100, 104);
+        put_be64(&mut file, CLUSTER, (2 * CLUSTER) as u64 | 1 << 63);
+
+        for (index, cluster) in disk.chunks(CLUSTER).enumerate() {
+            let mut cluster = cluster.to_vec();
+            cluster.resize(CLUSTER, 0);
+            let entry = if cluster.iter().all(|&byte| byte == 0) {
+                (index % 2) as u64
+            } else if compress && index % 2 == 1 {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(&cluster).unwrap();
+                let deflated = encoder.finish().unwrap();
+                let offset = file.len();
+                file.extend_from_slice(&deflated);
+                let extra_sectors = ((offset + deflated.len() - 1) / 512 - offset / 512) as u64;
+                1 << 62 | extra_sectors << 58 | offset as u64
+            } else {
+                let offset = file.len().next_multiple_of(CLUSTER);
+                put(&mut file, offset, &cluster);
+                offset as u64 | 1 << 63
+            };
+            put_be64(&mut file, 2 * CLUSTER + index * 8, entry);
+        }
+        file
+    }
+
+    // 4 KiB grains and one grain table after the embedded descriptor. Zero
+    // grains are left out or marked as zeroed.
+    fn vmdk(disk: &[u8]) -> Vec<u8> {
+        const GRAIN: usize = 4096;
+        let mut file = vec![0; 512];
+        put(&mut file, 0, b"KDMV");
+        put_le32(&mut file, 4, 1);
+        put_le32(&mut file, 8, 0x3 | 1 << 2);
+        put_le64(&mut file, 12, (disk.len() / 512) as u64);
+        put_le64(&mut file, 20, (GRAIN / 512) as u64);
+        put_le64(&mut file, 28, 1);
+        put_le64(&mut file, 36, 2);
+        put_le32(&mut file, 44, 512);
+        put_le64(&mut file, 56, 3);
+        let descriptor = format!(
+            "# Disk DescriptorFile\nversion=1\nCID=12345678\nparentCID=ffffffff\ncreateType=\"monolithicSparse\"\n\n\
+             RW {} SPARSE \"test.vmdk\"\n",
+            disk.len() / 512
+        );
+        put(&mut file, 512, descriptor.as_bytes());
+        put_le32(&mut file, 3 * 512, 4);
+
+        let mut position = 8 * 512;
+        for (index, grain) in disk.chunks(GRAIN).enumerate() {
+            let entry = if grain.iter().all(|&byte| byte == 0) {
+                (index % 2) as u32
+            } else {
+                put(&mut file, position, grain);
+                position += GRAIN;
+                ((position - GRAIN) / 512) as u32
+            };
+            put_le32(&mut file, 4 * 512 + index * 4, entry);
+        }
+        file
+    }
+
+    fn read_back(dir: &TempDir, name: &str, file: &[u8]) -> io::Result<(DiskReader, Vec<u8>)> {
+        let path = dir.write(name, file);
+        let mut reader = open(File::open(&path)?)?.expect("not recognized as a virtual disk");
+        let mut flat = Vec::new();
+        reader.read_to_end(&mut flat)?;
+        Ok((reader, flat))
+    }
+
+    #[test]
+    fn reads_every_format_as_the_flat_disk() {
+        let dir = TempDir::new("vdisk");
+        let small = disk(40 * KIB, 8 * KIB..20 * KIB);
+        let large = disk(3 * MIB + 512 * KIB, MIB..2 * MIB);
+        let cases = [
+            ("fixed.vhd", fixed_vhd(&small), Format::FixedVhd, &small),
+            ("dynamic.vhd", dynamic_vhd(&small, 4096), Format::DynamicVhd, &small),
+            ("disk.vhdx", vhdx(&large), Format::Vhdx, &large),
+            ("plain.qcow2", qcow2(&small[..small.len() - 100], false), Format::Qcow2, &small[..small.len() - 100].to_vec()),
+            ("zlib.qcow2", qcow2(&small, true), Format::Qcow2, &small),
+            ("disk.vmdk", vmdk(&small), Format::SparseVmdk, &small),
+        ];
+
+        for (name, file, format, expected) in cases {
+            let (reader, flat) = read_back(&dir, name, &file).unwrap();
+            assert!(reader.disk.format == format, "{}", name);
+            assert_eq!(reader.disk.size, expected.len() as u64, "{}", name);
+            assert_eq!(reader.disk.file_size, file.len() as u64, "{}", name);
+            assert!(flat == *expected, "{} reads back differently", name);
+        }
+
+        // Anything else is no virtual disk.
+        let path = dir.write("plain.img", &small);
+        assert!(probe(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_damaged_disks() {
+        let dir = TempDir::new("vdisk-damaged");
+        let small = disk(40 * KIB, 8 * KIB..20 * KIB);
+
+        let mut file = dynamic_vhd(&small, 4096);
+        file[512 + 40] ^= 1;
+        let error = read_back(&dir, "header.vhd", &file).err().unwrap();
+        assert_eq!(error.to_string(), "The VHD dynamic disk header is damaged");
+
+        let file = qcow2(&small, false);
+        let error = read_back(&dir, "short.qcow2", &file[..file.len() - 100]).err().unwrap();
+        assert_eq!(error.to_string(), "The disk file ends before the data its tables point to");
+
+        let mut file = qcow2(&small, true);
+        // Cluster 0 is stored whole, cluster 1 deflated right after it.
+        file[4 * 4096..4 * 4096 + 64].fill(0xFF);
+        let error = read_back(&dir, "deflate.qcow2", &file).err().unwrap();
+        assert_eq!(error.to_string(), "Compressed cluster 1 of the disk is damaged");
+
+        // Capacities or grain sizes that overflow once counted in bytes.
+        for field in [12, 20] {
+            let mut file = vmdk(&small);
+            file[field..field + 8].copy_from_slice(&(u64::MAX / 256).to_le_bytes());
+            let error = read_back(&dir, "overflow.vmdk", &file).err().unwrap();
+            assert_eq!(error.to_string(), "The VMDK header is damaged");
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io;
+
+use super::{invalid, read_at, unsupported, Format, Layout, Location};
+
+pub const MAGIC: &[u8] = b"QFI\xFB";
+// Version 3 headers are at least 104 bytes, followed by the compression
+// type when they are longer.
+const HEADER_SIZE: usize = 105;
+
+// Incompatible feature bits of version 3. A dirty image only has stale
+// reference counts, which reading does not use.
+const CORRUPT: u64 = 1 << 1;
+const EXTERNAL_DATA_FILE: u64 = 1 << 2;
+const COMPRESSION_TYPE: u64 = 1 << 3;
+const EXTENDED_L2: u64 = 1 << 4;
+const KNOWN_FEATURES: u64 = 0x1F;
+
+const OFFSET_MASK: u64 = 0x00FF_FFFF_FFFF_FE00;
+const COMPRESSED: u64 = 1 << 62;
+const ALL_ZEROS: u64 = 1;
+const SECTOR_SIZE: u64 = 512;
+
+pub fn open(file: &mut File) -> io::Result<(Format, u64, Box<dyn Layout>)> {
+    let header = read_at(file, 0, HEADER_SIZE)?;
+    let version = u32_at(&header, 4);
+    if !(2..=3).contains(&version) {
+        return Err(unsupported(&format!("qcow2 version {} is not supported", version)));
+    }
+    if u64_at(&header, 8) != 0 {
+        return Err(unsupported(
+            "The qcow2 image only holds changes to a backing file and cannot be written on its own",
+        ));
+    }
+    if u32_at(&header, 32) != 0 {
+        return Err(unsupported("Encrypted qcow2 images are not supported"));
+    }
+
+    let features = if version >= 3 { u64_at(&header, 72) } else { 0 };
+    if features & CORRUPT != 0 {
+        return Err(invalid("The qcow2 image is marked as corrupt"));
+    }
+    if features & EXTERNAL_DATA_FILE != 0 {
+        return Err(unsupported("qcow2 images that keep their data in a separate file are not supported"));
+    }
+    // Only zlib is supported; the compression type field names any other.
+    if features & COMPRESSION_TYPE != 0 && u32_at(&header, 100) > 104 && header[104] != 0 {
+        return Err(unsupported("Only qcow2 images with zlib-compressed clusters are supported"));
+    }
+    if features & EXTENDED_L2 != 0 || features & !KNOWN_FEATURES != 0 {
+        return Err(unsupported("The qcow2 image uses features this reader does not know"));
+    }
+
+    let cluster_bits = u32_at(&header, 20);
+    if !(9..=21).contains(&cluster_bits) {
+        return Err(invalid(&format!("qcow2 cluster size 2^{} is not valid", cluster_bits)));
+    }
+    let size = u64_at(&header, 24);
+    let l1_size = u32_at(&header, 36) as u64;
+    // An L2 table fills one cluster with 8-byte entries.
+    let l2_bits = cluster_bits - 3;
+    if size.div_ceil(1 << (cluster_bits + l2_bits)) > l1_size {
+        return Err(invalid("The qcow2 L1 table is too short for its disk"));
+    }
+    let l1 = read_at(file, u64_at(&header, 40), l1_size as usize * 8)?;
+
+    Ok((
+        Format::Qcow2,
+        size,
+        Box::new(Qcow2 {
+            cluster_bits,
+            l2_bits,
+            l1: l1.chunks_exact(8).map(|entry| u64_at(entry, 0)).collect(),
+            l2: None,
+        }),
+    ))
+}
+
+// Clusters are found through two levels of tables: the L1 table, read up
+// front, points to L2 tables, read as they come up. Images are read front
+// to back, so keeping the last L2 table is enough.
+struct Qcow2 {
+    cluster_bits: u32,
+    l2_bits: u32,
+    l1: Vec<u64>,
+    l2: Option<(u64, Vec<u64>)>,
+}
+
+impl Layout for Qcow2 {
+    fn block_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn locate(&mut self, file: &mut File, block: u64) -> io::Result<Location> {
+        let l2_offset = self.l1[(block >> self.l2_bits) as usize] & OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(Location::Zero);
+        }
+        if self.l2.as_ref().is_none_or(|(offset, _)| *offset != l2_offset) {
+            let table = read_at(file, l2_offset, 8 << self.l2_bits)?;
+            self.l2 = Some((l2_offset, table.chunks_exact(8).map(|entry| u64_at(entry, 0)).collect()));
+        }
+        let entry = self.l2.as_ref().unwrap().1[(block & ((1 << self.l2_bits) - 1)) as usize];
+
+        if entry & COMPRESSED != 0 {
+            // The host offset takes the low bits, the number of 512-byte
+            // sectors it spans beyond the first the rest.
+            let offset_bits = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << offset_bits) - 1);
+            let sectors = ((entry >> offset_bits) & ((1 << (self.cluster_bits - 8)) - 1)) + 1;
+            return Ok(Location::Deflated {
+                offset,
+                length: sectors * SECTOR_SIZE - (offset % SECTOR_SIZE),
+            });
+        }
+        Ok(match entry & OFFSET_MASK {
+            _ if entry & ALL_ZEROS != 0 => Location::Zero,
+            0 => Location::Zero,
+            offset => Location::Stored(offset),
+        })
+    }
+}
+
+// qcow2 fields are big-endian.
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
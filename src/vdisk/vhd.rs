@@ -0,0 +1,130 @@
+use std::fs::File;
+use std::io;
+
+use super::{invalid, read_at, unsupported, Format, Layout, Location};
+
+const COOKIE: &[u8] = b"conectix";
+const DYNAMIC_COOKIE: &[u8] = b"cxsparse";
+const FOOTER_SIZE: usize = 512;
+const DYNAMIC_HEADER_SIZE: usize = 1024;
+const SECTOR_SIZE: u64 = 512;
+
+const DISK_FIXED: u32 = 2;
+const DISK_DYNAMIC: u32 = 3;
+const DISK_DIFFERENCING: u32 = 4;
+
+const UNALLOCATED: u32 = 0xFFFF_FFFF;
+
+// A fixed disk is one run of data; it is read in pieces of this size.
+const FIXED_BLOCK_SIZE: u64 = 1024 * 1024;
+
+// Every VHD ends in its footer. Dynamic disks keep a copy at the start as
+// well, which stands in when the end was lost.
+pub fn find_footer(file: &mut File, file_size: u64) -> io::Result<Option<Vec<u8>>> {
+    if file_size < FOOTER_SIZE as u64 {
+        return Ok(None);
+    }
+    let footer = read_at(file, file_size - FOOTER_SIZE as u64, FOOTER_SIZE)?;
+    if footer.starts_with(COOKIE) {
+        return Ok(Some(footer));
+    }
+    let copy = read_at(file, 0, FOOTER_SIZE)?;
+    Ok(copy.starts_with(COOKIE).then_some(copy))
+}
+
+pub fn open(file: &mut File, footer: &[u8], file_size: u64) -> io::Result<(Format, u64, Box<dyn Layout>)> {
+    if checksum(footer, 64) != u32_at(footer, 64) {
+        return Err(invalid("The VHD footer is damaged: its checksum does not match"));
+    }
+    let size = u64_at(footer, 48);
+
+    match u32_at(footer, 60) {
+        DISK_FIXED => {
+            if size.checked_add(FOOTER_SIZE as u64).is_none_or(|end| end > file_size) {
+                return Err(invalid("The VHD file is shorter than its disk"));
+            }
+            Ok((Format::FixedVhd, size, Box::new(Fixed)))
+        }
+        DISK_DYNAMIC => {
+            let header = read_at(file, u64_at(footer, 16), DYNAMIC_HEADER_SIZE)?;
+            if !header.starts_with(DYNAMIC_COOKIE) || checksum(&header, 36) != u32_at(&header, 36) {
+                return Err(invalid("The VHD dynamic disk header is damaged"));
+            }
+            let entries = u32_at(&header, 28) as u64;
+            let block_size = u32_at(&header, 32) as u64;
+            if block_size == 0 || !block_size.is_multiple_of(SECTOR_SIZE) || size.div_ceil(block_size) > entries {
+                return Err(invalid("The VHD dynamic disk header is damaged"));
+            }
+
+            let table = read_at(file, u64_at(&header, 16), entries as usize * 4)?;
+            // Each block starts with a bitmap of one bit per sector, padded
+            // to whole sectors. Sectors it leaves clear were never written
+            // and hold zeros in the block anyway, so it is not consulted.
+            let bitmap_size = (block_size / SECTOR_SIZE).div_ceil(8).next_multiple_of(SECTOR_SIZE);
+            Ok((
+                Format::DynamicVhd,
+                size,
+                Box::new(Dynamic {
+                    block_size,
+                    bitmap_size,
+                    table: table.chunks_exact(4).map(|entry| u32_at(entry, 0)).collect(),
+                }),
+            ))
+        }
+        DISK_DIFFERENCING => Err(unsupported(
+            "Differencing VHDs only hold changes to a parent disk and cannot be written on their own",
+        )),
+        other => Err(invalid(&format!("Unknown VHD disk type {}", other))),
+    }
+}
+
+struct Fixed;
+
+impl Layout for Fixed {
+    fn block_size(&self) -> u64 {
+        FIXED_BLOCK_SIZE
+    }
+
+    fn locate(&mut self, _file: &mut File, block: u64) -> io::Result<Location> {
+        Ok(Location::Stored(block * FIXED_BLOCK_SIZE))
+    }
+}
+
+struct Dynamic {
+    block_size: u64,
+    bitmap_size: u64,
+    // The block allocation table: the sector each block starts at.
+    table: Vec<u32>,
+}
+
+impl Layout for Dynamic {
+    fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn locate(&mut self, _file: &mut File, block: u64) -> io::Result<Location> {
+        Ok(match self.table[block as usize] {
+            UNALLOCATED => Location::Zero,
+            sector => Location::Stored(sector as u64 * SECTOR_SIZE + self.bitmap_size),
+        })
+    }
+}
+
+// The one's complement of the byte sum, skipping the checksum field itself.
+fn checksum(data: &[u8], field: usize) -> u32 {
+    let sum = data
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| !(field..field + 4).contains(index))
+        .fold(0u32, |sum, (_, &byte)| sum.wrapping_add(byte as u32));
+    !sum
+}
+
+// VHD fields are big-endian.
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(data[offset..offset + 8].try_into().unwrap())
+}
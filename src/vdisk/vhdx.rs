@@ -0,0 +1,211 @@
+use std::fs::File;
+use std::io;
+
+use super::{invalid, read_at, unsupported, Format, Layout, Location};
+
+pub const SIGNATURE: &[u8] = b"vhdxfile";
+const HEADER_SIGNATURE: &[u8] = b"head";
+const REGION_TABLE_SIGNATURE: &[u8] = b"regi";
+const METADATA_SIGNATURE: &[u8] = b"metadata";
+
+const KIB: u64 = 1024;
+// Headers and region tables are each kept twice. The newest intact header
+// counts, and the first intact region table.
+const HEADER_OFFSETS: [u64; 2] = [64 * KIB, 128 * KIB];
+const HEADER_SIZE: usize = 4096;
+const REGION_TABLE_OFFSETS: [u64; 2] = [192 * KIB, 256 * KIB];
+const REGION_TABLE_SIZE: usize = 64 * 1024;
+const METADATA_TABLE_SIZE: usize = 64 * 1024;
+const TABLE_ENTRY_SIZE: usize = 32;
+
+const BAT_REGION: [u8; 16] = guid(0x2DC2_7766, 0xF623, 0x4200, [0x9D, 0x64, 0x11, 0x5E, 0x9B, 0xFD, 0x4A, 0x08]);
+const METADATA_REGION: [u8; 16] = guid(0x8B7C_A206, 0x4790, 0x4B9A, [0xB8, 0xFE, 0x57, 0x5F, 0x05, 0x0F, 0x88, 0x6E]);
+
+const FILE_PARAMETERS: [u8; 16] = guid(0xCAA1_6737, 0xFA36, 0x4D43, [0xB3, 0xB6, 0x33, 0xF0, 0xAA, 0x44, 0xE7, 0x6B]);
+const VIRTUAL_DISK_SIZE: [u8; 16] = guid(0x2FA5_4224, 0xCD1B, 0x4876, [0xB2, 0x11, 0x5D, 0xBE, 0xD8, 0x3B, 0xF4, 0xB8]);
+const LOGICAL_SECTOR_SIZE: [u8; 16] = guid(0x8141_BF1D, 0xA96F, 0x4709, [0xBA, 0x47, 0xF2, 0x33, 0xA8, 0xFA, 0xAB, 0x5F]);
+const PHYSICAL_SECTOR_SIZE: [u8; 16] = guid(0xCDA3_48C7, 0x445D, 0x4471, [0x9C, 0xC9, 0xE9, 0x88, 0x52, 0x51, 0xC5, 0x56]);
+const PAGE_83_DATA: [u8; 16] = guid(0xBECA_12AB, 0xB2E6, 0x4523, [0x93, 0xEF, 0xC3, 0x09, 0xE0, 0x00, 0xC7, 0x46]);
+const KNOWN_METADATA: [[u8; 16]; 5] =
+    [FILE_PARAMETERS, VIRTUAL_DISK_SIZE, LOGICAL_SECTOR_SIZE, PHYSICAL_SECTOR_SIZE, PAGE_83_DATA];
+
+const REGION_REQUIRED: u32 = 0x1;
+const METADATA_REQUIRED: u32 = 0x4;
+const HAS_PARENT: u32 = 0x2;
+
+// Block states in the allocation table. Blocks not present, undefined,
+// zeroed or unmapped all read as zeros on a disk without a parent.
+const BLOCK_UNMAPPED: u64 = 3;
+const BLOCK_FULLY_PRESENT: u64 = 6;
+
+// Every block of the allocation table covers 2^23 sectors of payload, after
+// which it holds one sector bitmap entry, only used by differencing disks.
+const SECTORS_PER_CHUNK: u64 = 1 << 23;
+
+pub fn open(file: &mut File) -> io::Result<(Format, u64, Box<dyn Layout>)> {
+    let mut header = None;
+    for offset in HEADER_OFFSETS {
+        let candidate = read_at(file, offset, HEADER_SIZE)?;
+        if candidate.starts_with(HEADER_SIGNATURE)
+            && is_intact(&candidate)
+            && header.as_ref().is_none_or(|header: &Vec<u8>| u64_at(&candidate, 8) > u64_at(header, 8))
+        {
+            header = Some(candidate);
+        }
+    }
+    let header = header.ok_or_else(|| invalid("Both VHDX headers are damaged"))?;
+    if u16_at(&header, 66) != 1 {
+        return Err(unsupported(&format!("VHDX version {} is not supported", u16_at(&header, 66))));
+    }
+    // A log that was never replayed holds writes the disk does not show yet.
+    if header[48..64] != [0; 16] {
+        return Err(unsupported(
+            "The VHDX was not closed cleanly; attach it once in Windows so its log is replayed",
+        ));
+    }
+
+    let mut regions = None;
+    for offset in REGION_TABLE_OFFSETS {
+        let table = read_at(file, offset, REGION_TABLE_SIZE)?;
+        if table.starts_with(REGION_TABLE_SIGNATURE) && is_intact(&table) {
+            regions = Some(table);
+            break;
+        }
+    }
+    let regions = regions.ok_or_else(|| invalid("Both VHDX region tables are damaged"))?;
+    let (mut bat, mut metadata) = (None, None);
+    for entry in entries(&regions, 16, u32_at(&regions, 8) as usize)? {
+        let region = (u64_at(entry, 16), u32_at(entry, 24) as usize);
+        let id: [u8; 16] = entry[..16].try_into().unwrap();
+        match id {
+            BAT_REGION => bat = Some(region),
+            METADATA_REGION => metadata = Some(region),
+            _ if u32_at(entry, 28) & REGION_REQUIRED != 0 => {
+                return Err(unsupported("The VHDX needs a region this reader does not know"));
+            }
+            _ => {}
+        }
+    }
+    let ((bat_offset, bat_length), (metadata_offset, _)) = bat
+        .zip(metadata)
+        .ok_or_else(|| invalid("The VHDX has no block allocation table or metadata"))?;
+
+    let table = read_at(file, metadata_offset, METADATA_TABLE_SIZE)?;
+    if !table.starts_with(METADATA_SIGNATURE) {
+        return Err(invalid("The VHDX metadata is damaged"));
+    }
+    let (mut block_size, mut size, mut sector_size) = (None, None, None);
+    for entry in entries(&table, 32, u16_at(&table, 10) as usize)? {
+        let id: [u8; 16] = entry[..16].try_into().unwrap();
+        if !KNOWN_METADATA.contains(&id) {
+            if u32_at(entry, 24) & METADATA_REQUIRED != 0 {
+                return Err(unsupported("The VHDX needs metadata this reader does not know"));
+            }
+            continue;
+        }
+        let value_at = |file: &mut File, length| read_at(file, metadata_offset + u32_at(entry, 16) as u64, length);
+        match id {
+            FILE_PARAMETERS => {
+                let value = value_at(file, 8)?;
+                if u32_at(&value, 4) & HAS_PARENT != 0 {
+                    return Err(unsupported(
+                        "Differencing VHDXs only hold changes to a parent disk and cannot be written on their own",
+                    ));
+                }
+                block_size = Some(u32_at(&value, 0) as u64);
+            }
+            VIRTUAL_DISK_SIZE => size = Some(u64_at(&value_at(file, 8)?, 0)),
+            LOGICAL_SECTOR_SIZE => sector_size = Some(u32_at(&value_at(file, 4)?, 0) as u64),
+            _ => {}
+        }
+    }
+    let (Some(block_size), Some(size), Some(sector_size)) = (block_size, size, sector_size) else {
+        return Err(invalid("The VHDX metadata is incomplete"));
+    };
+    if block_size == 0 || !(SECTORS_PER_CHUNK * sector_size).is_multiple_of(block_size) {
+        return Err(invalid(&format!("VHDX block size {} is not valid", block_size)));
+    }
+    let chunk_ratio = SECTORS_PER_CHUNK * sector_size / block_size;
+
+    let bat = read_at(file, bat_offset, bat_length)?;
+    let blocks = size.div_ceil(block_size);
+    if blocks + blocks / chunk_ratio > (bat.len() / 8) as u64 {
+        return Err(invalid("The VHDX block allocation table is too short for its disk"));
+    }
+    Ok((
+        Format::Vhdx,
+        size,
+        Box::new(Vhdx {
+            block_size,
+            chunk_ratio,
+            table: bat.chunks_exact(8).map(|entry| u64_at(entry, 0)).collect(),
+        }),
+    ))
+}
+
+struct Vhdx {
+    block_size: u64,
+    chunk_ratio: u64,
+    table: Vec<u64>,
+}
+
+impl Layout for Vhdx {
+    fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn locate(&mut self, _file: &mut File, block: u64) -> io::Result<Location> {
+        let entry = self.table[(block + block / self.chunk_ratio) as usize];
+        match entry & 0x7 {
+            0..=BLOCK_UNMAPPED => Ok(Location::Zero),
+            // The upper 44 bits are the offset in MiB.
+            BLOCK_FULLY_PRESENT => Ok(Location::Stored(entry >> 20 << 20)),
+            state => Err(invalid(&format!("Block {} of the VHDX has unknown state {}", block, state))),
+        }
+    }
+}
+
+fn entries(table: &[u8], start: usize, count: usize) -> io::Result<impl Iterator<Item = &[u8]>> {
+    table
+        .get(start..start + count * TABLE_ENTRY_SIZE)
+        .map(|entries| entries.chunks_exact(TABLE_ENTRY_SIZE))
+        .ok_or_else(|| invalid("A VHDX table lists more entries than it holds"))
+}
+
+// Headers and region tables carry a CRC-32C of themselves, taken with the
+// checksum field zeroed.
+fn is_intact(data: &[u8]) -> bool {
+    let mut zeroed = data.to_vec();
+    zeroed[4..8].fill(0);
+    crc32c(&zeroed) == u32_at(data, 4)
+}
+
+pub(super) fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82F6_3B78 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// GUIDs are stored with their first three fields little-endian.
+const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> [u8; 16] {
+    let (a, b, c) = (a.to_le_bytes(), b.to_le_bytes(), c.to_le_bytes());
+    [a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]]
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
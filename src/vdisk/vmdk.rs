@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io;
+
+use super::{invalid, read_at, unsupported, Format, Layout, Location};
+
+pub const MAGIC: &[u8] = b"KDMV";
+const HEADER_SIZE: usize = 512;
+const SECTOR_SIZE: u64 = 512;
+
+const ZEROED_GRAIN_ENTRIES: u32 = 1 << 2;
+const COMPRESSED: u32 = 1 << 16;
+const MARKERS: u32 = 1 << 17;
+
+// With this flag, a grain table entry of 1 stands for a grain of zeros.
+const ZEROED_GRAIN: u32 = 1;
+
+pub fn open(file: &mut File) -> io::Result<(Format, u64, Box<dyn Layout>)> {
+    let header = read_at(file, 0, HEADER_SIZE)?;
+    let version = u32_at(&header, 4);
+    if !(1..=3).contains(&version) {
+        return Err(unsupported(&format!("VMDK version {} is not supported", version)));
+    }
+    let flags = u32_at(&header, 8);
+    if flags & (COMPRESSED | MARKERS) != 0 {
+        return Err(unsupported(
+            "Stream-optimized VMDKs are not supported; convert the disk to monolithic sparse first",
+        ));
+    }
+
+    // The embedded descriptor names the disk type. Extents of split disks
+    // have none.
+    let descriptor = read_at(file, sectors(u64_at(&header, 28))?, sectors(u64_at(&header, 36))? as usize)?;
+    let descriptor = String::from_utf8_lossy(&descriptor);
+    let create_type = descriptor
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("createType="))
+        .map(|value| value.trim().trim_matches('"'));
+    match create_type {
+        Some("monolithicSparse") => {}
+        Some(other) => return Err(unsupported(&format!("VMDK type {} is not supported, only monolithicSparse", other))),
+        None => {
+            return Err(unsupported(
+                "This VMDK is one extent of a larger disk; only single-file monolithic sparse VMDKs are supported",
+            ))
+        }
+    }
+    if descriptor.contains("parentFileNameHint") {
+        return Err(unsupported(
+            "This VMDK only holds changes to a parent disk and cannot be written on its own",
+        ));
+    }
+
+    let capacity = sectors(u64_at(&header, 12))?;
+    let grain_size = sectors(u64_at(&header, 20))?;
+    let grain_table_entries = u32_at(&header, 44) as u64;
+    if grain_size == 0 || grain_table_entries == 0 {
+        return Err(invalid("The VMDK header is damaged"));
+    }
+    let tables = capacity.div_ceil(grain_size).div_ceil(grain_table_entries);
+    let directory = read_at(file, sectors(u64_at(&header, 56))?, tables as usize * 4)?;
+
+    Ok((
+        Format::SparseVmdk,
+        capacity,
+        Box::new(Vmdk {
+            grain_size,
+            grain_table_entries,
+            zeroed_grains: flags & ZEROED_GRAIN_ENTRIES != 0,
+            directory: directory.chunks_exact(4).map(|entry| u32_at(entry, 0)).collect(),
+            table: None,
+        }),
+    ))
+}
+
+// Grains are found through the grain directory, read up front, which points
+// to grain tables, read as they come up. Only the last one is kept.
+struct Vmdk {
+    grain_size: u64,
+    grain_table_entries: u64,
+    zeroed_grains: bool,
+    directory: Vec<u32>,
+    table: Option<(u32, Vec<u32>)>,
+}
+
+impl Layout for Vmdk {
+    fn block_size(&self) -> u64 {
+        self.grain_size
+    }
+
+    fn locate(&mut self, file: &mut File, block: u64) -> io::Result<Location> {
+        let table_sector = self.directory[(block / self.grain_table_entries) as usize];
+        if table_sector == 0 {
+            return Ok(Location::Zero);
+        }
+        if self.table.as_ref().is_none_or(|(sector, _)| *sector != table_sector) {
+            let table = read_at(
+                file,
+                table_sector as u64 * SECTOR_SIZE,
+                self.grain_table_entries as usize * 4,
+            )?;
+            self.table = Some((table_sector, table.chunks_exact(4).map(|entry| u32_at(entry, 0)).collect()));
+        }
+
+        Ok(match self.table.as_ref().unwrap().1[(block % self.grain_table_entries) as usize] {
+            0 => Location::Zero,
+            ZEROED_GRAIN if self.zeroed_grains => Location::Zero,
+            sector => Location::Stored(sector as u64 * SECTOR_SIZE),
+        })
+    }
+}
+
+// Header fields count 512-byte sectors; a damaged one can overflow in
+// bytes.
+fn sectors(count: u64) -> io::Result<u64> {
+    count
+        .checked_mul(SECTOR_SIZE)
+        .ok_or_else(|| invalid("The VMDK header is damaged"))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
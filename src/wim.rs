@@ -0,0 +1,635 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"MSWIM\0\0\0";
+const HEADER_SIZE: usize = 208;
+const LOOKUP_ENTRY_SIZE: usize = 50;
+
+// Header flags.
+const HEADER_FLAG_SPANNED: u32 = 0x08;
+
+// Resource flags.
+const RESOURCE_METADATA: u8 = 0x02;
+const RESOURCE_COMPRESSED: u8 = 0x04;
+const RESOURCE_SOLID: u8 = 0x10;
+
+// Parts a little under 4 GiB, the size DISM suggests, leave FAT32 some room
+// for the headers and tables each part repeats.
+pub const DEFAULT_PART_SIZE: u64 = 3800 * 1024 * 1024;
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+// Where a resource lives: its stored size is 56 bits with the flags in the
+// top byte.
+#[derive(Clone, Copy, Default)]
+struct ResourceHeader {
+    size: u64,
+    flags: u8,
+    offset: u64,
+    original_size: u64,
+}
+
+impl ResourceHeader {
+    fn parse(bytes: &[u8]) -> Self {
+        let packed = u64_at(bytes, 0);
+        Self {
+            size: packed & 0x00FF_FFFF_FFFF_FFFF,
+            flags: (packed >> 56) as u8,
+            offset: u64_at(bytes, 8),
+            original_size: u64_at(bytes, 16),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 24] {
+        let mut bytes = [0u8; 24];
+        bytes[0..8].copy_from_slice(&(self.size | (self.flags as u64) << 56).to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.original_size.to_le_bytes());
+        bytes
+    }
+}
+
+// One row of the resource (lookup) table.
+#[derive(Clone, Copy)]
+struct Resource {
+    header: ResourceHeader,
+    part_number: u16,
+    reference_count: u32,
+    hash: [u8; 20],
+}
+
+impl Resource {
+    fn parse(bytes: &[u8]) -> Self {
+        Self {
+            header: ResourceHeader::parse(&bytes[0..24]),
+            part_number: u16::from_le_bytes([bytes[24], bytes[25]]),
+            reference_count: u32::from_le_bytes(bytes[26..30].try_into().unwrap()),
+            hash: bytes[30..50].try_into().unwrap(),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; LOOKUP_ENTRY_SIZE] {
+        let mut bytes = [0u8; LOOKUP_ENTRY_SIZE];
+        bytes[0..24].copy_from_slice(&self.header.to_bytes());
+        bytes[24..26].copy_from_slice(&self.part_number.to_le_bytes());
+        bytes[26..30].copy_from_slice(&self.reference_count.to_le_bytes());
+        bytes[30..50].copy_from_slice(&self.hash);
+        bytes
+    }
+
+    fn is_metadata(&self) -> bool {
+        self.header.flags & RESOURCE_METADATA != 0
+    }
+}
+
+pub struct WimImage {
+    pub index: u32,
+    pub name: String,
+}
+
+pub struct Wim {
+    // The raw header; splitting copies it and patches the fields that differ.
+    header: [u8; HEADER_SIZE],
+    flags: u32,
+    total_parts: u16,
+    boot_metadata: ResourceHeader,
+    resources: Vec<Resource>,
+    xml: String,
+    pub images: Vec<WimImage>,
+}
+
+pub fn read_wim<R: Read + Seek>(reader: &mut R) -> io::Result<Wim> {
+    let mut header = [0u8; HEADER_SIZE];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut header)?;
+    if &header[0..8] != MAGIC || u32_at(&header, 8) as usize != HEADER_SIZE {
+        return Err(invalid("Not a WIM file"));
+    }
+
+    let lookup_table = ResourceHeader::parse(&header[48..72]);
+    let xml_data = ResourceHeader::parse(&header[72..96]);
+    if (lookup_table.flags | xml_data.flags) & RESOURCE_COMPRESSED != 0 {
+        return Err(invalid("The WIM's resource table or XML data is compressed"));
+    }
+
+    let table = read_resource(reader, lookup_table)?;
+    let resources: Vec<Resource> = table.chunks_exact(LOOKUP_ENTRY_SIZE).map(Resource::parse).collect();
+
+    // The XML data is UTF-16 with a byte order mark.
+    let xml_bytes = read_resource(reader, xml_data)?;
+    let units: Vec<u16> = xml_bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .skip_while(|&unit| unit == 0xFEFF)
+        .collect();
+    let xml = String::from_utf16_lossy(&units);
+
+    Ok(Wim {
+        header,
+        flags: u32_at(&header, 16),
+        total_parts: u16::from_le_bytes([header[42], header[43]]),
+        boot_metadata: ResourceHeader::parse(&header[96..120]),
+        images: parse_images(&xml),
+        resources,
+        xml,
+    })
+}
+
+fn read_resource<R: Read + Seek>(reader: &mut R, resource: ResourceHeader) -> io::Result<Vec<u8>> {
+    // Tables this large are not real.
+    if resource.size > 256 * 1024 * 1024 {
+        return Err(invalid("A WIM table is implausibly large"));
+    }
+
+    let mut bytes = vec![0; resource.size as usize];
+    reader.seek(SeekFrom::Start(resource.offset))?;
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Picks the index and name of each image out of the XML. A full parser would
+// be overkill for a document this regular.
+fn parse_images(xml: &str) -> Vec<WimImage> {
+    let mut images = Vec::new();
+    let mut rest = xml;
+
+    while let Some(start) = rest.find("<IMAGE INDEX=\"") {
+        rest = &rest[start + "<IMAGE INDEX=\"".len()..];
+        let end = rest.find("</IMAGE>").unwrap_or(rest.len());
+        let image = &rest[..end];
+
+        let index = image.split('"').next().and_then(|index| index.parse().ok()).unwrap_or(0);
+        let name = element(image, "DISPLAYNAME")
+            .or_else(|| element(image, "NAME"))
+            .unwrap_or_default();
+        images.push(WimImage { index, name });
+        rest = &rest[end..];
+    }
+    images
+}
+
+fn element(xml: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find("</")? + start;
+    Some(xml[start..end].trim().to_string())
+}
+
+// One `.swm` file: its name and how to assemble it from fresh tables and
+// resources copied verbatim from the original WIM.
+pub struct SwmPart {
+    pub name: String,
+    pub size: u64,
+    segments: Vec<Segment>,
+}
+
+enum Segment {
+    Bytes(Vec<u8>),
+    Copy { offset: u64, length: u64 },
+}
+
+impl SwmPart {
+    pub fn reader<'a, R: Read + Seek>(&'a self, source: &'a mut R) -> PartReader<'a, R> {
+        PartReader {
+            source,
+            segments: &self.segments,
+            position: 0,
+        }
+    }
+}
+
+pub struct PartReader<'a, R> {
+    source: &'a mut R,
+    segments: &'a [Segment],
+    // Offset within the first remaining segment.
+    position: u64,
+}
+
+impl<R: Read + Seek> Read for PartReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(segment) = self.segments.first() {
+            let length = match segment {
+                Segment::Bytes(bytes) => bytes.len() as u64,
+                Segment::Copy { length, .. } => *length,
+            };
+            if self.position >= length {
+                self.segments = &self.segments[1..];
+                self.position = 0;
+                continue;
+            }
+
+            let wanted = (length - self.position).min(buf.len() as u64) as usize;
+            let read = match segment {
+                Segment::Bytes(bytes) => {
+                    let start = self.position as usize;
+                    buf[..wanted].copy_from_slice(&bytes[start..start + wanted]);
+                    wanted
+                }
+                Segment::Copy { offset, .. } => {
+                    self.source.seek(SeekFrom::Start(offset + self.position))?;
+                    let read = self.source.read(&mut buf[..wanted])?;
+                    if read == 0 {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The WIM file ends inside a resource"));
+                    }
+                    read
+                }
+            };
+            self.position += read as u64;
+            return Ok(read);
+        }
+        Ok(0)
+    }
+}
+
+impl Wim {
+    // Lays the WIM out as spanned parts of at most `part_size` bytes. The
+    // first part holds the image metadata; file resources follow in their
+    // original order and are never cut in two. Every part carries its own
+    // resource table, listing what it holds, and the full XML data.
+    pub fn plan_split(&self, base_name: &str, part_size: u64) -> io::Result<Vec<SwmPart>> {
+        if self.total_parts != 1 || self.flags & HEADER_FLAG_SPANNED != 0 {
+            return Err(invalid("The WIM is already split"));
+        }
+        if self.resources.iter().any(|resource| resource.header.flags & RESOURCE_SOLID != 0) {
+            return Err(invalid("Solid (ESD) WIM files cannot be split"));
+        }
+
+        // What every part repeats, with room for TOTALBYTES to grow a few
+        // digits.
+        let overhead = (HEADER_SIZE + (self.xml.len() + 64) * 2) as u64;
+        let mut groups: Vec<Vec<Resource>> = vec![Vec::new()];
+        let mut used = overhead;
+
+        let (metadata, mut files): (Vec<Resource>, Vec<Resource>) =
+            self.resources.iter().copied().partition(Resource::is_metadata);
+        files.sort_by_key(|resource| resource.header.offset);
+
+        for resource in metadata.into_iter().chain(files) {
+            let cost = resource.header.size + LOOKUP_ENTRY_SIZE as u64;
+            if overhead + cost > part_size {
+                return Err(invalid(&format!(
+                    "A resource of {} bytes does not fit in a {} byte part",
+                    resource.header.size, part_size
+                )));
+            }
+            if used + cost > part_size {
+                // Image metadata has to stay together in the first part.
+                if resource.is_metadata() {
+                    return Err(invalid("The image metadata does not fit in the first part"));
+                }
+                groups.push(Vec::new());
+                used = overhead;
+            }
+            groups.last_mut().unwrap().push(resource);
+            used += cost;
+        }
+
+        let total_parts = groups.len() as u16;
+        groups
+            .iter()
+            .enumerate()
+            .map(|(index, group)| self.part(base_name, index as u16 + 1, total_parts, group))
+            .collect()
+    }
+
+    fn part(&self, base_name: &str, part_number: u16, total_parts: u16, group: &[Resource]) -> io::Result<SwmPart> {
+        let mut segments = Vec::new();
+        let mut table = Vec::with_capacity(group.len() * LOOKUP_ENTRY_SIZE);
+        let mut boot_metadata = ResourceHeader::default();
+        let mut offset = HEADER_SIZE as u64;
+
+        for resource in group {
+            segments.push(Segment::Copy {
+                offset: resource.header.offset,
+                length: resource.header.size,
+            });
+
+            let moved = Resource {
+                header: ResourceHeader { offset, ..resource.header },
+                part_number,
+                ..*resource
+            };
+            if resource.is_metadata() && resource.header.offset == self.boot_metadata.offset {
+                boot_metadata = moved.header;
+            }
+            table.extend_from_slice(&moved.to_bytes());
+            offset += resource.header.size;
+        }
+
+        let table_header = ResourceHeader {
+            size: table.len() as u64,
+            flags: 0,
+            offset,
+            original_size: table.len() as u64,
+        };
+        offset += table.len() as u64;
+
+        // TOTALBYTES counts everything ahead of the XML data.
+        let xml = replace_element(&self.xml, "TOTALBYTES", &offset.to_string());
+        let xml: Vec<u8> = std::iter::once(0xFEFF)
+            .chain(xml.encode_utf16())
+            .flat_map(|unit: u16| unit.to_le_bytes())
+            .collect();
+        let xml_header = ResourceHeader {
+            size: xml.len() as u64,
+            flags: 0,
+            offset,
+            original_size: xml.len() as u64,
+        };
+        let size = offset + xml.len() as u64;
+
+        let mut header = self.header;
+        header[16..20].copy_from_slice(&(self.flags | HEADER_FLAG_SPANNED).to_le_bytes());
+        header[40..42].copy_from_slice(&part_number.to_le_bytes());
+        header[42..44].copy_from_slice(&total_parts.to_le_bytes());
+        header[48..72].copy_from_slice(&table_header.to_bytes());
+        header[72..96].copy_from_slice(&xml_header.to_bytes());
+        header[96..120].copy_from_slice(&boot_metadata.to_bytes());
+        // The integrity table covers the original file only.
+        header[124..148].fill(0);
+
+        segments.insert(0, Segment::Bytes(header.to_vec()));
+        segments.push(Segment::Bytes(table));
+        segments.push(Segment::Bytes(xml));
+
+        Ok(SwmPart {
+            name: part_name(base_name, part_number),
+            size,
+            segments,
+        })
+    }
+}
+
+// Windows Setup looks for `install.swm`, `install2.swm`, `install3.swm`...
+fn part_name(base_name: &str, part_number: u16) -> String {
+    if part_number == 1 {
+        format!("{}.swm", base_name)
+    } else {
+        format!("{}{}.swm", base_name, part_number)
+    }
+}
+
+fn replace_element(xml: &str, tag: &str, value: &str) -> String {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    match (xml.find(&open), xml.find(&close)) {
+        (Some(start), Some(end)) if start < end => {
+            format!("{}{}{}", &xml[..start + open.len()], value, &xml[end..])
+        }
+        _ => xml.to_string(),
+    }
+}
+
+// The name a WIM's parts are based on: `install` for `.../install.wim`.
+pub fn base_name(path: &str) -> &str {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    name.rsplit_once('.').map_or(name, |(stem, _)| stem)
+}
+
+pub fn is_wim(path: &str) -> bool {
+    path.to_ascii_lowercase().ends_with(".wim")
+}
+
+// Splits a WIM file into `.swm` parts in `destination`, returning their
+// paths. `on_progress` gets the byte count of every chunk written.
+pub fn split_file(
+    source: &Path,
+    destination: &Path,
+    part_size: u64,
+    on_progress: &mut dyn FnMut(u64),
+) -> io::Result<Vec<PathBuf>> {
+    let mut file = File::open(source)?;
+    let wim = read_wim(&mut file)?;
+    let base_name = base_name(source.file_name().and_then(|name| name.to_str()).unwrap_or("install"));
+    let parts = wim.plan_split(base_name, part_size)?;
+
+    let mut paths = Vec::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    for part in &parts {
+        let path = destination.join(&part.name);
+        let mut output = File::create(&path)?;
+        let mut reader = part.reader(&mut file);
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            output.write_all(&buffer[..read])?;
+            on_progress(read as u64);
+        }
+        output.flush()?;
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use super::*;
+
+    const FILE_SIZES: [usize; 4] = [3000, 2500, 4000, 1000];
+    const METADATA_SIZE: usize = 600;
+    const PART_SIZE: u64 = 6000;
+
+    fn resource_bytes(header: ResourceHeader, part_number: u16, hash: u8) -> Vec<u8> {
+        Resource {
+            header,
+            part_number,
+            reference_count: 1,
+            hash: [hash; 20],
+        }
+        .to_bytes()
+        .to_vec()
+    }
+
+    // An uncompressed WIM with four file resources and, behind them, the
+    // metadata of two images. Each resource is filled with its own hash
+    // byte, 1 to 6, so copies can be traced back to it.
+    fn synthetic_wim() -> Vec<u8> {
+        let mut wim = vec![0; HEADER_SIZE];
+        let mut table = Vec::new();
+        let sizes = FILE_SIZES.iter().map(|&size| (size, 0)).chain([(METADATA_SIZE, RESOURCE_METADATA); 2]);
+        let mut boot_metadata = ResourceHeader::default();
+        for (index, (size, flags)) in sizes.enumerate() {
+            let header = ResourceHeader {
+                size: size as u64,
+                flags,
+                offset: wim.len() as u64,
+                original_size: size as u64,
+            };
+            boot_metadata = header;
+            table.extend(resource_bytes(header, 1, index as u8 + 1));
+            wim.resize(wim.len() + size, index as u8 + 1);
+        }
+
+        let table_header = ResourceHeader {
+            size: table.len() as u64,
+            flags: 0,
+            offset: wim.len() as u64,
+            original_size: table.len() as u64,
+        };
+        wim.extend(&table);
+
+        let xml = format!(
+            "<WIM><TOTALBYTES>{}</TOTALBYTES><IMAGE INDEX=\"1\"><NAME>Windows 11 Home</NAME>\
+             <DISPLAYNAME>Windows 11 Home N</DISPLAYNAME></IMAGE><IMAGE INDEX=\"2\"><NAME>Windows 11 Pro</NAME></IMAGE></WIM>",
+            wim.len()
+        );
+        let xml: Vec<u8> = std::iter::once(0xFEFF).chain(xml.encode_utf16()).flat_map(u16::to_le_bytes).collect();
+        let xml_header = ResourceHeader {
+            size: xml.len() as u64,
+            flags: 0,
+            offset: wim.len() as u64,
+            original_size: xml.len() as u64,
+        };
+        wim.extend(&xml);
+
+        let header = &mut wim[..HEADER_SIZE];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&0x10D00u32.to_le_bytes());
+        header[20..24].copy_from_slice(&32768u32.to_le_bytes());
+        header[24..40].copy_from_slice(&[0xAB; 16]);
+        header[40..42].copy_from_slice(&1u16.to_le_bytes());
+        header[42..44].copy_from_slice(&1u16.to_le_bytes());
+        header[44..48].copy_from_slice(&2u32.to_le_bytes());
+        header[48..72].copy_from_slice(&table_header.to_bytes());
+        header[72..96].copy_from_slice(&xml_header.to_bytes());
+        header[96..120].copy_from_slice(&boot_metadata.to_bytes());
+        header[120..124].copy_from_slice(&2u32.to_le_bytes());
+        header[124..148].copy_from_slice(&ResourceHeader { size: 16, ..xml_header }.to_bytes());
+        wim
+    }
+
+    // Reads a part through many small reads, as a slow consumer would.
+    fn read_part(part: &SwmPart, source: &mut Cursor<Vec<u8>>) -> Vec<u8> {
+        let mut reader = part.reader(source);
+        let mut bytes = Vec::new();
+        let mut buffer = [0; 777];
+        loop {
+            let read = reader.read(&mut buffer).unwrap();
+            if read == 0 {
+                return bytes;
+            }
+            bytes.extend_from_slice(&buffer[..read]);
+        }
+    }
+
+    #[test]
+    fn reads_the_images_and_resources_of_a_wim() {
+        let wim = read_wim(&mut Cursor::new(synthetic_wim())).unwrap();
+        let images: Vec<(u32, &str)> = wim.images.iter().map(|image| (image.index, image.name.as_str())).collect();
+        assert_eq!(images, [(1, "Windows 11 Home N"), (2, "Windows 11 Pro")]);
+        assert_eq!(wim.resources.len(), 6);
+        assert_eq!(wim.resources.iter().filter(|resource| resource.is_metadata()).count(), 2);
+        assert_eq!(wim.total_parts, 1);
+
+        let error = read_wim(&mut Cursor::new(vec![0; HEADER_SIZE])).err().unwrap();
+        assert_eq!(error.to_string(), "Not a WIM file");
+    }
+
+    #[test]
+    fn splits_into_spanned_parts_of_whole_resources() {
+        let original = synthetic_wim();
+        let wim = read_wim(&mut Cursor::new(original.clone())).unwrap();
+        let parts = wim.plan_split("install", PART_SIZE).unwrap();
+        let names: Vec<&str> = parts.iter().map(|part| part.name.as_str()).collect();
+        assert_eq!(names, ["install.swm", "install2.swm", "install3.swm"]);
+
+        let mut source = Cursor::new(original.clone());
+        let mut seen = HashMap::new();
+        for (index, part) in parts.iter().enumerate() {
+            let part_number = index as u16 + 1;
+            let bytes = read_part(part, &mut source);
+            assert_eq!(bytes.len() as u64, part.size);
+            assert!(part.size <= PART_SIZE);
+
+            // Only the fields that differ between parts are patched.
+            let header = &bytes[..HEADER_SIZE];
+            assert_eq!(header[..16], original[..16]);
+            assert_eq!(u32_at(header, 16), HEADER_FLAG_SPANNED);
+            assert_eq!(header[20..40], original[20..40]);
+            assert_eq!(u16::from_le_bytes([header[40], header[41]]), part_number);
+            assert_eq!(u16::from_le_bytes([header[42], header[43]]), 3);
+            assert_eq!(header[120..124], original[120..124]);
+            assert!(header[124..148].iter().all(|&byte| byte == 0));
+
+            let spanned = read_wim(&mut Cursor::new(bytes.clone())).unwrap();
+            assert_eq!(spanned.total_parts, 3);
+            assert_eq!(spanned.images.len(), 2);
+            let table = ResourceHeader::parse(&header[48..72]);
+            let xml = ResourceHeader::parse(&header[72..96]);
+            assert_eq!(xml.offset + xml.size, part.size);
+            assert!(spanned.xml.contains(&format!("<TOTALBYTES>{}</TOTALBYTES>", xml.offset)));
+
+            for resource in &spanned.resources {
+                let header = resource.header;
+                assert_eq!(resource.part_number, part_number);
+                assert!(header.offset + header.size <= table.offset);
+                let data = &bytes[header.offset as usize..(header.offset + header.size) as usize];
+                assert!(data.iter().all(|&byte| byte == resource.hash[0]));
+                assert!(!resource.is_metadata() || part_number == 1, "metadata outside the first part");
+                assert!(seen.insert(resource.hash[0], header.size).is_none());
+            }
+
+            // The boot image's metadata moves along within the first part.
+            let boot = ResourceHeader::parse(&header[96..120]);
+            if part_number == 1 {
+                assert_eq!(bytes[boot.offset as usize], 6);
+                assert_eq!(boot.size, METADATA_SIZE as u64);
+            } else {
+                assert_eq!(boot.size, 0);
+            }
+
+            let error = spanned.plan_split("install", PART_SIZE).err().unwrap();
+            assert_eq!(error.to_string(), "The WIM is already split");
+        }
+        let sizes = FILE_SIZES.iter().chain(&[METADATA_SIZE; 2]);
+        let expected: HashMap<u8, u64> = (1..=6).zip(sizes).map(|(hash, &size)| (hash, size as u64)).collect();
+        assert_eq!(seen, expected);
+
+        // A part cut short in the source shows up as an error, not as a
+        // short part.
+        let mut truncated = Cursor::new(original[..HEADER_SIZE + 1000].to_vec());
+        let error = io::copy(&mut parts[0].reader(&mut truncated), &mut io::sink()).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn refuses_splits_that_cannot_fit() {
+        let wim = read_wim(&mut Cursor::new(synthetic_wim())).unwrap();
+        let error = wim.plan_split("install", 4000).err().unwrap();
+        assert_eq!(error.to_string(), "A resource of 4000 bytes does not fit in a 4000 byte part");
+
+        // Room for one image's metadata, but not for both. Metadata goes
+        // first, so the files that do not fit either are not reached.
+        let overhead = (HEADER_SIZE + (wim.xml.len() + 64) * 2) as u64;
+        let error = wim.plan_split("install", overhead + 1000).err().unwrap();
+        assert_eq!(error.to_string(), "The image metadata does not fit in the first part");
+    }
+
+    #[test]
+    fn names_parts_after_the_wim() {
+        assert_eq!(base_name("/sources/install.wim"), "install");
+        assert_eq!(part_name("install", 1), "install.swm");
+        assert_eq!(part_name("install", 12), "install12.swm");
+        assert!(is_wim("/SOURCES/INSTALL.WIM"));
+        assert!(!is_wim("/sources/install.esd"));
+    }
+}
@@ -0,0 +1,495 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::bmap::{self, Bmap};
+use crate::compression;
+use crate::iso::ImageInfo;
+use crate::platform::{format_size, BlockTarget};
+use crate::sparse::{self, SparseHeader};
+use crate::vdisk;
+use crate::zip::{self, ZipEntry};
+
+pub const SECTOR_SIZE: usize = 512;
+
+// 1 MiB keeps every write a whole number of sectors and is large enough that
+// USB sticks are not dominated by per-request overhead.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+// The granularity of zero skipping. Large enough that skipping never splits
+// the image into writes too small for a USB stick to take quickly.
+const ZERO_BLOCK_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    Extract,
+    CopyFile,
+    RawImage,
+}
+
+impl WriteMode {
+    pub fn label(&self) -> &'static str {
+        match self {
+            WriteMode::Extract => "Extract files to a FAT32, exFAT or NTFS drive for UEFI (ISO mode)",
+            WriteMode::CopyFile => "Copy ISO file to drive",
+            WriteMode::RawImage => "Write raw image (DD mode)",
+        }
+    }
+
+    // Hybrid images carry their own partition table and are meant to be
+    // written as-is. Everything else, Windows installers in particular, only
+    // boots from a USB drive when its files are put on a filesystem.
+    pub fn recommended_for(info: &ImageInfo) -> Self {
+        if info.hybrid.is_hybrid() {
+            WriteMode::RawImage
+        } else {
+            WriteMode::Extract
+        }
+    }
+}
+
+// What a write is busy with, shown next to its progress.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum WritePhase {
+    #[default]
+    Writing,
+    Verifying,
+}
+
+impl WritePhase {
+    pub fn label(&self) -> &'static str {
+        match self {
+            WritePhase::Writing => "Writing",
+            WritePhase::Verifying => "Verifying",
+        }
+    }
+}
+
+// How a raw image gets written, besides where from and to.
+#[derive(Clone, Default)]
+pub struct RawOptions {
+    // The image to write when the source is a ZIP archive.
+    pub zip_entry: Option<String>,
+    // Limits the write, and the verification, to the blocks it lists.
+    pub bmap: Option<Arc<Bmap>>,
+    pub verify: bool,
+    // Seeks over all-zero blocks instead of writing them. They then keep
+    // whatever the drive held before, which is only harmless on a wiped drive.
+    pub skip_zeros: bool,
+}
+
+// What a raw write did, for the summary shown once it is done.
+#[derive(Clone, Copy, Default)]
+pub struct WriteSummary {
+    pub written: u64,
+    pub skipped: u64,
+}
+
+impl WriteSummary {
+    pub fn describe(&self) -> String {
+        if self.skipped == 0 {
+            format!("Wrote {}.", format_size(self.written))
+        } else {
+            format!(
+                "Wrote {} and skipped {} that the image leaves empty.",
+                format_size(self.written),
+                format_size(self.skipped)
+            )
+        }
+    }
+}
+
+// Progress is left to the source, see `open_image`.
+pub fn write_image<R: Read + ?Sized, W: Write + Seek + ?Sized>(
+    source: &mut R,
+    target: &mut W,
+    skip_zeros: bool,
+) -> io::Result<WriteSummary> {
+    target.seek(SeekFrom::Start(0))?;
+
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut summary = WriteSummary::default();
+    // Zeros passed over but not yet seeked past.
+    let mut pending_skip = 0u64;
+
+    loop {
+        let filled = read_full(source, &mut buffer)?;
+        if filled == 0 {
+            break;
+        }
+
+        // Devices only accept whole sectors, so the tail of an image that is
+        // not sector aligned gets padded with zeros.
+        let padded = filled.div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+        buffer[filled..padded].fill(0);
+
+        // Runs of data blocks still go out in one write; small writes are
+        // slow on USB sticks.
+        let zero_blocks: Vec<bool> = buffer[..padded]
+            .chunks(ZERO_BLOCK_SIZE)
+            .map(|block| skip_zeros && block.iter().all(|&byte| byte == 0))
+            .collect();
+        let mut offset = 0;
+        for run in zero_blocks.chunk_by(|a, b| a == b) {
+            let length = (run.len() * ZERO_BLOCK_SIZE).min(padded - offset);
+            if run[0] {
+                pending_skip += length as u64;
+            } else {
+                if pending_skip > 0 {
+                    target.seek(SeekFrom::Current(pending_skip as i64))?;
+                    summary.skipped += pending_skip;
+                    pending_skip = 0;
+                }
+                target.write_all(&buffer[offset..offset + length])?;
+                summary.written += length as u64;
+            }
+            offset += length;
+        }
+
+        if filled < buffer.len() {
+            break;
+        }
+    }
+
+    // The last sector is always written, so an image file as target ends up
+    // as long as the image even when it ends in zeros.
+    if pending_skip > 0 {
+        target.seek(SeekFrom::Current(pending_skip as i64 - SECTOR_SIZE as i64))?;
+        target.write_all(&[0; SECTOR_SIZE])?;
+        summary.skipped += pending_skip - SECTOR_SIZE as u64;
+        summary.written += SECTOR_SIZE as u64;
+    }
+
+    target.flush()?;
+    Ok(summary)
+}
+
+// Reads the image back from the target and compares it chunk by chunk, so a
+// drive that silently drops writes, or claims more space than it has, is
+// caught. The zero padding after an unaligned tail is not compared.
+pub fn verify_image<R: Read + ?Sized, T: Read + Seek + ?Sized>(source: &mut R, target: &mut T) -> io::Result<()> {
+    target.seek(SeekFrom::Start(0))?;
+
+    let mut expected = vec![0; CHUNK_SIZE];
+    let mut actual = vec![0; CHUNK_SIZE];
+    let mut verified = 0u64;
+
+    loop {
+        let filled = read_full(source, &mut expected)?;
+        if filled == 0 {
+            break;
+        }
+
+        let padded = filled.div_ceil(SECTOR_SIZE) * SECTOR_SIZE;
+        let read = read_full(target, &mut actual[..padded])?;
+        if read < filled || expected[..filled] != actual[..filled] {
+            let mismatch = expected[..filled.min(read)]
+                .iter()
+                .zip(&actual[..filled.min(read)])
+                .position(|(expected, actual)| expected != actual);
+            let message = match mismatch {
+                Some(index) => format!(
+                    "Verification failed: the drive returned different data at byte {} (0x{:X})",
+                    verified + index as u64,
+                    verified + index as u64
+                ),
+                None => format!(
+                    "Verification failed: the drive ends at byte {}, before the end of the image",
+                    verified + read as u64
+                ),
+            };
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        verified += filled as u64;
+
+        if filled < expected.len() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn write_image_to_target(
+    source: &Path,
+    target: &mut dyn BlockTarget,
+    options: &RawOptions,
+    phase: &Arc<Mutex<WritePhase>>,
+    progress: &Arc<Mutex<f32>>,
+) -> Result<WriteSummary, String> {
+    let entry = options.zip_entry.as_deref();
+    let bmap = options.bmap.as_deref();
+
+    // A compressed image whose size is not recorded can only be found too
+    // large once the drive runs out.
+    let total_size = match entry {
+        Some(name) => Some(find_zip_entry(source, name).map_err(|e| e.to_string())?.size),
+        None => match (
+            vdisk::probe(source).map_err(|e| e.to_string())?,
+            compression::probe(source).map_err(|e| e.to_string())?,
+        ) {
+            (Some(disk), _) => Some(disk.size),
+            (None, Some(compressed)) => compressed.size,
+            (None, None) => Some(fs::metadata(source).map_err(|e| e.to_string())?.len()),
+        },
+    };
+    // The probes above only see the file; a sparse image, perhaps inside an
+    // archive, shows itself once the image is opened.
+    let image = open_image(source, entry, progress).map_err(|e| e.to_string())?;
+    let (sparse, mut image) = sparse::detect(image).map_err(|e| e.to_string())?;
+    if sparse.is_some() && bmap.is_some() {
+        return Err("A block map cannot be used with an Android sparse image, which leaves out empty blocks itself.".to_string());
+    }
+    let total_size = sparse.as_ref().map(SparseHeader::image_size).or(total_size);
+    if let (Some(bmap), Some(total_size)) = (bmap, total_size) {
+        if bmap.image_size != total_size {
+            return Err(format!(
+                "The block map describes a {} byte image, but this image is {} bytes.",
+                bmap.image_size, total_size
+            ));
+        }
+    }
+    let total_size = total_size.or(bmap.map(|bmap| bmap.image_size));
+    if let (Some(capacity), Some(total_size)) = (target.capacity().map_err(|e| e.to_string())?, total_size) {
+        if total_size > capacity {
+            return Err(format!(
+                "The image ({} bytes) does not fit on the target ({} bytes).",
+                total_size, capacity
+            ));
+        }
+    }
+
+    let summary = match (&sparse, bmap) {
+        (Some(header), _) => sparse::write_sparse(&mut image, header, target, options.skip_zeros),
+        (None, Some(bmap)) => bmap::write_mapped(&mut image, bmap, target),
+        (None, None) => write_image(&mut image, target, options.skip_zeros),
+    }
+    .map_err(|e| e.to_string())?;
+    target.sync().map_err(|e| e.to_string())?;
+
+    if options.verify {
+        *phase.lock().unwrap() = WritePhase::Verifying;
+        *progress.lock().unwrap() = 0.0;
+        target.drop_cache().map_err(|e| e.to_string())?;
+        match bmap {
+            Some(bmap) => bmap::verify_mapped(target, bmap, progress).map_err(|e| e.to_string())?,
+            None => {
+                let image = open_image(source, entry, progress).map_err(|e| e.to_string())?;
+                let (sparse, mut image) = sparse::detect(image).map_err(|e| e.to_string())?;
+                match sparse {
+                    Some(header) => sparse::verify_sparse(&mut image, &header, target),
+                    None => verify_image(&mut image, target),
+                }
+                .map_err(|e| e.to_string())?;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+// Opens the image for one pass, decompressing it on the fly. Progress
+// follows the bytes taken from the file, the one total known up front for
+// every compression format. Virtual disks are read out of order, so there
+// it follows the disk instead.
+fn open_image(source: &Path, entry: Option<&str>, progress: &Arc<Mutex<f32>>) -> io::Result<Box<dyn Read>> {
+    let mut file = File::open(source)?;
+    let progress = Arc::clone(progress);
+
+    if entry.is_none() {
+        if let Some(disk) = vdisk::open(File::open(source)?)? {
+            let total_size = disk.disk.size;
+            return Ok(Box::new(ProgressReader {
+                inner: disk,
+                read: 0,
+                total_size,
+                progress,
+            }));
+        }
+    }
+
+    if let Some(name) = entry {
+        let entry = find_zip_entry(source, name)?;
+        entry.seek_to_data(&mut file)?;
+        let reader = ProgressReader {
+            inner: file,
+            read: 0,
+            total_size: entry.compressed_size,
+            progress,
+        };
+        return entry.decoder(reader);
+    }
+
+    let compression = compression::detect(&mut file)?;
+    let total_size = file.metadata()?.len();
+    let reader = ProgressReader {
+        inner: file,
+        read: 0,
+        total_size,
+        progress,
+    };
+    compression::decoder(reader, compression)
+}
+
+fn find_zip_entry(source: &Path, name: &str) -> io::Result<ZipEntry> {
+    zip::read_entries(&mut File::open(source)?)?
+        .into_iter()
+        .find(|entry| entry.name == name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the archive", name)))
+}
+
+struct ProgressReader<R> {
+    inner: R,
+    read: u64,
+    total_size: u64,
+    progress: Arc<Mutex<f32>>,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.read += count as u64;
+        if self.total_size > 0 {
+            *self.progress.lock().unwrap() = self.read as f32 / self.total_size as f32;
+        }
+        Ok(count)
+    }
+}
+
+// Reads until the buffer is full or the source is exhausted, so short reads
+// from pipes or decoders never produce a write that ends mid-sector.
+fn read_full<R: Read + ?Sized>(source: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match source.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::platform::{DriveProvider, MockProvider, Transport};
+    use crate::testutil::TempDir;
+
+    const MIB: usize = 1024 * 1024;
+
+    fn pattern(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 251) as u8 + 1).collect()
+    }
+
+    fn mock_target(size: u64) -> Box<dyn BlockTarget> {
+        let mut provider = MockProvider::default();
+        provider.add_drive("mock", "Ayumi", "Test", size, Transport::Usb);
+        provider.open_target(&provider.list_drives()[0]).unwrap()
+    }
+
+    fn raw_options() -> RawOptions {
+        RawOptions {
+            verify: true,
+            ..RawOptions::default()
+        }
+    }
+
+    #[test]
+    fn pads_unaligned_tail_to_a_sector() {
+        let image = pattern(MIB + 1000);
+        let mut target = Cursor::new(Vec::new());
+        let summary = write_image(&mut image.as_slice(), &mut target, false).unwrap();
+
+        let written = target.into_inner();
+        assert_eq!(written.len(), MIB + 1024);
+        assert_eq!(&written[..image.len()], image.as_slice());
+        assert!(written[image.len()..].iter().all(|&byte| byte == 0));
+        assert_eq!((summary.written, summary.skipped), (MIB as u64 + 1024, 0));
+    }
+
+    #[test]
+    fn skips_zero_blocks_but_keeps_the_length() {
+        let mut image = pattern(3 * MIB);
+        image[MIB..2 * MIB].fill(0);
+        image.extend_from_slice(&[0; 2 * ZERO_BLOCK_SIZE]);
+        let mut target = Cursor::new(vec![0xAA; image.len()]);
+        let summary = write_image(&mut image.as_slice(), &mut target, true).unwrap();
+
+        let written = target.into_inner();
+        assert_eq!(written.len(), image.len());
+        assert!(written[MIB..2 * MIB].iter().all(|&byte| byte == 0xAA));
+        assert_eq!(&written[2 * MIB..3 * MIB], &image[2 * MIB..3 * MIB]);
+        assert_eq!(summary.skipped, (MIB + 2 * ZERO_BLOCK_SIZE - SECTOR_SIZE) as u64);
+        assert_eq!(summary.written + summary.skipped, image.len() as u64);
+    }
+
+    #[test]
+    fn writes_and_verifies_on_a_mock_drive() {
+        let dir = TempDir::new("writer-mock");
+        let image = pattern(3 * MIB + 100);
+        let source = dir.write("image.img", &image);
+        let mut target = mock_target(4 * MIB as u64);
+        let phase = Arc::new(Mutex::new(WritePhase::Writing));
+        let progress = Arc::new(Mutex::new(0.0));
+
+        assert!(write_image_to_target(&source, target.as_mut(), &raw_options(), &phase, &progress).is_ok());
+        assert!(*phase.lock().unwrap() == WritePhase::Verifying);
+        assert_eq!(*progress.lock().unwrap(), 1.0);
+
+        let mut written = vec![0; 4 * MIB];
+        target.seek(SeekFrom::Start(0)).unwrap();
+        target.read_exact(&mut written).unwrap();
+        assert_eq!(&written[..image.len()], image.as_slice());
+        assert!(written[image.len()..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn refuses_an_image_larger_than_the_drive() {
+        let dir = TempDir::new("writer-too-large");
+        let source = dir.write("image.img", &pattern(2 * MIB));
+        let mut target = mock_target(MIB as u64);
+        let phase = Arc::new(Mutex::new(WritePhase::Writing));
+        let progress = Arc::new(Mutex::new(0.0));
+
+        let error = write_image_to_target(&source, target.as_mut(), &raw_options(), &phase, &progress).err().unwrap();
+        assert!(error.contains("does not fit"), "{}", error);
+
+        // Nothing reaches the drive before the size check.
+        let mut written = vec![0xFF; MIB];
+        target.seek(SeekFrom::Start(0)).unwrap();
+        target.read_exact(&mut written).unwrap();
+        assert!(written.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn verification_reports_the_first_corrupted_byte() {
+        let dir = TempDir::new("writer-corrupt");
+        let image = pattern(3 * MIB + 100);
+        let source = dir.write("image.img", &image);
+        let mut target = mock_target(4 * MIB as u64);
+        let phase = Arc::new(Mutex::new(WritePhase::Writing));
+        let progress = Arc::new(Mutex::new(0.0));
+        let options = RawOptions::default();
+        write_image_to_target(&source, target.as_mut(), &options, &phase, &progress).unwrap();
+
+        // Two flipped bytes in the second chunk, as a failing drive would
+        // return them; only the first is reported.
+        let first = 2 * MIB as u64 - 4096 + 77;
+        for offset in [first + 3000, first] {
+            target.seek(SeekFrom::Start(offset)).unwrap();
+            target.write_all(&[!image[offset as usize]]).unwrap();
+        }
+
+        let error = verify_image(&mut image.as_slice(), target.as_mut()).err().unwrap();
+        assert!(error.kind() == io::ErrorKind::InvalidData);
+        assert_eq!(
+            error.to_string(),
+            format!("Verification failed: the drive returned different data at byte {} (0x{:X})", first, first)
+        );
+    }
+}
@@ -0,0 +1,480 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use flate2::read::DeflateDecoder;
+
+const LOCAL_HEADER_MAGIC: u32 = 0x0403_4B50;
+const CENTRAL_HEADER_MAGIC: u32 = 0x0201_4B50;
+const END_MAGIC: u32 = 0x0605_4B50;
+const ZIP64_END_MAGIC: u32 = 0x0606_4B50;
+const ZIP64_LOCATOR_MAGIC: u32 = 0x0706_4B50;
+
+const LOCAL_HEADER_SIZE: usize = 30;
+const CENTRAL_HEADER_SIZE: usize = 46;
+const END_SIZE: usize = 22;
+const ZIP64_END_SIZE: usize = 56;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+// The end record closes the archive, followed only by a comment of at most
+// 64 KiB.
+const MAX_COMMENT_SIZE: usize = 0xFFFF;
+
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+// General purpose flags.
+const FLAG_ENCRYPTED: u16 = 0x0001;
+const FLAG_UTF8: u16 = 0x0800;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+// File types worth writing to a drive; firmware bundles carry readmes and
+// tools next to them.
+const IMAGE_EXTENSIONS: [&str; 5] = ["iso", "img", "simg", "raw", "bin"];
+
+#[derive(Clone)]
+pub struct ZipEntry {
+    pub name: String,
+    pub size: u64,
+    pub compressed_size: u64,
+    method: u16,
+    flags: u16,
+    crc32: u32,
+    local_header_offset: u64,
+}
+
+impl ZipEntry {
+    pub fn is_disk_image(&self) -> bool {
+        let name = self.name.to_ascii_lowercase();
+        IMAGE_EXTENSIONS
+            .iter()
+            .any(|extension| name.rsplit_once('.').is_some_and(|(_, ext)| ext == *extension))
+    }
+
+    pub fn method_label(&self) -> String {
+        match self.method {
+            METHOD_STORED => "stored".to_string(),
+            METHOD_DEFLATED => "deflated".to_string(),
+            method => format!("method {}", method),
+        }
+    }
+
+    // Moves `source` to the first stored byte of the entry. Only the local
+    // header knows how long its extra field is.
+    pub fn seek_to_data<R: Read + Seek + ?Sized>(&self, source: &mut R) -> io::Result<()> {
+        let mut header = [0; LOCAL_HEADER_SIZE];
+        source.seek(SeekFrom::Start(self.local_header_offset))?;
+        source.read_exact(&mut header)?;
+        if u32_at(&header, 0) != LOCAL_HEADER_MAGIC {
+            return Err(invalid(&format!("{} has no local header", self.name)));
+        }
+        let skip = u16_at(&header, 26) as i64 + u16_at(&header, 28) as i64;
+        source.seek(SeekFrom::Current(skip))?;
+        Ok(())
+    }
+
+    // Wraps the entry's stored bytes so they come out decompressed, with the
+    // CRC checked once the last byte has been read.
+    pub fn decoder<'a, R: Read + 'a>(&self, stored: R) -> io::Result<Box<dyn Read + 'a>> {
+        if self.flags & FLAG_ENCRYPTED != 0 {
+            return Err(unsupported(&format!("{} is encrypted", self.name)));
+        }
+        let stored = stored.take(self.compressed_size);
+        let data: Box<dyn Read + 'a> = match self.method {
+            METHOD_STORED => Box::new(stored),
+            METHOD_DEFLATED => Box::new(DeflateDecoder::new(stored)),
+            method => {
+                return Err(unsupported(&format!(
+                    "{} uses compression method {}; only stored and deflated entries are supported",
+                    self.name, method
+                )))
+            }
+        };
+        Ok(Box::new(CheckedReader {
+            inner: data,
+            name: self.name.clone(),
+            hasher: crc32fast::Hasher::new(),
+            expected_crc: self.crc32,
+            expected_size: self.size,
+            read: 0,
+        }))
+    }
+}
+
+pub fn is_zip<R: Read + Seek + ?Sized>(source: &mut R) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    source.seek(SeekFrom::Start(0))?;
+    let is_zip = match source.read_exact(&mut magic) {
+        Ok(()) => matches!(u32::from_le_bytes(magic), LOCAL_HEADER_MAGIC | END_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+    source.seek(SeekFrom::Start(0))?;
+    Ok(is_zip)
+}
+
+// Lists the archive from its central directory, the only reliable index:
+// local headers may leave their sizes to a descriptor after the data.
+pub fn read_entries<R: Read + Seek + ?Sized>(source: &mut R) -> io::Result<Vec<ZipEntry>> {
+    let file_size = source.seek(SeekFrom::End(0))?;
+    if file_size < END_SIZE as u64 {
+        return Err(invalid("Not a ZIP archive: too short"));
+    }
+    let tail_size = file_size.min((END_SIZE + MAX_COMMENT_SIZE) as u64) as usize;
+    let mut tail = vec![0; tail_size];
+    source.seek(SeekFrom::Start(file_size - tail_size as u64))?;
+    source.read_exact(&mut tail)?;
+
+    let end = (0..=tail_size.saturating_sub(END_SIZE))
+        .rev()
+        .find(|&offset| u32_at(&tail, offset) == END_MAGIC)
+        .ok_or_else(|| invalid("Not a ZIP archive: no end of central directory record"))?;
+    let end_offset = file_size - (tail_size - end) as u64;
+    let record = &tail[end..end + END_SIZE];
+    let mut entry_count = u16_at(record, 10) as u64;
+    let mut directory_size = u32_at(record, 12) as u64;
+    let mut directory_offset = u32_at(record, 16) as u64;
+
+    // Zip64 archives max out the classic fields and keep the real values
+    // in a second end record, found through the locator just before.
+    if entry_count == 0xFFFF || directory_size == 0xFFFF_FFFF || directory_offset == 0xFFFF_FFFF {
+        let locator_offset = end_offset
+            .checked_sub(ZIP64_LOCATOR_SIZE as u64)
+            .ok_or_else(|| invalid("The Zip64 end of central directory locator is missing"))?;
+        let mut locator = [0; ZIP64_LOCATOR_SIZE];
+        source.seek(SeekFrom::Start(locator_offset))?;
+        source.read_exact(&mut locator)?;
+        if u32_at(&locator, 0) != ZIP64_LOCATOR_MAGIC {
+            return Err(invalid("The Zip64 end of central directory locator is missing"));
+        }
+
+        let mut end64 = [0; ZIP64_END_SIZE];
+        source.seek(SeekFrom::Start(u64_at(&locator, 8)))?;
+        source.read_exact(&mut end64)?;
+        if u32_at(&end64, 0) != ZIP64_END_MAGIC {
+            return Err(invalid("The Zip64 end of central directory record is damaged"));
+        }
+        entry_count = u64_at(&end64, 32);
+        directory_size = u64_at(&end64, 40);
+        directory_offset = u64_at(&end64, 48);
+    }
+
+    if directory_offset.checked_add(directory_size).is_none_or(|end| end > file_size) {
+        return Err(invalid("The central directory lies outside the archive"));
+    }
+    let mut directory = vec![0; directory_size as usize];
+    source.seek(SeekFrom::Start(directory_offset))?;
+    source.read_exact(&mut directory)?;
+
+    let mut entries = Vec::new();
+    let mut position = 0;
+    for _ in 0..entry_count {
+        let header = directory
+            .get(position..position + CENTRAL_HEADER_SIZE)
+            .filter(|header| u32_at(header, 0) == CENTRAL_HEADER_MAGIC)
+            .ok_or_else(|| invalid("The central directory is damaged"))?;
+        let name_length = u16_at(header, 28) as usize;
+        let extra_length = u16_at(header, 30) as usize;
+        let comment_length = u16_at(header, 32) as usize;
+        let variable = directory
+            .get(position + CENTRAL_HEADER_SIZE..position + CENTRAL_HEADER_SIZE + name_length + extra_length)
+            .ok_or_else(|| invalid("The central directory is damaged"))?;
+        let (name, extra) = variable.split_at(name_length);
+
+        // Names are UTF-8 when flagged so, otherwise code page 437, which
+        // agrees with ASCII.
+        let flags = u16_at(header, 8);
+        let name = if flags & FLAG_UTF8 != 0 {
+            String::from_utf8_lossy(name).into_owned()
+        } else {
+            name.iter().map(|&byte| if byte.is_ascii() { byte as char } else { '?' }).collect()
+        };
+
+        let mut entry = ZipEntry {
+            name,
+            size: u32_at(header, 24) as u64,
+            compressed_size: u32_at(header, 20) as u64,
+            method: u16_at(header, 10),
+            flags,
+            crc32: u32_at(header, 16),
+            local_header_offset: u32_at(header, 42) as u64,
+        };
+        apply_zip64_extra(&mut entry, extra);
+        entries.push(entry);
+
+        position += CENTRAL_HEADER_SIZE + name_length + extra_length + comment_length;
+    }
+    Ok(entries)
+}
+
+// The Zip64 extra field holds, in this order, whichever of the sizes and
+// the header offset did not fit in 32 bits.
+fn apply_zip64_extra(entry: &mut ZipEntry, mut extra: &[u8]) {
+    while extra.len() >= 4 {
+        let id = u16_at(extra, 0);
+        let size = (u16_at(extra, 2) as usize).min(extra.len() - 4);
+        let mut data = &extra[4..4 + size];
+        extra = &extra[4 + size..];
+        if id != ZIP64_EXTRA_ID {
+            continue;
+        }
+
+        for field in [&mut entry.size, &mut entry.compressed_size, &mut entry.local_header_offset] {
+            if *field == 0xFFFF_FFFF && data.len() >= 8 {
+                *field = u64_at(data, 0);
+                data = &data[8..];
+            }
+        }
+    }
+}
+
+// Passes the entry through, failing at the end if it did not come out as
+// long or with the CRC the central directory promised.
+struct CheckedReader<R> {
+    inner: R,
+    name: String,
+    hasher: crc32fast::Hasher,
+    expected_crc: u32,
+    expected_size: u64,
+    read: u64,
+}
+
+impl<R: Read> Read for CheckedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.hasher.update(&buf[..count]);
+        self.read += count as u64;
+
+        if count == 0 && !buf.is_empty() {
+            if self.read != self.expected_size {
+                return Err(invalid(&format!(
+                    "{} came out as {} bytes instead of {}",
+                    self.name, self.read, self.expected_size
+                )));
+            }
+            if self.hasher.clone().finalize() != self.expected_crc {
+                return Err(invalid(&format!("{} is damaged: its CRC-32 does not match", self.name)));
+            }
+        }
+        Ok(count)
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn unsupported(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use super::*;
+
+    struct Stored<'a> {
+        name: &'a str,
+        method: u16,
+        stored: Vec<u8>,
+        crc32: u32,
+        size: u64,
+    }
+
+    fn stored<'a>(name: &'a str, data: &[u8]) -> Stored<'a> {
+        Stored {
+            name,
+            method: METHOD_STORED,
+            stored: data.to_vec(),
+            crc32: crc32fast::hash(data),
+            size: data.len() as u64,
+        }
+    }
+
+    fn deflated<'a>(name: &'a str, data: &[u8]) -> Stored<'a> {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        Stored {
+            method: METHOD_DEFLATED,
+            stored: encoder.finish().unwrap(),
+            ..stored(name, data)
+        }
+    }
+
+    // Lays out an archive the way zip tools do. With `zip64`, every size and
+    // offset that can goes into the Zip64 fields and records instead.
+    fn archive(entries: &[Stored], zip64: bool, comment: &[u8]) -> Vec<u8> {
+        let maxed = |value: u64| if zip64 { 0xFFFF_FFFF } else { value as u32 };
+        let (mut out, mut central) = (Vec::new(), Vec::new());
+        for entry in entries {
+            let offset = out.len() as u64;
+            let mut fixed = Vec::new();
+            fixed.extend_from_slice(&[20, 0]);
+            fixed.extend_from_slice(&FLAG_UTF8.to_le_bytes());
+            fixed.extend_from_slice(&entry.method.to_le_bytes());
+            fixed.extend_from_slice(&[0; 4]);
+            fixed.extend_from_slice(&entry.crc32.to_le_bytes());
+            fixed.extend_from_slice(&maxed(entry.stored.len() as u64).to_le_bytes());
+            fixed.extend_from_slice(&maxed(entry.size).to_le_bytes());
+            fixed.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+
+            // The local header carries an extra field of its own.
+            out.extend_from_slice(&LOCAL_HEADER_MAGIC.to_le_bytes());
+            out.extend_from_slice(&fixed);
+            out.extend_from_slice(&5u16.to_le_bytes());
+            out.extend_from_slice(entry.name.as_bytes());
+            out.extend_from_slice(&[0x75, 0x70, 1, 0, 0]);
+            out.extend_from_slice(&entry.stored);
+
+            // An unrelated extended timestamp field comes first.
+            let mut extra = vec![0x55, 0x54, 5, 0, 1, 0, 0, 0, 0];
+            if zip64 {
+                extra.extend_from_slice(&ZIP64_EXTRA_ID.to_le_bytes());
+                extra.extend_from_slice(&24u16.to_le_bytes());
+                for value in [entry.size, entry.stored.len() as u64, offset] {
+                    extra.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+            central.extend_from_slice(&CENTRAL_HEADER_MAGIC.to_le_bytes());
+            central.extend_from_slice(&[20, 3]);
+            central.extend_from_slice(&fixed);
+            central.extend_from_slice(&(extra.len() as u16).to_le_bytes());
+            central.extend_from_slice(&[0; 6]);
+            central.extend_from_slice(&0o100644u32.to_le_bytes());
+            central.extend_from_slice(&maxed(offset).to_le_bytes());
+            central.extend_from_slice(entry.name.as_bytes());
+            central.extend_from_slice(&extra);
+        }
+
+        let (directory_offset, directory_size) = (out.len() as u64, central.len() as u64);
+        out.extend_from_slice(&central);
+        if zip64 {
+            let end64_offset = out.len() as u64;
+            out.extend_from_slice(&ZIP64_END_MAGIC.to_le_bytes());
+            out.extend_from_slice(&(ZIP64_END_SIZE as u64 - 12).to_le_bytes());
+            out.extend_from_slice(&[45, 0, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            for value in [entries.len() as u64, entries.len() as u64, directory_size, directory_offset] {
+                out.extend_from_slice(&value.to_le_bytes());
+            }
+            out.extend_from_slice(&ZIP64_LOCATOR_MAGIC.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&end64_offset.to_le_bytes());
+            out.extend_from_slice(&1u32.to_le_bytes());
+        }
+        let count = if zip64 { 0xFFFF } else { entries.len() as u16 };
+        out.extend_from_slice(&END_MAGIC.to_le_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&count.to_le_bytes());
+        out.extend_from_slice(&maxed(directory_size).to_le_bytes());
+        out.extend_from_slice(&maxed(directory_offset).to_le_bytes());
+        out.extend_from_slice(&(comment.len() as u16).to_le_bytes());
+        out.extend_from_slice(comment);
+        out
+    }
+
+    fn read_back(archive: &[u8], entry: &ZipEntry) -> io::Result<Vec<u8>> {
+        let mut source = Cursor::new(archive);
+        entry.seek_to_data(&mut source)?;
+        let mut data = Vec::new();
+        entry.decoder(source)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn image(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i % 241) as u8).collect()
+    }
+
+    #[test]
+    fn finds_the_end_record_behind_a_comment() {
+        let (disk, notes) = (image(50_000), b"Flash firmware.img with a USB writer".to_vec());
+        for comment in [Vec::new(), b"Built by CI".to_vec(), vec![b'#'; MAX_COMMENT_SIZE]] {
+            let archive = archive(&[deflated("firmware.img", &disk), stored("README.txt", &notes)], false, &comment);
+            assert!(is_zip(&mut Cursor::new(&archive)).unwrap());
+
+            let entries = read_entries(&mut Cursor::new(&archive)).unwrap();
+            let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+            assert_eq!(names, ["firmware.img", "README.txt"]);
+            assert!(entries[0].is_disk_image() && !entries[1].is_disk_image());
+            assert_eq!((entries[0].method_label(), entries[0].size), ("deflated".to_string(), 50_000));
+            assert!(entries[0].compressed_size < entries[0].size);
+            assert_eq!(read_back(&archive, &entries[0]).unwrap(), disk);
+            assert_eq!(read_back(&archive, &entries[1]).unwrap(), notes);
+        }
+
+        let mut cut = archive(&[stored("a.img", b"data")], false, b"");
+        cut.truncate(cut.len() - 4);
+        assert!(read_entries(&mut Cursor::new(&cut)).is_err());
+        assert!(!is_zip(&mut Cursor::new(b"\x7FELF")).unwrap());
+    }
+
+    #[test]
+    fn reads_sizes_and_offsets_from_zip64_fields() {
+        let (first, second) = (image(3000), image(70_000));
+        let archive = archive(&[stored("first.img", &first), deflated("disk.raw", &second)], true, b"zip64");
+        let entries = read_entries(&mut Cursor::new(&archive)).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].size, entries[0].compressed_size, entries[0].local_header_offset), (3000, 3000, 0));
+        assert_eq!(entries[1].local_header_offset, (LOCAL_HEADER_SIZE + 9 + 5 + 3000) as u64);
+        assert_eq!(read_back(&archive, &entries[0]).unwrap(), first);
+        assert_eq!(read_back(&archive, &entries[1]).unwrap(), second);
+
+        // Only the maxed fields are in the extra field, in its fixed order.
+        let mut entry = entries[0].clone();
+        entry.size = 10;
+        entry.compressed_size = 0xFFFF_FFFF;
+        entry.local_header_offset = 0xFFFF_FFFF;
+        let mut extra = vec![0x01, 0x00, 16, 0];
+        extra.extend_from_slice(&0x1_2345_6789u64.to_le_bytes());
+        extra.extend_from_slice(&0x2_0000_0000u64.to_le_bytes());
+        apply_zip64_extra(&mut entry, &extra);
+        assert_eq!((entry.size, entry.compressed_size, entry.local_header_offset), (10, 0x1_2345_6789, 0x2_0000_0000));
+    }
+
+    #[test]
+    fn damaged_entries_fail_at_their_end() {
+        let data = image(5000);
+        let checked = |crc32: u32, size: u64| {
+            let mut reader = CheckedReader {
+                inner: data.as_slice(),
+                name: "disk.img".to_string(),
+                hasher: crc32fast::Hasher::new(),
+                expected_crc: crc32,
+                expected_size: size,
+                read: 0,
+            };
+            let mut out = Vec::new();
+            reader.read_to_end(&mut out).map(|_| out).map_err(|e| e.to_string())
+        };
+
+        assert_eq!(checked(crc32fast::hash(&data), 5000).unwrap(), data);
+        assert_eq!(
+            checked(crc32fast::hash(&data) ^ 1, 5000).err().unwrap(),
+            "disk.img is damaged: its CRC-32 does not match"
+        );
+        assert_eq!(
+            checked(crc32fast::hash(&data), 5001).err().unwrap(),
+            "disk.img came out as 5000 bytes instead of 5001"
+        );
+
+        // Through an archive, a flipped stored byte is caught the same way.
+        let mut archive = archive(&[stored("disk.img", &data)], false, b"");
+        let entries = read_entries(&mut Cursor::new(&archive)).unwrap();
+        archive[LOCAL_HEADER_SIZE + 8 + 5 + 100] ^= 0xFF;
+        assert_eq!(
+            read_back(&archive, &entries[0]).err().unwrap().to_string(),
+            "disk.img is damaged: its CRC-32 does not match"
+        );
+    }
+}